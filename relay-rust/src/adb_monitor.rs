@@ -207,10 +207,7 @@ impl AdbMonitor {
 
     fn start_adb_daemon() -> bool {
         info!(target: TAG, "Restarting adb daemon");
-        match process::Command::new("adb")
-            .args(&["start-server"])
-            .status()
-        {
+        match process::Command::new("adb").args(["start-server"]).status() {
             Ok(exit_status) => {
                 if exit_status.success() {
                     true
@@ -0,0 +1,217 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::logger::{self, LogFilter};
+use log::*;
+use relaylib::{
+    ConfigLoader, EmulationPolicy, HostnameRoutes, LoopbackPolicy, NatTable, NoProxy, RelayConfig,
+    SocketBindingPolicy,
+};
+use std::fs;
+use std::sync::Arc;
+
+const TAG: &str = "ConfigFile";
+
+/// Settings read from a configuration file (`-R`), which may be reloaded while the relay is
+/// running.
+///
+/// Syntax: one `KEY=VALUE` per line, the value having the syntax of the matching command line
+/// parameter: `nat` (`-n`, may be repeated), `loopback` (`-l`), `no-proxy` (`-b`),
+/// `hostname-routes` (`-s`), `binding` (`-i`), `emulation` (`-e`) and `log` (`-L`). Empty lines
+/// and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Settings {
+    nat_table: Option<NatTable>,
+    loopback_policy: Option<LoopbackPolicy>,
+    no_proxy: Option<NoProxy>,
+    hostname_routes: Option<HostnameRoutes>,
+    socket_binding_policy: Option<SocketBindingPolicy>,
+    emulation_policy: Option<EmulationPolicy>,
+    log_filter: Option<LogFilter>,
+}
+
+// parse the value of a setting which must not be repeated
+fn parse_once<T, F>(setting: &mut Option<T>, key: &str, value: &str, parse: F) -> Result<(), String>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    if setting.is_some() {
+        return Err(format!("Setting \"{}\" already set", key));
+    }
+    *setting = Some(parse(value).map_err(|err| format!("Invalid \"{}\": {}", key, err))?);
+    Ok(())
+}
+
+impl Settings {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut settings = Self::default();
+        let mut nat_rules = Vec::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid setting (expected KEY=VALUE): {}", line))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                // the rules of all the "nat" lines, in order
+                "nat" => nat_rules.push(value),
+                "loopback" => parse_once(
+                    &mut settings.loopback_policy,
+                    key,
+                    value,
+                    LoopbackPolicy::parse,
+                )?,
+                "no-proxy" => parse_once(&mut settings.no_proxy, key, value, NoProxy::parse)?,
+                "hostname-routes" => parse_once(
+                    &mut settings.hostname_routes,
+                    key,
+                    value,
+                    HostnameRoutes::parse,
+                )?,
+                "binding" => parse_once(
+                    &mut settings.socket_binding_policy,
+                    key,
+                    value,
+                    SocketBindingPolicy::parse,
+                )?,
+                "emulation" => parse_once(
+                    &mut settings.emulation_policy,
+                    key,
+                    value,
+                    EmulationPolicy::parse,
+                )?,
+                "log" => parse_once(&mut settings.log_filter, key, value, LogFilter::parse)?,
+                _ => return Err(format!("Unknown setting: {}", key)),
+            }
+        }
+        if !nat_rules.is_empty() {
+            let nat_table = NatTable::parse(&nat_rules.join("\n"))
+                .map_err(|err| format!("Invalid \"nat\": {}", err))?;
+            settings.nat_table = Some(nat_table);
+        }
+        Ok(settings)
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read configuration from \"{}\": {}", path, err))?;
+        Self::parse(&content).map_err(|err| format!("Invalid configuration \"{}\": {}", path, err))
+    }
+}
+
+/// Apply the settings of a configuration file on startup and whenever the relay reloads its
+/// configuration (on SIGHUP or on `POST /reload` on the control port).
+///
+/// The settings absent from the file keep the value given on the command line.
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: String,
+    // the settings not stored in the relay config, to restore once removed from the file
+    emulation_policy: EmulationPolicy,
+    log_filter: LogFilter,
+}
+
+impl ConfigFile {
+    pub fn new(path: String, emulation_policy: EmulationPolicy, log_filter: LogFilter) -> Self {
+        Self {
+            path,
+            emulation_policy,
+            log_filter,
+        }
+    }
+}
+
+impl ConfigLoader for ConfigFile {
+    fn load(&self, config: &mut RelayConfig) -> Result<(), String> {
+        // read all the settings before applying any of them
+        let settings = Settings::read(&self.path)?;
+        info!(target: TAG, "Loading configuration from \"{}\"", self.path);
+        if let Some(nat_table) = settings.nat_table {
+            for rule in nat_table.rules() {
+                info!(target: TAG, "NAT rule: {}", rule);
+            }
+            config.set_nat_table(nat_table);
+        }
+        if let Some(loopback_policy) = settings.loopback_policy {
+            info!(target: TAG, "Loopback policy: {}", loopback_policy);
+            config.set_loopback_policy(loopback_policy);
+        }
+        if let Some(no_proxy) = settings.no_proxy {
+            info!(target: TAG, "No proxy for: {}", no_proxy);
+            config.set_no_proxy(no_proxy);
+        }
+        if let Some(hostname_routes) = settings.hostname_routes {
+            info!(target: TAG, "Host name routes: {}", hostname_routes);
+            config.set_upstream_selector(Arc::new(hostname_routes));
+        }
+        if let Some(socket_binding_policy) = settings.socket_binding_policy {
+            info!(target: TAG, "Socket binding: {}", socket_binding_policy);
+            config.set_socket_binding_policy(socket_binding_policy);
+        }
+        let emulation_policy = settings
+            .emulation_policy
+            .unwrap_or_else(|| self.emulation_policy.clone());
+        info!(target: TAG, "Network emulation: {}", emulation_policy);
+        config.network_emulation().set_policy(emulation_policy);
+        let log_filter = settings
+            .log_filter
+            .unwrap_or_else(|| self.log_filter.clone());
+        info!(target: TAG, "Log levels: {}", log_filter);
+        logger::set_filter(log_filter);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        let settings = Settings::parse(
+            "# test settings\n\
+             nat = 192.0.2.2=127.0.0.1\n\
+             \n\
+             nat=192.0.2.3:80=127.0.0.1:8080\n\
+             loopback = none;0=8080\n\
+             emulation = 3g\n\
+             log = warn,Router=debug\n",
+        )
+        .unwrap();
+        // 2 custom rules + the default one
+        assert_eq!(3, settings.nat_table.unwrap().rules().len());
+        assert_eq!("none;0=8080", settings.loopback_policy.unwrap().to_string());
+        assert!(settings.emulation_policy.is_some());
+        assert_eq!(
+            LogFilter::parse("warn,Router=debug").unwrap(),
+            settings.log_filter.unwrap()
+        );
+        assert!(settings.no_proxy.is_none());
+        assert!(settings.hostname_routes.is_none());
+        assert!(settings.socket_binding_policy.is_none());
+    }
+
+    #[test]
+    fn reject_invalid_settings() {
+        assert!(Settings::parse("loopback").is_err());
+        assert!(Settings::parse("proxy = socks5://127.0.0.1:1080").is_err());
+        assert!(Settings::parse("log = info\nlog = debug").is_err());
+        assert!(Settings::parse("emulation = 5g").is_err());
+        assert!(Settings::parse("nat = 192.0.2.2").is_err());
+    }
+}
@@ -16,10 +16,20 @@
 
 mod relay;
 pub use crate::relay::byte_buffer;
+pub use crate::relay::parse_size;
+pub use crate::relay::{
+    ByteCount, CloseReason, ConfigLoader, ConfigReload, ConnectionId, ConnectionLimits,
+    Credentials, DeviceTunnels, DropReason, EmulationPolicy, ExportFormat, ExportTarget,
+    FlowExporter, FlowLog, FlowRecord, HostnameRoutes, LoopbackAccess, LoopbackPolicy,
+    MemoryBudget, MemoryUsage, NatRule, NatTable, NetworkEmulation, NetworkProfile, NoProxy,
+    Protocol, ProxyConfig, ProxyKind, Quota, QuotaAction, QuotaPolicy, RelayConfig, RelayObserver,
+    Route, SocketBinding, SocketBindingPolicy, TrafficAccounting, TrafficCounters,
+    UpstreamSelector,
+};
 
 use crate::relay::Relay;
 use std::io;
 
-pub fn relay(port: u16) -> io::Result<()> {
-    Relay::new(port).run()
+pub fn relay(port: u16, config: RelayConfig) -> io::Result<()> {
+    Relay::new(port, config).run()
 }
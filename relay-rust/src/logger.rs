@@ -16,30 +16,271 @@
 
 use chrono::prelude::Local;
 use log::*;
+use relaylib::parse_size;
+use std::cmp::Reverse;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
 
-static LOGGER: SimpleLogger = SimpleLogger;
-const THRESHOLD: LevelFilter = LevelFilter::Info;
+static LOGGER: SimpleLogger = SimpleLogger {
+    filter: RwLock::new(LogFilter::new(LevelFilter::Info)),
+    json: AtomicBool::new(false),
+    file: Mutex::new(None),
+};
 
-pub struct SimpleLogger;
+/// Environment variable overriding the default log filter (same syntax as `-L`).
+pub const ENV_LOG_FILTER: &str = "GNIREHTET_LOG";
+/// Environment variable overriding the default log output (same syntax as `-o`).
+pub const ENV_LOG_OUTPUT: &str = "GNIREHTET_LOG_OUTPUT";
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEPT_FILES: u32 = 5;
+
+/// Log levels, globally and per target.
+///
+/// Syntax: `[LEVEL][,TARGET=LEVEL...]`, for example `info,TcpConnection=debug,Router=trace`. A
+/// target also matches its sub-targets (`relaylib` matches `relaylib::relay`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut filter = Self::new(LevelFilter::Info);
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.find('=') {
+                Some(index) => {
+                    let target = entry[..index].trim();
+                    if target.is_empty() {
+                        return Err(format!("Missing log target: \"{}\"", entry));
+                    }
+                    let level = parse_level(&entry[index + 1..])?;
+                    filter.targets.push((target.to_string(), level));
+                }
+                None => filter.default = parse_level(entry)?,
+            }
+        }
+        // the most specific targets first
+        filter
+            .targets
+            .sort_by_key(|(target, _)| Reverse(target.len()));
+        Ok(filter)
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| {
+                target == prefix
+                    || (target.starts_with(prefix.as_str())
+                        && target[prefix.len()..].starts_with("::"))
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most verbose level of all the targets.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(value.trim()).map_err(|_| format!("Invalid log level: \"{}\"", value))
+}
+
+/// Destination and format of the logs.
+///
+/// Syntax: `PATH[,size=SIZE][,keep=COUNT][,json]`, where `PATH` is a file (or `-` for the
+/// console). A file is rotated when it reaches `SIZE` (default 10MiB): it is renamed to
+/// `PATH.1`, the previous `PATH.1` to `PATH.2`, and so on, keeping `COUNT` files (default 5).
+/// With `json`, every record is written as a JSON object on its own line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogOutput {
+    path: Option<PathBuf>,
+    max_size: u64,
+    keep: u32,
+    json: bool,
+}
+
+impl LogOutput {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut items = s.split(',').map(str::trim);
+        let path = match items.next() {
+            Some("-") => None,
+            Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => return Err(format!("Missing log file: \"{}\"", s)),
+        };
+        let mut output = Self {
+            path,
+            max_size: DEFAULT_MAX_FILE_SIZE,
+            keep: DEFAULT_KEPT_FILES,
+            json: false,
+        };
+        for item in items {
+            if item == "json" {
+                output.json = true;
+            } else if let Some(value) = item.strip_prefix("size=") {
+                output.max_size = parse_size(value)?;
+                if output.max_size == 0 {
+                    return Err(format!("Invalid log file size: \"{}\"", value));
+                }
+            } else if let Some(value) = item.strip_prefix("keep=") {
+                output.keep = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of log files: \"{}\"", value))?;
+            } else {
+                return Err(format!("Invalid log output option: \"{}\"", item));
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Log file, renamed once it reaches its maximal size.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = File::create(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+}
+
+pub struct SimpleLogger {
+    filter: RwLock<LogFilter>,
+    json: AtomicBool,
+    file: Mutex<Option<RotatingFile>>,
+}
+
+impl SimpleLogger {
+    fn format_text(record: &Record) -> String {
+        let date = Local::now();
+        let formatted_date = date.format("%Y-%m-%d %H:%M:%S%.3f");
+        format!(
+            "{} {} {}: {}",
+            formatted_date,
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    }
+
+    fn format_json(record: &Record) -> String {
+        let date = Local::now();
+        let mut json = String::new();
+        json.push_str("{\"time\":");
+        push_json_string(
+            &mut json,
+            &date.format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+        );
+        json.push_str(",\"level\":");
+        push_json_string(&mut json, record.level().as_str());
+        json.push_str(",\"target\":");
+        push_json_string(&mut json, record.target());
+        if let Some(name) = thread::current().name() {
+            json.push_str(",\"thread\":");
+            push_json_string(&mut json, name);
+        }
+        json.push_str(",\"message\":");
+        push_json_string(&mut json, &record.args().to_string());
+        json.push('}');
+        json
+    }
+}
 
 impl Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= THRESHOLD
+        metadata.level() <= self.filter.read().unwrap().level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let date = Local::now();
-            let formatted_date = date.format("%Y-%m-%d %H:%M:%S%.3f");
-            let msg = format!(
-                "{} {} {}: {}",
-                formatted_date,
-                record.level(),
-                record.target(),
-                record.args()
-            );
-            if record.level() == Level::Error {
+            let msg = if self.json.load(Ordering::Relaxed) {
+                Self::format_json(record)
+            } else {
+                Self::format_text(record)
+            };
+            let mut file = self.file.lock().unwrap();
+            if let Some(file) = file.as_mut() {
+                if let Err(err) = file.write_line(&msg) {
+                    eprintln!("Cannot write to the log file: {}", err);
+                    eprintln!("{}", msg);
+                }
+            } else if record.level() == Level::Error {
                 eprintln!("{}", msg);
             } else {
                 println!("{}", msg);
@@ -48,12 +289,143 @@ impl Log for SimpleLogger {
     }
 
     fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.file.flush();
+        }
         io::stdout().flush().unwrap();
         io::stderr().flush().unwrap();
     }
 }
 
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
 pub fn init() -> Result<(), SetLoggerError> {
-    set_max_level(THRESHOLD);
+    set_max_level(LevelFilter::Info);
     set_logger(&LOGGER)
 }
+
+/// Apply the log settings from the environment variables, if any.
+pub fn configure_from_env() -> Result<(), String> {
+    if let Ok(value) = env::var(ENV_LOG_FILTER) {
+        set_filter(LogFilter::parse(&value)?);
+    }
+    if let Ok(value) = env::var(ENV_LOG_OUTPUT) {
+        set_output(&LogOutput::parse(&value)?)?;
+    }
+    Ok(())
+}
+
+pub fn filter() -> LogFilter {
+    LOGGER.filter.read().unwrap().clone()
+}
+
+pub fn set_filter(filter: LogFilter) {
+    set_max_level(filter.max_level());
+    *LOGGER.filter.write().unwrap() = filter;
+}
+
+pub fn set_output(output: &LogOutput) -> Result<(), String> {
+    let file = match output.path {
+        Some(ref path) => Some(
+            RotatingFile::open(path.clone(), output.max_size, output.keep)
+                .map_err(|err| format!("Cannot open log file \"{}\": {}", path.display(), err))?,
+        ),
+        None => None,
+    };
+    *LOGGER.file.lock().unwrap() = file;
+    LOGGER.json.store(output.json, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let filter = LogFilter::parse("warn,TcpConnection=debug,Router=trace").unwrap();
+        assert_eq!(LevelFilter::Warn, filter.level("Client"));
+        assert_eq!(LevelFilter::Debug, filter.level("TcpConnection"));
+        assert_eq!(LevelFilter::Trace, filter.level("Router"));
+        assert_eq!(LevelFilter::Trace, filter.max_level());
+
+        let filter = LogFilter::parse("Router=debug").unwrap();
+        assert_eq!(LevelFilter::Info, filter.level("Main"));
+
+        assert!(LogFilter::parse("verbose").is_err());
+        assert!(LogFilter::parse("=debug").is_err());
+    }
+
+    #[test]
+    fn test_filter_sub_targets() {
+        let filter = LogFilter::parse("error,mio=debug,mio::poll=trace").unwrap();
+        assert_eq!(LevelFilter::Trace, filter.level("mio::poll"));
+        assert_eq!(LevelFilter::Debug, filter.level("mio::sys"));
+        assert_eq!(LevelFilter::Error, filter.level("mioo"));
+    }
+
+    #[test]
+    fn test_display_filter() {
+        let filter = LogFilter::parse("debug,Router=trace").unwrap();
+        assert_eq!("debug,Router=trace", filter.to_string());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let output = LogOutput::parse("/tmp/gnirehtet.log,size=1MiB,keep=3,json").unwrap();
+        assert_eq!(Some(PathBuf::from("/tmp/gnirehtet.log")), output.path);
+        assert_eq!(1 << 20, output.max_size);
+        assert_eq!(3, output.keep);
+        assert!(output.json);
+
+        let output = LogOutput::parse("-,json").unwrap();
+        assert_eq!(None, output.path);
+
+        assert!(LogOutput::parse("").is_err());
+        assert!(LogOutput::parse("file.log,size=0").is_err());
+        assert!(LogOutput::parse("file.log,colors").is_err());
+    }
+
+    #[test]
+    fn test_json_string() {
+        let mut json = String::new();
+        push_json_string(&mut json, "a \"b\"\\\n\u{1}");
+        assert_eq!(r#""a \"b\"\\\n\u0001""#, json);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = env::temp_dir().join(format!("gnirehtet-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("relay.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in &["line1", "line2", "line3", "line4"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!("line4\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "line3\n",
+            fs::read_to_string(dir.join("relay.log.1")).unwrap()
+        );
+        assert_eq!(
+            "line2\n",
+            fs::read_to_string(dir.join("relay.log.2")).unwrap()
+        );
+        assert!(!dir.join("relay.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -22,14 +22,21 @@ extern crate relaylib;
 
 mod adb_monitor;
 mod cli_args;
+mod config_file;
 mod execution_error;
+mod json;
 mod logger;
+mod top;
 
 use crate::adb_monitor::AdbMonitor;
 use crate::cli_args::CommandLineArguments;
+use crate::config_file::ConfigFile;
 use crate::execution_error::{Cmd, CommandExecutionError, ProcessIoError, ProcessStatusError};
+use relaylib::{ConfigReload, DeviceTunnels, NetworkEmulation, RelayConfig};
 use std::env;
+use std::fs;
 use std::process::{self, exit};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -66,11 +73,12 @@ const COMMANDS: &[&dyn Command] = &[
     &RestartCommand,
     &TunnelCommand,
     &RelayCommand,
+    &TopCommand,
 ];
 
 trait Command {
     fn command(&self) -> &'static str;
-    fn accepted_parameters(&self) -> u8;
+    fn accepted_parameters(&self) -> u32;
     fn description(&self) -> &'static str;
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError>;
 }
@@ -86,13 +94,14 @@ struct StopCommand;
 struct RestartCommand;
 struct TunnelCommand;
 struct RelayCommand;
+struct TopCommand;
 
 impl Command for InstallCommand {
     fn command(&self) -> &'static str {
         "install"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
     }
 
@@ -112,7 +121,7 @@ impl Command for UninstallCommand {
         "uninstall"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
     }
 
@@ -132,7 +141,7 @@ impl Command for ReinstallCommand {
         "reinstall"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
     }
 
@@ -150,11 +159,30 @@ impl Command for RunCommand {
         "run"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
             | cli_args::PARAM_PORT
+            | cli_args::PARAM_NAT_RULES
+            | cli_args::PARAM_LOOPBACK_POLICY
+            | cli_args::PARAM_PROXY
+            | cli_args::PARAM_NO_PROXY
+            | cli_args::PARAM_HOSTNAME_ROUTES
+            | cli_args::PARAM_SOCKET_BINDING
+            | cli_args::PARAM_NETWORK_EMULATION
+            | cli_args::PARAM_QUOTA
+            | cli_args::PARAM_TRAFFIC_FILE
+            | cli_args::PARAM_FLOW_LOG
+            | cli_args::PARAM_FLOW_EXPORT
+            | cli_args::PARAM_CONNECTION_LIMITS
+            | cli_args::PARAM_WORKER_THREADS
+            | cli_args::PARAM_MEMORY_BUDGET
+            | cli_args::PARAM_LOG_FILTER
+            | cli_args::PARAM_LOG_OUTPUT
+            | cli_args::PARAM_CONTROL_PORT
+            | cli_args::PARAM_METRICS_PORT
+            | cli_args::PARAM_CONFIG_FILE
     }
 
     fn description(&self) -> &'static str {
@@ -171,6 +199,7 @@ impl Command for RunCommand {
             args.dns_servers(),
             args.routes(),
             args.port(),
+            create_relay_config(args),
         )
     }
 }
@@ -180,8 +209,29 @@ impl Command for AutorunCommand {
         "autorun"
     }
 
-    fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_DNS_SERVERS | cli_args::PARAM_ROUTES | cli_args::PARAM_PORT
+    fn accepted_parameters(&self) -> u32 {
+        cli_args::PARAM_DNS_SERVERS
+            | cli_args::PARAM_ROUTES
+            | cli_args::PARAM_PORT
+            | cli_args::PARAM_NAT_RULES
+            | cli_args::PARAM_LOOPBACK_POLICY
+            | cli_args::PARAM_PROXY
+            | cli_args::PARAM_NO_PROXY
+            | cli_args::PARAM_HOSTNAME_ROUTES
+            | cli_args::PARAM_SOCKET_BINDING
+            | cli_args::PARAM_NETWORK_EMULATION
+            | cli_args::PARAM_QUOTA
+            | cli_args::PARAM_TRAFFIC_FILE
+            | cli_args::PARAM_FLOW_LOG
+            | cli_args::PARAM_FLOW_EXPORT
+            | cli_args::PARAM_CONNECTION_LIMITS
+            | cli_args::PARAM_WORKER_THREADS
+            | cli_args::PARAM_MEMORY_BUDGET
+            | cli_args::PARAM_LOG_FILTER
+            | cli_args::PARAM_LOG_OUTPUT
+            | cli_args::PARAM_CONTROL_PORT
+            | cli_args::PARAM_METRICS_PORT
+            | cli_args::PARAM_CONFIG_FILE
     }
 
     fn description(&self) -> &'static str {
@@ -191,7 +241,12 @@ impl Command for AutorunCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_autorun(args.dns_servers(), args.routes(), args.port())
+        cmd_autorun(
+            args.dns_servers(),
+            args.routes(),
+            args.port(),
+            create_relay_config(args),
+        )
     }
 }
 
@@ -200,7 +255,7 @@ impl Command for StartCommand {
         "start"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
@@ -237,7 +292,7 @@ impl Command for AutostartCommand {
         "autostart"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_DNS_SERVERS | cli_args::PARAM_ROUTES | cli_args::PARAM_PORT
     }
 
@@ -249,7 +304,8 @@ impl Command for AutostartCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_autostart(args.dns_servers(), args.routes(), args.port())
+        // the relay runs in another process, the devices use its main port
+        cmd_autostart(args.dns_servers(), args.routes(), args.port(), None)
     }
 }
 
@@ -258,7 +314,7 @@ impl Command for StopCommand {
         "stop"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
     }
 
@@ -278,7 +334,7 @@ impl Command for RestartCommand {
         "restart"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
@@ -306,7 +362,7 @@ impl Command for TunnelCommand {
         "tunnel"
     }
 
-    fn accepted_parameters(&self) -> u8 {
+    fn accepted_parameters(&self) -> u32 {
         cli_args::PARAM_SERIAL | cli_args::PARAM_PORT
     }
 
@@ -327,16 +383,156 @@ impl Command for RelayCommand {
         "relay"
     }
 
-    fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_NONE | cli_args::PARAM_PORT
+    fn accepted_parameters(&self) -> u32 {
+        cli_args::PARAM_NONE
+            | cli_args::PARAM_PORT
+            | cli_args::PARAM_NAT_RULES
+            | cli_args::PARAM_LOOPBACK_POLICY
+            | cli_args::PARAM_PROXY
+            | cli_args::PARAM_NO_PROXY
+            | cli_args::PARAM_HOSTNAME_ROUTES
+            | cli_args::PARAM_SOCKET_BINDING
+            | cli_args::PARAM_NETWORK_EMULATION
+            | cli_args::PARAM_QUOTA
+            | cli_args::PARAM_TRAFFIC_FILE
+            | cli_args::PARAM_FLOW_LOG
+            | cli_args::PARAM_FLOW_EXPORT
+            | cli_args::PARAM_CONNECTION_LIMITS
+            | cli_args::PARAM_WORKER_THREADS
+            | cli_args::PARAM_MEMORY_BUDGET
+            | cli_args::PARAM_LOG_FILTER
+            | cli_args::PARAM_LOG_OUTPUT
+            | cli_args::PARAM_CONTROL_PORT
+            | cli_args::PARAM_METRICS_PORT
+            | cli_args::PARAM_CONFIG_FILE
     }
 
     fn description(&self) -> &'static str {
-        "Start the relay server in the current terminal."
+        "Start the relay server in the current terminal.\n\
+         If -n is given, then rewrite the destination of the connections\n\
+         matching the rules (MATCH=TARGET, where MATCH is CIDR[:PORT] or\n\
+         HOST[:PORT] and TARGET is IP[:PORT]). A HOST (a name or *.DOMAIN)\n\
+         matches the addresses the device resolved from it. The first\n\
+         matching rule applies. Use -n @FILE to read the rules from a file\n\
+         (one per line).\n\
+         10.0.2.2 is mapped to the host 'localhost' unless another rule\n\
+         matches.\n\
+         If -l is given, then restrict the access to the host loopback:\n\
+         'all' (default), 'none' or a list of ports (e.g. 8080,8081). It\n\
+         may be set per device serial with ';'-separated entries, for\n\
+         example \"none;emulator-5554=8080\" only allows emulator-5554 to\n\
+         access port 8080. The per-device settings only apply to the\n\
+         devices started by \"run\" or \"autorun\", which connect through\n\
+         their own tunnel port.\n\
+         If -x is given, then open the connections through a SOCKS5 proxy\n\
+         (socks5://[USER:PASSWORD@]HOST:PORT) or an HTTP proxy supporting\n\
+         CONNECT (http://[USER:PASSWORD@]HOST:PORT). UDP is relayed using\n\
+         UDP ASSOCIATE with SOCKS5, and directly with an HTTP proxy.\n\
+         Connections to the host loopback are never proxied.\n\
+         If -b is given, then connect directly to the listed destinations\n\
+         (CIDR, IP address, host name or *.DOMAIN), bypassing the proxy.\n\
+         Host names are matched against the names the device resolved.\n\
+         If -s is given, then select the route by the host name the device\n\
+         resolved (through DNS) to the destination: PATTERN=direct or\n\
+         PATTERN=proxy, where PATTERN is a host name, *.DOMAIN or * (any).\n\
+         The first matching rule applies; by default, use the proxy. For\n\
+         example, \"*.internal.example=direct\" only proxies external hosts.\n\
+         If -i is given, then bind the upstream sockets: src:IP (source\n\
+         address), dev:INTERFACE (SO_BINDTODEVICE) and/or mark:MARK\n\
+         (SO_MARK), separated by ','. It may be set per device serial\n\
+         with ';'-separated entries, for example\n\
+         \"dev:eth0;emulator-5554=dev:wlan0\" (like -l).\n\
+         If -e is given, then emulate degraded network conditions: a\n\
+         profile (edge, 3g, 4g, flaky-wifi or none) and/or settings among\n\
+         delay=MS, jitter=MS, rate=BITS (or down=/up=, e.g. 1mbit),\n\
+         loss=PERCENT and reorder=PERCENT, separated by ','. It may be set\n\
+         per device serial with ';'-separated entries, for example\n\
+         \"none;emulator-5554=3g,loss=2%\" (like -l). Use -e @FILE to read\n\
+         the settings from a file, which is reloaded whenever it changes.\n\
+         If -t is given, then persist the traffic (bytes and packets in\n\
+         each direction) of each device to a file, restored on reconnection\n\
+         and on the next run. The devices are only identified when started\n\
+         by \"run\" or \"autorun\"; the other clients are counted for their\n\
+         session only.\n\
+         If -a is given, then append a record to a file for every closed\n\
+         connection (one JSON object per line): client id, protocol,\n\
+         source, destination (before and after NAT), start and end times,\n\
+         bytes and packets in each direction and close reason.\n\
+         If -f is given, then export the closed connections to a flow\n\
+         collector over UDP: HOST:PORT[,format=FORMAT], where FORMAT is\n\
+         'ipfix' (default) or 'netflow9', for example \"127.0.0.1:4739\".\n\
+         Each connection is exported as two records, one per direction.\n\
+         If -q is given, then limit the data (in both directions) of each\n\
+         client: LIMIT[:block] refuses the new connections once LIMIT\n\
+         (e.g. 500MB, 2GB) is reached, LIMIT:throttle=RATE caps the\n\
+         bandwidth instead. It may be set per device serial with\n\
+         ';'-separated entries, for example \"1GB;emulator-5554=none\" (like\n\
+         -l). Combine with -t to count the data of the devices across runs.\n\
+         If -c is given, then limit the number of simultaneous connections\n\
+         (TCP connections and UDP flows) per client and/or for the whole\n\
+         relay, for example \"client=256,relay=4096\". When a limit is\n\
+         reached, the least recently used UDP flow of the client is evicted;\n\
+         if there is none, the new connection is refused.\n\
+         If -w is given, then run the clients on THREADS event loops (one\n\
+         thread each), or one per CPU core with 'auto' (default is 1). Each\n\
+         client is handed to the least loaded event loop on connection.\n\
+         If -m is given, then limit the memory of the connection buffers of\n\
+         all the clients (e.g. 256MiB). Once it is reached, the connections\n\
+         slow down until some memory is released.\n\
+         If -L is given, then set the log level, globally and/or per\n\
+         component, for example \"info,TcpConnection=debug,Router=trace\"\n\
+         (levels: off, error, warn, info, debug, trace). The default may\n\
+         be set by the GNIREHTET_LOG environment variable.\n\
+         If -o is given, then write the logs to a file, rotated when it\n\
+         reaches a size, for example \"relay.log,size=10MiB,keep=5\"; add\n\
+         \",json\" to write one JSON object per line ('-' instead of a file\n\
+         name writes to the console). The default may be set by the\n\
+         GNIREHTET_LOG_OUTPUT environment variable.\n\
+         If -C is given, then serve the statistics of the relay (clients,\n\
+         connections, traffic, buffers) as JSON on http://127.0.0.1:PORT.\n\
+         GET /stats to read them, DELETE /clients/ID to disconnect a client\n\
+         and DELETE /clients/ID/connections/PROTOCOL/SOURCE/DESTINATION to\n\
+         close a connection. Run \"gnirehtet top -C PORT\" to watch them.\n\
+         If -M is given, then serve the metrics of the relay (clients,\n\
+         connections by state, traffic, dropped packets, connect failures,\n\
+         labelled by client id) in the Prometheus text format on\n\
+         http://127.0.0.1:PORT/metrics (also served on the -C port).\n\
+         If -R is given, then read settings from a file, one KEY=VALUE per\n\
+         line: nat (-n, may be repeated), loopback (-l), no-proxy (-b),\n\
+         hostname-routes (-s), binding (-i), emulation (-e) and log (-L).\n\
+         They override the command line, and are reloaded on SIGHUP or on\n\
+         POST /reload (with -C), without disconnecting the clients: the new\n\
+         connections use the new settings, the existing ones are kept."
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_relay(args.port())?;
+        cmd_relay(args.port(), create_relay_config(args))?;
+        Ok(())
+    }
+}
+
+impl Command for TopCommand {
+    fn command(&self) -> &'static str {
+        "top"
+    }
+
+    fn accepted_parameters(&self) -> u32 {
+        cli_args::PARAM_CONTROL_PORT
+    }
+
+    fn description(&self) -> &'static str {
+        "Display the activity of a running relay, refreshed every second:\n\
+         the connected clients and their throughput, and the active\n\
+         connections, the busiest first.\n\
+         The relay must be started with -C PORT; pass the same -C PORT\n\
+         here (default is 31417)."
+    }
+
+    fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
+        let port = args
+            .control_port()
+            .unwrap_or(cli_args::DEFAULT_CONTROL_PORT);
+        top::run(port)?;
         Ok(())
     }
 }
@@ -362,9 +558,18 @@ fn cmd_run(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config: RelayConfig,
 ) -> Result<(), CommandExecutionError> {
+    // identify the device, so that its settings apply to its clients
+    let serial = serial.map(String::from).or_else(get_serial);
+    let tunnel_port = match serial {
+        Some(ref serial) => open_device_tunnel(config.device_tunnels(), serial, port),
+        None => port,
+    };
+    let serial = serial.as_deref();
+
     // start in parallel so that the relay server is ready when the client connects
-    async_start(serial, dns_servers, routes, port);
+    async_start(serial, dns_servers, routes, tunnel_port);
 
     let ctrlc_serial = serial.map(String::from);
     ctrlc::set_handler(move || {
@@ -379,27 +584,29 @@ fn cmd_run(
     })
     .expect("Error setting Ctrl-C handler");
 
-    cmd_relay(port)
+    cmd_relay(port, config)
 }
 
 fn cmd_autorun(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config: RelayConfig,
 ) -> Result<(), CommandExecutionError> {
     {
         let autostart_dns_servers = dns_servers.map(String::from);
         let autostart_routes = routes.map(String::from);
+        let device_tunnels = config.device_tunnels().clone();
         thread::spawn(move || {
             let dns_servers = autostart_dns_servers.as_ref().map(String::as_ref);
             let routes = autostart_routes.as_ref().map(String::as_ref);
-            if let Err(err) = cmd_autostart(dns_servers, routes, port) {
+            if let Err(err) = cmd_autostart(dns_servers, routes, port, Some(device_tunnels)) {
                 error!(target: TAG, "Cannot auto start clients: {}", err);
             }
         });
     }
 
-    cmd_relay(port)
+    cmd_relay(port, config)
 }
 
 fn cmd_start(
@@ -436,17 +643,23 @@ fn cmd_start(
     exec_adb(serial, adb_args)
 }
 
+/// `device_tunnels` opens the tunnel ports of the devices, if the relay runs in this process.
 fn cmd_autostart(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    device_tunnels: Option<DeviceTunnels>,
 ) -> Result<(), CommandExecutionError> {
     let start_dns_servers = dns_servers.map(String::from);
     let start_routes = routes.map(String::from);
     let mut adb_monitor = AdbMonitor::new(Box::new(move |serial: &str| {
         let dns_servers = start_dns_servers.as_ref().map(String::as_ref);
         let routes = start_routes.as_ref().map(String::as_ref);
-        async_start(Some(serial), dns_servers, routes, port)
+        let tunnel_port = match device_tunnels {
+            Some(ref device_tunnels) => open_device_tunnel(device_tunnels, serial, port),
+            None => port,
+        };
+        async_start(Some(serial), dns_servers, routes, tunnel_port)
     }));
     adb_monitor.monitor();
     Ok(())
@@ -479,12 +692,190 @@ fn cmd_tunnel(serial: Option<&str>, port: u16) -> Result<(), CommandExecutionErr
     )
 }
 
-fn cmd_relay(port: u16) -> Result<(), CommandExecutionError> {
+fn cmd_relay(port: u16, config: RelayConfig) -> Result<(), CommandExecutionError> {
     info!(target: TAG, "Starting relay server on port {}...", port);
-    relaylib::relay(port)?;
+    for rule in config.nat_table().rules() {
+        info!(target: TAG, "NAT rule: {}", rule);
+    }
+    info!(
+        target: TAG,
+        "Loopback policy: {}",
+        config.loopback_policy()
+    );
+    info!(
+        target: TAG,
+        "Socket binding: {}",
+        config.socket_binding_policy()
+    );
+    info!(
+        target: TAG,
+        "Network emulation: {}",
+        config.network_emulation().policy()
+    );
+    if let Some(path) = config.traffic_accounting().path() {
+        info!(target: TAG, "Traffic file: {}", path.display());
+    }
+    if let Some(path) = config.flow_log().path() {
+        info!(target: TAG, "Flow log: {}", path.display());
+    }
+    if let Some(target) = config.flow_exporter().target() {
+        info!(target: TAG, "Flow export: {}", target);
+    }
+    info!(target: TAG, "Data quota: {}", config.quota_policy());
+    info!(
+        target: TAG,
+        "Connection limits: {}",
+        config.connection_limits()
+    );
+    info!(target: TAG, "Worker threads: {}", config.worker_threads());
+    info!(target: TAG, "Memory budget: {}", config.memory_budget());
+    if let Some(proxy) = config.upstream_proxy() {
+        info!(target: TAG, "Upstream proxy: {}", proxy);
+        let no_proxy = config.no_proxy().to_string();
+        if !no_proxy.is_empty() {
+            info!(target: TAG, "No proxy for: {}", no_proxy);
+        }
+    }
+    relaylib::relay(port, config)?;
     Ok(())
 }
 
+fn create_relay_config(args: &CommandLineArguments) -> RelayConfig {
+    let mut config = RelayConfig::new();
+    if let Some(path) = args.config_file() {
+        // before spawning any thread, so that none of them receives SIGHUP
+        watch_reload_signal(config.config_reload().clone());
+        let emulation_policy = args.emulation_policy().cloned().unwrap_or_default();
+        let config_file = ConfigFile::new(path.to_string(), emulation_policy, logger::filter());
+        config.set_config_loader(Some(Arc::new(config_file)));
+    }
+    if let Some(nat_table) = args.nat_table() {
+        config.set_nat_table(nat_table.clone());
+    }
+    if let Some(loopback_policy) = args.loopback_policy() {
+        config.set_loopback_policy(loopback_policy.clone());
+    }
+    if let Some(socket_binding_policy) = args.socket_binding_policy() {
+        config.set_socket_binding_policy(socket_binding_policy.clone());
+    }
+    config.set_upstream_proxy(args.proxy().cloned());
+    if let Some(no_proxy) = args.no_proxy() {
+        config.set_no_proxy(no_proxy.clone());
+    }
+    if let Some(hostname_routes) = args.hostname_routes() {
+        info!(target: TAG, "Host name routes: {}", hostname_routes);
+        config.set_upstream_selector(Arc::new(hostname_routes.clone()));
+    }
+    if let Some(emulation_policy) = args.emulation_policy() {
+        let network_emulation = NetworkEmulation::new(emulation_policy.clone());
+        if let Some(path) = args.emulation_file() {
+            watch_emulation_file(path, network_emulation.clone());
+        }
+        config.set_network_emulation(network_emulation);
+    }
+    if let Some(quota_policy) = args.quota_policy() {
+        config.set_quota_policy(quota_policy.clone());
+    }
+    if let Some(traffic_accounting) = args.traffic_accounting() {
+        config.set_traffic_accounting(traffic_accounting.clone());
+    }
+    if let Some(flow_log) = args.flow_log() {
+        config.set_flow_log(flow_log.clone());
+    }
+    if let Some(flow_exporter) = args.flow_exporter() {
+        config.set_flow_exporter(flow_exporter.clone());
+    }
+    if let Some(connection_limits) = args.connection_limits() {
+        config.set_connection_limits(connection_limits.clone());
+    }
+    if let Some(worker_threads) = args.worker_threads() {
+        config.set_worker_threads(worker_threads);
+    }
+    if let Some(memory_budget) = args.memory_budget() {
+        config.set_memory_budget(memory_budget.clone());
+    }
+    config.set_control_port(args.control_port());
+    config.set_metrics_port(args.metrics_port());
+    config
+}
+
+// reload the emulation policy whenever the file is modified
+fn watch_emulation_file(path: &str, network_emulation: NetworkEmulation) {
+    let path = String::from(path);
+    let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        match CommandLineArguments::read_emulation_policy(&path) {
+            Ok(policy) => {
+                info!(target: TAG, "Network emulation: {}", policy);
+                network_emulation.set_policy(policy);
+            }
+            Err(err) => error!(target: TAG, "Cannot reload network emulation: {}", err),
+        }
+    });
+}
+
+// reload the configuration of the relay on SIGHUP
+#[cfg(target_os = "linux")]
+fn watch_reload_signal(config_reload: ConfigReload) {
+    // block SIGHUP in this thread (and the threads it spawns), to receive it only from sigwait()
+    let mut signals: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+    }
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            error!(target: TAG, "Cannot wait for SIGHUP");
+            return;
+        }
+        info!(target: TAG, "SIGHUP received, reloading the configuration");
+        if let Err(err) = config_reload.request() {
+            error!(target: TAG, "Cannot reload the configuration: {}", err);
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_reload_signal(_: ConfigReload) {
+    warn!(target: TAG, "Reloading on SIGHUP is not supported on this platform");
+}
+
+// open the tunnel port of the device (or fall back to the main port)
+fn open_device_tunnel(device_tunnels: &DeviceTunnels, serial: &str, port: u16) -> u16 {
+    match device_tunnels.open(serial) {
+        Ok(tunnel_port) => tunnel_port,
+        Err(err) => {
+            error!(
+                target: TAG,
+                "Cannot open the tunnel port of device {}: {}", serial, err
+            );
+            port
+        }
+    }
+}
+
+// the serial of the device adb selects without a serial, if there is exactly one
+fn get_serial() -> Option<String> {
+    let adb = get_adb_path();
+    debug!(target: TAG, "Execute: {:?} get-serialno", adb);
+    let output = process::Command::new(&adb)
+        .arg("get-serialno")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let serial = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Some(serial).filter(|serial| !serial.is_empty() && serial != "unknown")
+}
+
 fn async_start(serial: Option<&str>, dns_servers: Option<&str>, routes: Option<&str>, port: u16) {
     let start_serial = serial.map(String::from);
     let start_dns_servers = dns_servers.map(String::from);
@@ -551,7 +942,7 @@ fn must_install_client(serial: Option<&str>) -> Result<bool, CommandExecutionErr
                 // read the versionCode of the installed package
                 if let Some(index) = dumpsys.find("    versionCode=") {
                     let start = index + 16; // size of "    versionCode=\""
-                    if let Some(end) = (&dumpsys[start..]).find(' ') {
+                    if let Some(end) = dumpsys[start..].find(' ') {
                         let installed_version_code = &dumpsys[start..start + end];
                         Ok(installed_version_code != REQUIRED_APK_VERSION_CODE)
                     } else {
@@ -605,6 +996,63 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_ROUTES) != 0 {
         msg.push_str(" [-r ROUTE[,ROUTE2,...]]");
     }
+    if (accepted_parameters & cli_args::PARAM_NAT_RULES) != 0 {
+        msg.push_str(" [-n RULE[,RULE2,...]]");
+    }
+    if (accepted_parameters & cli_args::PARAM_LOOPBACK_POLICY) != 0 {
+        msg.push_str(" [-l POLICY]");
+    }
+    if (accepted_parameters & cli_args::PARAM_PROXY) != 0 {
+        msg.push_str(" [-x PROXY]");
+    }
+    if (accepted_parameters & cli_args::PARAM_NO_PROXY) != 0 {
+        msg.push_str(" [-b NO_PROXY[,NO_PROXY2,...]]");
+    }
+    if (accepted_parameters & cli_args::PARAM_HOSTNAME_ROUTES) != 0 {
+        msg.push_str(" [-s ROUTE[,ROUTE2,...]]");
+    }
+    if (accepted_parameters & cli_args::PARAM_SOCKET_BINDING) != 0 {
+        msg.push_str(" [-i BINDING]");
+    }
+    if (accepted_parameters & cli_args::PARAM_NETWORK_EMULATION) != 0 {
+        msg.push_str(" [-e PROFILE]");
+    }
+    if (accepted_parameters & cli_args::PARAM_QUOTA) != 0 {
+        msg.push_str(" [-q QUOTA]");
+    }
+    if (accepted_parameters & cli_args::PARAM_TRAFFIC_FILE) != 0 {
+        msg.push_str(" [-t FILE]");
+    }
+    if (accepted_parameters & cli_args::PARAM_FLOW_LOG) != 0 {
+        msg.push_str(" [-a FILE]");
+    }
+    if (accepted_parameters & cli_args::PARAM_FLOW_EXPORT) != 0 {
+        msg.push_str(" [-f COLLECTOR]");
+    }
+    if (accepted_parameters & cli_args::PARAM_CONNECTION_LIMITS) != 0 {
+        msg.push_str(" [-c LIMITS]");
+    }
+    if (accepted_parameters & cli_args::PARAM_WORKER_THREADS) != 0 {
+        msg.push_str(" [-w THREADS]");
+    }
+    if (accepted_parameters & cli_args::PARAM_MEMORY_BUDGET) != 0 {
+        msg.push_str(" [-m SIZE]");
+    }
+    if (accepted_parameters & cli_args::PARAM_LOG_FILTER) != 0 {
+        msg.push_str(" [-L LEVELS]");
+    }
+    if (accepted_parameters & cli_args::PARAM_LOG_OUTPUT) != 0 {
+        msg.push_str(" [-o OUTPUT]");
+    }
+    if (accepted_parameters & cli_args::PARAM_CONTROL_PORT) != 0 {
+        msg.push_str(" [-C PORT]");
+    }
+    if (accepted_parameters & cli_args::PARAM_METRICS_PORT) != 0 {
+        msg.push_str(" [-M PORT]");
+    }
+    if (accepted_parameters & cli_args::PARAM_CONFIG_FILE) != 0 {
+        msg.push_str(" [-R FILE]");
+    }
     msg.push('\n');
     for desc_line in command.description().split('\n') {
         msg.push_str("      ");
@@ -619,8 +1067,22 @@ fn print_command_usage(command: &dyn Command) {
     eprint!("{}", msg);
 }
 
+fn configure_logger(arguments: &CommandLineArguments) -> Result<(), String> {
+    if let Some(log_filter) = arguments.log_filter() {
+        logger::set_filter(log_filter.clone());
+    }
+    if let Some(log_output) = arguments.log_output() {
+        logger::set_output(log_output)?;
+    }
+    Ok(())
+}
+
 fn main() {
     logger::init().unwrap();
+    if let Err(err) = logger::configure_from_env() {
+        error!(target: TAG, "Invalid log settings from the environment: {}", err);
+        exit(2);
+    }
     let mut args = env::args();
     // args.nth(1) will consume the two first arguments (the binary name and the command name)
     if let Some(command_name) = args.nth(1) {
@@ -634,6 +1096,10 @@ fn main() {
                     CommandLineArguments::parse(command.accepted_parameters(), args.collect());
                 match arguments {
                     Ok(arguments) => {
+                        if let Err(err) = configure_logger(&arguments) {
+                            error!(target: TAG, "{}", err);
+                            exit(2);
+                        }
                         if let Err(err) = command.execute(&arguments) {
                             error!(target: TAG, "Execution error: {}", err);
                             exit(3);
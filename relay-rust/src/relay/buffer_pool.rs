@@ -0,0 +1,318 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::traffic::{self, ByteCount};
+
+/// The smallest block allocated, so that small buffers do not grow one byte at a time.
+pub const MIN_BLOCK_SIZE: usize = 4 * 1024;
+
+// idle memory kept by the pool of each thread, the excess is freed
+const MAX_POOLED_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct MemoryCounters {
+    used: AtomicUsize,
+    pooled: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Memory held by the buffers of all the clients, with an optional limit.
+///
+/// The limit is soft: once it is reached, the buffers stop growing beyond their guaranteed
+/// capacity, so that the connections apply backpressure (the TCP windows close, the datagrams are
+/// dropped) until some memory is released.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget {
+    counters: Arc<MemoryCounters>,
+    limit: Option<usize>,
+}
+
+/// Snapshot of the memory held by the buffers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes held by the buffers in use.
+    pub used: usize,
+    /// Bytes kept by the pools for reuse.
+    pub pooled: usize,
+    /// Highest value of `used` so far.
+    pub peak: usize,
+    pub limit: Option<usize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            counters: Default::default(),
+            limit,
+        }
+    }
+
+    /// Parse a limit (like `256MiB`), or `none`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "none" {
+            return Ok(Self::new(None));
+        }
+        let limit = traffic::parse_size(s)?;
+        if limit == 0 {
+            return Err(format!("Invalid memory budget: \"{}\"", s));
+        }
+        Ok(Self::new(Some(limit as usize)))
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| self.counters.used.load(Ordering::Relaxed) >= limit)
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            used: self.counters.used.load(Ordering::Relaxed),
+            pooled: self.counters.pooled.load(Ordering::Relaxed),
+            peak: self.counters.peak.load(Ordering::Relaxed),
+            limit: self.limit,
+        }
+    }
+
+    fn acquire(&self, size: usize) {
+        let used = self.counters.used.fetch_add(size, Ordering::Relaxed) + size;
+        self.counters.peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    fn release(&self, size: usize) {
+        self.counters.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            Some(limit) => traffic::write_size(f, limit as u64),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} in use (peak {}), {} pooled",
+            ByteCount(self.used as u64),
+            ByteCount(self.peak as u64),
+            ByteCount(self.pooled as u64)
+        )?;
+        if let Some(limit) = self.limit {
+            write!(f, ", budget ")?;
+            traffic::write_size(f, limit as u64)?;
+            if self.used >= limit {
+                write!(f, " (exhausted)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Free blocks of the current thread, by size.
+struct BufferPool {
+    budget: MemoryBudget,
+    free: HashMap<usize, Vec<Box<[u8]>>>,
+    pooled_bytes: usize,
+}
+
+impl BufferPool {
+    fn take(&mut self, size: usize) -> Box<[u8]> {
+        self.budget.acquire(size);
+        if let Some(block) = self.free.get_mut(&size).and_then(Vec::pop) {
+            self.pooled_bytes -= size;
+            self.budget
+                .counters
+                .pooled
+                .fetch_sub(size, Ordering::Relaxed);
+            block
+        } else {
+            vec![0; size].into_boxed_slice()
+        }
+    }
+
+    fn give_back(&mut self, block: Box<[u8]>) {
+        let size = block.len();
+        self.budget.release(size);
+        if self.pooled_bytes + size <= MAX_POOLED_BYTES {
+            self.pooled_bytes += size;
+            self.budget
+                .counters
+                .pooled
+                .fetch_add(size, Ordering::Relaxed);
+            self.free.entry(size).or_default().push(block);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.budget
+            .counters
+            .pooled
+            .fetch_sub(self.pooled_bytes, Ordering::Relaxed);
+        self.pooled_bytes = 0;
+        self.free.clear();
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<BufferPool> = RefCell::new(BufferPool {
+        budget: MemoryBudget::default(),
+        free: HashMap::new(),
+        pooled_bytes: 0,
+    });
+}
+
+/// Account the buffers of the current thread to `budget`.
+///
+/// Must be called before any buffer is allocated on this thread.
+pub fn install(budget: MemoryBudget) {
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        pool.clear();
+        pool.budget = budget;
+    });
+}
+
+/// Indicate whether the memory budget of the current thread is exhausted.
+pub fn is_exhausted() -> bool {
+    POOL.with(|pool| pool.borrow().budget.is_exhausted())
+}
+
+/// The size to allocate for storing `length` bytes, at most `max_size`.
+///
+/// Buffers grow by powers of two, so that the blocks may be reused by other buffers.
+pub fn block_size(length: usize, max_size: usize) -> usize {
+    cmp::min(
+        cmp::max(length.next_power_of_two(), MIN_BLOCK_SIZE),
+        max_size,
+    )
+}
+
+/// Memory block borrowed from the pool of the current thread, given back on drop.
+///
+/// An empty buffer holds no memory.
+#[derive(Default)]
+pub struct PooledBuffer {
+    block: Option<Box<[u8]>>,
+}
+
+impl PooledBuffer {
+    /// Borrow a block of exactly `size` bytes (its content is unspecified).
+    pub fn allocate(size: usize) -> Self {
+        let block = POOL.with(|pool| pool.borrow_mut().take(size));
+        Self { block: Some(block) }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.block.as_deref().unwrap_or(&[])
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.block.as_deref_mut().unwrap_or(&mut [])
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(block) = self.block.take() {
+            // the pool may already be destroyed if the thread is exiting
+            let _ = POOL.try_with(|pool| pool.borrow_mut().give_back(block));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_blocks() {
+        let budget = MemoryBudget::new(Some(3 * MIN_BLOCK_SIZE));
+        install(budget.clone());
+
+        let buffer = PooledBuffer::allocate(MIN_BLOCK_SIZE);
+        assert_eq!(MIN_BLOCK_SIZE, buffer.len());
+        assert_eq!(MIN_BLOCK_SIZE, budget.usage().used);
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+
+        assert_eq!(0, budget.usage().used);
+        assert_eq!(MIN_BLOCK_SIZE, budget.usage().pooled);
+
+        let buffer = PooledBuffer::allocate(MIN_BLOCK_SIZE);
+        assert_eq!(ptr, buffer.as_ptr());
+        assert_eq!(0, budget.usage().pooled);
+        assert!(!budget.is_exhausted());
+
+        let other = PooledBuffer::allocate(2 * MIN_BLOCK_SIZE);
+        assert!(budget.is_exhausted());
+        assert!(is_exhausted());
+        assert_eq!(3 * MIN_BLOCK_SIZE, budget.usage().peak);
+
+        drop(other);
+        drop(buffer);
+        assert!(!budget.is_exhausted());
+        assert_eq!(3 * MIN_BLOCK_SIZE, budget.usage().peak);
+        install(MemoryBudget::default());
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let buffer = PooledBuffer::default();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn compute_block_size() {
+        assert_eq!(MIN_BLOCK_SIZE, block_size(1, 1 << 20));
+        assert_eq!(8192, block_size(4097, 1 << 20));
+        assert_eq!(5000, block_size(4097, 5000));
+        assert_eq!(10, block_size(6, 10));
+    }
+
+    #[test]
+    fn parse_budget() {
+        assert_eq!(
+            Some(256 << 20),
+            MemoryBudget::parse("256MiB").unwrap().limit()
+        );
+        assert_eq!(None, MemoryBudget::parse("none").unwrap().limit());
+        assert!(MemoryBudget::parse("0").is_err());
+        assert!(MemoryBudget::parse("lots").is_err());
+    }
+}
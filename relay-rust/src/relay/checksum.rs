@@ -0,0 +1,172 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryInto;
+
+/// Internet checksum accumulator (RFC 1071).
+///
+/// The one's complement sum does not depend on the word size (as long as the 16-bit alignment is
+/// preserved) nor on the byte order (the sum of byte-swapped words is the byte-swapped sum). So
+/// the data is summed by native 32-bit words into a 64-bit accumulator, folded and converted only
+/// once at the end. Without carries to propagate, the loop is vectorized by the compiler.
+///
+/// The accumulator cannot overflow for less than 16GiB of data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checksum {
+    sum: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn add_u16(&mut self, value: u16) {
+        // in the byte order of the data
+        self.sum += u64::from(value.to_be());
+    }
+
+    #[inline]
+    pub fn add_u32(&mut self, value: u32) {
+        self.sum += u64::from(value.to_be());
+    }
+
+    /// Add `data`, which must start at an even offset of the checksummed content.
+    ///
+    /// If its length is odd, the last byte is padded (so it must be the last data added).
+    pub fn add_bytes(&mut self, data: &[u8]) {
+        let mut chunks = data.chunks_exact(4);
+        self.sum += (&mut chunks)
+            .map(|chunk| u64::from(u32::from_ne_bytes(chunk.try_into().unwrap())))
+            .sum::<u64>();
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            let mut last = [0u8; 4];
+            last[..remainder.len()].copy_from_slice(remainder);
+            self.sum += u64::from(u32::from_ne_bytes(last));
+        }
+    }
+
+    /// The checksum (the one's complement of the folded sum).
+    pub fn finish(&self) -> u16 {
+        let mut sum = (self.sum & 0xFFFF_FFFF) + (self.sum >> 32);
+        while (sum & !0xFFFF) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !u16::from_be(sum as u16)
+    }
+}
+
+/// Compute the checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut checksum = Checksum::new();
+    checksum.add_bytes(data);
+    checksum.finish()
+}
+
+/// Update `checksum` incrementally when a 16-bit word of the content changes from `old` to
+/// `new` (RFC 1624, equation 3).
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    // HC' = ~(~HC + ~m + m')
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while (sum & !0xFFFF) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reference implementation, summing 16-bit words
+    fn naive_checksum(data: &[u8]) -> u16 {
+        let mut sum = data
+            .chunks(2)
+            .map(|chunk| {
+                let low = if chunk.len() == 2 { chunk[1] } else { 0 };
+                u32::from(chunk[0]) << 8 | u32::from(low)
+            })
+            .sum::<u32>();
+        while (sum & !0xFFFF) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !sum as u16
+    }
+
+    #[test]
+    fn compute_rfc1071_example() {
+        // example from RFC 1071 section 3: the sum is ddf2
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(!0xddf2, checksum(&data));
+    }
+
+    #[test]
+    fn compute_any_length() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 37 + 11) as u8).collect();
+        for length in 0..data.len() {
+            assert_eq!(naive_checksum(&data[..length]), checksum(&data[..length]));
+        }
+    }
+
+    #[test]
+    fn compute_with_carries() {
+        let data = [0xff; 1500];
+        assert_eq!(naive_checksum(&data), checksum(&data));
+    }
+
+    #[test]
+    fn combine_parts() {
+        let data: Vec<u8> = (0..101u32).map(|i| (i * 13) as u8).collect();
+        let mut checksum = Checksum::new();
+        checksum.add_u32(0x1234_5678);
+        checksum.add_u16(6);
+        checksum.add_bytes(&data[..20]);
+        checksum.add_bytes(&data[20..]);
+
+        let mut expected = vec![0x12, 0x34, 0x56, 0x78, 0x00, 0x06];
+        expected.extend_from_slice(&data);
+        assert_eq!(naive_checksum(&expected), checksum.finish());
+    }
+
+    #[test]
+    fn update_incrementally() {
+        let mut data: Vec<u8> = (0..40u32).map(|i| (i * 29 + 3) as u8).collect();
+        let mut sum = checksum(&data);
+
+        let old = u16::from_be_bytes(data[2..4].try_into().unwrap());
+        data[2..4].copy_from_slice(&0xabcdu16.to_be_bytes());
+        sum = update(sum, old, 0xabcd);
+        assert_eq!(checksum(&data), sum);
+
+        // successive updates accumulate
+        let old = u16::from_be_bytes(data[38..40].try_into().unwrap());
+        data[38..40].copy_from_slice(&0x0102u16.to_be_bytes());
+        sum = update(sum, old, 0x0102);
+        assert_eq!(checksum(&data), sum);
+    }
+
+    #[test]
+    fn update_to_zero_sum() {
+        // the content sums to 0xffff after the update: the checksum must be 0x0000, never 0xffff
+        let data = [0x12, 0x34, 0x00, 0x00];
+        let sum = checksum(&data);
+        let updated = update(sum, 0x0000, !0x1234);
+        assert_eq!(0x0000, updated);
+        assert_eq!(checksum(&[0x12, 0x34, 0xed, 0xcb]), updated);
+    }
+}
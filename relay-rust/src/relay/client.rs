@@ -19,21 +19,32 @@ use mio::net::TcpStream;
 use mio::{Event, PollOpt, Ready, Token};
 use std::cell::RefCell;
 use std::io::{self, Write};
-use std::mem;
 use std::net::Shutdown;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
 use super::binary;
 use super::close_listener::CloseListener;
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::ipv4_header::Protocol;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::ipv4_packet_buffer::Ipv4PacketBuffer;
+use super::packet_scheduler::{PacketScheduler, TrafficClass};
 use super::packet_source::PacketSource;
 use super::router::Router;
 use super::selector::Selector;
+use super::shaper::{Shaper, Verdict};
+use super::stats::ClientStats;
 use super::stream_buffer::StreamBuffer;
+use super::traffic::{ByteCount, Quota, QuotaAction, TrafficCounters};
 
 const TAG: &str = "Client";
 
+// space of the client buffer reserved to the interactive traffic (DNS responses, short flows)
+const INTERACTIVE_HEADROOM: usize = 64 * 1024;
+
 pub struct Client {
     id: u32,
     stream: TcpStream,
@@ -42,11 +53,20 @@ pub struct Client {
     client_to_network: Ipv4PacketBuffer,
     network_to_client: StreamBuffer,
     router: Router,
+    shaper: Shaper,
+    config: Rc<RelayConfig>,
+    // including the previous traffic of the device, if it is identified
+    traffic: TrafficCounters,
+    quota: Option<Quota>,
+    quota_exceeded: bool,
     close_listener: Box<dyn CloseListener<Client>>,
     closed: bool,
-    pending_packet_sources: Vec<Rc<RefCell<dyn PacketSource>>>,
+    packet_scheduler: PacketScheduler,
     // number of remaining bytes of "id" to send to the client before relaying any data
     pending_id_bytes: usize,
+    connected_at: Instant,
+    // number of times a packet could not be written to the client buffer
+    buffer_full_events: u64,
 }
 
 /// Channel for connections to send back data immediately to the client
@@ -55,6 +75,9 @@ pub struct ClientChannel<'a> {
     stream: &'a TcpStream,
     token: Token,
     interests: &'a mut Ready,
+    shaper: &'a mut Shaper,
+    traffic: &'a mut TrafficCounters,
+    buffer_full_events: &'a mut u64,
 }
 
 impl<'a> ClientChannel<'a> {
@@ -63,12 +86,18 @@ impl<'a> ClientChannel<'a> {
         stream: &'a TcpStream,
         token: Token,
         interests: &'a mut Ready,
+        shaper: &'a mut Shaper,
+        traffic: &'a mut TrafficCounters,
+        buffer_full_events: &'a mut u64,
     ) -> Self {
         Self {
             network_to_client,
             stream,
             token,
             interests,
+            shaper,
+            traffic,
+            buffer_full_events,
         }
     }
 
@@ -78,13 +107,49 @@ impl<'a> ClientChannel<'a> {
         &mut self,
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
+        class: TrafficClass,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
+        self.send(selector, ipv4_packet, class)
+    }
+
+    fn send(
+        &mut self,
+        selector: &mut Selector,
+        ipv4_packet: &Ipv4Packet,
+        class: TrafficClass,
+    ) -> io::Result<()> {
+        if self.shaper.refresh() {
+            // TCP segments are never retransmitted to the client, they must not be lost
+            let droppable = ipv4_packet.ipv4_header_data().protocol() != Protocol::Tcp;
+            return match self
+                .shaper
+                .push_downlink(selector, ipv4_packet.raw(), droppable)
+            {
+                Verdict::Queued => {
+                    self.traffic.record_downlink(ipv4_packet.raw().len());
+                    Ok(())
+                }
+                Verdict::Dropped => Ok(()),
+                Verdict::Full => Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Shaping queue full",
+                )),
+            };
+        }
+        // bulk traffic must not fill the whole buffer, so that the interactive and control packets
+        // are never dropped
+        let reserved = match TrafficClass::of(ipv4_packet, class) {
+            TrafficClass::Interactive => 0,
+            TrafficClass::Bulk => INTERACTIVE_HEADROOM,
+        };
+        if ipv4_packet.length() as usize + reserved <= self.network_to_client.remaining() {
             self.network_to_client.read_from(ipv4_packet.raw());
+            self.traffic.record_downlink(ipv4_packet.raw().len());
             self.update_interests(selector);
             Ok(())
         } else {
             warn!(target: TAG, "Client buffer full");
+            *self.buffer_full_events += 1;
             Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
                 "Client buffer full",
@@ -109,12 +174,20 @@ impl<'a> ClientChannel<'a> {
 }
 
 impl Client {
+    /// `device` is the serial of the device, if identified by its tunnel port.
     pub fn create(
         id: u32,
+        device: Option<Arc<str>>,
         selector: &mut Selector,
         stream: TcpStream,
+        config: Rc<RelayConfig>,
         close_listener: Box<dyn CloseListener<Client>>,
     ) -> io::Result<Rc<RefCell<Self>>> {
+        let shaper = Shaper::new(id, device.clone(), config.network_emulation().clone());
+        let traffic = config
+            .traffic_accounting()
+            .client_connected(id, device.clone());
+        let quota = config.quota_policy().quota(device.as_deref()).cloned();
         // on start, we are interested only in writing (we must first send the client id)
         let interests = Ready::writable();
         let rc = Rc::new(RefCell::new(Self {
@@ -124,17 +197,27 @@ impl Client {
             token: Token(0), // default value, will be set afterwards
             client_to_network: Ipv4PacketBuffer::new(),
             network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
-            router: Router::new(),
+            router: Router::new(id, device, config.clone()),
+            shaper,
+            config,
+            traffic,
+            quota,
+            quota_exceeded: false,
             closed: false,
             close_listener,
-            pending_packet_sources: Vec::new(),
+            packet_scheduler: PacketScheduler::new(),
             pending_id_bytes: 4,
+            connected_at: Instant::now(),
+            buffer_full_events: 0,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
             // set client as router owner
             self_ref.router.set_client(Rc::downgrade(&rc));
+            self_ref.shaper.set_client(Rc::downgrade(&rc));
+            // the quota may already be exceeded by the previous traffic of the device
+            self_ref.check_quota();
 
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
@@ -143,6 +226,7 @@ impl Client {
             let token =
                 selector.register(&self_ref.stream, handler, interests, PollOpt::level())?;
             self_ref.token = token;
+            self_ref.config.observer().client_connected(id);
         }
         Ok(rc)
     }
@@ -151,27 +235,122 @@ impl Client {
         self.id
     }
 
+    /// Use `config` for the connections opened from now on.
+    pub fn set_config(&mut self, config: Rc<RelayConfig>) {
+        self.router.set_config(config.clone());
+        self.config = config;
+    }
+
     pub fn router(&mut self) -> &mut Router {
         &mut self.router
     }
 
-    pub fn channel(&mut self) -> ClientChannel {
+    pub fn channel(&mut self) -> ClientChannel<'_> {
         ClientChannel::new(
             &mut self.network_to_client,
             &self.stream,
             self.token,
             &mut self.interests,
+            &mut self.shaper,
+            &mut self.traffic,
+            &mut self.buffer_full_events,
         )
     }
 
+    /// Publish the traffic counters to the relay accounting.
+    pub fn publish_traffic(&self) {
+        self.config
+            .traffic_accounting()
+            .update(self.id, self.traffic);
+    }
+
+    /// Disconnect the client on request.
+    pub fn disconnect(&mut self, selector: &mut Selector) {
+        if !self.closed {
+            info!(target: TAG, "Client #{} disconnected on request", self.id);
+            self.close(selector);
+        }
+    }
+
+    /// Close one of the connections of the client on request.
+    ///
+    /// Return `false` if the connection does not exist.
+    pub fn abort_connection(&mut self, selector: &mut Selector, id: &ConnectionId) -> bool {
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            &mut self.shaper,
+            &mut self.traffic,
+            &mut self.buffer_full_events,
+        );
+        self.router
+            .abort_connection(selector, &mut client_channel, id)
+    }
+
+    pub fn stats(&self, worker: usize) -> ClientStats {
+        ClientStats {
+            id: self.id,
+            worker,
+            age: self.connected_at.elapsed(),
+            counters: self.traffic,
+            downlink_buffered: self.network_to_client.size(),
+            buffer_full_events: self.buffer_full_events,
+            connections: self.router.stats(),
+        }
+    }
+
+    fn check_quota(&mut self) {
+        let quota = match self.quota {
+            Some(quota) if !self.quota_exceeded && quota.is_exceeded(&self.traffic) => quota,
+            _ => return,
+        };
+        self.quota_exceeded = true;
+        match quota.action() {
+            QuotaAction::Block => {
+                warn!(
+                    target: TAG,
+                    "Client #{} exceeded its data quota ({} used, limit {}), blocking new connections",
+                    self.id,
+                    ByteCount(self.traffic.total_bytes()),
+                    ByteCount(quota.limit())
+                );
+                self.router.block_new_connections();
+            }
+            QuotaAction::Throttle(rate) => {
+                warn!(
+                    target: TAG,
+                    "Client #{} exceeded its data quota ({} used, limit {}), throttling to {} kbit/s",
+                    self.id,
+                    ByteCount(self.traffic.total_bytes()),
+                    ByteCount(quota.limit()),
+                    rate / 1000
+                );
+                self.shaper.throttle(rate);
+            }
+        }
+    }
+
     fn close(&mut self, selector: &mut Selector) {
         self.closed = true;
+        self.publish_traffic();
+        self.config
+            .traffic_accounting()
+            .client_disconnected(self.id);
+        info!(target: TAG, "Client #{} traffic: {}", self.id, self.traffic);
+        if let Err(err) = self.config.traffic_accounting().save() {
+            error!(target: TAG, "Cannot save traffic counters: {}", err);
+        }
         selector.deregister(&self.stream, self.token).unwrap();
         // shutdown only (there is no close), the socket will be closed on drop
         if self.stream.shutdown(Shutdown::Both).is_err() {
             warn!(target: TAG, "Cannot shutdown client socket");
         }
         self.router.clear(selector);
+        self.config
+            .observer()
+            .client_disconnected(self.id, &self.traffic);
         self.close_listener.on_closed(self);
     }
 
@@ -223,7 +402,11 @@ impl Client {
             }
         } else {
             match self.write() {
-                Ok(_) => self.process_pending(selector),
+                Ok(_) => {
+                    // shaped packets may be waiting for space in the client buffer
+                    self.release_downlink(selector);
+                    self.process_pending(selector);
+                }
                 Err(err) => {
                     error!(target: TAG, "Cannot write: [{:?}] {}", err.kind(), err);
                     self.close(selector);
@@ -257,22 +440,17 @@ impl Client {
         &mut self,
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
+        class: TrafficClass,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
-            self.update_interests(selector);
-            Ok(())
-        } else {
-            warn!(target: TAG, "Client buffer full");
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Client buffer full",
-            ))
-        }
+        self.channel().send_to_client(selector, ipv4_packet, class)
     }
 
-    pub fn register_pending_packet_source(&mut self, source: Rc<RefCell<dyn PacketSource>>) {
-        self.pending_packet_sources.push(source);
+    pub fn register_pending_packet_source(
+        &mut self,
+        source: Rc<RefCell<dyn PacketSource>>,
+        class: TrafficClass,
+    ) {
+        self.packet_scheduler.register(source, class);
     }
 
     fn send_id(&mut self) -> io::Result<()> {
@@ -303,13 +481,26 @@ impl Client {
     }
 
     fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> bool {
+        // new connections are only created by packets from the client
+        self.check_quota();
         match self.client_to_network.as_ipv4_packet() {
             Some(ref packet) => {
+                self.traffic.record_uplink(packet.raw().len());
+                if self.shaper.refresh() {
+                    if self.shaper.push_uplink(selector, packet.raw()) == Verdict::Full {
+                        // the client will retransmit if necessary
+                        debug!(target: TAG, "Shaping queue full, dropping packet");
+                    }
+                    return true;
+                }
                 let mut client_channel = ClientChannel::new(
                     &mut self.network_to_client,
                     &self.stream,
                     self.token,
                     &mut self.interests,
+                    &mut self.shaper,
+                    &mut self.traffic,
+                    &mut self.buffer_full_events,
                 );
                 self.router
                     .send_to_network(selector, &mut client_channel, packet);
@@ -319,39 +510,59 @@ impl Client {
         }
     }
 
-    fn process_pending(&mut self, selector: &mut Selector) {
-        let mut vec = Vec::new();
-        mem::swap(&mut self.pending_packet_sources, &mut vec);
-        for pending in vec.into_iter() {
-            let consumed = {
-                let mut source = pending.borrow_mut();
-                let result = {
-                    let ipv4_packet = source
-                        .get()
-                        .expect("Unexpected pending source with no packet");
-                    self.send_to_client(selector, &ipv4_packet)
-                };
-                #[allow(clippy::match_wild_err_arm)]
-                match result {
-                    Ok(_) => {
-                        source.next(selector);
-                        true
-                    }
-                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => false,
-                    Err(_) => {
-                        panic!("Cannot send packet to client for unknown reason");
-                    }
-                }
-            };
-            if !consumed {
-                // keep it pending
-                self.pending_packet_sources.push(pending);
-            }
+    pub fn on_shaper_timer(&mut self, selector: &mut Selector, deadline: Instant) {
+        if self.closed || !self.shaper.on_timer(deadline) {
+            return;
+        }
+        self.release_uplink(selector);
+        self.release_downlink(selector);
+        self.process_pending(selector);
+        self.shaper.schedule(selector);
+    }
+
+    fn release_uplink(&mut self, selector: &mut Selector) {
+        let now = Instant::now();
+        while let Some(mut raw) = self.shaper.uplink().pop_due(now) {
+            let packet = Ipv4Packet::parse(&mut raw);
+            let mut client_channel = ClientChannel::new(
+                &mut self.network_to_client,
+                &self.stream,
+                self.token,
+                &mut self.interests,
+                &mut self.shaper,
+                &mut self.traffic,
+                &mut self.buffer_full_events,
+            );
+            self.router
+                .send_to_network(selector, &mut client_channel, &packet);
         }
     }
 
-    pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        self.router.clean_expired_connections(selector);
+    fn release_downlink(&mut self, selector: &mut Selector) {
+        // the packets not fitting are released once the client buffer is written
+        self.shaper
+            .release_downlink(&mut self.network_to_client, Instant::now());
+        self.update_interests(selector);
+        self.shaper.schedule(selector);
+    }
+
+    fn process_pending(&mut self, selector: &mut Selector) {
+        if self.packet_scheduler.is_empty() {
+            return;
+        }
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            &mut self.shaper,
+            &mut self.traffic,
+            &mut self.buffer_full_events,
+        );
+        self.packet_scheduler
+            .run(selector, |selector, ipv4_packet, class| {
+                client_channel.send(selector, ipv4_packet, class)
+            });
     }
 
     fn must_send_id(&self) -> bool {
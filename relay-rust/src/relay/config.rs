@@ -0,0 +1,261 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::buffer_pool::MemoryBudget;
+use super::connection_limits::ConnectionLimits;
+use super::device_tunnels::DeviceTunnels;
+use super::flow_export::FlowExporter;
+use super::flow_log::FlowLog;
+use super::loopback_policy::LoopbackPolicy;
+use super::nat_table::NatTable;
+use super::network_profile::NetworkEmulation;
+use super::observer::{NoObserver, ObserverPair, RelayObserver};
+use super::proxy::{NoProxy, ProxyConfig};
+use super::reload::{ConfigLoader, ConfigReload};
+use super::socket_binding::SocketBindingPolicy;
+use super::traffic::{QuotaPolicy, TrafficAccounting};
+use super::upstream_selector::{HostnameRoutes, UpstreamSelector};
+use std::sync::Arc;
+
+/// Settings shared by all the clients and connections of a relay.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    nat_table: NatTable,
+    loopback_policy: LoopbackPolicy,
+    upstream_proxy: Option<ProxyConfig>,
+    no_proxy: NoProxy,
+    upstream_selector: Arc<dyn UpstreamSelector>,
+    socket_binding_policy: SocketBindingPolicy,
+    network_emulation: NetworkEmulation,
+    quota_policy: QuotaPolicy,
+    traffic_accounting: TrafficAccounting,
+    flow_log: FlowLog,
+    flow_exporter: FlowExporter,
+    connection_limits: ConnectionLimits,
+    memory_budget: MemoryBudget,
+    worker_threads: usize,
+    control_port: Option<u16>,
+    metrics_port: Option<u16>,
+    observer: Arc<dyn RelayObserver>,
+    config_loader: Option<Arc<dyn ConfigLoader>>,
+    config_reload: ConfigReload,
+    device_tunnels: DeviceTunnels,
+}
+
+impl RelayConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nat_table(&self) -> &NatTable {
+        &self.nat_table
+    }
+
+    pub fn set_nat_table(&mut self, nat_table: NatTable) {
+        self.nat_table = nat_table;
+    }
+
+    pub fn loopback_policy(&self) -> &LoopbackPolicy {
+        &self.loopback_policy
+    }
+
+    pub fn set_loopback_policy(&mut self, loopback_policy: LoopbackPolicy) {
+        self.loopback_policy = loopback_policy;
+    }
+
+    pub fn upstream_proxy(&self) -> Option<&ProxyConfig> {
+        self.upstream_proxy.as_ref()
+    }
+
+    pub fn set_upstream_proxy(&mut self, upstream_proxy: Option<ProxyConfig>) {
+        self.upstream_proxy = upstream_proxy;
+    }
+
+    pub fn no_proxy(&self) -> &NoProxy {
+        &self.no_proxy
+    }
+
+    pub fn set_no_proxy(&mut self, no_proxy: NoProxy) {
+        self.no_proxy = no_proxy;
+    }
+
+    pub fn socket_binding_policy(&self) -> &SocketBindingPolicy {
+        &self.socket_binding_policy
+    }
+
+    pub fn set_socket_binding_policy(&mut self, socket_binding_policy: SocketBindingPolicy) {
+        self.socket_binding_policy = socket_binding_policy;
+    }
+
+    /// The network emulation settings, which may be changed while the relay is running.
+    pub fn network_emulation(&self) -> &NetworkEmulation {
+        &self.network_emulation
+    }
+
+    pub fn set_network_emulation(&mut self, network_emulation: NetworkEmulation) {
+        self.network_emulation = network_emulation;
+    }
+
+    pub fn quota_policy(&self) -> &QuotaPolicy {
+        &self.quota_policy
+    }
+
+    pub fn set_quota_policy(&mut self, quota_policy: QuotaPolicy) {
+        self.quota_policy = quota_policy;
+    }
+
+    /// The traffic counters of the clients, which may be read while the relay is running.
+    pub fn traffic_accounting(&self) -> &TrafficAccounting {
+        &self.traffic_accounting
+    }
+
+    pub fn set_traffic_accounting(&mut self, traffic_accounting: TrafficAccounting) {
+        self.traffic_accounting = traffic_accounting;
+    }
+
+    /// The destination of the records of the closed connections.
+    pub fn flow_log(&self) -> &FlowLog {
+        &self.flow_log
+    }
+
+    pub fn set_flow_log(&mut self, flow_log: FlowLog) {
+        self.flow_log = flow_log;
+    }
+
+    /// The collector to export the records of the closed connections to.
+    pub fn flow_exporter(&self) -> &FlowExporter {
+        &self.flow_exporter
+    }
+
+    pub fn set_flow_exporter(&mut self, flow_exporter: FlowExporter) {
+        self.flow_exporter = flow_exporter;
+    }
+
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.connection_limits
+    }
+
+    pub fn set_connection_limits(&mut self, connection_limits: ConnectionLimits) {
+        self.connection_limits = connection_limits;
+    }
+
+    /// The memory used by the buffers of all the clients, which may be read while the relay is
+    /// running.
+    pub fn memory_budget(&self) -> &MemoryBudget {
+        &self.memory_budget
+    }
+
+    pub fn set_memory_budget(&mut self, memory_budget: MemoryBudget) {
+        self.memory_budget = memory_budget;
+    }
+
+    /// The number of event loops (one thread each) running the clients.
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads
+    }
+
+    pub fn set_worker_threads(&mut self, worker_threads: usize) {
+        assert!(worker_threads > 0, "At least one worker thread is required");
+        self.worker_threads = worker_threads;
+    }
+
+    /// The localhost port of the control server, if enabled.
+    pub fn control_port(&self) -> Option<u16> {
+        self.control_port
+    }
+
+    pub fn set_control_port(&mut self, control_port: Option<u16>) {
+        self.control_port = control_port;
+    }
+
+    /// The localhost port serving the metrics (in the Prometheus format), if enabled.
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    pub fn set_metrics_port(&mut self, metrics_port: Option<u16>) {
+        self.metrics_port = metrics_port;
+    }
+
+    /// The callbacks notified of the relay activity.
+    pub fn observer(&self) -> &dyn RelayObserver {
+        &*self.observer
+    }
+
+    pub fn set_observer(&mut self, observer: Arc<dyn RelayObserver>) {
+        self.observer = observer;
+    }
+
+    /// Notify `observer` of the relay activity, in addition to the current observer.
+    pub fn add_observer(&mut self, observer: Arc<dyn RelayObserver>) {
+        self.observer = Arc::new(ObserverPair::new(self.observer.clone(), observer));
+    }
+
+    /// The source of the settings applied on startup and on reload, if any.
+    pub fn config_loader(&self) -> Option<&dyn ConfigLoader> {
+        self.config_loader.as_deref()
+    }
+
+    pub fn set_config_loader(&mut self, config_loader: Option<Arc<dyn ConfigLoader>>) {
+        self.config_loader = config_loader;
+    }
+
+    /// The handle to request the running relay to reload its configuration.
+    pub fn config_reload(&self) -> &ConfigReload {
+        &self.config_reload
+    }
+
+    /// The handle to open the tunnel ports identifying the devices.
+    pub fn device_tunnels(&self) -> &DeviceTunnels {
+        &self.device_tunnels
+    }
+
+    pub fn upstream_selector(&self) -> &dyn UpstreamSelector {
+        &*self.upstream_selector
+    }
+
+    pub fn set_upstream_selector(&mut self, upstream_selector: Arc<dyn UpstreamSelector>) {
+        self.upstream_selector = upstream_selector;
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            nat_table: NatTable::default(),
+            loopback_policy: LoopbackPolicy::default(),
+            upstream_proxy: None,
+            no_proxy: NoProxy::default(),
+            // without rules, every connection goes through the proxy (if any)
+            upstream_selector: Arc::new(HostnameRoutes::default()),
+            socket_binding_policy: SocketBindingPolicy::default(),
+            network_emulation: NetworkEmulation::default(),
+            quota_policy: QuotaPolicy::default(),
+            traffic_accounting: TrafficAccounting::default(),
+            flow_log: FlowLog::default(),
+            flow_exporter: FlowExporter::default(),
+            connection_limits: ConnectionLimits::default(),
+            memory_budget: MemoryBudget::default(),
+            worker_threads: 1,
+            control_port: None,
+            metrics_port: None,
+            observer: Arc::new(NoObserver),
+            config_loader: None,
+            config_reload: ConfigReload::default(),
+            device_tunnels: DeviceTunnels::default(),
+        }
+    }
+}
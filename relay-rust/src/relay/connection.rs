@@ -16,17 +16,17 @@
 
 use std::fmt;
 use std::net::SocketAddrV4;
+use std::time::Instant;
 
 use super::client::ClientChannel;
+use super::flow_log::CloseReason;
 use super::ipv4_header::{Ipv4HeaderData, Protocol};
 use super::ipv4_packet::Ipv4Packet;
 use super::net;
 use super::selector::Selector;
+use super::stats::ConnectionStats;
 use super::transport_header::TransportHeaderData;
 
-const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
-const LOCALHOST: u32 = 0x7F_00_00_01; // 127.0.0.1
-
 pub trait Connection {
     fn id(&self) -> &ConnectionId;
     fn send_to_network(
@@ -35,22 +35,37 @@ pub trait Connection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     );
-    fn close(&mut self, selector: &mut Selector);
-    fn is_expired(&self) -> bool;
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason);
+    /// Close the connection on request, resetting it on the client side if possible.
+    fn abort(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel);
     fn is_closed(&self) -> bool;
+    /// The instant since which the connection is idle, if it may be evicted to make room for new
+    /// connections.
+    fn idle_since(&self) -> Option<Instant>;
+    fn stats(&self) -> ConnectionStats;
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+// used as key to find the connection of every packet, keep it cheap to build and to hash
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ConnectionId {
     protocol: Protocol,
     source_ip: u32,
     source_port: u16,
     destination_ip: u32,
     destination_port: u16,
-    id_string: String,
 }
 
 impl ConnectionId {
+    pub fn new(protocol: Protocol, source: SocketAddrV4, destination: SocketAddrV4) -> Self {
+        Self {
+            protocol,
+            source_ip: u32::from(*source.ip()),
+            source_port: source.port(),
+            destination_ip: u32::from(*destination.ip()),
+            destination_port: destination.port(),
+        }
+    }
+
     pub fn from_headers(
         ipv4_header_data: &Ipv4HeaderData,
         transport_header_data: &TransportHeaderData,
@@ -59,18 +74,12 @@ impl ConnectionId {
         let source_port = transport_header_data.source_port();
         let destination_ip = ipv4_header_data.destination();
         let destination_port = transport_header_data.destination_port();
-        let id_string = format!(
-            "{} -> {}",
-            net::to_socket_addr(source_ip, source_port),
-            net::to_socket_addr(destination_ip, destination_port)
-        );
         Self {
             protocol: ipv4_header_data.protocol(),
             source_ip,
             source_port,
             destination_ip,
             destination_port,
-            id_string,
         }
     }
 
@@ -78,19 +87,26 @@ impl ConnectionId {
         self.protocol
     }
 
-    pub fn rewritten_destination(&self) -> SocketAddrV4 {
-        let ip = if self.destination_ip == LOCALHOST_FORWARD {
-            LOCALHOST
-        } else {
-            self.destination_ip
-        };
-        net::to_socket_addr(ip, self.destination_port)
+    pub fn source(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.source_ip, self.source_port)
+    }
+
+    pub fn destination_ip(&self) -> u32 {
+        self.destination_ip
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        self.destination_port
+    }
+
+    pub fn destination(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.destination_ip, self.destination_port)
     }
 }
 
 impl fmt::Display for ConnectionId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.id_string)
+        write!(f, "{} -> {}", self.source(), self.destination())
     }
 }
 
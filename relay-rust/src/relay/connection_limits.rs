@@ -0,0 +1,224 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// not exposed by std::io::ErrorKind (same values on Linux, macOS and BSD)
+#[cfg(unix)]
+const EMFILE: i32 = 24;
+#[cfg(unix)]
+const ENFILE: i32 = 23;
+#[cfg(windows)]
+const WSAEMFILE: i32 = 10024;
+
+/// Maximal number of simultaneous connections (TCP connections and UDP flows).
+///
+/// Syntax: `KEY=VALUE[,KEY=VALUE]`, where `KEY` is `client` (limit for each client) or `relay`
+/// (limit for all the clients).
+///
+/// For example, `client=256,relay=4096`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    per_client: Option<usize>,
+    per_relay: Option<usize>,
+    // number of connections of all the clients, shared by the clones
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimits {
+    pub fn new(per_client: Option<usize>, per_relay: Option<usize>) -> Self {
+        Self {
+            per_client,
+            per_relay,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut limits = Self::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let index = setting
+                .find('=')
+                .ok_or_else(|| format!("Invalid connection limit: \"{}\"", setting))?;
+            let value = setting[index + 1..]
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&value| value > 0)
+                .ok_or_else(|| format!("Invalid connection limit: \"{}\"", setting))?;
+            match setting[..index].trim() {
+                "client" => limits.per_client = Some(value),
+                "relay" => limits.per_relay = Some(value),
+                key => return Err(format!("Unknown connection limit: \"{}\"", key)),
+            }
+        }
+        Ok(limits)
+    }
+
+    pub fn per_client(&self) -> Option<usize> {
+        self.per_client
+    }
+
+    pub fn per_relay(&self) -> Option<usize> {
+        self.per_relay
+    }
+
+    /// The number of connections currently open by all the clients.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Account for a new connection of a client having already `client_open` connections.
+    ///
+    /// On success, the connection must be released by `release()` once closed.
+    pub fn acquire(&self, client_open: usize) -> io::Result<()> {
+        if let Some(max) = self.per_client.filter(|&max| client_open >= max) {
+            return Err(limit_reached("client", max));
+        }
+        let result =
+            self.open
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                    match self.per_relay {
+                        Some(max) if open >= max => None,
+                        _ => Some(open + 1),
+                    }
+                });
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(limit_reached("relay", self.per_relay.unwrap())),
+        }
+    }
+
+    pub fn release(&self, count: usize) {
+        let previous = self.open.fetch_sub(count, Ordering::Relaxed);
+        debug_assert!(
+            previous >= count,
+            "Releasing more connections than acquired"
+        );
+    }
+}
+
+/// Error of a connection refused because a limit is reached.
+#[derive(Debug)]
+struct LimitReached {
+    scope: &'static str,
+    max: usize,
+}
+
+impl fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Connection limit reached ({} per {})",
+            self.max, self.scope
+        )
+    }
+}
+
+impl error::Error for LimitReached {}
+
+fn limit_reached(scope: &'static str, max: usize) -> io::Error {
+    io::Error::other(LimitReached { scope, max })
+}
+
+/// Indicate whether `err` is caused by a connection limit.
+pub fn is_limit_reached(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<LimitReached>())
+}
+
+/// Indicate whether `err` is caused by the exhaustion of the file descriptors.
+pub fn is_descriptor_exhaustion(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        #[cfg(unix)]
+        Some(EMFILE) | Some(ENFILE) => true,
+        #[cfg(windows)]
+        Some(WSAEMFILE) => true,
+        _ => false,
+    }
+}
+
+impl fmt::Display for ConnectionLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.per_client, self.per_relay) {
+            (None, None) => write!(f, "none"),
+            (Some(client), None) => write!(f, "client={}", client),
+            (None, Some(relay)) => write!(f, "relay={}", relay),
+            (Some(client), Some(relay)) => write!(f, "client={},relay={}", client, relay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        let limits = ConnectionLimits::parse("client=256, relay=4096").unwrap();
+        assert_eq!(Some(256), limits.per_client());
+        assert_eq!(Some(4096), limits.per_relay());
+        assert_eq!("client=256,relay=4096", limits.to_string());
+        assert_eq!(
+            "relay=10",
+            ConnectionLimits::parse("relay=10").unwrap().to_string()
+        );
+        assert_eq!("none", ConnectionLimits::default().to_string());
+
+        assert!(ConnectionLimits::parse("client=0").is_err());
+        assert!(ConnectionLimits::parse("client").is_err());
+        assert!(ConnectionLimits::parse("device=10").is_err());
+    }
+
+    #[test]
+    fn limit_per_client() {
+        let limits = ConnectionLimits::new(Some(2), None);
+        assert!(limits.acquire(0).is_ok());
+        assert!(limits.acquire(1).is_ok());
+        let err = limits.acquire(2).unwrap_err();
+        assert!(is_limit_reached(&err));
+        assert_eq!("Connection limit reached (2 per client)", err.to_string());
+        assert!(!is_limit_reached(&io::Error::other("other")));
+        assert_eq!(2, limits.open());
+    }
+
+    #[test]
+    fn limit_per_relay() {
+        let limits = ConnectionLimits::new(None, Some(2));
+        let shared = limits.clone();
+        assert!(limits.acquire(0).is_ok());
+        assert!(shared.acquire(0).is_ok());
+        assert!(limits.acquire(1).is_err());
+        shared.release(1);
+        assert!(limits.acquire(1).is_ok());
+        assert_eq!(2, shared.open());
+    }
+
+    #[test]
+    fn detect_descriptor_exhaustion() {
+        #[cfg(unix)]
+        assert!(is_descriptor_exhaustion(&io::Error::from_raw_os_error(
+            EMFILE
+        )));
+        assert!(!is_descriptor_exhaustion(&io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        )));
+    }
+}
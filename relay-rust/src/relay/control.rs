@@ -0,0 +1,592 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Event, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::rc::{Rc, Weak};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::ipv4_header::Protocol;
+use super::metrics::{self, EventCounters};
+use super::reload;
+use super::selector::Selector;
+use super::stats::RelayStats;
+use super::worker::WorkerHandle;
+
+const TAG: &str = "ControlServer";
+const MAX_REQUEST_LENGTH: usize = 8 * 1024;
+
+/// HTTP server on localhost, to inspect and administrate a running relay.
+///
+///  - `GET /stats` returns the state of the relay, its clients and their connections (JSON);
+///  - `GET /metrics` returns the metrics of the relay (Prometheus text format);
+///  - `DELETE /clients/{id}` disconnects a client;
+///  - `DELETE /clients/{id}/connections/{tcp|udp}/{source}/{destination}` closes a connection;
+///  - `POST /reload` reloads the configuration.
+///
+/// It runs on its own event loop (see `spawn()`): the state of the clients is requested to the
+/// workers synchronously, which must never delay the main event loop accepting the clients.
+pub struct ControlServer {
+    self_weak: Weak<RefCell<ControlServer>>,
+    tcp_listener: TcpListener,
+    role: Role,
+    workers: Arc<[WorkerHandle]>,
+    config: RelayConfig,
+    event_counters: EventCounters,
+    started_at: Instant,
+}
+
+/// The requests served by a `ControlServer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Statistics, metrics and admin actions.
+    Control,
+    /// Metrics only, to be exposed to a monitoring system.
+    Metrics,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Stats,
+    Metrics,
+    DisconnectClient(u32),
+    AbortConnection(u32, ConnectionId),
+    Reload,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        let mut body = String::from("{\"error\":\"");
+        for c in message.chars() {
+            match c {
+                '"' | '\\' => {
+                    body.push('\\');
+                    body.push(c);
+                }
+                c if (c as u32) < 0x20 => body.push_str(&format!("\\u{:04x}", c as u32)),
+                c => body.push(c),
+            }
+        }
+        body.push_str("\"}\n");
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn reason_phrase(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.status,
+            self.reason_phrase(),
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+// return Ok(None) if the request headers are incomplete
+fn parse_request(data: &[u8]) -> Result<Option<(String, String)>, Response> {
+    let end = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None if data.len() > MAX_REQUEST_LENGTH => {
+            return Err(Response::error(431, "Request too long"));
+        }
+        None => return Ok(None),
+    };
+    let head = String::from_utf8_lossy(&data[..end]);
+    let request_line = head.lines().next().unwrap_or("");
+    let mut tokens = request_line.split_whitespace();
+    match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/") => {
+            // the query string, if any, is ignored
+            let path = target.split('?').next().unwrap();
+            Ok(Some((method.to_string(), path.to_string())))
+        }
+        _ => Err(Response::error(400, "Malformed request")),
+    }
+}
+
+fn parse_command(method: &str, path: &str) -> Result<Command, Response> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (expected_method, command) = match segments.as_slice() {
+        ["stats"] => ("GET", Some(Command::Stats)),
+        ["metrics"] => ("GET", Some(Command::Metrics)),
+        ["reload"] => ("POST", Some(Command::Reload)),
+        ["clients", client_id] => (
+            "DELETE",
+            parse_client_id(client_id).map(Command::DisconnectClient),
+        ),
+        ["clients", client_id, "connections", protocol, source, destination] => {
+            let command = parse_client_id(client_id).and_then(|client_id| {
+                let id = parse_connection_id(protocol, source, destination)?;
+                Some(Command::AbortConnection(client_id, id))
+            });
+            ("DELETE", command)
+        }
+        _ => return Err(Response::error(404, "Not found")),
+    };
+    match command {
+        Some(_) if method != expected_method => Err(Response::error(405, "Method not allowed")),
+        Some(command) => Ok(command),
+        None => Err(Response::error(404, "Not found")),
+    }
+}
+
+fn parse_client_id(value: &str) -> Option<u32> {
+    value.parse().ok()
+}
+
+fn parse_connection_id(protocol: &str, source: &str, destination: &str) -> Option<ConnectionId> {
+    let protocol = match protocol {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        _ => return None,
+    };
+    let source: SocketAddrV4 = source.parse().ok()?;
+    let destination: SocketAddrV4 = destination.parse().ok()?;
+    Some(ConnectionId::new(protocol, source, destination))
+}
+
+/// Start the control servers listening on `ports`, on a new thread running their event loop.
+pub fn spawn(
+    ports: Vec<(u16, Role)>,
+    workers: Arc<[WorkerHandle]>,
+    config: RelayConfig,
+    event_counters: EventCounters,
+) -> io::Result<()> {
+    let (startup_sender, startup_receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name(String::from("control"))
+        .spawn(move || {
+            let mut selector = match Selector::create() {
+                Ok(selector) => selector,
+                Err(err) => {
+                    let _ = startup_sender.send(Err(err));
+                    return;
+                }
+            };
+            // the servers are kept alive by their handlers
+            for (port, role) in ports {
+                let result = ControlServer::create(
+                    port,
+                    role,
+                    workers.clone(),
+                    config.clone(),
+                    event_counters.clone(),
+                    &mut selector,
+                );
+                if let Err(err) = result {
+                    let _ = startup_sender.send(Err(err));
+                    return;
+                }
+            }
+            let _ = startup_sender.send(Ok(()));
+            if let Err(err) = selector.run() {
+                error!(target: TAG, "Control servers stopped: {}", err);
+            }
+        })?;
+    startup_receiver
+        .recv()
+        .map_err(|_| io::Error::other("Control servers failed to start"))?
+}
+
+impl ControlServer {
+    pub fn create(
+        port: u16,
+        role: Role,
+        workers: Arc<[WorkerHandle]>,
+        config: RelayConfig,
+        event_counters: EventCounters,
+        selector: &mut Selector,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let localhost = Ipv4Addr::new(127, 0, 0, 1).into();
+        let tcp_listener = TcpListener::bind(&SocketAddr::new(localhost, port))?;
+        let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
+            tcp_listener,
+            role,
+            workers,
+            config,
+            event_counters,
+            started_at: Instant::now(),
+        }));
+
+        // keep a shared reference to this
+        rc.borrow_mut().self_weak = Rc::downgrade(&rc);
+
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        selector.register(
+            &rc.borrow().tcp_listener,
+            handler,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        Ok(rc)
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, _: Event) {
+        // the listener is edge-triggered, accept all the pending connections
+        loop {
+            match self.tcp_listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) =
+                        ControlConnection::create(selector, stream, self.self_weak.clone())
+                    {
+                        error!(target: TAG, "Cannot register control connection: {}", err);
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    error!(target: TAG, "Cannot accept control connection: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn execute(&self, command: &Command) -> Response {
+        if self.role == Role::Metrics && *command != Command::Metrics {
+            return Response::error(404, "Not found");
+        }
+        match self.try_execute(command) {
+            Ok(response) => response,
+            Err(err) => {
+                error!(target: TAG, "Cannot execute {:?}: {}", command, err);
+                Response::error(503, "Worker unavailable")
+            }
+        }
+    }
+
+    fn try_execute(&self, command: &Command) -> io::Result<Response> {
+        match *command {
+            Command::Stats => {
+                let mut json = self.stats()?.to_json();
+                json.push('\n');
+                Ok(Response::ok(json))
+            }
+            Command::Metrics => {
+                let stats = self.stats()?;
+                let events = self.event_counters.snapshot();
+                Ok(Response::text(metrics::format_metrics(&stats, &events)))
+            }
+            Command::DisconnectClient(client_id) => {
+                for worker in self.workers.iter() {
+                    if worker.disconnect_client(client_id)? {
+                        info!(target: TAG, "Disconnecting client #{}", client_id);
+                        return Ok(Response::ok("{\"result\":\"disconnected\"}\n".to_string()));
+                    }
+                }
+                Ok(Response::error(404, "Unknown client"))
+            }
+            Command::AbortConnection(client_id, ref id) => {
+                for worker in self.workers.iter() {
+                    if worker.abort_connection(client_id, id)? {
+                        info!(
+                            target: TAG,
+                            "Closed connection {} of client #{}", id, client_id
+                        );
+                        return Ok(Response::ok("{\"result\":\"closed\"}\n".to_string()));
+                    }
+                }
+                Ok(Response::error(404, "Unknown connection"))
+            }
+            Command::Reload => {
+                if self.config.config_loader().is_none() {
+                    return Ok(Response::error(404, "No configuration to reload"));
+                }
+                match reload::reload(&self.config, &self.workers) {
+                    Ok(()) => Ok(Response::ok("{\"result\":\"reloaded\"}\n".to_string())),
+                    Err(err) => {
+                        error!(target: TAG, "Cannot reload the configuration: {}", err);
+                        Ok(Response::error(500, &err))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collect the state of the relay from all the workers.
+    pub fn stats(&self) -> io::Result<RelayStats> {
+        let mut clients = Vec::new();
+        for worker in self.workers.iter() {
+            clients.extend(worker.stats()?);
+        }
+        clients.sort_by_key(|client| client.id);
+        Ok(RelayStats {
+            uptime: self.started_at.elapsed(),
+            workers: self.workers.len(),
+            open_connections: self.config.connection_limits().open(),
+            memory: self.config.memory_budget().usage(),
+            clients,
+        })
+    }
+}
+
+// an HTTP exchange: a single request and its response, then the connection is closed
+struct ControlConnection {
+    stream: TcpStream,
+    token: Token,
+    server: Weak<RefCell<ControlServer>>,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    written: usize,
+    closed: bool,
+}
+
+impl ControlConnection {
+    fn create(
+        selector: &mut Selector,
+        stream: TcpStream,
+        server: Weak<RefCell<ControlServer>>,
+    ) -> io::Result<()> {
+        let rc = Rc::new(RefCell::new(Self {
+            stream,
+            token: Token(0), // default value, will be set afterwards
+            server,
+            request: Vec::new(),
+            response: None,
+            written: 0,
+            closed: false,
+        }));
+        let rc2 = rc.clone();
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        let mut self_ref = rc.borrow_mut();
+        // the selector keeps the connection alive until it is deregistered
+        self_ref.token = selector.register(
+            &self_ref.stream,
+            handler,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
+        Ok(())
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        if self.closed {
+            return;
+        }
+        let ready = event.readiness();
+        if self.response.is_none() && ready.is_readable() {
+            self.process_receive(selector);
+        }
+        if !self.closed && self.response.is_some() {
+            self.process_send(selector);
+        }
+    }
+
+    fn process_receive(&mut self, selector: &mut Selector) {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    // closed before sending a complete request
+                    self.close(selector);
+                    return;
+                }
+                Ok(r) => self.request.extend_from_slice(&buf[..r]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    debug!(target: TAG, "Cannot read control request: {}", err);
+                    self.close(selector);
+                    return;
+                }
+            }
+        }
+        let response = match parse_request(&self.request) {
+            Ok(None) => return, // wait for the rest of the request
+            Ok(Some((method, path))) => {
+                debug!(target: TAG, "{} {}", method, path);
+                match parse_command(&method, &path) {
+                    Ok(command) => match self.server.upgrade() {
+                        Some(server) => server.borrow().execute(&command),
+                        None => Response::error(503, "Relay stopped"),
+                    },
+                    Err(response) => response,
+                }
+            }
+            Err(response) => response,
+        };
+        self.response = Some(response.to_bytes());
+        if let Err(err) = selector.reregister(
+            &self.stream,
+            self.token,
+            Ready::writable(),
+            PollOpt::level(),
+        ) {
+            error!(target: TAG, "Cannot register control connection: {}", err);
+            self.close(selector);
+        }
+    }
+
+    fn process_send(&mut self, selector: &mut Selector) {
+        let response = self.response.as_ref().unwrap();
+        while self.written < response.len() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(w) => self.written += w,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    debug!(target: TAG, "Cannot write control response: {}", err);
+                    break;
+                }
+            }
+        }
+        self.close(selector);
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        self.closed = true;
+        if let Err(err) = selector.deregister(&self.stream, self.token) {
+            warn!(target: TAG, "Cannot deregister control connection: {:?}", err);
+        }
+        // socket will be closed by RAII
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_complete_request() {
+        let request = b"GET /stats?pretty HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            Some(("GET".to_string(), "/stats".to_string())),
+            parse_request(request).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_incomplete_request() {
+        assert_eq!(
+            None,
+            parse_request(b"GET /stats HTTP/1.1\r\nHost:").unwrap()
+        );
+        let long = vec![b'a'; MAX_REQUEST_LENGTH + 1];
+        assert_eq!(431, parse_request(&long).unwrap_err().status);
+        assert_eq!(400, parse_request(b"GET\r\n\r\n").unwrap_err().status);
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::Stats, parse_command("GET", "/stats").unwrap());
+        assert_eq!(Command::Metrics, parse_command("GET", "/metrics").unwrap());
+        assert_eq!(Command::Reload, parse_command("POST", "/reload").unwrap());
+        assert_eq!(
+            Command::DisconnectClient(3),
+            parse_command("DELETE", "/clients/3").unwrap()
+        );
+        let id = ConnectionId::new(
+            Protocol::Tcp,
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 41234),
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443),
+        );
+        assert_eq!(
+            Command::AbortConnection(3, id),
+            parse_command(
+                "DELETE",
+                "/clients/3/connections/tcp/10.0.0.2:41234/1.2.3.4:443"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_commands() {
+        assert_eq!(405, parse_command("DELETE", "/stats").unwrap_err().status);
+        assert_eq!(405, parse_command("GET", "/clients/3").unwrap_err().status);
+        assert_eq!(405, parse_command("GET", "/reload").unwrap_err().status);
+        assert_eq!(404, parse_command("GET", "/").unwrap_err().status);
+        assert_eq!(
+            404,
+            parse_command("DELETE", "/clients/x").unwrap_err().status
+        );
+        assert_eq!(
+            404,
+            parse_command("DELETE", "/clients/3/connections/icmp/10.0.0.2:1/1.2.3.4:2")
+                .unwrap_err()
+                .status
+        );
+    }
+
+    #[test]
+    fn format_response() {
+        let response = Response::error(404, "Unknown client");
+        assert_eq!(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 27\r\n\
+             Connection: close\r\n\r\n{\"error\":\"Unknown client\"}\n",
+            String::from_utf8(response.to_bytes()).unwrap()
+        );
+
+        let response = Response::error(500, "Cannot read \"a\\b.conf\"\n");
+        assert_eq!(
+            "{\"error\":\"Cannot read \\\"a\\\\b.conf\\\"\\u000a\"}\n",
+            response.body
+        );
+    }
+}
@@ -18,10 +18,24 @@ use mio::net::UdpSocket;
 use std::cmp;
 use std::io;
 
+use super::datagram_batch;
+
 pub const MAX_DATAGRAM_LENGTH: usize = 1 << 16;
 
 pub trait DatagramSender {
     fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Send several datagrams, and return the number of datagrams sent.
+    ///
+    /// An error is returned only if no datagram was sent.
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        for (i, datagram) in datagrams.iter().enumerate() {
+            if let Err(err) = self.send(datagram) {
+                return if i > 0 { Ok(i) } else { Err(err) };
+            }
+        }
+        Ok(datagrams.len())
+    }
 }
 
 pub trait DatagramReceiver {
@@ -34,6 +48,10 @@ impl DatagramSender for UdpSocket {
         // call the Self implementation
         (self as &Self).send(buf)
     }
+
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        datagram_batch::send_batch(self, datagrams)
+    }
 }
 
 // Expose UdpSocket as DatagramReceiver
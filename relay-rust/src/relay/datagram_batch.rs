@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::net::UdpSocket;
+use std::cell::RefCell;
+use std::io;
+
+use super::datagram::MAX_DATAGRAM_LENGTH;
+
+/// Maximum number of datagrams moved by a single system call.
+pub const MAX_BATCH_SIZE: usize = 16;
+
+// without recvmmsg(), the datagrams are received one by one
+#[cfg(target_os = "linux")]
+const RECV_BUFFER_COUNT: usize = MAX_BATCH_SIZE;
+#[cfg(not(target_os = "linux"))]
+const RECV_BUFFER_COUNT: usize = 1;
+
+thread_local! {
+    // receive buffers, shared by all the connections of an event loop
+    static RECV_BUFFERS: RefCell<Vec<Box<[u8]>>> = RefCell::new(
+        (0..RECV_BUFFER_COUNT)
+            .map(|_| vec![0; MAX_DATAGRAM_LENGTH].into_boxed_slice())
+            .collect(),
+    );
+}
+
+/// Receive up to `MAX_BATCH_SIZE` datagrams from `socket` and call `f` for each of them.
+///
+/// On Linux, the datagrams are received by a single `recvmmsg()` call; elsewhere, only one
+/// datagram is received. Return the number of datagrams received.
+pub fn recv_batch<F>(socket: &UdpSocket, mut f: F) -> io::Result<usize>
+where
+    F: FnMut(&[u8]),
+{
+    RECV_BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let mut lengths = [0; RECV_BUFFER_COUNT];
+        let count = sys::recv(socket, &mut buffers, &mut lengths)?;
+        for (buffer, &length) in buffers.iter().zip(&lengths[..count]) {
+            f(&buffer[..length]);
+        }
+        Ok(count)
+    })
+}
+
+/// Send `datagrams` (at most `MAX_BATCH_SIZE`) to the connected `socket`.
+///
+/// On Linux, the datagrams are sent by a single `sendmmsg()` call; elsewhere, they are sent one
+/// by one. Return the number of datagrams sent (an error is returned only if none was sent).
+pub fn send_batch(socket: &UdpSocket, datagrams: &[&[u8]]) -> io::Result<usize> {
+    assert!(datagrams.len() <= MAX_BATCH_SIZE, "Too many datagrams");
+    sys::send(socket, datagrams)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::MAX_BATCH_SIZE;
+    use mio::net::UdpSocket;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    fn messages(iovecs: &mut [libc::iovec]) -> [libc::mmsghdr; MAX_BATCH_SIZE] {
+        // SAFETY: mmsghdr is a plain C struct, for which all-zero is a valid value
+        let mut messages: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (message, iovec) in messages.iter_mut().zip(iovecs) {
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+        }
+        messages
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        buffers: &mut [Box<[u8]>],
+        lengths: &mut [usize],
+    ) -> io::Result<usize> {
+        // SAFETY: iovec is a plain C struct, for which all-zero is a valid value
+        let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (iovec, buffer) in iovecs.iter_mut().zip(buffers.iter_mut()) {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = buffer.len();
+        }
+        let mut messages = messages(&mut iovecs);
+        // SAFETY: the first buffers.len() messages point to the buffers, which outlive the call
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                buffers.len() as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let count = count as usize;
+        for (length, message) in lengths.iter_mut().zip(&messages[..count]) {
+            *length = message.msg_len as usize;
+        }
+        Ok(count)
+    }
+
+    pub fn send(socket: &UdpSocket, datagrams: &[&[u8]]) -> io::Result<usize> {
+        // SAFETY: iovec is a plain C struct, for which all-zero is a valid value
+        let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (iovec, datagram) in iovecs.iter_mut().zip(datagrams) {
+            // the data is only read by sendmmsg()
+            iovec.iov_base = datagram.as_ptr() as *mut libc::c_void;
+            iovec.iov_len = datagram.len();
+        }
+        let mut messages = messages(&mut iovecs);
+        // SAFETY: the first datagrams.len() messages point to the datagrams, which outlive the
+        // call
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                datagrams.len() as libc::c_uint,
+                0,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use mio::net::UdpSocket;
+    use std::io;
+
+    pub fn recv(
+        socket: &UdpSocket,
+        buffers: &mut [Box<[u8]>],
+        lengths: &mut [usize],
+    ) -> io::Result<usize> {
+        lengths[0] = socket.recv(&mut buffers[0])?;
+        Ok(1)
+    }
+
+    pub fn send(socket: &UdpSocket, datagrams: &[&[u8]]) -> io::Result<usize> {
+        for (i, datagram) in datagrams.iter().enumerate() {
+            if let Err(err) = socket.send(datagram) {
+                return if i > 0 { Ok(i) } else { Err(err) };
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let a = UdpSocket::bind(&localhost).unwrap();
+        let b = UdpSocket::bind(&localhost).unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn send_and_recv_batch() {
+        let (a, b) = connected_pair();
+        let datagrams: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let slices: Vec<&[u8]> = datagrams.iter().map(|datagram| &datagram[..]).collect();
+        let mut sent = 0;
+        while sent < slices.len() {
+            sent += send_batch(&a, &slices[sent..]).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            match recv_batch(&b, |datagram| received.push(datagram.to_vec())) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("Cannot receive: {}", err),
+            }
+        }
+        assert_eq!(datagrams, received);
+    }
+}
@@ -18,39 +18,43 @@ use byteorder::{BigEndian, ByteOrder};
 use log::*;
 use std::io;
 
+use super::buffer_pool::{self, PooledBuffer};
 use super::datagram::{DatagramSender, MAX_DATAGRAM_LENGTH};
+use super::datagram_batch::MAX_BATCH_SIZE;
 
 const HEADER_LENGTH: usize = 2;
 const MAX_BLOCK_LENGTH: usize = HEADER_LENGTH + MAX_DATAGRAM_LENGTH;
 
 const TAG: &str = "DatagramBuffer";
 
-/// Circular buffer to store datagrams (preserving their boundaries).
+/// Buffer to store datagrams (preserving their boundaries).
 ///
 /// ```text
-///     circularBufferLength
-/// |<------------------------->| extra space for storing the last datagram in one block
-/// +---------------------------+------+
-/// |                           |      |
-/// |[D4]     [  D1  ][ D2 ][  D3  ]   |
-/// +---------------------------+------+
-///     ^     ^
-///  head     tail
+///   consumed           stored            free
+/// |..........[ D1 ][  D2  ][D3]|.......................|
+///            ^                 ^
+///         tail                 head
 /// ```
+///
+/// Every datagram is stored in one block, prefixed by its length. When there is not enough space
+/// after the last datagram, the stored datagrams are moved to the front, or to a larger storage.
+///
+/// The storage is borrowed from the buffer pool lazily, and given back as soon as the buffer is
+/// empty.
 pub struct DatagramBuffer {
-    buf: Box<[u8]>,
+    buf: PooledBuffer,
     head: usize,
     tail: usize,
-    circular_buffer_length: usize,
+    capacity: usize,
 }
 
 impl DatagramBuffer {
     pub fn new(capacity: usize) -> Self {
         Self {
-            buf: vec![0; capacity + MAX_BLOCK_LENGTH].into_boxed_slice(),
+            buf: PooledBuffer::default(),
             head: 0,
             tail: 0,
-            circular_buffer_length: capacity + 1,
+            capacity,
         }
     }
 
@@ -58,14 +62,23 @@ impl DatagramBuffer {
         self.head == self.tail
     }
 
+    /// The number of bytes stored (including the headers of the datagrams).
+    pub fn size(&self) -> usize {
+        self.head - self.tail
+    }
+
+    /// Indicate whether a datagram may be stored.
+    ///
+    /// The last datagram may exceed the capacity. When the memory budget is exhausted, the buffer
+    /// does not grow beyond its current storage, but always accepts one datagram when empty.
     pub fn has_enough_space_for(&self, datagram_length: usize) -> bool {
-        if self.head >= self.tail {
-            // there is at least the extra space for storing 1 packet
+        if self.is_empty() {
             return true;
         }
-        // 1 extra byte to distinguish empty vs full
-        let remaining = self.tail - self.head - 1;
-        HEADER_LENGTH + datagram_length <= remaining
+        if buffer_pool::is_exhausted() {
+            return self.size() + HEADER_LENGTH + datagram_length <= self.buf.len();
+        }
+        self.size() < self.capacity
     }
 
     pub fn write_to<S: DatagramSender>(&mut self, destination: &mut S) -> io::Result<()> {
@@ -73,26 +86,50 @@ impl DatagramBuffer {
             !self.is_empty(),
             "DatagramBuffer.write_to() called while empty"
         );
-        let length = self.read_length() as usize;
-        let source_slice = &self.buf[self.tail..self.tail + length];
-        self.tail += length;
-        if self.tail >= self.circular_buffer_length {
-            self.tail = 0;
-        }
-        let w = destination.send(source_slice)?;
+        let (start, end) = self.block_at(self.tail);
+        let result = destination.send(&self.buf[start..end]);
+        self.consume(end);
+        let w = result?;
+        let length = end - start;
         if w != length {
             error!(
                 target: TAG,
                 "Cannot write the whole datagram to the buffer (only {}/{})", w, length
             );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Cannot write the whole datagram",
-            ));
+            return Err(io::Error::other("Cannot write the whole datagram"));
         }
         Ok(())
     }
 
+    /// Write up to `MAX_BATCH_SIZE` datagrams at once.
+    ///
+    /// The datagrams not sent are kept, except the first one on error (like `write_to()`).
+    pub fn write_batch_to<S: DatagramSender>(&mut self, destination: &mut S) -> io::Result<()> {
+        assert!(
+            !self.is_empty(),
+            "DatagramBuffer.write_batch_to() called while empty"
+        );
+        let mut blocks = [(0, 0); MAX_BATCH_SIZE];
+        let mut count = 0;
+        let mut index = self.tail;
+        while index != self.head && count < MAX_BATCH_SIZE {
+            let block = self.block_at(index);
+            blocks[count] = block;
+            count += 1;
+            index = block.1;
+        }
+        let mut datagrams: [&[u8]; MAX_BATCH_SIZE] = [&[]; MAX_BATCH_SIZE];
+        for (datagram, &(start, end)) in datagrams.iter_mut().zip(&blocks[..count]) {
+            *datagram = &self.buf[start..end];
+        }
+        let result = destination.send_batch(&datagrams[..count]);
+        // on error, drop the datagram which could not be sent
+        let consumed = *result.as_ref().unwrap_or(&1);
+        let (_, end) = blocks[consumed - 1];
+        self.consume(end);
+        result.map(|_| ())
+    }
+
     pub fn read_from(&mut self, source: &[u8]) -> io::Result<()> {
         let length = source.len();
         assert!(
@@ -101,29 +138,54 @@ impl DatagramBuffer {
             MAX_DATAGRAM_LENGTH
         );
         if !self.has_enough_space_for(length) {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Datagram buffer is full",
-            ));
-        }
-        self.write_length(length as u16);
-        let target_slice = &mut self.buf[self.head..self.head + length];
-        target_slice.copy_from_slice(source);
-        self.head += length;
-        if self.head >= self.circular_buffer_length {
-            self.head = 0;
+            return Err(io::Error::other("Datagram buffer is full"));
         }
+        self.reserve(HEADER_LENGTH + length);
+        BigEndian::write_u16(
+            &mut self.buf[self.head..self.head + HEADER_LENGTH],
+            length as u16,
+        );
+        self.head += HEADER_LENGTH;
+        self.buf[self.head..self.head + length].copy_from_slice(source);
+        self.head += length;
         Ok(())
     }
 
-    fn read_length(&mut self) -> u16 {
-        self.tail += 2;
-        BigEndian::read_u16(&self.buf[self.tail - 2..self.tail])
+    /// The bounds of the datagram whose block starts at `index`.
+    fn block_at(&self, index: usize) -> (usize, usize) {
+        let length = BigEndian::read_u16(&self.buf[index..index + HEADER_LENGTH]) as usize;
+        let start = index + HEADER_LENGTH;
+        (start, start + length)
     }
 
-    fn write_length(&mut self, length: u16) {
-        BigEndian::write_u16(&mut self.buf[self.head..self.head + 2], length);
-        self.head += 2;
+    /// Consume the datagrams until `index`.
+    fn consume(&mut self, index: usize) {
+        self.tail = index;
+        if self.is_empty() {
+            self.head = 0;
+            self.tail = 0;
+            self.buf = PooledBuffer::default();
+        }
+    }
+
+    /// Make room for `length` bytes after the last datagram.
+    fn reserve(&mut self, length: usize) {
+        if self.head + length <= self.buf.len() {
+            return;
+        }
+        let size = self.size();
+        if size + length <= self.buf.len() {
+            // enough space once the datagrams are moved to the front
+            self.buf.copy_within(self.tail..self.head, 0);
+        } else {
+            let max_size = self.capacity + MAX_BLOCK_LENGTH;
+            let block_size = buffer_pool::block_size(size + length, max_size);
+            let mut buf = PooledBuffer::allocate(block_size);
+            buf[..size].copy_from_slice(&self.buf[self.tail..self.head]);
+            self.buf = buf;
+        }
+        self.tail = 0;
+        self.head = size;
     }
 }
 
@@ -132,10 +194,63 @@ mod tests {
     use super::*;
     use crate::relay::datagram::tests::MockDatagramSocket;
 
+    // record the datagrams, accepting at most `capacity` datagrams per batch
+    #[derive(Default)]
+    struct BatchSender {
+        sent: Vec<Vec<u8>>,
+        capacity: Option<usize>,
+    }
+
+    impl DatagramSender for BatchSender {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self
+                .capacity
+                .is_some_and(|capacity| self.sent.len() >= capacity)
+            {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Full"));
+            }
+            self.sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
     fn create_datagram(length: u8) -> Vec<u8> {
         (0..length).collect()
     }
 
+    #[test]
+    fn write_batch() {
+        let mut datagram_buffer = DatagramBuffer::new(64);
+        let datagrams: Vec<Vec<u8>> = (3..7).map(create_datagram).collect();
+        for datagram in &datagrams {
+            datagram_buffer.read_from(datagram).unwrap();
+        }
+
+        let mut sender = BatchSender::default();
+        datagram_buffer.write_batch_to(&mut sender).unwrap();
+        assert_eq!(datagrams, sender.sent);
+        assert!(datagram_buffer.is_empty());
+    }
+
+    #[test]
+    fn write_batch_partially() {
+        let mut datagram_buffer = DatagramBuffer::new(64);
+        let datagrams: Vec<Vec<u8>> = (3..7).map(create_datagram).collect();
+        for datagram in &datagrams {
+            datagram_buffer.read_from(datagram).unwrap();
+        }
+
+        let mut sender = BatchSender {
+            capacity: Some(3),
+            ..Default::default()
+        };
+        datagram_buffer.write_batch_to(&mut sender).unwrap();
+        assert_eq!(datagrams[..3], sender.sent[..]);
+        // the last one is kept
+        assert_eq!(read_datagram(&mut datagram_buffer), datagrams[3]);
+        assert!(datagram_buffer.is_empty());
+    }
+
     #[test]
     fn bufferize_datagram() {
         let datagram = create_datagram(5);
@@ -188,6 +303,41 @@ mod tests {
         assert_eq!(read_datagram(&mut datagram_buffer), datagram3);
     }
 
+    #[test]
+    fn grow_lazily() {
+        let mut datagram_buffer = DatagramBuffer::new(64 * 1024);
+        assert_eq!(0, datagram_buffer.buf.len());
+
+        let datagram = vec![42u8; 1000];
+        for _ in 0..5 {
+            datagram_buffer.read_from(&datagram).unwrap();
+        }
+        assert_eq!(2 * buffer_pool::MIN_BLOCK_SIZE, datagram_buffer.buf.len());
+
+        // consume some datagrams, so that the others are moved to the front instead of growing
+        read_datagram(&mut datagram_buffer);
+        read_datagram(&mut datagram_buffer);
+        for _ in 0..4 {
+            datagram_buffer.read_from(&datagram).unwrap();
+        }
+        assert_eq!(2 * buffer_pool::MIN_BLOCK_SIZE, datagram_buffer.buf.len());
+
+        for _ in 0..7 {
+            assert_eq!(datagram, read_datagram(&mut datagram_buffer));
+        }
+        assert!(datagram_buffer.is_empty());
+        assert_eq!(0, datagram_buffer.buf.len());
+    }
+
+    #[test]
+    fn refuse_when_full() {
+        let mut datagram_buffer = DatagramBuffer::new(16);
+        datagram_buffer.read_from(&create_datagram(10)).unwrap();
+        // exceeds the capacity, but accepted since the buffer is not full
+        datagram_buffer.read_from(&create_datagram(10)).unwrap();
+        assert!(datagram_buffer.read_from(&create_datagram(1)).is_err());
+    }
+
     fn read_datagram(datagram_buffer: &mut DatagramBuffer) -> Vec<u8> {
         let mut mock = MockDatagramSocket::new();
         datagram_buffer.write_to(&mut mock).unwrap();
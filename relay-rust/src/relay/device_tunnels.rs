@@ -0,0 +1,119 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use super::selector::Wake;
+
+/// Handle to open a tunnel port dedicated to a device, identifying the clients of this device.
+///
+/// The devices all reach the relay through `adb reverse`, from the local host: the relay cannot
+/// tell them apart on the main port. A device given its own port instead (as the target of its
+/// `adb reverse`) is identified by its serial, so that the per-device settings apply to its
+/// clients, whatever their client id.
+///
+/// It may be used from any thread, before or while the relay is running.
+#[derive(Clone, Default)]
+pub struct DeviceTunnels {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ports: HashMap<String, u16>,
+    // opened, but not yet listened to by the relay
+    pending: Vec<(Arc<str>, TcpListener)>,
+    // set once the relay is running
+    waker: Option<Box<dyn Wake>>,
+}
+
+impl DeviceTunnels {
+    /// Open the tunnel port of the device `serial` (once), and return it.
+    pub fn open(&self, serial: &str) -> io::Result<u16> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&port) = inner.ports.get(serial) {
+            return Ok(port);
+        }
+        let localhost = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+        let listener = TcpListener::bind(localhost)?;
+        let port = listener.local_addr()?.port();
+        inner.ports.insert(serial.to_string(), port);
+        inner.pending.push((Arc::from(serial), listener));
+        if let Some(ref waker) = inner.waker {
+            waker.wake()?;
+        }
+        Ok(port)
+    }
+
+    pub(crate) fn bind(&self, waker: Box<dyn Wake>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.pending.is_empty() {
+            // tunnels opened before the relay started
+            waker.wake()?;
+        }
+        inner.waker = Some(waker);
+        Ok(())
+    }
+
+    /// Take the listeners opened since the last call, along with the serial of their device.
+    pub(crate) fn take_pending(&self) -> Vec<(Arc<str>, TcpListener)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.drain(..).collect()
+    }
+}
+
+/// Parse the device serial of a per-device setting.
+pub fn parse_serial(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() || s.contains(char::is_whitespace) {
+        Err(format!("Invalid device serial: \"{}\"", s))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+impl fmt::Debug for DeviceTunnels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("DeviceTunnels")
+            .field("ports", &inner.ports)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_once_per_device() {
+        let device_tunnels = DeviceTunnels::default();
+        let port = device_tunnels.open("emulator-5554").unwrap();
+        assert_eq!(port, device_tunnels.open("emulator-5554").unwrap());
+        let other_port = device_tunnels.open("192.168.1.2:5555").unwrap();
+        assert_ne!(port, other_port);
+
+        let pending = device_tunnels.take_pending();
+        assert_eq!(2, pending.len());
+        assert_eq!("emulator-5554", &*pending[0].0);
+        assert_eq!(port, pending[0].1.local_addr().unwrap().port());
+        assert!(device_tunnels.take_pending().is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::Ipv4Addr;
+
+// See RFC1035: <https://tools.ietf.org/html/rfc1035#section-4>
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LENGTH: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_MASK: u16 = 0x000F;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+// guard against compression loops
+const MAX_POINTERS: usize = 16;
+
+/// The IPv4 addresses a host name resolves to, extracted from a DNS response.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DnsAnswer {
+    /// The queried name, lowercase, without the trailing dot
+    ///
+    /// Addresses reached through CNAME records are associated to the queried name, which is the
+    /// one the device asked for.
+    pub hostname: String,
+    pub addresses: Vec<Ipv4Addr>,
+    /// The smallest TTL of the A records, in seconds
+    pub ttl: u32,
+}
+
+/// Parse the A records of a DNS response.
+///
+/// Return `None` if the message is not a successful response containing A records.
+pub fn parse_response(message: &[u8]) -> Option<DnsAnswer> {
+    if message.len() < HEADER_LENGTH {
+        return None;
+    }
+    let flags = BigEndian::read_u16(&message[2..4]);
+    if flags & FLAG_RESPONSE == 0 || flags & RCODE_MASK != 0 {
+        return None;
+    }
+    let question_count = BigEndian::read_u16(&message[4..6]);
+    let answer_count = BigEndian::read_u16(&message[6..8]);
+    if question_count != 1 {
+        // never sent by resolvers in practice
+        return None;
+    }
+
+    let (hostname, mut offset) = read_name(message, HEADER_LENGTH)?;
+    offset += 4; // QTYPE and QCLASS
+
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answer_count {
+        let (_, name_end) = read_name(message, offset)?;
+        let record = message.get(name_end..name_end + 10)?;
+        let record_type = BigEndian::read_u16(&record[0..2]);
+        let class = BigEndian::read_u16(&record[2..4]);
+        let record_ttl = BigEndian::read_u32(&record[4..8]);
+        let data_length = BigEndian::read_u16(&record[8..10]) as usize;
+        let data = message.get(name_end + 10..name_end + 10 + data_length)?;
+        if record_type == TYPE_A && class == CLASS_IN && data_length == 4 {
+            addresses.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            ttl = ttl.min(record_ttl);
+        }
+        offset = name_end + 10 + data_length;
+    }
+    if addresses.is_empty() {
+        return None;
+    }
+    Some(DnsAnswer {
+        hostname,
+        addresses,
+        ttl,
+    })
+}
+
+// return the name and the offset following it in the message
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(offset)? as usize;
+        if length & 0xC0 == 0xC0 {
+            // compression pointer
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            let pointer = BigEndian::read_u16(message.get(offset..offset + 2)?) & 0x3FFF;
+            if end.is_none() {
+                end = Some(offset + 2);
+            }
+            offset = pointer as usize;
+        } else if length == 0 {
+            return Some((name, end.unwrap_or(offset + 1)));
+        } else {
+            let label = message.get(offset + 1..offset + 1 + length)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+            offset += 1 + length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // response for "www.Example.com", CNAME to "example.com", with 2 A records
+    fn create_response() -> Vec<u8> {
+        let mut message = vec![
+            0x12, 0x34, // id
+            0x81, 0x80, // flags: response, no error
+            0, 1, // questions
+            0, 3, // answers
+            0, 0, 0, 0, // authority and additional records
+        ];
+        // question (offset 12)
+        message.extend_from_slice(b"\x03www\x07Example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        // CNAME www.example.com -> example.com (pointer to offset 16)
+        message.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 2, 0xC0, 16]);
+        // A example.com (pointer to offset 16) 93.184.216.34, TTL 60
+        message.extend_from_slice(&[0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        // A example.com 93.184.216.35, TTL 30
+        message.extend_from_slice(&[0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 35]);
+        message
+    }
+
+    #[test]
+    fn parse_a_records() {
+        let answer = parse_response(&create_response()).unwrap();
+        assert_eq!("www.example.com", answer.hostname);
+        assert_eq!(
+            vec![
+                Ipv4Addr::new(93, 184, 216, 34),
+                Ipv4Addr::new(93, 184, 216, 35)
+            ],
+            answer.addresses
+        );
+        assert_eq!(30, answer.ttl);
+    }
+
+    #[test]
+    fn ignore_queries_and_errors() {
+        let mut message = create_response();
+        message[2] = 0x01; // query
+        assert!(parse_response(&message).is_none());
+
+        let mut message = create_response();
+        message[3] = 0x83; // NXDOMAIN
+        assert!(parse_response(&message).is_none());
+    }
+
+    #[test]
+    fn ignore_truncated_response() {
+        let message = create_response();
+        assert!(parse_response(&message[..message.len() - 2]).is_none());
+        assert!(parse_response(&message[..8]).is_none());
+    }
+
+    #[test]
+    fn reject_compression_loop() {
+        let mut message = vec![0, 0, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        message.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert!(parse_response(&message).is_none());
+    }
+}
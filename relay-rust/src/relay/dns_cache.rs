@@ -0,0 +1,120 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::dns::DnsAnswer;
+
+// devices often keep resolved addresses longer than their TTL, and reuse them for new connections
+const MIN_TTL: Duration = Duration::from_secs(300);
+const MAX_ENTRIES: usize = 4096;
+
+struct Entry {
+    hostname: Rc<str>,
+    expiration: Instant,
+}
+
+/// Host names resolved by a client, as observed in the DNS responses relayed to it.
+pub struct DnsCache {
+    entries: HashMap<Ipv4Addr, Entry>,
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, answer: &DnsAnswer) {
+        self.record_at(answer, Instant::now());
+    }
+
+    fn record_at(&mut self, answer: &DnsAnswer, now: Instant) {
+        let ttl = Duration::from_secs(u64::from(answer.ttl)).max(MIN_TTL);
+        let expiration = now + ttl;
+        if self.entries.len() + answer.addresses.len() > MAX_ENTRIES {
+            self.entries.retain(|_, entry| entry.expiration > now);
+        }
+        let hostname: Rc<str> = Rc::from(answer.hostname.as_str());
+        for &address in &answer.addresses {
+            if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&address) {
+                // full of valid entries, keep the existing ones
+                break;
+            }
+            self.entries.insert(
+                address,
+                Entry {
+                    hostname: hostname.clone(),
+                    expiration,
+                },
+            );
+        }
+    }
+
+    /// The host name which resolved to `address`, if known.
+    pub fn hostname(&self, address: &Ipv4Addr) -> Option<&str> {
+        self.hostname_at(address, Instant::now())
+    }
+
+    fn hostname_at(&self, address: &Ipv4Addr, now: Instant) -> Option<&str> {
+        self.entries
+            .get(address)
+            .filter(|entry| entry.expiration > now)
+            .map(|entry| &*entry.hostname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(hostname: &str, address: Ipv4Addr, ttl: u32) -> DnsAnswer {
+        DnsAnswer {
+            hostname: hostname.to_string(),
+            addresses: vec![address],
+            ttl,
+        }
+    }
+
+    #[test]
+    fn lookup_hostname() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        let address = Ipv4Addr::new(10, 1, 2, 3);
+        cache.record_at(&answer("a.internal.example", address, 3600), now);
+        assert_eq!(Some("a.internal.example"), cache.hostname_at(&address, now));
+        assert_eq!(None, cache.hostname_at(&Ipv4Addr::new(10, 1, 2, 4), now));
+
+        // the most recent response wins
+        cache.record_at(&answer("b.internal.example", address, 3600), now);
+        assert_eq!(Some("b.internal.example"), cache.hostname_at(&address, now));
+    }
+
+    #[test]
+    fn expire_entries() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        let address = Ipv4Addr::new(10, 1, 2, 3);
+        cache.record_at(&answer("a.internal.example", address, 1), now);
+        // the TTL is raised to the minimum
+        assert!(cache.hostname_at(&address, now + MIN_TTL / 2).is_some());
+        assert!(cache.hostname_at(&address, now + MIN_TTL).is_none());
+    }
+}
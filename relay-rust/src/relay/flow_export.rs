@@ -0,0 +1,536 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use log::*;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::flow_log::{CloseReason, FlowRecord};
+use super::ipv4_header::Protocol;
+
+const TAG: &str = "FlowExporter";
+
+// IPFIX over UDP must resend the templates regularly, for collectors started after the relay
+const TEMPLATE_INTERVAL: Duration = Duration::from_secs(60);
+
+const UPLINK_TEMPLATE_ID: u16 = 256;
+const DOWNLINK_TEMPLATE_ID: u16 = 257;
+
+// Information Elements (same ids in NetFlow v9, except for the timestamps)
+// <https://www.iana.org/assignments/ipfix/ipfix.xhtml>
+const OCTET_DELTA_COUNT: u16 = 1;
+const PACKET_DELTA_COUNT: u16 = 2;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const LAST_SWITCHED: u16 = 21;
+const FIRST_SWITCHED: u16 = 22;
+const FLOW_END_REASON: u16 = 136;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+const POST_NAT_SOURCE_IPV4_ADDRESS: u16 = 225;
+const POST_NAT_DESTINATION_IPV4_ADDRESS: u16 = 226;
+const POST_NAPT_SOURCE_TRANSPORT_PORT: u16 = 227;
+const POST_NAPT_DESTINATION_TRANSPORT_PORT: u16 = 228;
+
+// flowEndReason values
+const IDLE_TIMEOUT: u8 = 1;
+const END_OF_FLOW_DETECTED: u8 = 3;
+const FORCED_END: u8 = 4;
+const LACK_OF_RESOURCES: u8 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// IPFIX (RFC 7011).
+    Ipfix,
+    /// NetFlow version 9 (RFC 3954).
+    NetflowV9,
+}
+
+impl ExportFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Ipfix => "ipfix",
+            ExportFormat::NetflowV9 => "netflow9",
+        }
+    }
+}
+
+/// Where and how to export the flow records.
+///
+/// Syntax: `HOST:PORT[,format=FORMAT]`, where `FORMAT` is `ipfix` (the default) or `netflow9`.
+///
+/// For example, `127.0.0.1:4739` or `collector.example:2055,format=netflow9`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExportTarget {
+    collector: SocketAddr,
+    format: ExportFormat,
+}
+
+impl ExportTarget {
+    pub fn new(collector: SocketAddr, format: ExportFormat) -> Self {
+        Self { collector, format }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut settings = s.split(',').map(str::trim);
+        let address = settings.next().unwrap_or("");
+        let collector = address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| format!("Invalid collector address: \"{}\"", address))?;
+        let mut format = ExportFormat::Ipfix;
+        for setting in settings.filter(|s| !s.is_empty()) {
+            format = match setting.strip_prefix("format=") {
+                Some("ipfix") => ExportFormat::Ipfix,
+                Some("netflow9") => ExportFormat::NetflowV9,
+                _ => return Err(format!("Invalid flow export setting: \"{}\"", setting)),
+            };
+        }
+        Ok(Self::new(collector, format))
+    }
+
+    pub fn collector(&self) -> SocketAddr {
+        self.collector
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+}
+
+impl fmt::Display for ExportTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},format={}", self.collector, self.format.as_str())
+    }
+}
+
+/// Exporter of the flow records to a collector, shared by all the clients.
+///
+/// Every closed connection is exported as two unidirectional records: the uplink (from the
+/// device, with the destination after NAT) and the downlink (to the device, with the source
+/// before NAT).
+#[derive(Clone, Debug, Default)]
+pub struct FlowExporter {
+    exporter: Option<Arc<Mutex<Exporter>>>,
+}
+
+impl FlowExporter {
+    pub fn open(target: ExportTarget) -> io::Result<Self> {
+        let bind_address: SocketAddr = if target.collector.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind_address)?;
+        let exporter = Exporter {
+            target,
+            socket,
+            encoder: Encoder::new(target.format, Utc::now()),
+            last_templates: None,
+            failing: false,
+        };
+        Ok(Self {
+            exporter: Some(Arc::new(Mutex::new(exporter))),
+        })
+    }
+
+    pub fn target(&self) -> Option<ExportTarget> {
+        self.exporter
+            .as_ref()
+            .map(|exporter| exporter.lock().unwrap().target)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.exporter.is_some()
+    }
+
+    pub fn export(&self, record: &FlowRecord) {
+        if let Some(exporter) = &self.exporter {
+            exporter.lock().unwrap().export(record);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Exporter {
+    target: ExportTarget,
+    socket: UdpSocket,
+    encoder: Encoder,
+    last_templates: Option<Instant>,
+    // set after a failure, to report it only once
+    failing: bool,
+}
+
+impl Exporter {
+    fn export(&mut self, record: &FlowRecord) {
+        let now = Instant::now();
+        let with_templates = match self.last_templates {
+            Some(last) => now.duration_since(last) >= TEMPLATE_INTERVAL,
+            None => true,
+        };
+        let message = self.encoder.encode(record, with_templates, Utc::now());
+        match self.socket.send_to(&message, self.target.collector) {
+            Ok(_) => {
+                if with_templates {
+                    self.last_templates = Some(now);
+                }
+                self.failing = false;
+            }
+            Err(err) => {
+                if !self.failing {
+                    warn!(
+                        target: TAG,
+                        "Cannot export flow to {}: {}", self.target.collector, err
+                    );
+                    self.failing = true;
+                }
+            }
+        }
+    }
+}
+
+type Template = [(u16, u16); 12];
+
+/// Encoder of the export messages (one per flow), keeping the sequence number.
+#[derive(Debug)]
+struct Encoder {
+    format: ExportFormat,
+    // the reference of the NetFlow v9 timestamps (sysUpTime)
+    start: DateTime<Utc>,
+    sequence_number: u32,
+}
+
+impl Encoder {
+    fn new(format: ExportFormat, start: DateTime<Utc>) -> Self {
+        Self {
+            format,
+            start,
+            sequence_number: 0,
+        }
+    }
+
+    fn templates(&self) -> [(u16, Template); 2] {
+        let (start_field, end_field, time_length) = match self.format {
+            ExportFormat::Ipfix => (FLOW_START_MILLISECONDS, FLOW_END_MILLISECONDS, 8),
+            ExportFormat::NetflowV9 => (FIRST_SWITCHED, LAST_SWITCHED, 4),
+        };
+        let template = |nat_address, nat_port| {
+            [
+                (SOURCE_IPV4_ADDRESS, 4),
+                (DESTINATION_IPV4_ADDRESS, 4),
+                (SOURCE_TRANSPORT_PORT, 2),
+                (DESTINATION_TRANSPORT_PORT, 2),
+                (PROTOCOL_IDENTIFIER, 1),
+                (OCTET_DELTA_COUNT, 8),
+                (PACKET_DELTA_COUNT, 8),
+                (start_field, time_length),
+                (end_field, time_length),
+                (FLOW_END_REASON, 1),
+                (nat_address, 4),
+                (nat_port, 2),
+            ]
+        };
+        [
+            (
+                UPLINK_TEMPLATE_ID,
+                template(
+                    POST_NAT_DESTINATION_IPV4_ADDRESS,
+                    POST_NAPT_DESTINATION_TRANSPORT_PORT,
+                ),
+            ),
+            (
+                DOWNLINK_TEMPLATE_ID,
+                template(
+                    POST_NAT_SOURCE_IPV4_ADDRESS,
+                    POST_NAPT_SOURCE_TRANSPORT_PORT,
+                ),
+            ),
+        ]
+    }
+
+    fn encode(&mut self, record: &FlowRecord, with_templates: bool, now: DateTime<Utc>) -> Vec<u8> {
+        let mut sets = Vec::new();
+        let mut record_count = 0;
+        if with_templates {
+            let mut set = Vec::new();
+            for (template_id, fields) in &self.templates() {
+                set.write_u16::<BigEndian>(*template_id).unwrap();
+                set.write_u16::<BigEndian>(fields.len() as u16).unwrap();
+                for &(id, length) in fields {
+                    set.write_u16::<BigEndian>(id).unwrap();
+                    set.write_u16::<BigEndian>(length).unwrap();
+                }
+                record_count += 1;
+            }
+            let set_id = match self.format {
+                ExportFormat::Ipfix => 2,
+                ExportFormat::NetflowV9 => 0,
+            };
+            self.write_set(&mut sets, set_id, &set);
+        }
+
+        let counters = record.counters();
+        let uplink = self.encode_data(
+            record,
+            (record.source(), record.destination()),
+            (counters.uplink_bytes(), counters.uplink_packets()),
+            record.upstream(),
+        );
+        self.write_set(&mut sets, UPLINK_TEMPLATE_ID, &uplink);
+        let downlink = self.encode_data(
+            record,
+            (record.destination(), record.source()),
+            (counters.downlink_bytes(), counters.downlink_packets()),
+            record.upstream(),
+        );
+        self.write_set(&mut sets, DOWNLINK_TEMPLATE_ID, &downlink);
+        record_count += 2;
+
+        let mut message = Vec::new();
+        let export_time = now.timestamp() as u32;
+        match self.format {
+            ExportFormat::Ipfix => {
+                message.write_u16::<BigEndian>(10).unwrap();
+                message
+                    .write_u16::<BigEndian>((16 + sets.len()) as u16)
+                    .unwrap();
+                message.write_u32::<BigEndian>(export_time).unwrap();
+                // IPFIX counts the data records sent before this message
+                message
+                    .write_u32::<BigEndian>(self.sequence_number)
+                    .unwrap();
+                // observation domain id
+                message.write_u32::<BigEndian>(0).unwrap();
+                self.sequence_number = self.sequence_number.wrapping_add(2);
+            }
+            ExportFormat::NetflowV9 => {
+                message.write_u16::<BigEndian>(9).unwrap();
+                message.write_u16::<BigEndian>(record_count).unwrap();
+                message.write_u32::<BigEndian>(self.uptime(now)).unwrap();
+                message.write_u32::<BigEndian>(export_time).unwrap();
+                // NetFlow v9 counts the export packets
+                message
+                    .write_u32::<BigEndian>(self.sequence_number)
+                    .unwrap();
+                // source id
+                message.write_u32::<BigEndian>(0).unwrap();
+                self.sequence_number = self.sequence_number.wrapping_add(1);
+            }
+        }
+        message.extend_from_slice(&sets);
+        message
+    }
+
+    fn encode_data(
+        &self,
+        record: &FlowRecord,
+        (source, destination): (SocketAddrV4, SocketAddrV4),
+        (bytes, packets): (u64, u64),
+        nat: SocketAddrV4,
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&source.ip().octets());
+        data.extend_from_slice(&destination.ip().octets());
+        data.write_u16::<BigEndian>(source.port()).unwrap();
+        data.write_u16::<BigEndian>(destination.port()).unwrap();
+        data.push(protocol_number(record.protocol()));
+        data.write_u64::<BigEndian>(bytes).unwrap();
+        data.write_u64::<BigEndian>(packets).unwrap();
+        match self.format {
+            ExportFormat::Ipfix => {
+                data.write_u64::<BigEndian>(record.start().timestamp_millis() as u64)
+                    .unwrap();
+                data.write_u64::<BigEndian>(record.end().timestamp_millis() as u64)
+                    .unwrap();
+            }
+            ExportFormat::NetflowV9 => {
+                data.write_u32::<BigEndian>(self.uptime(record.start()))
+                    .unwrap();
+                data.write_u32::<BigEndian>(self.uptime(record.end()))
+                    .unwrap();
+            }
+        }
+        data.push(flow_end_reason(record.close_reason()));
+        data.extend_from_slice(&nat.ip().octets());
+        data.write_u16::<BigEndian>(nat.port()).unwrap();
+        data
+    }
+
+    fn write_set(&self, out: &mut Vec<u8>, set_id: u16, content: &[u8]) {
+        // NetFlow v9 flowsets are padded to 32 bits
+        let padding = match self.format {
+            ExportFormat::Ipfix => 0,
+            ExportFormat::NetflowV9 => (4 - content.len() % 4) % 4,
+        };
+        out.write_u16::<BigEndian>(set_id).unwrap();
+        out.write_u16::<BigEndian>((4 + content.len() + padding) as u16)
+            .unwrap();
+        out.extend_from_slice(content);
+        out.resize(out.len() + padding, 0);
+    }
+
+    // milliseconds since the exporter started, wrapping like sysUpTime
+    fn uptime(&self, time: DateTime<Utc>) -> u32 {
+        (time - self.start).num_milliseconds().max(0) as u32
+    }
+}
+
+fn protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Tcp => 6,
+        Protocol::Udp => 17,
+        Protocol::Other => 0,
+    }
+}
+
+fn flow_end_reason(close_reason: CloseReason) -> u8 {
+    match close_reason {
+        CloseReason::IdleTimeout => IDLE_TIMEOUT,
+        CloseReason::Fin | CloseReason::Reset | CloseReason::ConnectFailed => END_OF_FLOW_DETECTED,
+        CloseReason::Evicted => LACK_OF_RESOURCES,
+        CloseReason::Refused
+        | CloseReason::ClientClosed
+        | CloseReason::Aborted
+        | CloseReason::Error => FORCED_END,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::flow_log::tests::create_record;
+    use byteorder::ByteOrder;
+
+    #[test]
+    fn parse_target() {
+        let target = ExportTarget::parse("127.0.0.1:4739").unwrap();
+        assert_eq!("127.0.0.1:4739".parse(), Ok(target.collector()));
+        assert_eq!(ExportFormat::Ipfix, target.format());
+
+        let target = ExportTarget::parse("127.0.0.1:2055,format=netflow9").unwrap();
+        assert_eq!(ExportFormat::NetflowV9, target.format());
+        assert_eq!("127.0.0.1:2055,format=netflow9", target.to_string());
+
+        assert!(ExportTarget::parse("127.0.0.1").is_err());
+        assert!(ExportTarget::parse("127.0.0.1:4739,format=sflow").is_err());
+    }
+
+    #[test]
+    fn encode_ipfix() {
+        let mut encoder = Encoder::new(ExportFormat::Ipfix, Utc::now());
+        let record = create_record();
+        let message = encoder.encode(&record, true, record.end());
+        // header + template set (2 templates of 12 fields) + 2 data sets (52 bytes each)
+        assert_eq!(16 + 4 + 2 * 52 + 2 * (4 + 52), message.len());
+        assert_eq!(10, BigEndian::read_u16(&message[0..2]));
+        assert_eq!(message.len(), BigEndian::read_u16(&message[2..4]) as usize);
+        assert_eq!(1_500_000_002, BigEndian::read_u32(&message[4..8]));
+        assert_eq!(0, BigEndian::read_u32(&message[8..12]));
+        // template set
+        assert_eq!(2, BigEndian::read_u16(&message[16..18]));
+        assert_eq!(UPLINK_TEMPLATE_ID, BigEndian::read_u16(&message[20..22]));
+        assert_eq!(12, BigEndian::read_u16(&message[22..24]));
+
+        let uplink = &message[124..180];
+        assert_eq!(UPLINK_TEMPLATE_ID, BigEndian::read_u16(&uplink[0..2]));
+        assert_eq!(56, BigEndian::read_u16(&uplink[2..4]));
+        let data = &uplink[4..];
+        assert_eq!([10, 0, 0, 2], data[0..4]);
+        assert_eq!([1, 2, 3, 4], data[4..8]);
+        assert_eq!(41234, BigEndian::read_u16(&data[8..10]));
+        assert_eq!(443, BigEndian::read_u16(&data[10..12]));
+        assert_eq!(6, data[12]);
+        assert_eq!(600, BigEndian::read_u64(&data[13..21]));
+        assert_eq!(2, BigEndian::read_u64(&data[21..29]));
+        assert_eq!(1_500_000_000_000, BigEndian::read_u64(&data[29..37]));
+        assert_eq!(1_500_000_002_500, BigEndian::read_u64(&data[37..45]));
+        assert_eq!(END_OF_FLOW_DETECTED, data[45]);
+        assert_eq!([127, 0, 0, 1], data[46..50]);
+        assert_eq!(8443, BigEndian::read_u16(&data[50..52]));
+
+        let downlink = &message[180..];
+        assert_eq!(DOWNLINK_TEMPLATE_ID, BigEndian::read_u16(&downlink[0..2]));
+        let data = &downlink[4..];
+        assert_eq!([1, 2, 3, 4], data[0..4]);
+        assert_eq!([10, 0, 0, 2], data[4..8]);
+        assert_eq!(1500, BigEndian::read_u64(&data[13..21]));
+        assert_eq!(1, BigEndian::read_u64(&data[21..29]));
+
+        // the sequence number counts the data records, the templates are not repeated
+        let message = encoder.encode(&record, false, record.end());
+        assert_eq!(16 + 2 * (4 + 52), message.len());
+        assert_eq!(2, BigEndian::read_u32(&message[8..12]));
+    }
+
+    #[test]
+    fn encode_netflow_v9() {
+        let record = create_record();
+        let mut encoder = Encoder::new(ExportFormat::NetflowV9, record.start());
+        let message = encoder.encode(&record, true, record.end());
+        // header + template flowset + 2 data flowsets (44 bytes each)
+        assert_eq!(20 + 4 + 2 * 52 + 2 * (4 + 44), message.len());
+        assert_eq!(9, BigEndian::read_u16(&message[0..2]));
+        // 2 templates and 2 data records
+        assert_eq!(4, BigEndian::read_u16(&message[2..4]));
+        assert_eq!(2500, BigEndian::read_u32(&message[4..8]));
+        assert_eq!(1_500_000_002, BigEndian::read_u32(&message[8..12]));
+        assert_eq!(0, BigEndian::read_u32(&message[12..16]));
+        assert_eq!(0, BigEndian::read_u16(&message[20..22]));
+
+        let data = &message[128..176];
+        assert_eq!(UPLINK_TEMPLATE_ID, BigEndian::read_u16(&data[0..2]));
+        // relative to the exporter start
+        assert_eq!(0, BigEndian::read_u32(&data[33..37]));
+        assert_eq!(2500, BigEndian::read_u32(&data[37..41]));
+
+        // the sequence number counts the export packets
+        let message = encoder.encode(&record, false, record.end());
+        assert_eq!(2, BigEndian::read_u16(&message[2..4]));
+        assert_eq!(1, BigEndian::read_u32(&message[12..16]));
+    }
+
+    #[test]
+    fn export_to_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let target = ExportTarget::new(collector.local_addr().unwrap(), ExportFormat::Ipfix);
+        let exporter = FlowExporter::open(target).unwrap();
+        let record = create_record();
+        exporter.export(&record);
+        exporter.export(&record);
+
+        let mut buf = [0; 1500];
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(10, BigEndian::read_u16(&buf[0..2]));
+        assert_eq!(len, BigEndian::read_u16(&buf[2..4]) as usize);
+        assert_eq!(2, BigEndian::read_u16(&buf[16..18]));
+        // the templates are sent only once per interval
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(16 + 2 * (4 + 52), len);
+        assert_eq!(UPLINK_TEMPLATE_ID, BigEndian::read_u16(&buf[16..18]));
+    }
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::*;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::ipv4_header::Protocol;
+use super::observer::DropReason;
+use super::traffic::TrafficCounters;
+
+const TAG: &str = "FlowLog";
+
+/// Why a connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both ends closed the connection gracefully.
+    Fin,
+    /// The connection was reset, by the client or by the destination.
+    Reset,
+    /// No traffic for too long.
+    IdleTimeout,
+    /// Closed to make room for a new connection.
+    Evicted,
+    /// The destination (or the proxy) could not be reached.
+    ConnectFailed,
+    /// The connection was refused by the relay policy (e.g. the loopback policy).
+    Refused,
+    /// The client disconnected from the relay.
+    ClientClosed,
+    /// Closed on request, from the control endpoint.
+    Aborted,
+    /// The upstream socket failed.
+    Error,
+}
+
+impl CloseReason {
+    /// The reason to report when an upstream socket fails with `err`.
+    pub fn of_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionReset => CloseReason::Reset,
+            io::ErrorKind::ConnectionRefused => CloseReason::ConnectFailed,
+            _ => CloseReason::Error,
+        }
+    }
+
+    /// The reason to report when the upstream socket of a connection cannot be opened.
+    pub fn of_open_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => CloseReason::Refused,
+            _ => CloseReason::ConnectFailed,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Fin => "fin",
+            CloseReason::Reset => "reset",
+            CloseReason::IdleTimeout => "idle-timeout",
+            CloseReason::Evicted => "evicted",
+            CloseReason::ConnectFailed => "connect-failed",
+            CloseReason::Refused => "refused",
+            CloseReason::ClientClosed => "client-closed",
+            CloseReason::Aborted => "aborted",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Summary of a relayed connection, built when it is closed.
+///
+/// The uplink is the traffic from the device to the network, the downlink the traffic from the
+/// network to the device (IP packets, as exchanged with the device).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlowRecord {
+    client_id: u32,
+    id: ConnectionId,
+    upstream: SocketAddrV4,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    counters: TrafficCounters,
+    close_reason: CloseReason,
+}
+
+impl FlowRecord {
+    pub fn client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    pub fn id(&self) -> &ConnectionId {
+        &self.id
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.id.protocol()
+    }
+
+    /// The address of the application on the device.
+    pub fn source(&self) -> SocketAddrV4 {
+        self.id.source()
+    }
+
+    /// The destination requested by the device.
+    pub fn destination(&self) -> SocketAddrV4 {
+        self.id.destination()
+    }
+
+    /// The destination actually contacted, after NAT.
+    pub fn upstream(&self) -> SocketAddrV4 {
+        self.upstream
+    }
+
+    pub fn start(&self) -> DateTime<Utc> {
+        self.start
+    }
+
+    pub fn end(&self) -> DateTime<Utc> {
+        self.end
+    }
+
+    pub fn counters(&self) -> &TrafficCounters {
+        &self.counters
+    }
+
+    pub fn close_reason(&self) -> CloseReason {
+        self.close_reason
+    }
+
+    /// Format the record as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        // all the values are numbers, addresses or keywords, nothing to escape
+        format!(
+            "{{\"client\":{},\"protocol\":\"{}\",\"source\":\"{}\",\"destination\":\"{}\",\
+             \"upstream\":\"{}\",\"start\":\"{}\",\"end\":\"{}\",\"uplink_bytes\":{},\
+             \"uplink_packets\":{},\"downlink_bytes\":{},\"downlink_packets\":{},\
+             \"close_reason\":\"{}\"}}",
+            self.client_id,
+            protocol_name(self.protocol()),
+            self.source(),
+            self.destination(),
+            self.upstream,
+            format_time(&self.start),
+            format_time(&self.end),
+            self.counters.uplink_bytes(),
+            self.counters.uplink_packets(),
+            self.counters.downlink_bytes(),
+            self.counters.downlink_packets(),
+            self.close_reason
+        )
+    }
+}
+
+pub fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+        Protocol::Other => "other",
+    }
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Lifecycle of a connection, to report its events and emit its record once closed.
+pub struct Flow {
+    client_id: u32,
+    id: ConnectionId,
+    upstream: SocketAddrV4,
+    start: DateTime<Utc>,
+    counters: TrafficCounters,
+    config: Rc<RelayConfig>,
+    finished: bool,
+}
+
+impl Flow {
+    pub fn new(
+        client_id: u32,
+        id: &ConnectionId,
+        upstream: SocketAddrV4,
+        config: Rc<RelayConfig>,
+    ) -> Self {
+        Self {
+            client_id,
+            id: id.clone(),
+            upstream,
+            start: Utc::now(),
+            counters: TrafficCounters::default(),
+            config,
+            finished: false,
+        }
+    }
+
+    pub fn upstream(&self) -> SocketAddrV4 {
+        self.upstream
+    }
+
+    /// The time elapsed since the connection was opened.
+    pub fn age(&self) -> Duration {
+        (Utc::now() - self.start).to_std().unwrap_or_default()
+    }
+
+    pub fn counters(&self) -> &TrafficCounters {
+        &self.counters
+    }
+
+    pub fn record_uplink(&mut self, length: usize) {
+        self.counters.record_uplink(length);
+    }
+
+    pub fn record_downlink(&mut self, length: usize) {
+        self.counters.record_downlink(length);
+    }
+
+    pub fn packet_dropped(&self, reason: DropReason) {
+        self.config
+            .observer()
+            .packet_dropped(self.client_id, reason);
+    }
+
+    /// Emit the record of a connection which could not be opened, accounting its first packet.
+    pub fn open_failed(&mut self, packet_length: usize, err: &io::Error) {
+        self.record_uplink(packet_length);
+        self.finish(CloseReason::of_open_error(err));
+    }
+
+    /// Emit the record of the flow (only the first call has an effect).
+    pub fn finish(&mut self, close_reason: CloseReason) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        let record = self.record(close_reason);
+        self.config.flow_log().write(&record);
+        self.config.flow_exporter().export(&record);
+        self.config.observer().connection_closed(&record);
+    }
+
+    fn record(&self, close_reason: CloseReason) -> FlowRecord {
+        FlowRecord {
+            client_id: self.client_id,
+            id: self.id.clone(),
+            upstream: self.upstream,
+            start: self.start,
+            end: Utc::now(),
+            counters: self.counters,
+            close_reason,
+        }
+    }
+}
+
+/// Destination of the flow records, shared by all the clients.
+///
+/// If a file is set, a JSON record is appended to it for every closed connection.
+#[derive(Clone, Debug, Default)]
+pub struct FlowLog {
+    file: Option<Arc<FlowLogFile>>,
+}
+
+#[derive(Debug)]
+struct FlowLogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FlowLog {
+    /// Append the records to `path` (created if it does not exist).
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            file: Some(Arc::new(FlowLogFile {
+                path,
+                file: Mutex::new(file),
+            })),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|file| file.path.as_path())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn write(&self, record: &FlowRecord) {
+        if let Some(file) = &self.file {
+            let mut line = record.to_json();
+            line.push('\n');
+            // a single write per record, so that concurrent workers never interleave their lines
+            if let Err(err) = file.file.lock().unwrap().write_all(line.as_bytes()) {
+                error!(target: TAG, "Cannot write flow record: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::observer::RelayObserver;
+    use chrono::TimeZone;
+    use std::env;
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    pub fn create_record() -> FlowRecord {
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(60);
+        counters.record_uplink(540);
+        counters.record_downlink(1500);
+        FlowRecord {
+            client_id: 3,
+            id: ConnectionId::new(
+                Protocol::Tcp,
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 41234),
+                SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443),
+            ),
+            upstream: SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8443),
+            start: Utc.timestamp_millis_opt(1_500_000_000_000).unwrap(),
+            end: Utc.timestamp_millis_opt(1_500_000_002_500).unwrap(),
+            counters,
+            close_reason: CloseReason::Fin,
+        }
+    }
+
+    #[test]
+    fn format_record() {
+        assert_eq!(
+            "{\"client\":3,\"protocol\":\"tcp\",\"source\":\"10.0.0.2:41234\",\
+             \"destination\":\"1.2.3.4:443\",\"upstream\":\"127.0.0.1:8443\",\
+             \"start\":\"2017-07-14T02:40:00.000Z\",\"end\":\"2017-07-14T02:40:02.500Z\",\
+             \"uplink_bytes\":600,\"uplink_packets\":2,\"downlink_bytes\":1500,\
+             \"downlink_packets\":1,\"close_reason\":\"fin\"}",
+            create_record().to_json()
+        );
+    }
+
+    #[test]
+    fn append_records() {
+        let path = env::temp_dir().join(format!("gnirehtet-flows-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let flow_log = FlowLog::open(&path).unwrap();
+        let record = create_record();
+        flow_log.write(&record);
+        flow_log.write(&record);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(vec![record.to_json(); 2], lines);
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        closed: Mutex<Vec<FlowRecord>>,
+        dropped: Mutex<Vec<(u32, DropReason)>>,
+    }
+
+    impl RelayObserver for RecordingObserver {
+        fn connection_closed(&self, record: &FlowRecord) {
+            self.closed.lock().unwrap().push(record.clone());
+        }
+
+        fn packet_dropped(&self, client_id: u32, reason: DropReason) {
+            self.dropped.lock().unwrap().push((client_id, reason));
+        }
+    }
+
+    #[test]
+    fn notify_observer() {
+        let observer = Arc::new(RecordingObserver::default());
+        let mut config = RelayConfig::new();
+        config.set_observer(observer.clone());
+        let record = create_record();
+        let mut flow = Flow::new(3, record.id(), record.upstream(), Rc::new(config));
+        flow.record_uplink(60);
+        flow.record_downlink(1500);
+        flow.packet_dropped(DropReason::ClientBufferFull);
+        flow.finish(CloseReason::Reset);
+        // only the first close is reported
+        flow.finish(CloseReason::Fin);
+
+        assert_eq!(
+            vec![(3, DropReason::ClientBufferFull)],
+            *observer.dropped.lock().unwrap()
+        );
+        let closed = observer.closed.lock().unwrap();
+        assert_eq!(1, closed.len());
+        assert_eq!(record.id(), closed[0].id());
+        assert_eq!(record.upstream(), closed[0].upstream());
+        assert_eq!(CloseReason::Reset, closed[0].close_reason());
+        assert_eq!(60, closed[0].counters().uplink_bytes());
+        assert_eq!(1, closed[0].counters().downlink_packets());
+    }
+}
@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use super::checksum::{self, Checksum};
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
 
@@ -37,7 +38,7 @@ pub struct Ipv4HeaderData {
     destination: u32,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Protocol {
     Tcp,
     Udp,
@@ -109,10 +110,7 @@ macro_rules! ipv4_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             pub fn raw(&self) -> &[u8] {
@@ -191,19 +189,18 @@ impl<'a> Ipv4HeaderMut<'a> {
     }
 
     pub fn update_checksum(&mut self) {
-        let j = self.data.header_length as usize / 2;
         // skip checksum field at 10..12
-        let mut sum = (0..5)
-            .chain(6..j)
-            .map(|i| {
-                let range = 2 * i..2 * (i + 1);
-                u32::from(BigEndian::read_u16(&self.raw[range]))
-            })
-            .sum::<u32>();
-        while (sum & !0xffff) != 0 {
-            sum = (sum & 0xffff) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        let mut checksum = Checksum::new();
+        checksum.add_bytes(&self.raw[..10]);
+        checksum.add_bytes(&self.raw[12..self.data.header_length as usize]);
+        self.set_checksum(checksum.finish());
+    }
+
+    /// Set the total length, and update the (valid) checksum incrementally.
+    pub fn update_total_length(&mut self, total_length: u16) {
+        let checksum = checksum::update(self.checksum(), self.data.total_length, total_length);
+        self.set_total_length(total_length);
+        self.set_checksum(checksum);
     }
 }
 
@@ -212,6 +209,7 @@ mod tests {
     use super::*;
     use byteorder::{BigEndian, WriteBytesExt};
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_header() -> Vec<u8> {
         let mut raw: Vec<u8> = Vec::new();
         raw.reserve(20);
@@ -272,6 +270,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn compute_checksum() {
         let raw = &mut create_header()[..];
         let mut header_data = Ipv4HeaderData::parse(raw);
@@ -291,6 +290,20 @@ mod tests {
         assert_eq!(sum, header.checksum());
     }
 
+    #[test]
+    fn update_total_length_incrementally() {
+        let raw = &mut create_header()[..];
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header = header_data.bind_mut(raw);
+        header.update_checksum();
+
+        header.update_total_length(1500);
+        let checksum = header.checksum();
+        header.update_checksum();
+        assert_eq!(1500, header.total_length());
+        assert_eq!(header.checksum(), checksum);
+    }
+
     #[test]
     fn peek_version_length_unavailable() {
         let raw: [u8; 0] = [];
@@ -55,7 +55,7 @@ impl Ipv4PacketBuffer {
         }
     }
 
-    pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet> {
+    pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet<'_>> {
         if self.available_packet_length().is_some() {
             let data = self.buf.peek_mut();
             Some(Ipv4Packet::parse(data))
@@ -125,6 +125,7 @@ mod tests {
         raw.write_u8(0x99).unwrap(); // payload
     }
 
+    #[allow(clippy::needless_borrowed_reference)]
     fn check_packet_headers(ipv4_packet: &Ipv4Packet) {
         let ipv4_header = ipv4_packet.ipv4_header();
         assert_eq!(20, ipv4_header.header_length());
@@ -142,6 +143,7 @@ mod tests {
         }
     }
 
+    #[allow(clippy::needless_borrowed_reference)]
     fn check_another_packet_headers(ipv4_packet: &Ipv4Packet) {
         let ipv4_header = ipv4_packet.ipv4_header();
         assert_eq!(20, ipv4_header.header_length());
@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use super::device_tunnels;
+
+/// Which services listening on the host loopback may be reached by a device.
+///
+/// `0.0.0.0/8` is considered as loopback, since connecting to `0.0.0.0` reaches the local host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoopbackAccess {
+    All,
+    None,
+    Ports(Vec<u16>),
+}
+
+/// Loopback exposure policy, with optional per-device overrides.
+///
+/// Syntax: `ENTRY[;ENTRY...]`, where `ENTRY` is `[SERIAL=]ACCESS` and `ACCESS` is `all`, `none`
+/// or `PORT[,PORT...]`. An entry without serial sets the default access.
+///
+/// For example, `none;emulator-5554=8080,8081` denies loopback access to every device except
+/// `emulator-5554`, which may only reach ports 8080 and 8081.
+///
+/// The overrides only apply to the devices identified by their tunnel port.
+#[derive(Clone, Debug)]
+pub struct LoopbackPolicy {
+    default: LoopbackAccess,
+    per_device: HashMap<String, LoopbackAccess>,
+}
+
+/// Indicate whether connecting to `ip` reaches the local host.
+pub fn is_loopback(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.octets()[0] == 0
+}
+
+impl LoopbackAccess {
+    /// Check whether connecting to `destination` is allowed.
+    ///
+    /// Destinations outside the loopback are always allowed.
+    pub fn check(&self, destination: &SocketAddrV4) -> io::Result<()> {
+        if !is_loopback(destination.ip()) || self.allows_port(destination.port()) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Access to host loopback {} denied by policy", destination),
+            ))
+        }
+    }
+
+    fn allows_port(&self, port: u16) -> bool {
+        match self {
+            LoopbackAccess::All => true,
+            LoopbackAccess::None => false,
+            LoopbackAccess::Ports(ports) => ports.contains(&port),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "all" => Ok(LoopbackAccess::All),
+            "none" => Ok(LoopbackAccess::None),
+            _ => {
+                let mut ports = Vec::new();
+                for port in s.split(',') {
+                    let port = port
+                        .trim()
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|&port| port != 0)
+                        .ok_or_else(|| format!("Invalid loopback access: \"{}\"", s))?;
+                    ports.push(port);
+                }
+                Ok(LoopbackAccess::Ports(ports))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LoopbackAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoopbackAccess::All => write!(f, "all"),
+            LoopbackAccess::None => write!(f, "none"),
+            LoopbackAccess::Ports(ports) => {
+                let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+                write!(f, "{}", ports.join(","))
+            }
+        }
+    }
+}
+
+impl LoopbackPolicy {
+    pub fn new(default: LoopbackAccess) -> Self {
+        Self {
+            default,
+            per_device: HashMap::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.find('=') {
+                Some(index) => {
+                    let serial = device_tunnels::parse_serial(&entry[..index])?;
+                    let access = LoopbackAccess::parse(entry[index + 1..].trim())?;
+                    policy.set_device_access(serial, access);
+                }
+                None => policy.default = LoopbackAccess::parse(entry)?,
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn set_device_access(&mut self, serial: String, access: LoopbackAccess) {
+        self.per_device.insert(serial, access);
+    }
+
+    /// The access of the device `device`, or the default one if it is not identified.
+    pub fn access(&self, device: Option<&str>) -> &LoopbackAccess {
+        device
+            .and_then(|serial| self.per_device.get(serial))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Default for LoopbackPolicy {
+    fn default() -> Self {
+        // expose the whole host loopback, as historically
+        Self::new(LoopbackAccess::All)
+    }
+}
+
+impl fmt::Display for LoopbackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        let mut serials: Vec<&String> = self.per_device.keys().collect();
+        serials.sort();
+        for serial in serials {
+            write!(f, ";{}={}", serial, self.per_device[serial])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localhost(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)
+    }
+
+    #[test]
+    fn default_allows_all() {
+        let policy = LoopbackPolicy::default();
+        assert!(policy.access(None).check(&localhost(5432)).is_ok());
+    }
+
+    #[test]
+    fn deny_loopback_only() {
+        let policy = LoopbackPolicy::parse("none").unwrap();
+        let access = policy.access(None);
+        assert!(access.check(&localhost(5432)).is_err());
+        assert!(access
+            .check(&SocketAddrV4::new(Ipv4Addr::new(127, 1, 2, 3), 80))
+            .is_err());
+        assert!(access
+            .check(&SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 80))
+            .is_err());
+        assert!(access
+            .check(&SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80))
+            .is_ok());
+    }
+
+    #[test]
+    fn allow_listed_ports() {
+        let policy = LoopbackPolicy::parse("8080,8081").unwrap();
+        let access = policy.access(None);
+        assert!(access.check(&localhost(8080)).is_ok());
+        assert!(access.check(&localhost(8081)).is_ok());
+        assert!(access.check(&localhost(5432)).is_err());
+    }
+
+    #[test]
+    fn per_device_access() {
+        let policy =
+            LoopbackPolicy::parse("none; emulator-5554=8080 ;192.168.1.2:5555=all").unwrap();
+        assert_eq!(&LoopbackAccess::None, policy.access(None));
+        assert_eq!(&LoopbackAccess::None, policy.access(Some("emulator-5556")));
+        assert_eq!(
+            &LoopbackAccess::Ports(vec![8080]),
+            policy.access(Some("emulator-5554"))
+        );
+        assert_eq!(
+            &LoopbackAccess::All,
+            policy.access(Some("192.168.1.2:5555"))
+        );
+        assert_eq!(
+            "none;192.168.1.2:5555=all;emulator-5554=8080",
+            policy.to_string()
+        );
+    }
+
+    #[test]
+    fn parse_invalid_policy() {
+        assert!(LoopbackPolicy::parse("some").is_err());
+        assert!(LoopbackPolicy::parse("8080,0").is_err());
+        assert!(LoopbackPolicy::parse("=all").is_err());
+        assert!(LoopbackPolicy::parse("emulator 5554=all").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use super::flow_log::{self, CloseReason, FlowRecord};
+use super::observer::{DropReason, RelayObserver};
+use super::stats::RelayStats;
+use super::traffic::TrafficCounters;
+
+const DROP_REASONS: [DropReason; 4] = [
+    DropReason::Invalid,
+    DropReason::Refused,
+    DropReason::NetworkBufferFull,
+    DropReason::ClientBufferFull,
+];
+
+/// Events of a client, counted since it connected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientEvents {
+    dropped_packets: [u64; 4],
+    connect_failures: u64,
+}
+
+impl ClientEvents {
+    pub fn dropped_packets(&self, reason: DropReason) -> u64 {
+        self.dropped_packets[reason as usize]
+    }
+
+    pub fn connect_failures(&self) -> u64 {
+        self.connect_failures
+    }
+}
+
+/// Observer counting the events of the connected clients, to expose them as metrics.
+#[derive(Clone, Debug, Default)]
+pub struct EventCounters {
+    clients: Arc<Mutex<HashMap<u32, ClientEvents>>>,
+}
+
+impl EventCounters {
+    pub fn snapshot(&self) -> HashMap<u32, ClientEvents> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    fn update<F: FnOnce(&mut ClientEvents)>(&self, client_id: u32, f: F) {
+        f(self.clients.lock().unwrap().entry(client_id).or_default());
+    }
+}
+
+impl RelayObserver for EventCounters {
+    fn client_disconnected(&self, client_id: u32, _: &TrafficCounters) {
+        // like the other metrics of the client, the counters disappear with it
+        self.clients.lock().unwrap().remove(&client_id);
+    }
+
+    fn connection_closed(&self, record: &FlowRecord) {
+        if record.close_reason() == CloseReason::ConnectFailed {
+            self.update(record.client_id(), |events| events.connect_failures += 1);
+        }
+    }
+
+    fn packet_dropped(&self, client_id: u32, reason: DropReason) {
+        self.update(client_id, |events| {
+            events.dropped_packets[reason as usize] += 1
+        });
+    }
+}
+
+/// Format the metrics of the relay in the Prometheus text exposition format.
+pub fn format_metrics(stats: &RelayStats, events: &HashMap<u32, ClientEvents>) -> String {
+    let mut out = String::new();
+    write_header(
+        &mut out,
+        "uptime_seconds",
+        "gauge",
+        "Time since the relay started.",
+    );
+    writeln!(out, "gnirehtet_uptime_seconds {}", stats.uptime.as_secs()).unwrap();
+
+    write_header(&mut out, "clients", "gauge", "Connected clients.");
+    writeln!(out, "gnirehtet_clients {}", stats.clients.len()).unwrap();
+
+    write_header(
+        &mut out,
+        "connections",
+        "gauge",
+        "Open connections, by protocol and state.",
+    );
+    for client in &stats.clients {
+        let mut counts = BTreeMap::new();
+        for connection in &client.connections {
+            let protocol = flow_log::protocol_name(connection.id.protocol());
+            *counts.entry((protocol, connection.state)).or_insert(0) += 1;
+        }
+        for ((protocol, state), count) in counts {
+            writeln!(
+                out,
+                "gnirehtet_connections{{client=\"{}\",protocol=\"{}\",state=\"{}\"}} {}",
+                client.id, protocol, state, count
+            )
+            .unwrap();
+        }
+    }
+
+    write_header(
+        &mut out,
+        "bytes_total",
+        "counter",
+        "Bytes relayed, by direction (uplink is from the device).",
+    );
+    for client in &stats.clients {
+        let counters = &client.counters;
+        write_directions(
+            &mut out,
+            "bytes_total",
+            client.id,
+            counters.uplink_bytes(),
+            counters.downlink_bytes(),
+        );
+    }
+
+    write_header(
+        &mut out,
+        "packets_total",
+        "counter",
+        "Packets relayed, by direction (uplink is from the device).",
+    );
+    for client in &stats.clients {
+        let counters = &client.counters;
+        write_directions(
+            &mut out,
+            "packets_total",
+            client.id,
+            counters.uplink_packets(),
+            counters.downlink_packets(),
+        );
+    }
+
+    write_header(
+        &mut out,
+        "dropped_packets_total",
+        "counter",
+        "Packets dropped by the relay, by reason.",
+    );
+    for client in &stats.clients {
+        let client_events = events.get(&client.id).cloned().unwrap_or_default();
+        for &reason in &DROP_REASONS {
+            writeln!(
+                out,
+                "gnirehtet_dropped_packets_total{{client=\"{}\",reason=\"{}\"}} {}",
+                client.id,
+                reason,
+                client_events.dropped_packets(reason)
+            )
+            .unwrap();
+        }
+    }
+
+    write_header(
+        &mut out,
+        "client_buffer_full_total",
+        "counter",
+        "Packets which could not be written to the client buffer (dropped or postponed).",
+    );
+    for client in &stats.clients {
+        writeln!(
+            out,
+            "gnirehtet_client_buffer_full_total{{client=\"{}\"}} {}",
+            client.id, client.buffer_full_events
+        )
+        .unwrap();
+    }
+
+    write_header(
+        &mut out,
+        "connect_failures_total",
+        "counter",
+        "Connections which could not reach their destination (or the proxy).",
+    );
+    for client in &stats.clients {
+        let client_events = events.get(&client.id).cloned().unwrap_or_default();
+        writeln!(
+            out,
+            "gnirehtet_connect_failures_total{{client=\"{}\"}} {}",
+            client.id,
+            client_events.connect_failures()
+        )
+        .unwrap();
+    }
+
+    write_header(
+        &mut out,
+        "memory_used_bytes",
+        "gauge",
+        "Memory held by the connection buffers.",
+    );
+    writeln!(out, "gnirehtet_memory_used_bytes {}", stats.memory.used).unwrap();
+    write_header(
+        &mut out,
+        "memory_pooled_bytes",
+        "gauge",
+        "Memory kept by the buffer pools for reuse.",
+    );
+    writeln!(out, "gnirehtet_memory_pooled_bytes {}", stats.memory.pooled).unwrap();
+    out
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP gnirehtet_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE gnirehtet_{} {}", name, kind).unwrap();
+}
+
+fn write_directions(out: &mut String, name: &str, client_id: u32, uplink: u64, downlink: u64) {
+    for (direction, value) in [("uplink", uplink), ("downlink", downlink)] {
+        writeln!(
+            out,
+            "gnirehtet_{}{{client=\"{}\",direction=\"{}\"}} {}",
+            name, client_id, direction, value
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::buffer_pool::MemoryUsage;
+    use crate::relay::connection::ConnectionId;
+    use crate::relay::flow_log::tests::create_record;
+    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::stats::{ClientStats, ConnectionStats};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    fn create_connection(protocol: Protocol, port: u16, state: &'static str) -> ConnectionStats {
+        let destination = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443);
+        ConnectionStats {
+            id: ConnectionId::new(
+                protocol,
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port),
+                destination,
+            ),
+            upstream: destination,
+            state,
+            age: Duration::from_secs(1),
+            counters: TrafficCounters::default(),
+            uplink_buffered: 0,
+        }
+    }
+
+    #[test]
+    fn count_events() {
+        let event_counters = EventCounters::default();
+        event_counters.packet_dropped(3, DropReason::Invalid);
+        event_counters.packet_dropped(3, DropReason::Invalid);
+        event_counters.packet_dropped(4, DropReason::ClientBufferFull);
+        // create_record() is a record of client 3
+        event_counters.connection_closed(&create_record());
+        let events = event_counters.snapshot();
+        assert_eq!(2, events[&3].dropped_packets(DropReason::Invalid));
+        assert_eq!(0, events[&3].connect_failures());
+        assert_eq!(1, events[&4].dropped_packets(DropReason::ClientBufferFull));
+
+        event_counters.client_disconnected(3, &TrafficCounters::default());
+        assert!(!event_counters.snapshot().contains_key(&3));
+    }
+
+    #[test]
+    fn format_client_metrics() {
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(600);
+        counters.record_downlink(1500);
+        let stats = RelayStats {
+            uptime: Duration::from_secs(3600),
+            workers: 1,
+            open_connections: 3,
+            memory: MemoryUsage::default(),
+            clients: vec![ClientStats {
+                id: 3,
+                worker: 0,
+                age: Duration::from_secs(60),
+                counters,
+                downlink_buffered: 0,
+                buffer_full_events: 7,
+                connections: vec![
+                    create_connection(Protocol::Tcp, 1000, "established"),
+                    create_connection(Protocol::Udp, 1001, "open"),
+                    create_connection(Protocol::Tcp, 1002, "established"),
+                ],
+            }],
+        };
+        let event_counters = EventCounters::default();
+        event_counters.packet_dropped(3, DropReason::Refused);
+        let metrics = format_metrics(&stats, &event_counters.snapshot());
+        let samples: Vec<&str> = metrics
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            vec![
+                "gnirehtet_uptime_seconds 3600",
+                "gnirehtet_clients 1",
+                "gnirehtet_connections{client=\"3\",protocol=\"tcp\",state=\"established\"} 2",
+                "gnirehtet_connections{client=\"3\",protocol=\"udp\",state=\"open\"} 1",
+                "gnirehtet_bytes_total{client=\"3\",direction=\"uplink\"} 600",
+                "gnirehtet_bytes_total{client=\"3\",direction=\"downlink\"} 1500",
+                "gnirehtet_packets_total{client=\"3\",direction=\"uplink\"} 1",
+                "gnirehtet_packets_total{client=\"3\",direction=\"downlink\"} 1",
+                "gnirehtet_dropped_packets_total{client=\"3\",reason=\"invalid\"} 0",
+                "gnirehtet_dropped_packets_total{client=\"3\",reason=\"refused\"} 1",
+                "gnirehtet_dropped_packets_total{client=\"3\",reason=\"network-buffer-full\"} 0",
+                "gnirehtet_dropped_packets_total{client=\"3\",reason=\"client-buffer-full\"} 0",
+                "gnirehtet_client_buffer_full_total{client=\"3\"} 7",
+                "gnirehtet_connect_failures_total{client=\"3\"} 0",
+                "gnirehtet_memory_used_bytes 0",
+                "gnirehtet_memory_pooled_bytes 0",
+            ],
+            samples
+        );
+        assert!(metrics.contains("# TYPE gnirehtet_bytes_total counter\n"));
+    }
+}
@@ -14,32 +14,82 @@
  * limitations under the License.
  */
 
+pub use self::buffer_pool::{MemoryBudget, MemoryUsage};
+pub use self::config::RelayConfig;
+pub use self::connection::ConnectionId;
+pub use self::connection_limits::ConnectionLimits;
+pub use self::device_tunnels::DeviceTunnels;
+pub use self::flow_export::{ExportFormat, ExportTarget, FlowExporter};
+pub use self::flow_log::{CloseReason, FlowLog, FlowRecord};
+pub use self::ipv4_header::Protocol;
+pub use self::loopback_policy::{LoopbackAccess, LoopbackPolicy};
+pub use self::nat_table::{NatRule, NatTable};
+pub use self::network_profile::{EmulationPolicy, NetworkEmulation, NetworkProfile};
+pub use self::observer::{DropReason, RelayObserver};
+pub use self::proxy::{Credentials, NoProxy, ProxyConfig, ProxyKind};
 pub use self::relay::Relay;
+pub use self::reload::{ConfigLoader, ConfigReload};
+pub use self::socket_binding::{SocketBinding, SocketBindingPolicy};
+pub use self::traffic::{
+    parse_size, ByteCount, Quota, QuotaAction, QuotaPolicy, TrafficAccounting, TrafficCounters,
+};
+pub use self::upstream_selector::{HostnameRoutes, Route, UpstreamSelector};
 pub mod byte_buffer;
 
 mod binary;
+mod buffer_pool;
+mod checksum;
 mod client;
 mod close_listener;
+mod config;
 #[macro_use]
 mod connection;
+mod connection_limits;
+mod control;
 mod datagram;
+mod datagram_batch;
 mod datagram_buffer;
+mod device_tunnels;
+mod dns;
+mod dns_cache;
+mod flow_export;
+mod flow_log;
+mod http_connect;
 #[macro_use]
 mod interrupt;
 mod ipv4_header;
 mod ipv4_packet;
 mod ipv4_packet_buffer;
+mod loopback_policy;
+mod metrics;
+mod nat_table;
 mod net;
+mod network_profile;
+mod observer;
+mod packet_scheduler;
 mod packet_source;
 mod packetizer;
+mod proxy;
+mod proxy_handshake;
+mod reject;
 #[allow(clippy::module_inception)] // relay.rs is in relay/
 mod relay;
+mod reload;
 mod router;
 mod selector;
+mod shaper;
+mod socket_binding;
+mod socks5;
+mod stats;
 mod stream_buffer;
 mod tcp_connection;
 mod tcp_header;
+mod timer_wheel;
+mod traffic;
 mod transport_header;
 mod tunnel_server;
 mod udp_connection;
 mod udp_header;
+mod upstream;
+mod upstream_selector;
+mod worker;
@@ -0,0 +1,283 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use super::net::{self, Cidr};
+use super::upstream_selector::HostnamePattern;
+
+pub const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
+pub const LOCALHOST: u32 = 0x7F_00_00_01; // 127.0.0.1
+
+/// Rewrite rule for the destination of the connections initiated by the devices.
+///
+/// Syntax: `MATCH=TARGET`, where `MATCH` is `CIDR[:PORT]` or `HOST[:PORT]` and `TARGET` is
+/// `IP[:PORT]`. If the target port is omitted, the original destination port is kept.
+///
+/// A `HOST` (a host name or `*.DOMAIN`) matches the connections to the addresses the device
+/// resolved from this name, so that the rule follows the DNS changes. The target must be a
+/// literal address: it is never resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatRule {
+    matching: Match,
+    port: Option<u16>,
+    target_ip: u32,
+    target_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Match {
+    Network(Cidr),
+    Host(HostnamePattern),
+}
+
+/// Ordered list of destination rewrite rules (the first matching rule applies).
+///
+/// The historical `10.0.2.2` → `127.0.0.1` mapping is always present, after the custom rules.
+#[derive(Clone, Debug)]
+pub struct NatTable {
+    rules: Vec<NatRule>,
+}
+
+impl NatRule {
+    pub fn new(network: Cidr, port: Option<u16>, target_ip: u32, target_port: Option<u16>) -> Self {
+        Self {
+            matching: Match::Network(network),
+            port,
+            target_ip,
+            target_port,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let index = s
+            .find('=')
+            .ok_or_else(|| format!("Invalid NAT rule (expected MATCH=TARGET): \"{}\"", s))?;
+        let (matching, port) = split_port(&s[..index])?;
+        let (target, target_port) = split_port(&s[index + 1..])?;
+        let target_ip = target
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("Invalid NAT target (expected an IPv4 address): \"{}\"", s))?;
+
+        let matching = if matching.contains('/') || matching.parse::<Ipv4Addr>().is_ok() {
+            Match::Network(matching.parse::<Cidr>()?)
+        } else {
+            Match::Host(HostnamePattern::parse(matching)?)
+        };
+        Ok(Self {
+            matching,
+            port,
+            target_ip: net::to_ipv4(target_ip),
+            target_port,
+        })
+    }
+
+    fn rewrite(&self, ip: u32, port: u16, hostname: Option<&str>) -> Option<SocketAddrV4> {
+        let matches = match self.matching {
+            Match::Network(ref network) => network.contains(ip),
+            Match::Host(ref pattern) => pattern.matches(hostname),
+        };
+        if matches && (self.port.is_none() || self.port == Some(port)) {
+            let target_port = self.target_port.unwrap_or(port);
+            Some(net::to_socket_addr(self.target_ip, target_port))
+        } else {
+            None
+        }
+    }
+}
+
+fn split_port(s: &str) -> Result<(&str, Option<u16>), String> {
+    match s.rfind(':') {
+        Some(index) => {
+            let port = s[index + 1..]
+                .parse::<u16>()
+                .ok()
+                .filter(|&port| port != 0)
+                .ok_or_else(|| format!("Invalid port: \"{}\"", s))?;
+            Ok((&s[..index], Some(port)))
+        }
+        None => Ok((s, None)),
+    }
+}
+
+impl fmt::Display for NatRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.matching {
+            Match::Network(ref network) => write!(f, "{}", network)?,
+            Match::Host(ref pattern) => write!(f, "{}", pattern)?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        write!(f, "={}", net::to_addr(self.target_ip))?;
+        if let Some(port) = self.target_port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+impl NatTable {
+    pub fn new(mut rules: Vec<NatRule>) -> Self {
+        rules.push(NatRule::new(
+            Cidr::new(LOCALHOST_FORWARD, 32),
+            None,
+            LOCALHOST,
+            None,
+        ));
+        Self { rules }
+    }
+
+    /// Parse a list of rules, separated by commas or new lines.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            for rule in line.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+                rules.push(NatRule::parse(rule)?);
+            }
+        }
+        Ok(Self::new(rules))
+    }
+
+    pub fn rules(&self) -> &[NatRule] {
+        &self.rules
+    }
+
+    /// Rewrite the destination `ip:port`.
+    ///
+    /// `hostname` is the name the device resolved to `ip`, if known.
+    pub fn rewrite(&self, ip: u32, port: u16, hostname: Option<&str>) -> SocketAddrV4 {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.rewrite(ip, port, hostname))
+            .unwrap_or_else(|| net::to_socket_addr(ip, port))
+    }
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_localhost_forward() {
+        let table = NatTable::default();
+        assert_eq!(
+            "127.0.0.1:8080",
+            table.rewrite(LOCALHOST_FORWARD, 8080, None).to_string()
+        );
+        assert_eq!(
+            "1.2.3.4:80",
+            table.rewrite(0x01_02_03_04, 80, None).to_string()
+        );
+    }
+
+    #[test]
+    fn rewrite_address_and_port() {
+        let table = NatTable::parse("1.2.3.4:443=127.0.0.1:8443").unwrap();
+        assert_eq!(
+            "127.0.0.1:8443",
+            table.rewrite(0x01_02_03_04, 443, None).to_string()
+        );
+        // other ports are not rewritten
+        assert_eq!(
+            "1.2.3.4:80",
+            table.rewrite(0x01_02_03_04, 80, None).to_string()
+        );
+    }
+
+    #[test]
+    fn rewrite_network_keeping_port() {
+        let table = NatTable::parse("10.0.3.0/24=172.17.0.2").unwrap();
+        assert_eq!(
+            "172.17.0.2:5432",
+            table.rewrite(0x0A_00_03_07, 5432, None).to_string()
+        );
+        assert_eq!(
+            "10.0.4.7:5432",
+            table.rewrite(0x0A_00_04_07, 5432, None).to_string()
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let table = NatTable::parse("10.0.2.2:80=127.0.0.1:8080, 10.0.2.0/24=10.1.1.1").unwrap();
+        assert_eq!(
+            "127.0.0.1:8080",
+            table.rewrite(LOCALHOST_FORWARD, 80, None).to_string()
+        );
+        // the custom network rule takes precedence over the default localhost forward
+        assert_eq!(
+            "10.1.1.1:22",
+            table.rewrite(LOCALHOST_FORWARD, 22, None).to_string()
+        );
+    }
+
+    #[test]
+    fn rewrite_resolved_host_name() {
+        let table =
+            NatTable::parse("api.prod:443=127.0.0.1:8443, *.mock.example=10.1.1.1").unwrap();
+        assert_eq!(
+            "127.0.0.1:8443",
+            table
+                .rewrite(0x01_02_03_04, 443, Some("api.prod"))
+                .to_string()
+        );
+        // the address alone does not match
+        assert_eq!(
+            "1.2.3.4:443",
+            table.rewrite(0x01_02_03_04, 443, None).to_string()
+        );
+        assert_eq!(
+            "10.1.1.1:80",
+            table
+                .rewrite(0x05_06_07_08, 80, Some("a.mock.example"))
+                .to_string()
+        );
+        assert_eq!("api.prod:443=127.0.0.1:8443", table.rules()[0].to_string());
+    }
+
+    #[test]
+    fn parse_multiline_with_comments() {
+        let table =
+            NatTable::parse("# mock servers\n1.2.3.4=5.6.7.8\n\n2.3.4.5=6.7.8.9\n").unwrap();
+        // 2 custom rules + the default one
+        assert_eq!(3, table.rules().len());
+        assert_eq!("1.2.3.4/32=5.6.7.8", table.rules()[0].to_string());
+    }
+
+    #[test]
+    fn parse_invalid_rules() {
+        assert!(NatTable::parse("1.2.3.4").is_err());
+        assert!(NatTable::parse("1.2.3.4:0=5.6.7.8").is_err());
+        assert!(NatTable::parse("1.2.3.4=5.6.7.8:port").is_err());
+        assert!(NatTable::parse("1.2.3.0/40=5.6.7.8").is_err());
+        // targets are never resolved
+        assert!(NatTable::parse("1.2.3.4=mock.example").is_err());
+    }
+}
@@ -15,7 +15,9 @@
  */
 
 use super::binary;
+use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
 
 pub fn to_addr(ipv4: u32) -> Ipv4Addr {
     let raw = binary::to_byte_array(ipv4);
@@ -26,3 +28,105 @@ pub fn to_socket_addr(ipv4: u32, port: u16) -> SocketAddrV4 {
     let addr = to_addr(ipv4);
     SocketAddrV4::new(addr, port)
 }
+
+pub fn to_ipv4(addr: Ipv4Addr) -> u32 {
+    u32::from(addr)
+}
+
+/// IPv4 network in CIDR notation (e.g. `10.0.2.0/24`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    address: u32,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn new(address: u32, prefix_length: u8) -> Self {
+        assert!(
+            prefix_length <= 32,
+            "Invalid prefix length: {}",
+            prefix_length
+        );
+        let mask = Self::mask(prefix_length);
+        Self {
+            address: address & mask,
+            prefix_length,
+        }
+    }
+
+    fn mask(prefix_length: u8) -> u32 {
+        if prefix_length == 0 {
+            0
+        } else {
+            !0 << (32 - prefix_length)
+        }
+    }
+
+    pub fn contains(&self, ipv4: u32) -> bool {
+        ipv4 & Self::mask(self.prefix_length) == self.address
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (address, prefix_length) = match s.find('/') {
+            Some(index) => {
+                let prefix_length = s[index + 1..]
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|&len| len <= 32)
+                    .ok_or_else(|| format!("Invalid prefix length: \"{}\"", s))?;
+                (&s[..index], prefix_length)
+            }
+            None => (s, 32),
+        };
+        let address = address
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("Invalid IPv4 address: \"{}\"", s))?;
+        Ok(Self::new(to_ipv4(address), prefix_length))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", to_addr(self.address), self.prefix_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cidr() {
+        let cidr = "10.0.2.0/24".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(0x0A_00_02_02));
+        assert!(cidr.contains(0x0A_00_02_FF));
+        assert!(!cidr.contains(0x0A_00_03_02));
+        assert_eq!("10.0.2.0/24", cidr.to_string());
+    }
+
+    #[test]
+    fn parse_single_address_as_cidr() {
+        let cidr = "10.0.2.2".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(0x0A_00_02_02));
+        assert!(!cidr.contains(0x0A_00_02_03));
+        assert_eq!("10.0.2.2/32", cidr.to_string());
+    }
+
+    #[test]
+    fn parse_default_route() {
+        let cidr = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(0x01_02_03_04));
+        assert!(cidr.contains(0xFF_FF_FF_FF));
+    }
+
+    #[test]
+    fn parse_invalid_cidr() {
+        assert!("10.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.2/24".parse::<Cidr>().is_err());
+        assert!("10.0.2.0/".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use super::device_tunnels;
+
+/// Network conditions to emulate between a device and the network.
+///
+/// Syntax: `[PROFILE][,KEY=VALUE...]`, where `PROFILE` is one of the predefined profiles (see
+/// `NetworkProfile::predefined()`) and `KEY` is:
+///  - `delay`: one-way latency added in each direction (e.g. `100ms`, `1s`);
+///  - `jitter`: maximal random variation of the delay;
+///  - `rate`: bandwidth cap in each direction (e.g. `500kbit`, `2mbit`);
+///  - `down`, `up`: bandwidth cap in one direction (to the device, from the device);
+///  - `loss`: probability to lose a packet (e.g. `1%`);
+///  - `reorder`: probability for a packet to overtake the ones queued before it.
+///
+/// For example, `3g,loss=2%` emulates a 3G network losing 2% of the packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkProfile {
+    delay: Duration,
+    jitter: Duration,
+    // in bits per second
+    downlink_rate: Option<u64>,
+    uplink_rate: Option<u64>,
+    // probabilities, in [0; 1]
+    loss: f64,
+    reorder: f64,
+}
+
+const PREDEFINED_PROFILES: &[(&str, &str)] = &[
+    ("none", ""),
+    ("edge", "delay=300ms,jitter=50ms,down=240kbit,up=200kbit"),
+    ("3g", "delay=150ms,jitter=20ms,down=750kbit,up=250kbit"),
+    ("4g", "delay=40ms,jitter=10ms,down=12mbit,up=5mbit"),
+    (
+        "flaky-wifi",
+        "delay=20ms,jitter=40ms,down=5mbit,up=2mbit,loss=5%,reorder=1%",
+    ),
+];
+
+impl NetworkProfile {
+    /// The names of the predefined profiles.
+    pub fn predefined() -> impl Iterator<Item = &'static str> {
+        PREDEFINED_PROFILES.iter().map(|&(name, _)| name)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut profile = Self::default();
+        for (i, item) in s.split(',').map(str::trim).enumerate() {
+            match item.find('=') {
+                Some(index) => {
+                    profile.set(item[..index].trim(), item[index + 1..].trim())?;
+                }
+                None if i == 0 => {
+                    // the base profile, "flaky wifi" and "flaky_wifi" are also accepted
+                    let name = item.to_ascii_lowercase().replace([' ', '_'], "-");
+                    let definition = PREDEFINED_PROFILES
+                        .iter()
+                        .find(|&&(n, _)| n == name)
+                        .map(|&(_, definition)| definition)
+                        .ok_or_else(|| format!("Unknown network profile: \"{}\"", item))?;
+                    if !definition.is_empty() {
+                        profile = Self::parse(definition)?;
+                    }
+                }
+                None => return Err(format!("Invalid network setting: \"{}\"", item)),
+            }
+        }
+        Ok(profile)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "delay" => self.delay = parse_duration(value)?,
+            "jitter" => self.jitter = parse_duration(value)?,
+            "rate" => {
+                self.downlink_rate = parse_rate(value)?;
+                self.uplink_rate = self.downlink_rate;
+            }
+            "down" => self.downlink_rate = parse_rate(value)?,
+            "up" => self.uplink_rate = parse_rate(value)?,
+            "loss" => self.loss = parse_probability(value)?,
+            "reorder" => self.reorder = parse_probability(value)?,
+            _ => return Err(format!("Unknown network setting: \"{}\"", key)),
+        }
+        Ok(())
+    }
+
+    /// Indicate whether the profile alters the traffic.
+    pub fn is_neutral(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    pub fn downlink_rate(&self) -> Option<u64> {
+        self.downlink_rate
+    }
+
+    pub fn uplink_rate(&self) -> Option<u64> {
+        self.uplink_rate
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+
+    pub fn reorder(&self) -> f64 {
+        self.reorder
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = split_unit(value);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid duration: \"{}\"", value))?;
+    match unit {
+        "" | "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => Err(format!("Invalid duration unit: \"{}\"", value)),
+    }
+}
+
+pub fn parse_rate(value: &str) -> Result<Option<u64>, String> {
+    let (number, unit) = split_unit(value);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid rate: \"{}\"", value))?;
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "bit" => 1,
+        "kbit" => 1_000,
+        "mbit" => 1_000_000,
+        "gbit" => 1_000_000_000,
+        _ => {
+            return Err(format!(
+                "Invalid rate unit (expected bit, kbit, mbit or gbit): \"{}\"",
+                value
+            ))
+        }
+    };
+    // 0 means unlimited
+    Ok(Some(number * multiplier).filter(|&rate| rate != 0))
+}
+
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let percent = value.strip_suffix('%').unwrap_or(value);
+    percent
+        .parse::<f64>()
+        .ok()
+        .filter(|p| (0.0..=100.0).contains(p))
+        .map(|p| p / 100.0)
+        .ok_or_else(|| format!("Invalid percentage: \"{}\"", value))
+}
+
+pub fn split_unit(value: &str) -> (&str, &str) {
+    let index = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    (&value[..index], &value[index..])
+}
+
+impl fmt::Display for NetworkProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_neutral() {
+            return write!(f, "none");
+        }
+        let mut settings = Vec::new();
+        if self.delay != Duration::ZERO {
+            settings.push(format!("delay={}ms", self.delay.as_millis()));
+        }
+        if self.jitter != Duration::ZERO {
+            settings.push(format!("jitter={}ms", self.jitter.as_millis()));
+        }
+        if let Some(rate) = self.downlink_rate {
+            settings.push(format!("down={}kbit", rate / 1000));
+        }
+        if let Some(rate) = self.uplink_rate {
+            settings.push(format!("up={}kbit", rate / 1000));
+        }
+        if self.loss != 0.0 {
+            settings.push(format!("loss={}%", self.loss * 100.0));
+        }
+        if self.reorder != 0.0 {
+            settings.push(format!("reorder={}%", self.reorder * 100.0));
+        }
+        write!(f, "{}", settings.join(","))
+    }
+}
+
+/// Network profiles, with optional per-device overrides.
+///
+/// Syntax: `ENTRY[;ENTRY...]`, where `ENTRY` is `[SERIAL=]PROFILE`. An entry without serial sets
+/// the default profile. Entries may also be separated by new lines, and lines starting with `#`
+/// are ignored.
+///
+/// For example, `none;emulator-5554=3g;emulator-5556=flaky-wifi,delay=50ms` only degrades the
+/// network of these two devices.
+///
+/// The overrides only apply to the devices identified by their tunnel port.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmulationPolicy {
+    default: NetworkProfile,
+    per_device: HashMap<String, NetworkProfile>,
+}
+
+impl EmulationPolicy {
+    pub fn new(default: NetworkProfile) -> Self {
+        Self {
+            default,
+            per_device: HashMap::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        let entries = s
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(';'))
+            .map(str::trim)
+            .filter(|e| !e.is_empty());
+        for entry in entries {
+            // a profile may contain '=', so the entry has a serial only if it is not a profile
+            match NetworkProfile::parse(entry) {
+                Ok(profile) => policy.default = profile,
+                Err(err) => {
+                    let index = entry.find('=').ok_or(err)?;
+                    let serial = device_tunnels::parse_serial(&entry[..index])?;
+                    let profile = NetworkProfile::parse(entry[index + 1..].trim())?;
+                    policy.set_device_profile(serial, profile);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn set_default_profile(&mut self, profile: NetworkProfile) {
+        self.default = profile;
+    }
+
+    pub fn set_device_profile(&mut self, serial: String, profile: NetworkProfile) {
+        self.per_device.insert(serial, profile);
+    }
+
+    /// The profile of the device `device`, or the default one if it is not identified.
+    pub fn profile(&self, device: Option<&str>) -> &NetworkProfile {
+        device
+            .and_then(|serial| self.per_device.get(serial))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl fmt::Display for EmulationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        let mut serials: Vec<&String> = self.per_device.keys().collect();
+        serials.sort();
+        for serial in serials {
+            write!(f, ";{}={}", serial, self.per_device[serial])?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle to the emulation policy of a running relay.
+///
+/// The policy may be changed at any time, from any thread; the changes apply to the next packets.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkEmulation {
+    policy: Arc<RwLock<EmulationPolicy>>,
+}
+
+impl NetworkEmulation {
+    pub fn new(policy: EmulationPolicy) -> Self {
+        Self {
+            policy: Arc::new(RwLock::new(policy)),
+        }
+    }
+
+    pub fn policy(&self) -> EmulationPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set_policy(&self, policy: EmulationPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn set_device_profile(&self, serial: String, profile: NetworkProfile) {
+        self.policy
+            .write()
+            .unwrap()
+            .set_device_profile(serial, profile);
+    }
+
+    pub fn profile(&self, device: Option<&str>) -> NetworkProfile {
+        *self.policy.read().unwrap().profile(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profile() {
+        let profile =
+            NetworkProfile::parse("delay=100ms,jitter=10,rate=2mbit,loss=1.5%,reorder=1").unwrap();
+        assert_eq!(Duration::from_millis(100), profile.delay());
+        assert_eq!(Duration::from_millis(10), profile.jitter());
+        assert_eq!(Some(2_000_000), profile.downlink_rate());
+        assert_eq!(Some(2_000_000), profile.uplink_rate());
+        assert!((profile.loss() - 0.015).abs() < 1e-9);
+        assert!((profile.reorder() - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_predefined_profile() {
+        let profile = NetworkProfile::parse("3G, loss=2%").unwrap();
+        assert_eq!(Duration::from_millis(150), profile.delay());
+        assert_eq!(Some(750_000), profile.downlink_rate());
+        assert!((profile.loss() - 0.02).abs() < 1e-9);
+
+        let profile = NetworkProfile::parse("flaky wifi").unwrap();
+        assert!(profile.loss() > 0.0);
+        assert!(NetworkProfile::parse("none").unwrap().is_neutral());
+        for name in NetworkProfile::predefined() {
+            assert!(NetworkProfile::parse(name).is_ok());
+        }
+    }
+
+    #[test]
+    fn parse_invalid_profile() {
+        assert!(NetworkProfile::parse("5g").is_err());
+        assert!(NetworkProfile::parse("delay=1h").is_err());
+        assert!(NetworkProfile::parse("rate=2mb").is_err());
+        assert!(NetworkProfile::parse("loss=120%").is_err());
+        assert!(NetworkProfile::parse("delay=10ms,3g").is_err());
+        assert!(NetworkProfile::parse("speed=1").is_err());
+    }
+
+    #[test]
+    fn per_device_profiles() {
+        let policy = EmulationPolicy::parse("delay=10ms;emulator-5554=3g;1=none").unwrap();
+        assert_eq!(Duration::from_millis(10), policy.profile(None).delay());
+        assert_eq!(
+            Duration::from_millis(150),
+            policy.profile(Some("emulator-5554")).delay()
+        );
+        assert!(policy.profile(Some("1")).is_neutral());
+        assert_eq!(
+            Duration::from_millis(10),
+            policy.profile(Some("emulator-5556")).delay()
+        );
+        assert_eq!(
+            "delay=10ms;1=none;emulator-5554=delay=150ms,jitter=20ms,down=750kbit,up=250kbit",
+            policy.to_string()
+        );
+        assert!(EmulationPolicy::parse("emulator-5554=5g").is_err());
+        assert!(EmulationPolicy::parse("=3g").is_err());
+    }
+
+    #[test]
+    fn parse_policy_lines() {
+        let policy =
+            EmulationPolicy::parse("# emulation\n4g\nemulator-5554=edge;1=none\n").unwrap();
+        assert_eq!(Duration::from_millis(40), policy.profile(None).delay());
+        assert_eq!(
+            Duration::from_millis(300),
+            policy.profile(Some("emulator-5554")).delay()
+        );
+        assert!(policy.profile(Some("1")).is_neutral());
+    }
+
+    #[test]
+    fn change_profile_at_runtime() {
+        let emulation = NetworkEmulation::default();
+        let shared = emulation.clone();
+        assert!(emulation.profile(Some("emulator-5554")).is_neutral());
+        shared.set_device_profile(
+            String::from("emulator-5554"),
+            NetworkProfile::parse("3g").unwrap(),
+        );
+        assert!(!emulation.profile(Some("emulator-5554")).is_neutral());
+        assert!(emulation.profile(None).is_neutral());
+    }
+}
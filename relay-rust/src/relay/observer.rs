@@ -0,0 +1,122 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::connection::ConnectionId;
+use super::flow_log::FlowRecord;
+use super::traffic::TrafficCounters;
+
+/// Why a packet was dropped by the relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The packet from the device is not a valid IPv4 packet.
+    Invalid,
+    /// No connection could be opened for the packet from the device (access policy, connection
+    /// limits, data quota or upstream error).
+    Refused,
+    /// The buffer of the connection was full (packet from the device).
+    NetworkBufferFull,
+    /// The buffer of the client was full (packet to the device).
+    ClientBufferFull,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Invalid => "invalid",
+            DropReason::Refused => "refused",
+            DropReason::NetworkBufferFull => "network-buffer-full",
+            DropReason::ClientBufferFull => "client-buffer-full",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Callbacks to follow the activity of a relay, for applications embedding it.
+///
+/// The callbacks are called from the relay threads (one per worker), so they must return quickly.
+/// All of them do nothing by default.
+pub trait RelayObserver: fmt::Debug + Send + Sync {
+    /// A client connected to the relay.
+    fn client_connected(&self, _client_id: u32) {}
+
+    /// A client disconnected from the relay.
+    ///
+    /// `traffic` includes the previous traffic of the device, if it is identified.
+    fn client_disconnected(&self, _client_id: u32, _traffic: &TrafficCounters) {}
+
+    /// A connection (TCP connection or UDP flow) was opened for a client.
+    fn connection_opened(&self, _client_id: u32, _id: &ConnectionId) {}
+
+    /// A connection was closed, `record` contains its byte and packet counts.
+    fn connection_closed(&self, _record: &FlowRecord) {}
+
+    /// A packet from or to a client was dropped.
+    fn packet_dropped(&self, _client_id: u32, _reason: DropReason) {}
+}
+
+/// Observer ignoring all the events.
+#[derive(Debug, Default)]
+pub struct NoObserver;
+
+impl RelayObserver for NoObserver {}
+
+/// Observer forwarding all the events to two observers.
+#[derive(Debug)]
+pub struct ObserverPair {
+    first: Arc<dyn RelayObserver>,
+    second: Arc<dyn RelayObserver>,
+}
+
+impl ObserverPair {
+    pub fn new(first: Arc<dyn RelayObserver>, second: Arc<dyn RelayObserver>) -> Self {
+        Self { first, second }
+    }
+}
+
+impl RelayObserver for ObserverPair {
+    fn client_connected(&self, client_id: u32) {
+        self.first.client_connected(client_id);
+        self.second.client_connected(client_id);
+    }
+
+    fn client_disconnected(&self, client_id: u32, traffic: &TrafficCounters) {
+        self.first.client_disconnected(client_id, traffic);
+        self.second.client_disconnected(client_id, traffic);
+    }
+
+    fn connection_opened(&self, client_id: u32, id: &ConnectionId) {
+        self.first.connection_opened(client_id, id);
+        self.second.connection_opened(client_id, id);
+    }
+
+    fn connection_closed(&self, record: &FlowRecord) {
+        self.first.connection_closed(record);
+        self.second.connection_closed(record);
+    }
+
+    fn packet_dropped(&self, client_id: u32, reason: DropReason) {
+        self.first.packet_dropped(client_id, reason);
+        self.second.packet_dropped(client_id, reason);
+    }
+}
@@ -0,0 +1,492 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use super::connection::ConnectionId;
+use super::dns;
+use super::ipv4_packet::Ipv4Packet;
+use super::packet_source::PacketSource;
+use super::selector::Selector;
+
+// bytes granted to each bulk flow per round
+const QUANTUM: usize = 4096;
+
+// flows having sent less data to the client are scheduled as interactive
+const SHORT_FLOW_LENGTH: u64 = 64 * 1024;
+
+/// Scheduling class of the traffic sent to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Latency-sensitive traffic (DNS, short flows), always served first.
+    Interactive,
+    Bulk,
+}
+
+impl TrafficClass {
+    /// Classify a connection: DNS and short flows are interactive.
+    pub fn of_flow(id: &ConnectionId, sent_to_client: u64) -> Self {
+        if id.destination_port() == dns::DNS_PORT || sent_to_client < SHORT_FLOW_LENGTH {
+            TrafficClass::Interactive
+        } else {
+            TrafficClass::Bulk
+        }
+    }
+
+    /// Classify a packet sent by a flow of class `flow_class`.
+    ///
+    /// The packets carrying no data (TCP control packets, ICMP errors) are always interactive.
+    pub fn of(ipv4_packet: &Ipv4Packet, flow_class: Self) -> Self {
+        let has_data = ipv4_packet
+            .payload()
+            .is_some_and(|payload| !payload.is_empty());
+        if has_data {
+            flow_class
+        } else {
+            TrafficClass::Interactive
+        }
+    }
+}
+
+struct BulkFlow {
+    source: Rc<RefCell<dyn PacketSource>>,
+    deficit: usize,
+}
+
+/// Scheduler of the packet sources waiting for space in the client buffer.
+///
+/// A source holds at most one pending packet at a time, but it may provide the next one as soon as
+/// the current one is consumed: each registered source is a flow, which remains scheduled as long
+/// as it has a pending packet. The interactive sources are served first, in registration order.
+/// The bulk sources share the remaining space by deficit round-robin, so that a flow sending large
+/// packets does not get more bytes than the others; a flow keeps its deficit while it remains
+/// backlogged.
+///
+/// A source is classified again whenever it is queued again, so that a short flow growing beyond
+/// the threshold becomes a bulk flow (starting with no deficit).
+#[derive(Default)]
+pub struct PacketScheduler {
+    interactive: VecDeque<Rc<RefCell<dyn PacketSource>>>,
+    bulk: VecDeque<BulkFlow>,
+}
+
+enum Pull {
+    Sent,
+    Blocked,
+}
+
+impl PacketScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interactive.is_empty() && self.bulk.is_empty()
+    }
+
+    pub fn register(&mut self, source: Rc<RefCell<dyn PacketSource>>, class: TrafficClass) {
+        match class {
+            TrafficClass::Interactive => self.interactive.push_back(source),
+            TrafficClass::Bulk => self.bulk.push_back(BulkFlow { source, deficit: 0 }),
+        }
+    }
+
+    /// Pull the pending packets and pass them to `send`, until it would block.
+    pub fn run<F>(&mut self, selector: &mut Selector, mut send: F)
+    where
+        F: FnMut(&mut Selector, &Ipv4Packet, TrafficClass) -> io::Result<()>,
+    {
+        'rounds: loop {
+            while let Some(source) = self.interactive.pop_front() {
+                if let Pull::Blocked = pull(selector, &source, TrafficClass::Interactive, &mut send)
+                {
+                    self.interactive.push_front(source);
+                    return;
+                }
+                if has_pending(&source) {
+                    // it may have become a bulk flow
+                    let class = source.borrow().traffic_class();
+                    self.register(source, class);
+                }
+            }
+
+            while let Some(mut flow) = self.bulk.pop_front() {
+                let length = pending_length(&flow.source);
+                if flow.deficit < length {
+                    // not enough credit for this round, serve the next flow
+                    flow.deficit += QUANTUM;
+                    self.bulk.push_back(flow);
+                    continue;
+                }
+                if let Pull::Blocked = pull(selector, &flow.source, TrafficClass::Bulk, &mut send) {
+                    // keep its turn (and its deficit) for the next run
+                    self.bulk.push_front(flow);
+                    return;
+                }
+                flow.deficit -= length;
+                if has_pending(&flow.source) {
+                    if flow.source.borrow().traffic_class() == TrafficClass::Interactive {
+                        // serve it before the other bulk flows
+                        self.interactive.push_back(flow.source);
+                        continue 'rounds;
+                    }
+                    // still backlogged, serve it again while its deficit allows
+                    self.bulk.push_front(flow);
+                }
+                // otherwise its queue is empty, the flow is over
+            }
+            return;
+        }
+    }
+}
+
+fn pending_length(source: &Rc<RefCell<dyn PacketSource>>) -> usize {
+    let mut source = source.borrow_mut();
+    let ipv4_packet = source
+        .get()
+        .expect("Unexpected pending source with no packet");
+    ipv4_packet.length() as usize
+}
+
+fn has_pending(source: &Rc<RefCell<dyn PacketSource>>) -> bool {
+    source.borrow_mut().get().is_some()
+}
+
+fn pull<F>(
+    selector: &mut Selector,
+    source: &Rc<RefCell<dyn PacketSource>>,
+    class: TrafficClass,
+    send: &mut F,
+) -> Pull
+where
+    F: FnMut(&mut Selector, &Ipv4Packet, TrafficClass) -> io::Result<()>,
+{
+    let mut source = source.borrow_mut();
+    let result = {
+        let ipv4_packet = source
+            .get()
+            .expect("Unexpected pending source with no packet");
+        send(selector, &ipv4_packet, class)
+    };
+    #[allow(clippy::match_wild_err_arm)]
+    match result {
+        Ok(_) => {
+            source.next(selector);
+            Pull::Sent
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Pull::Blocked,
+        Err(_) => {
+            panic!("Cannot send packet to client for unknown reason");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_header::Protocol;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    struct FakeSource {
+        raw: Vec<u8>,
+        pending: usize,
+        // the class it is registered with, kept until it grows beyond a short flow
+        class: TrafficClass,
+        sent: u64,
+    }
+
+    impl FakeSource {
+        fn create(source_port: u16, payload_length: usize) -> Rc<RefCell<Self>> {
+            Self::with_packets(source_port, payload_length, 1)
+        }
+
+        fn with_packets(
+            source_port: u16,
+            payload_length: usize,
+            count: usize,
+        ) -> Rc<RefCell<Self>> {
+            let total_length = 28 + payload_length;
+            let mut raw = vec![0u8; total_length];
+            raw[0] = 0x45;
+            raw[2..4].copy_from_slice(&(total_length as u16).to_be_bytes());
+            raw[9] = 17; // UDP
+            raw[20..22].copy_from_slice(&source_port.to_be_bytes());
+            raw[24..26].copy_from_slice(&(8 + payload_length as u16).to_be_bytes());
+            Rc::new(RefCell::new(Self {
+                raw,
+                pending: count,
+                class: TrafficClass::Interactive,
+                sent: 0,
+            }))
+        }
+    }
+
+    impl PacketSource for FakeSource {
+        fn get(&mut self) -> Option<Ipv4Packet<'_>> {
+            if self.pending > 0 {
+                Some(Ipv4Packet::parse(&mut self.raw))
+            } else {
+                None
+            }
+        }
+
+        fn next(&mut self, _: &mut Selector) {
+            self.pending -= 1;
+            self.sent += (self.raw.len() - 28) as u64;
+        }
+
+        fn traffic_class(&self) -> TrafficClass {
+            if self.sent < SHORT_FLOW_LENGTH {
+                self.class
+            } else {
+                TrafficClass::Bulk
+            }
+        }
+    }
+
+    fn register(
+        scheduler: &mut PacketScheduler,
+        source: Rc<RefCell<FakeSource>>,
+        class: TrafficClass,
+    ) {
+        source.borrow_mut().class = class;
+        scheduler.register(source, class);
+    }
+
+    fn source_port(ipv4_packet: &Ipv4Packet) -> u16 {
+        ipv4_packet.transport_header_data().unwrap().source_port()
+    }
+
+    #[test]
+    fn classify_flows() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 12345);
+        let dns = ConnectionId::new(
+            Protocol::Udp,
+            source,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 53),
+        );
+        let https = ConnectionId::new(
+            Protocol::Tcp,
+            source,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 443),
+        );
+        assert_eq!(
+            TrafficClass::Interactive,
+            TrafficClass::of_flow(&dns, 10 * SHORT_FLOW_LENGTH)
+        );
+        assert_eq!(TrafficClass::Interactive, TrafficClass::of_flow(&https, 0));
+        assert_eq!(
+            TrafficClass::Bulk,
+            TrafficClass::of_flow(&https, SHORT_FLOW_LENGTH)
+        );
+    }
+
+    #[test]
+    fn classify_packets_without_data_as_interactive() {
+        let data = FakeSource::create(443, 100);
+        let empty = FakeSource::create(443, 0);
+        assert_eq!(
+            TrafficClass::Bulk,
+            TrafficClass::of(&data.borrow_mut().get().unwrap(), TrafficClass::Bulk)
+        );
+        assert_eq!(
+            TrafficClass::Interactive,
+            TrafficClass::of(&empty.borrow_mut().get().unwrap(), TrafficClass::Bulk)
+        );
+    }
+
+    #[test]
+    fn serve_interactive_first() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        register(
+            &mut scheduler,
+            FakeSource::create(1000, 100),
+            TrafficClass::Bulk,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::create(53, 100),
+            TrafficClass::Interactive,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, class| {
+            sent.push((source_port(ipv4_packet), class));
+            Ok(())
+        });
+        assert_eq!(
+            vec![(53, TrafficClass::Interactive), (1000, TrafficClass::Bulk)],
+            sent
+        );
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn share_bulk_by_deficit() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        // the large packet needs several rounds of credit
+        register(
+            &mut scheduler,
+            FakeSource::create(1000, 3 * QUANTUM),
+            TrafficClass::Bulk,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::create(2000, 100),
+            TrafficClass::Bulk,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::create(3000, 100),
+            TrafficClass::Bulk,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, _| {
+            sent.push(source_port(ipv4_packet));
+            Ok(())
+        });
+        assert_eq!(vec![2000, 3000, 1000], sent);
+    }
+
+    #[test]
+    fn keep_deficit_while_backlogged() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        register(
+            &mut scheduler,
+            FakeSource::with_packets(1000, 1000, 6),
+            TrafficClass::Bulk,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::with_packets(2000, 3000, 2),
+            TrafficClass::Bulk,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, _| {
+            sent.push(source_port(ipv4_packet));
+            Ok(())
+        });
+        // each round, the flows get the same number of bytes, whatever their packet size
+        assert_eq!(vec![1000, 1000, 1000, 2000, 1000, 1000, 1000, 2000], sent);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn keep_interactive_source_while_pending() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        register(
+            &mut scheduler,
+            FakeSource::with_packets(53, 100, 2),
+            TrafficClass::Interactive,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::create(1000, 100),
+            TrafficClass::Bulk,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, _| {
+            sent.push(source_port(ipv4_packet));
+            Ok(())
+        });
+        assert_eq!(vec![53, 53, 1000], sent);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn move_growing_flow_to_bulk() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        // a short flow, until its 4th packet
+        register(
+            &mut scheduler,
+            FakeSource::with_packets(1000, 16 * 1024, 6),
+            TrafficClass::Interactive,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::with_packets(2000, 1000, 2),
+            TrafficClass::Bulk,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, class| {
+            sent.push((source_port(ipv4_packet), class));
+            Ok(())
+        });
+        let interactive = (1000, TrafficClass::Interactive);
+        assert_eq!(
+            vec![
+                interactive,
+                interactive,
+                interactive,
+                interactive,
+                // it now shares the bandwidth with the other bulk flow, from a fresh deficit
+                (2000, TrafficClass::Bulk),
+                (2000, TrafficClass::Bulk),
+                (1000, TrafficClass::Bulk),
+                (1000, TrafficClass::Bulk),
+            ],
+            sent
+        );
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn keep_pending_when_blocked() {
+        let mut selector = Selector::create().unwrap();
+        let mut scheduler = PacketScheduler::new();
+        register(
+            &mut scheduler,
+            FakeSource::create(1000, 100),
+            TrafficClass::Bulk,
+        );
+        register(
+            &mut scheduler,
+            FakeSource::create(2000, 100),
+            TrafficClass::Bulk,
+        );
+
+        let mut sent = Vec::new();
+        scheduler.run(&mut selector, |_, ipv4_packet, _| {
+            if sent.is_empty() {
+                sent.push(source_port(ipv4_packet));
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "Full"))
+            }
+        });
+        assert_eq!(vec![1000], sent);
+        assert!(!scheduler.is_empty());
+
+        scheduler.run(&mut selector, |_, ipv4_packet, _| {
+            sent.push(source_port(ipv4_packet));
+            Ok(())
+        });
+        assert_eq!(vec![1000, 2000], sent);
+        assert!(scheduler.is_empty());
+    }
+}
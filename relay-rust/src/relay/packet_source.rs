@@ -15,6 +15,7 @@
  */
 
 use super::ipv4_packet::Ipv4Packet;
+use super::packet_scheduler::TrafficClass;
 use super::selector::Selector;
 
 /// Source that may produce packets.
@@ -27,6 +28,8 @@ use super::selector::Selector;
 ///
 /// It is implemented by `TcpConnection`.
 pub trait PacketSource {
-    fn get(&mut self) -> Option<Ipv4Packet>;
+    fn get(&mut self) -> Option<Ipv4Packet<'_>>;
     fn next(&mut self, selector: &mut Selector);
+    /// The current class of the flow, which may change as it grows.
+    fn traffic_class(&self) -> TrafficClass;
 }
@@ -16,14 +16,22 @@
 
 use std::io;
 
+use super::buffer_pool::PooledBuffer;
 use super::datagram::{DatagramReceiver, ReadAdapter};
 use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
+// IPv4 and TCP headers with the maximal amount of options
+const MAX_HEADERS_LENGTH: usize = 60 + 60;
+
 /// Convert from level 5 to level 3 by appending correct IP and transport headers.
+///
+/// The headers are kept aside, the buffer to build the packets is borrowed from the buffer pool
+/// on demand, and given back by `release()`.
 pub struct Packetizer {
-    buffer: Box<[u8; MAX_PACKET_LENGTH]>,
+    headers: [u8; MAX_HEADERS_LENGTH],
+    buffer: PooledBuffer,
     transport_index: usize,
     payload_index: usize,
     ipv4_header_data: Ipv4HeaderData,
@@ -35,7 +43,7 @@ impl Packetizer {
         reference_ipv4_header: &Ipv4Header,
         reference_transport_header: &TransportHeader,
     ) -> Self {
-        let mut buffer = Box::new([0; MAX_PACKET_LENGTH]);
+        let mut headers = [0; MAX_HEADERS_LENGTH];
 
         let transport_index = reference_ipv4_header.header_length() as usize;
         let payload_index = transport_index + reference_transport_header.header_length() as usize;
@@ -44,21 +52,24 @@ impl Packetizer {
         let mut transport_header_data = reference_transport_header.data_clone();
 
         {
-            let ipv4_header_raw = &mut buffer[..transport_index];
+            let ipv4_header_raw = &mut headers[..transport_index];
             ipv4_header_raw.copy_from_slice(reference_ipv4_header.raw());
             let mut ipv4_header = ipv4_header_data.bind_mut(ipv4_header_raw);
             ipv4_header.swap_source_and_destination();
+            // only the total length changes afterwards, the checksum is updated incrementally
+            ipv4_header.update_checksum();
         }
 
         {
-            let transport_header_raw = &mut buffer[transport_index..payload_index];
+            let transport_header_raw = &mut headers[transport_index..payload_index];
             transport_header_raw.copy_from_slice(reference_transport_header.raw());
             let mut transport_header = transport_header_data.bind_mut(transport_header_raw);
             transport_header.swap_source_and_destination();
         }
 
         Self {
-            buffer,
+            headers,
+            buffer: PooledBuffer::default(),
             transport_index,
             payload_index,
             ipv4_header_data,
@@ -66,16 +77,25 @@ impl Packetizer {
         }
     }
 
-    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet {
+    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet<'_> {
         self.build(0)
     }
 
-    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet> {
+    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet<'_>> {
+        self.acquire_buffer();
         let r = source.recv(&mut self.buffer[self.payload_index..])?;
         let ipv4_packet = self.build(r as u16);
         Ok(ipv4_packet)
     }
 
+    /// Packetize a datagram already received.
+    pub fn packetize_datagram(&mut self, datagram: &[u8]) -> Ipv4Packet<'_> {
+        self.acquire_buffer();
+        let payload_end = self.payload_index + datagram.len();
+        self.buffer[self.payload_index..payload_end].copy_from_slice(datagram);
+        self.build(datagram.len() as u16)
+    }
+
     /// Packetize from stream (`Read`) source.
     ///
     /// `Ok(Some(_))` when packet is available
@@ -85,8 +105,9 @@ impl Packetizer {
         &mut self,
         source: &mut R,
         max_chunk_size: Option<usize>,
-    ) -> io::Result<Option<Ipv4Packet>> {
+    ) -> io::Result<Option<Ipv4Packet<'_>>> {
         let mut adapter = ReadAdapter::new(source, max_chunk_size);
+        self.acquire_buffer();
         let r = adapter.recv(&mut self.buffer[self.payload_index..])?;
         let option = if r > 0 {
             let ipv4_packet = self.build(r as u16);
@@ -97,33 +118,54 @@ impl Packetizer {
         Ok(option)
     }
 
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
-        let raw = &mut self.buffer[..self.transport_index];
+    /// Give the packet buffer back to the pool, once the last packet is not needed anymore.
+    pub fn release(&mut self) {
+        self.buffer = PooledBuffer::default();
+    }
+
+    fn acquire_buffer(&mut self) {
+        if self.buffer.is_empty() {
+            self.buffer = PooledBuffer::allocate(MAX_PACKET_LENGTH);
+        }
+    }
+
+    // private: the IPv4 header checksum must stay valid between packets
+    fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut<'_> {
+        let raw = &mut self.headers[..self.transport_index];
         self.ipv4_header_data.bind_mut(raw)
     }
 
-    pub fn transport_header_mut(&mut self) -> TransportHeaderMut {
-        let raw = &mut self.buffer[self.transport_index..self.payload_index];
+    pub fn transport_header_mut(&mut self) -> TransportHeaderMut<'_> {
+        let raw = &mut self.headers[self.transport_index..self.payload_index];
         self.transport_header_data.bind_mut(raw)
     }
 
-    fn build(&mut self, payload_length: u16) -> Ipv4Packet {
+    fn build(&mut self, payload_length: u16) -> Ipv4Packet<'_> {
         let total_length = self.payload_index as u16 + payload_length;
 
-        self.ipv4_header_mut().set_total_length(total_length);
+        self.ipv4_header_mut().update_total_length(total_length);
         self.transport_header_mut()
             .set_payload_length(payload_length);
 
+        let payload_index = self.payload_index;
+        self.acquire_buffer();
+        self.buffer[..payload_index].copy_from_slice(&self.headers[..payload_index]);
+
         let mut ipv4_packet = Ipv4Packet::new(
             &mut self.buffer[..total_length as usize],
             self.ipv4_header_data.clone(),
             self.transport_header_data.clone(),
         );
-        ipv4_packet.compute_checksums();
+        ipv4_packet.compute_transport_checksum();
         ipv4_packet
     }
 
-    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet {
+    /// Rebuild the last packet, which must not have been released.
+    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet<'_> {
+        assert!(
+            !self.buffer.is_empty(),
+            "The packet buffer has been released"
+        );
         Ipv4Packet::new(
             &mut self.buffer[..packet_length as usize],
             self.ipv4_header_data.clone(),
@@ -177,6 +219,21 @@ mod tests {
         assert_eq!(data, &packet.raw()[28..36]);
     }
 
+    #[test]
+    fn packetize_received_datagram() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw);
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+
+        let data = [0x11u8, 0x22, 0x33];
+        let packet = packetizer.packetize_datagram(&data);
+        assert_eq!(31, packet.ipv4_header_data().total_length());
+        assert_eq!(data, &packet.raw()[28..31]);
+    }
+
     #[test]
     fn last_packet() {
         let raw = &mut create_packet()[..];
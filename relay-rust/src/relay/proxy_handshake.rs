@@ -0,0 +1,66 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::net::SocketAddrV4;
+
+use super::http_connect::HttpConnectHandshake;
+use super::proxy::{ProxyConfig, ProxyKind};
+use super::socks5::{Socks5Command, Socks5Handshake};
+
+/// Negotiation of a TCP tunnel to a destination through an upstream proxy.
+pub enum ProxyHandshake {
+    Socks5(Socks5Handshake),
+    HttpConnect(HttpConnectHandshake),
+}
+
+impl ProxyHandshake {
+    pub fn connect(proxy: &ProxyConfig, destination: SocketAddrV4) -> Self {
+        match proxy.kind() {
+            ProxyKind::Socks5 => ProxyHandshake::Socks5(Socks5Handshake::new(
+                Socks5Command::Connect,
+                destination,
+                proxy.credentials().cloned(),
+            )),
+            ProxyKind::Http => ProxyHandshake::HttpConnect(HttpConnectHandshake::new(
+                destination,
+                proxy.credentials(),
+            )),
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        match self {
+            ProxyHandshake::Socks5(handshake) => handshake.wants_write(),
+            ProxyHandshake::HttpConnect(handshake) => handshake.wants_write(),
+        }
+    }
+
+    pub fn write_to<W: io::Write>(&mut self, destination: &mut W) -> io::Result<()> {
+        match self {
+            ProxyHandshake::Socks5(handshake) => handshake.write_to(destination),
+            ProxyHandshake::HttpConnect(handshake) => handshake.write_to(destination),
+        }
+    }
+
+    /// Return `Ok(true)` once the tunnel is established.
+    pub fn read_from<R: io::Read>(&mut self, source: &mut R) -> io::Result<bool> {
+        match self {
+            ProxyHandshake::Socks5(handshake) => handshake.read_from(source),
+            ProxyHandshake::HttpConnect(handshake) => handshake.read_from(source),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use super::checksum::checksum;
+use super::ipv4_header::Protocol;
+use super::ipv4_packet::Ipv4Packet;
+use super::tcp_header::{self, TcpHeader};
+use super::transport_header::TransportHeader;
+
+const IPV4_HEADER_LENGTH: u16 = 20;
+const TCP_HEADER_LENGTH: u16 = 20;
+const ICMP_HEADER_LENGTH: u16 = 8;
+const DEFAULT_TTL: u8 = 64;
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_COMMUNICATION_PROHIBITED: u8 = 13;
+
+/// Build the reply to send to the client to refuse `ipv4_packet`: a TCP RST or an ICMP
+/// "communication administratively prohibited" error for UDP, so that the application fails
+/// immediately rather than on timeout.
+///
+/// Return `None` if the packet must not be answered (a TCP RST).
+pub fn reject(ipv4_packet: &Ipv4Packet) -> Option<Vec<u8>> {
+    match ipv4_packet.transport_header()? {
+        TransportHeader::Tcp(ref tcp_header) if tcp_header.is_rst() => None,
+        TransportHeader::Tcp(ref tcp_header) => Some(tcp_reset(ipv4_packet, tcp_header)),
+        TransportHeader::Udp(_) => Some(icmp_prohibited(ipv4_packet)),
+    }
+}
+
+fn write_ipv4_header(raw: &mut Vec<u8>, protocol: u8, total_length: u16, source: u32, dest: u32) {
+    raw.write_u8(4 << 4 | 5).unwrap(); // version and IHL
+    raw.write_u8(0).unwrap(); // ToS
+    raw.write_u16::<BigEndian>(total_length).unwrap();
+    raw.write_u32::<BigEndian>(0).unwrap(); // id, flags and fragment offset
+    raw.write_u8(DEFAULT_TTL).unwrap();
+    raw.write_u8(protocol).unwrap();
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum, computed afterwards
+    raw.write_u32::<BigEndian>(source).unwrap();
+    raw.write_u32::<BigEndian>(dest).unwrap();
+}
+
+// RFC 793, section 3.4: reset generation for a connection which does not exist
+fn tcp_reset(ipv4_packet: &Ipv4Packet, tcp_header: &TcpHeader) -> Vec<u8> {
+    let ipv4_header = ipv4_packet.ipv4_header();
+    let (sequence_number, acknowledgement_number, flags) = if tcp_header.is_ack() {
+        (tcp_header.acknowledgement_number(), 0, tcp_header::FLAG_RST)
+    } else {
+        let payload_length = ipv4_packet.payload().map_or(0, <[u8]>::len) as u32;
+        // SYN and FIN count for 1 byte
+        let segment_length =
+            payload_length + tcp_header.is_syn() as u32 + tcp_header.is_fin() as u32;
+        let acknowledgement_number = tcp_header.sequence_number().wrapping_add(segment_length);
+        (
+            0,
+            acknowledgement_number,
+            tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+        )
+    };
+
+    let total_length = IPV4_HEADER_LENGTH + TCP_HEADER_LENGTH;
+    let mut raw = Vec::with_capacity(total_length as usize);
+    write_ipv4_header(
+        &mut raw,
+        PROTOCOL_TCP,
+        total_length,
+        ipv4_header.destination(),
+        ipv4_header.source(),
+    );
+    raw.write_u16::<BigEndian>(tcp_header.destination_port())
+        .unwrap();
+    raw.write_u16::<BigEndian>(tcp_header.source_port())
+        .unwrap();
+    raw.write_u32::<BigEndian>(sequence_number).unwrap();
+    raw.write_u32::<BigEndian>(acknowledgement_number).unwrap();
+    // data offset (in 32-bit words) and flags
+    let offset_and_flags = (TCP_HEADER_LENGTH / 4) << 12 | flags;
+    raw.write_u16::<BigEndian>(offset_and_flags).unwrap();
+    raw.write_u16::<BigEndian>(0).unwrap(); // window
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum, computed afterwards
+    raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+
+    Ipv4Packet::parse(&mut raw).compute_checksums();
+    raw
+}
+
+// RFC 792: the error contains the IP header and the first 64 bits of the original datagram
+fn icmp_prohibited(ipv4_packet: &Ipv4Packet) -> Vec<u8> {
+    let ipv4_header = ipv4_packet.ipv4_header();
+    let quoted_length = ipv4_header.header_length() as usize + 8;
+    let quoted = &ipv4_packet.raw()[..quoted_length.min(ipv4_packet.raw().len())];
+
+    let total_length = IPV4_HEADER_LENGTH + ICMP_HEADER_LENGTH + quoted.len() as u16;
+    let mut raw = Vec::with_capacity(total_length as usize);
+    write_ipv4_header(
+        &mut raw,
+        PROTOCOL_ICMP,
+        total_length,
+        ipv4_header.destination(),
+        ipv4_header.source(),
+    );
+    raw.write_u8(ICMP_DESTINATION_UNREACHABLE).unwrap();
+    raw.write_u8(ICMP_COMMUNICATION_PROHIBITED).unwrap();
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum, computed afterwards
+    raw.write_u32::<BigEndian>(0).unwrap(); // unused
+    raw.extend_from_slice(quoted);
+
+    let icmp_start = IPV4_HEADER_LENGTH as usize;
+    let checksum = checksum(&raw[icmp_start..]);
+    BigEndian::write_u16(&mut raw[icmp_start + 2..icmp_start + 4], checksum);
+    let mut packet = Ipv4Packet::parse(&mut raw);
+    debug_assert_eq!(Protocol::Other, packet.ipv4_header().protocol());
+    // only the IPv4 header checksum (there is no known transport header)
+    packet.compute_checksums();
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::transport_header::TransportHeaderData;
+
+    fn create_packet(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let total_length = IPV4_HEADER_LENGTH + transport.len() as u16;
+        let mut raw = Vec::new();
+        write_ipv4_header(&mut raw, protocol, total_length, 0x0a000002, 0x01020304);
+        raw.extend_from_slice(transport);
+        raw
+    }
+
+    fn create_syn() -> Vec<u8> {
+        let mut tcp = Vec::new();
+        tcp.write_u16::<BigEndian>(40000).unwrap(); // source port
+        tcp.write_u16::<BigEndian>(443).unwrap(); // destination port
+        tcp.write_u32::<BigEndian>(1000).unwrap(); // sequence number
+        tcp.write_u32::<BigEndian>(0).unwrap(); // acknowledgement number
+        tcp.write_u16::<BigEndian>(5 << 12 | tcp_header::FLAG_SYN)
+            .unwrap();
+        tcp.write_u16::<BigEndian>(65535).unwrap(); // window
+        tcp.write_u32::<BigEndian>(0).unwrap(); // checksum and urgent pointer
+        create_packet(PROTOCOL_TCP, &tcp)
+    }
+
+    #[test]
+    fn reset_syn() {
+        let mut raw = create_syn();
+        let syn = Ipv4Packet::parse(&mut raw);
+        let mut reply = reject(&syn).unwrap();
+        assert_eq!(0, checksum(&reply[..20]));
+
+        let reply = Ipv4Packet::parse(&mut reply);
+        let ipv4_header = reply.ipv4_header();
+        assert_eq!(0x01020304, ipv4_header.source());
+        assert_eq!(0x0a000002, ipv4_header.destination());
+        if let Some(TransportHeaderData::Tcp(tcp_header)) = reply.transport_header_data() {
+            assert_eq!(443, tcp_header.source_port());
+            assert_eq!(40000, tcp_header.destination_port());
+            assert!(tcp_header.is_rst() && tcp_header.is_ack());
+            assert_eq!(1001, tcp_header.acknowledgement_number());
+        } else {
+            panic!("No TCP transport header");
+        }
+    }
+
+    #[test]
+    fn never_reset_rst() {
+        let mut raw = create_syn();
+        // replace SYN by RST
+        BigEndian::write_u16(&mut raw[32..34], 5 << 12 | tcp_header::FLAG_RST);
+        assert!(reject(&Ipv4Packet::parse(&mut raw)).is_none());
+    }
+
+    #[test]
+    fn prohibit_udp() {
+        let mut udp = Vec::new();
+        udp.write_u16::<BigEndian>(5555).unwrap(); // source port
+        udp.write_u16::<BigEndian>(53).unwrap(); // destination port
+        udp.write_u16::<BigEndian>(12).unwrap(); // length
+        udp.write_u16::<BigEndian>(0).unwrap(); // checksum
+        udp.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+        let mut raw = create_packet(17, &udp);
+        let reply = reject(&Ipv4Packet::parse(&mut raw)).unwrap();
+
+        // IPv4 header + ICMP header + quoted IPv4 header + 8 bytes
+        assert_eq!(56, reply.len());
+        assert_eq!(PROTOCOL_ICMP, reply[9]);
+        assert_eq!(0, checksum(&reply[..20]));
+        assert_eq!(0, checksum(&reply[20..]));
+        assert_eq!(
+            [ICMP_DESTINATION_UNREACHABLE, ICMP_COMMUNICATION_PROHIBITED],
+            reply[20..22]
+        );
+        assert_eq!(raw[..28], reply[28..]);
+    }
+}
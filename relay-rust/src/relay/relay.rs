@@ -14,66 +14,140 @@
  * limitations under the License.
  */
 
-use chrono::Local;
 use log::*;
-use mio::Events;
-use std::cell::RefCell;
-use std::cmp::max;
 use std::io;
-use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::buffer_pool::{MemoryBudget, MemoryUsage};
+use super::config::RelayConfig;
+use super::control::{self, Role};
+use super::metrics::EventCounters;
+use super::reload;
 use super::selector::Selector;
+use super::traffic::TrafficAccounting;
 use super::tunnel_server::TunnelServer;
-use super::udp_connection::IDLE_TIMEOUT_SECONDS;
+use super::worker::{self, WorkerHandle};
 
 const TAG: &str = "Relay";
-const CLEANING_INTERVAL_SECONDS: i64 = 60;
+const TRAFFIC_SAVING_INTERVAL: Duration = Duration::from_secs(10);
+const MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Relay {
     port: u16,
+    config: RelayConfig,
 }
 
 impl Relay {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(port: u16, config: RelayConfig) -> Self {
+        Self { port, config }
     }
 
     pub fn run(&self) -> io::Result<()> {
-        let mut selector = Selector::create().unwrap();
-        let tunnel_server = TunnelServer::create(self.port, &mut selector)?;
-        info!(target: TAG, "Relay server started");
-        self.poll_loop(&mut selector, &tunnel_server)
+        let mut selector = Selector::create()?;
+        let mut config = self.config.clone();
+        let event_counters = EventCounters::default();
+        if config.control_port().is_some() || config.metrics_port().is_some() {
+            // count the events to expose as metrics
+            config.add_observer(Arc::new(event_counters.clone()));
+        }
+        // the settings from the config loader (if any) override the initial ones
+        let loaded =
+            reload::load(&config).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let workers: Arc<[WorkerHandle]> = (0..config.worker_threads())
+            .map(|id| worker::spawn(id, loaded.clone()))
+            .collect::<io::Result<Vec<WorkerHandle>>>()?
+            .into();
+        let _tunnel_server = TunnelServer::create(
+            self.port,
+            workers.clone(),
+            config.device_tunnels().clone(),
+            &mut selector,
+        )?;
+        info!(
+            target: TAG,
+            "Relay server started ({} worker threads)",
+            config.worker_threads()
+        );
+        let control_ports: Vec<(u16, Role)> = [
+            (config.control_port(), Role::Control),
+            (config.metrics_port(), Role::Metrics),
+        ]
+        .iter()
+        .filter_map(|&(port, role)| port.map(|port| (port, role)))
+        .collect();
+        if !control_ports.is_empty() {
+            // the control requests wait for the workers, serve them out of the main event loop
+            control::spawn(
+                control_ports.clone(),
+                workers.clone(),
+                config.clone(),
+                event_counters.clone(),
+            )?;
+            for (port, role) in control_ports {
+                match role {
+                    Role::Control => {
+                        info!(target: TAG, "Control server listening on 127.0.0.1:{}", port)
+                    }
+                    Role::Metrics => info!(
+                        target: TAG,
+                        "Metrics served on http://127.0.0.1:{}/metrics", port
+                    ),
+                }
+            }
+        }
+        if config.config_loader().is_some() {
+            Self::handle_reload_requests(&mut selector, config.clone(), workers.clone())?;
+        }
+        Self::schedule_traffic_saving(&mut selector, self.config.traffic_accounting().clone());
+        Self::schedule_memory_report(
+            &mut selector,
+            self.config.memory_budget().clone(),
+            MemoryUsage::default(),
+        );
+        selector.run()
     }
 
-    fn poll_loop(
-        &self,
+    // reload the configuration whenever requested through the config reload handle
+    fn handle_reload_requests(
         selector: &mut Selector,
-        tunnel_server: &Rc<RefCell<TunnelServer>>,
+        config: RelayConfig,
+        workers: Arc<[WorkerHandle]>,
     ) -> io::Result<()> {
-        let mut events = Events::with_capacity(1024);
-        // no connection may expire before the UDP idle timeout delay
-        let mut next_cleaning_deadline = Local::now().timestamp() + IDLE_TIMEOUT_SECONDS as i64;
-        loop {
-            retry_on_intr!({
-                let timeout_seconds = max(0, next_cleaning_deadline - Local::now().timestamp());
-                let timeout = Some(Duration::new(timeout_seconds as u64, 0));
-                selector.poll(&mut events, timeout)
-            })?;
+        let config_reload = config.config_reload().clone();
+        let handler = move |_: &mut Selector, _| {
+            if let Err(err) = reload::reload(&config, &workers) {
+                error!(target: TAG, "Cannot reload the configuration: {}", err);
+            }
+        };
+        config_reload.bind(selector.register_waker(handler)?);
+        Ok(())
+    }
 
-            let now = Local::now().timestamp();
-            if now >= next_cleaning_deadline {
-                tunnel_server.borrow_mut().clean_up(selector);
-                next_cleaning_deadline = now + CLEANING_INTERVAL_SECONDS;
-            } else if events.is_empty() {
-                debug!(
-                    target: TAG,
-                    "Spurious wakeup: poll() returned without any event"
-                );
-                continue;
+    // persist the traffic counters (published regularly by the workers), if requested
+    fn schedule_traffic_saving(selector: &mut Selector, traffic_accounting: TrafficAccounting) {
+        let deadline = Instant::now() + TRAFFIC_SAVING_INTERVAL;
+        selector.schedule(deadline, move |selector| {
+            if let Err(err) = traffic_accounting.save() {
+                error!(target: TAG, "Cannot save traffic counters: {}", err);
             }
+            Self::schedule_traffic_saving(selector, traffic_accounting);
+        });
+    }
 
-            selector.run_handlers(&events);
-        }
+    // log the memory used by the buffers, when it changed
+    fn schedule_memory_report(
+        selector: &mut Selector,
+        memory_budget: MemoryBudget,
+        last_usage: MemoryUsage,
+    ) {
+        let deadline = Instant::now() + MEMORY_REPORT_INTERVAL;
+        selector.schedule(deadline, move |selector| {
+            let usage = memory_budget.usage();
+            if usage != last_usage {
+                info!(target: TAG, "Memory: {}", usage);
+            }
+            Self::schedule_memory_report(selector, memory_budget, usage);
+        });
     }
 }
@@ -0,0 +1,105 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::config::RelayConfig;
+use super::selector::Wake;
+use super::worker::WorkerHandle;
+
+const TAG: &str = "Reload";
+
+/// Source of the settings to apply on startup and whenever the relay reloads its configuration.
+///
+/// The new settings apply to the connections opened afterwards (NAT rules, loopback policy,
+/// proxy routing and socket bindings); the existing connections are kept as is.
+pub trait ConfigLoader: fmt::Debug + Send + Sync {
+    /// Read the settings into `config`, a copy of the configuration the relay was started with.
+    ///
+    /// The settings stored in shared handles (e.g. the network emulation) may be changed
+    /// directly, they apply immediately to all the clients.
+    fn load(&self, config: &mut RelayConfig) -> Result<(), String>;
+}
+
+/// Handle to request a running relay to reload its configuration.
+///
+/// It may be used from any thread (e.g. from a signal handling thread).
+#[derive(Clone, Default)]
+pub struct ConfigReload {
+    // set once the relay is running
+    waker: Arc<Mutex<Option<Box<dyn Wake>>>>,
+}
+
+impl ConfigReload {
+    /// Request the relay to reload its configuration, asynchronously.
+    pub fn request(&self) -> io::Result<()> {
+        match *self.waker.lock().unwrap() {
+            Some(ref waker) => waker.wake(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The relay is not running",
+            )),
+        }
+    }
+
+    pub(crate) fn bind(&self, waker: Box<dyn Wake>) {
+        *self.waker.lock().unwrap() = Some(waker);
+    }
+}
+
+impl fmt::Debug for ConfigReload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bound = self.waker.lock().unwrap().is_some();
+        f.debug_struct("ConfigReload")
+            .field("bound", &bound)
+            .finish()
+    }
+}
+
+/// Apply the settings of the config loader (if any) to a copy of `config`.
+pub fn load(config: &RelayConfig) -> Result<RelayConfig, String> {
+    let mut loaded = config.clone();
+    if let Some(loader) = config.config_loader() {
+        loader.load(&mut loaded)?;
+    }
+    Ok(loaded)
+}
+
+/// Load the settings again, and hand them to the workers, without disconnecting the clients.
+///
+/// `config` is the configuration the relay was started with.
+///
+/// It may be called from several threads (the main event loop and the control server): the
+/// reloads are serialized, so that all the workers receive the configurations in the same order.
+pub fn reload(config: &RelayConfig, workers: &[WorkerHandle]) -> Result<(), String> {
+    static RELOADING: Mutex<()> = Mutex::new(());
+
+    if config.config_loader().is_none() {
+        return Err(String::from("No configuration to reload"));
+    }
+    let _guard = RELOADING.lock().unwrap();
+    let loaded = load(config)?;
+    for worker in workers {
+        worker
+            .reload(loaded.clone())
+            .map_err(|err| err.to_string())?;
+    }
+    info!(target: TAG, "Configuration reloaded");
+    Ok(())
+}
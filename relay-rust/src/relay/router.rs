@@ -16,39 +16,78 @@
 
 use log::*;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
+use std::net::Ipv4Addr;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
 
 use super::binary;
 use super::client::{Client, ClientChannel};
+use super::config::RelayConfig;
 use super::connection::{Connection, ConnectionId};
+use super::connection_limits;
+use super::dns;
+use super::dns_cache::DnsCache;
+use super::flow_log::{CloseReason, Flow};
 use super::ipv4_header::Protocol;
 use super::ipv4_packet::Ipv4Packet;
+use super::observer::DropReason;
+use super::packet_scheduler::TrafficClass;
+use super::reject;
 use super::selector::Selector;
+use super::stats::ConnectionStats;
 use super::tcp_connection::TcpConnection;
 use super::udp_connection::UdpConnection;
+use super::upstream::Upstream;
 
 const TAG: &str = "Router";
 
 pub struct Router {
     client: Weak<RefCell<Client>>,
-    // there are typically only few connections per client, HashMap would be less efficient
-    connections: Vec<Rc<RefCell<dyn Connection>>>,
+    client_id: u32,
+    // the serial of the device, if identified
+    device: Option<Arc<str>>,
+    config: Rc<RelayConfig>,
+    // a client may have hundreds of connections, and every packet must find its own
+    connections: HashMap<ConnectionId, Rc<RefCell<dyn Connection>>>,
+    // host names resolved by the client, to select the upstream of the connections
+    dns_cache: DnsCache,
+    // set once the data quota of the client is exceeded
+    blocked: bool,
+    // set while new connections are refused for lack of resources, to warn only once
+    refusing: bool,
 }
 
 impl Router {
-    pub fn new() -> Self {
+    pub fn new(client_id: u32, device: Option<Arc<str>>, config: Rc<RelayConfig>) -> Self {
         Self {
             client: Weak::new(),
-            connections: Vec::new(),
+            client_id,
+            device,
+            config,
+            connections: HashMap::new(),
+            dns_cache: DnsCache::new(),
+            blocked: false,
+            refusing: false,
         }
     }
 
+    /// Use `config` for the connections opened from now on.
+    pub fn set_config(&mut self, config: Rc<RelayConfig>) {
+        self.config = config;
+    }
+
     // expose client initialization after construction to break cyclic initialization dependencies
     pub fn set_client(&mut self, client: Weak<RefCell<Client>>) {
         self.client = client;
     }
 
+    /// Refuse the new connections (the existing ones are kept).
+    pub fn block_new_connections(&mut self) {
+        self.blocked = true;
+    }
+
     pub fn send_to_network(
         &mut self,
         selector: &mut Selector,
@@ -57,31 +96,26 @@ impl Router {
     ) {
         if ipv4_packet.is_valid() {
             match self.connection(selector, ipv4_packet) {
-                Ok(index) => {
-                    let closed = {
-                        let connection_ref = &self.connections[index];
-                        let mut connection = connection_ref.borrow_mut();
-                        connection.send_to_network(selector, client_channel, ipv4_packet);
-                        if connection.is_closed() {
-                            debug!(
-                                target: TAG,
-                                "Removing connection from router: {}",
-                                connection.id()
-                            );
-                            true
-                        } else {
-                            false
-                        }
-                    };
-                    if closed {
+                Ok(connection_ref) => {
+                    let mut connection = connection_ref.borrow_mut();
+                    connection.send_to_network(selector, client_channel, ipv4_packet);
+                    if connection.is_closed() {
+                        debug!(
+                            target: TAG,
+                            "Removing connection from router: {}",
+                            connection.id()
+                        );
                         // the connection is closed, remove it
-                        self.connections.swap_remove(index);
+                        self.remove_by_id(connection.id());
                     }
                 }
-                Err(err) => error!(target: TAG, "Cannot create route, dropping packet: {}", err),
+                Err(err) => self.refuse(selector, client_channel, ipv4_packet, &err),
             }
         } else {
             warn!(target: TAG, "Dropping invalid packet");
+            self.config
+                .observer()
+                .packet_dropped(self.client_id, DropReason::Invalid);
             if log_enabled!(target: TAG, Level::Trace) {
                 trace!(
                     target: TAG,
@@ -96,103 +130,318 @@ impl Router {
         &mut self,
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
-    ) -> io::Result<usize> {
+    ) -> io::Result<Rc<RefCell<dyn Connection>>> {
         let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
         let transport_header_data = transport_header_data.expect("No transport");
         let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
-        let index = match self.find_index(&id) {
-            Some(index) => index,
+        let connection = match self.connections.get(&id) {
+            Some(connection) => connection.clone(),
             None => {
-                let connection =
-                    Self::create_connection(selector, id, self.client.clone(), ipv4_packet)?;
-                let index = self.connections.len();
-                self.connections.push(connection);
-                index
+                if self.blocked {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Data quota exceeded",
+                    ));
+                }
+                self.acquire_connection(selector)?;
+                let hostname = self
+                    .dns_cache
+                    .hostname(&Ipv4Addr::from(id.destination_ip()));
+                if let Some(hostname) = hostname {
+                    debug!(target: TAG, "{} resolved from {}", id.destination(), hostname);
+                }
+                let upstream = Upstream::new(&self.config, self.device.as_deref(), &id, hostname);
+                let connection = Self::create_connection(
+                    selector,
+                    id.clone(),
+                    self.client.clone(),
+                    self.client_id,
+                    &self.config,
+                    &upstream,
+                    ipv4_packet,
+                )
+                .inspect_err(|_| self.config.connection_limits().release(1))?;
+                self.refusing = false;
+                self.config
+                    .observer()
+                    .connection_opened(self.client_id, &id);
+                self.connections.insert(id, connection.clone());
+                connection
             }
         };
-        Ok(index)
+        Ok(connection)
+    }
+
+    // account for a new connection, evicting idle connections if a limit is reached
+    fn acquire_connection(&mut self, selector: &mut Selector) -> io::Result<()> {
+        loop {
+            match self
+                .config
+                .connection_limits()
+                .acquire(self.connections.len())
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if !self.evict_idle_connection(selector) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn evict_idle_connection(&mut self, selector: &mut Selector) -> bool {
+        let oldest = self
+            .connections
+            .iter()
+            .filter_map(|(id, connection)| {
+                let idle_since = connection.borrow().idle_since()?;
+                Some((id, idle_since))
+            })
+            .min_by_key(|&(_, idle_since)| idle_since)
+            .map(|(id, _)| id.clone());
+        match oldest {
+            Some(id) => {
+                info!(
+                    target: TAG,
+                    "Connection limit reached, evicting idle connection: {}",
+                    id
+                );
+                let connection = self.remove_by_id(&id).unwrap();
+                connection
+                    .borrow_mut()
+                    .close(selector, CloseReason::Evicted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // refuse the packet which could not create a connection
+    fn refuse(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        err: &io::Error,
+    ) {
+        self.config
+            .observer()
+            .packet_dropped(self.client_id, DropReason::Refused);
+        if connection_limits::is_descriptor_exhaustion(err) {
+            if !self.refusing {
+                warn!(
+                    target: TAG,
+                    "Client #{}: too many open files, refusing new connections",
+                    self.client_id
+                );
+                self.refusing = true;
+            }
+        } else if connection_limits::is_limit_reached(err) {
+            if !self.refusing {
+                warn!(
+                    target: TAG,
+                    "Client #{}: {}, refusing new connections",
+                    self.client_id,
+                    err
+                );
+                self.refusing = true;
+            }
+        } else if err.kind() == io::ErrorKind::PermissionDenied {
+            // already reported by the policy
+            debug!(target: TAG, "Refusing connection: {}", err);
+        } else {
+            error!(target: TAG, "Cannot create route, dropping packet: {}", err);
+            return;
+        }
+        if let Some(mut raw) = reject::reject(ipv4_packet) {
+            let reply = Ipv4Packet::parse(&mut raw);
+            if client_channel
+                .send_to_client(selector, &reply, TrafficClass::Interactive)
+                .is_err()
+            {
+                debug!(target: TAG, "Cannot send refusal to client");
+            }
+        }
     }
 
     fn create_connection(
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        client_id: u32,
+        config: &Rc<RelayConfig>,
+        upstream: &Upstream,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<Rc<RefCell<dyn Connection>>> {
         let (ipv4_header, transport_header) = ipv4_packet.headers();
         let transport_header = transport_header.expect("No transport");
+        let flow = Flow::new(client_id, &id, upstream.destination(), config.clone());
         match id.protocol() {
-            Protocol::Tcp => Ok(TcpConnection::create(
+            Protocol::Tcp => TcpConnection::create(
                 selector,
-                id,
+                id.clone(),
                 client,
+                upstream,
+                flow,
                 ipv4_header,
                 transport_header,
-            )?),
-            Protocol::Udp => Ok(UdpConnection::create(
+            )
+            .map(|connection| connection as _),
+            Protocol::Udp => UdpConnection::create(
                 selector,
-                id,
+                id.clone(),
                 client,
+                upstream,
+                flow,
                 ipv4_header,
                 transport_header,
-            )?),
-            p => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported protocol: {:?}", p),
-            )),
+            )
+            .map(|connection| connection as _),
+            p => Err(io::Error::other(format!("Unsupported protocol: {:?}", p))),
         }
     }
 
-    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
-        self.connections
-            .iter()
-            .position(|connection| connection.borrow().id() == id)
+    /// Learn the host names resolved by the client from a DNS response relayed to it.
+    pub fn record_dns_response(&mut self, message: &[u8]) {
+        if let Some(answer) = dns::parse_response(message) {
+            debug!(
+                target: TAG,
+                "DNS: {} resolved to {:?}",
+                answer.hostname,
+                answer.addresses
+            );
+            self.dns_cache.record(&answer);
+        }
     }
 
     pub fn remove(&mut self, connection: &dyn Connection) {
-        let index = self
+        // compare (thin) pointers to make sure the connection is the one registered for its id
+        let known = self
             .connections
-            .iter()
-            .position(|item| {
-                // compare (thin) pointers to find the connection to remove
-                binary::ptr_data_eq(connection, item.as_ptr())
-            })
-            .expect("Removing an unknown connection");
+            .get(connection.id())
+            .is_some_and(|item| binary::ptr_data_eq(connection, item.as_ptr()));
+        assert!(known, "Removing an unknown connection");
         debug!(
             target: TAG,
             "Self-removing connection from router: {}",
             connection.id()
         );
-        self.connections.swap_remove(index);
+        self.remove_by_id(connection.id());
+    }
+
+    fn remove_by_id(&mut self, id: &ConnectionId) -> Option<Rc<RefCell<dyn Connection>>> {
+        let connection = self.connections.remove(id);
+        if connection.is_some() {
+            self.config.connection_limits().release(1);
+        }
+        connection
+    }
+
+    /// Close a connection on request.
+    ///
+    /// Return `false` if the connection does not exist.
+    pub fn abort_connection(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+    ) -> bool {
+        match self.remove_by_id(id) {
+            Some(connection) => {
+                connection.borrow_mut().abort(selector, client_channel);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .values()
+            .map(|connection| connection.borrow().stats())
+            .collect()
     }
 
     pub fn clear(&mut self, selector: &mut Selector) {
-        for connection in &mut self.connections {
-            connection.borrow_mut().close(selector);
+        for connection in self.connections.values() {
+            connection
+                .borrow_mut()
+                .close(selector, CloseReason::ClientClosed);
         }
+        self.config
+            .connection_limits()
+            .release(self.connections.len());
         self.connections.clear();
     }
+}
 
-    pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        // remove the last items first, otherwise i might not be less than len() on swap_remove(i)
-        for i in (0..self.connections.len()).rev() {
-            let expired = {
-                let mut connection = self.connections[i].borrow_mut();
-                if connection.is_expired() {
-                    debug!(
-                        target: TAG,
-                        "Removing expired connection from router: {}",
-                        connection.id()
-                    );
-                    connection.close(selector);
-                    true
-                } else {
-                    false
-                }
-            };
-            if expired {
-                self.connections.swap_remove(i);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::flow_log::FlowRecord;
+    use crate::relay::loopback_policy::{LoopbackAccess, LoopbackPolicy};
+    use crate::relay::observer::RelayObserver;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        closed: Mutex<Vec<FlowRecord>>,
+    }
+
+    impl RelayObserver for RecordingObserver {
+        fn connection_closed(&self, record: &FlowRecord) {
+            self.closed.lock().unwrap().push(record.clone());
         }
     }
+
+    fn create_syn(destination: Ipv4Addr, port: u16) -> Vec<u8> {
+        let mut raw = vec![0u8; 40];
+        raw[0] = 0x45;
+        raw[3] = 40; // total length
+        raw[8] = 64; // TTL
+        raw[9] = 6; // TCP
+        raw[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        raw[16..20].copy_from_slice(&destination.octets());
+        raw[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        raw[22..24].copy_from_slice(&port.to_be_bytes());
+        raw[32] = 5 << 4; // data offset
+        raw[33] = 0x02; // SYN
+        raw
+    }
+
+    #[test]
+    fn record_refused_connection() {
+        let observer = Arc::new(RecordingObserver::default());
+        let mut config = RelayConfig::new();
+        config.set_loopback_policy(LoopbackPolicy::new(LoopbackAccess::None));
+        config.set_observer(observer.clone());
+        let config = Rc::new(config);
+        let mut selector = Selector::create().unwrap();
+
+        let mut raw = create_syn(Ipv4Addr::LOCALHOST, 8080);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let upstream = Upstream::new(&config, None, &id, None);
+        let result = Router::create_connection(
+            &mut selector,
+            id.clone(),
+            Weak::new(),
+            0,
+            &config,
+            &upstream,
+            &ipv4_packet,
+        );
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            result.err().unwrap().kind()
+        );
+
+        let closed = observer.closed.lock().unwrap();
+        assert_eq!(1, closed.len());
+        assert_eq!(&id, closed[0].id());
+        assert_eq!(CloseReason::Refused, closed[0].close_reason());
+        assert_eq!(1, closed[0].counters().uplink_packets());
+    }
 }
@@ -15,11 +15,13 @@
  */
 
 use log::*;
-use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Token};
+use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use slab::Slab;
 use std::io;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use super::timer_wheel::{TimerId, TimerWheel};
 
 const TAG: &str = "Selector";
 
@@ -36,19 +38,41 @@ where
     }
 }
 
+type TimerHandler = Box<dyn FnOnce(&mut Selector)>;
+
+/// Wake up a `Selector` from another thread.
+pub trait Wake: Send + Sync {
+    fn wake(&self) -> io::Result<()>;
+}
+
+struct Waker(SetReadiness);
+
+impl Wake for Waker {
+    fn wake(&self) -> io::Result<()> {
+        self.0.set_readiness(Ready::readable())
+    }
+}
+
 pub struct Selector {
     poll: Poll,
+    events: Events,
+    // the registrations must be kept alive as long as their wakers are used
+    wakers: Vec<(Token, Registration, SetReadiness)>,
     handlers: Slab<Rc<dyn EventHandler>>,
     // tokens to be removed after all the current poll events are executed
     tokens_to_remove: Vec<Token>,
+    timers: TimerWheel<TimerHandler>,
 }
 
 impl Selector {
     pub fn create() -> io::Result<Self> {
         Ok(Self {
             poll: Poll::new()?,
+            events: Events::with_capacity(1024),
+            wakers: Vec::new(),
             handlers: Slab::with_capacity(1024),
             tokens_to_remove: Vec::new(),
+            timers: TimerWheel::new(),
         })
     }
 
@@ -96,6 +120,28 @@ impl Selector {
         Ok(())
     }
 
+    /// Register a handler called (at least once) after the returned waker is woken up.
+    ///
+    /// The waker may be used from any thread.
+    pub fn register_waker<H>(&mut self, handler: H) -> io::Result<Box<dyn Wake>>
+    where
+        H: EventHandler + 'static,
+    {
+        let token = Token(self.handlers.insert(Rc::new(handler)));
+        let (registration, set_readiness) = Registration::new2();
+        if let Err(err) =
+            self.poll
+                .register(&registration, token, Ready::readable(), PollOpt::edge())
+        {
+            // remove the token we just added
+            self.handlers.remove(token.0);
+            return Err(err);
+        }
+        self.wakers
+            .push((token, registration, set_readiness.clone()));
+        Ok(Box::new(Waker(set_readiness)))
+    }
+
     fn clean_removed_tokens(&mut self) {
         for &token in &self.tokens_to_remove {
             self.handlers.remove(token.0);
@@ -103,12 +149,94 @@ impl Selector {
         self.tokens_to_remove.clear();
     }
 
-    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<usize> {
-        self.poll.poll(events, timeout)
+    /// Call `handler` once `deadline` is reached (on the monotonic clock).
+    pub fn schedule<H>(&mut self, deadline: Instant, handler: H) -> TimerId
+    where
+        H: FnOnce(&mut Selector) + 'static,
+    {
+        self.timers.schedule(deadline, Box::new(handler))
+    }
+
+    /// Cancel a scheduled timer.
+    ///
+    /// Return `false` if it has already been executed.
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        self.timers.cancel(timer)
+    }
+
+    /// The deadline of the earliest scheduled timer, if any.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    fn poll(&mut self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        self.poll.poll(&mut self.events, timeout)?;
+        for event in &self.events {
+            if let Some((_, _, set_readiness)) = self
+                .wakers
+                .iter()
+                .find(|(token, _, _)| *token == event.token())
+            {
+                // reset before the handler runs, so that a wake-up meanwhile is not lost
+                set_readiness.set_readiness(Ready::empty())?;
+            }
+            events.push(event);
+        }
+        Ok(())
     }
 
-    pub fn run_handlers(&mut self, events: &Events) {
-        for event in events {
+    /// Execute the expired timers.
+    ///
+    /// Return `true` if at least one timer was executed.
+    fn run_timers(&mut self) -> bool {
+        let now = Instant::now();
+        let mut executed = false;
+        while let Some(handler) = self.timers.pop_expired(now) {
+            handler(self);
+            executed = true;
+        }
+        executed
+    }
+
+    /// Dispatch the events and execute the timers, until an error occurs.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Vec::with_capacity(1024);
+        loop {
+            self.run_once(&mut events)?;
+        }
+    }
+
+    fn run_once(&mut self, events: &mut Vec<Event>) -> io::Result<()> {
+        retry_on_intr!({
+            // wake up exactly when the next timer expires
+            let timeout = self
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            self.poll(events, timeout)
+        })?;
+
+        // the timers may deregister tokens having pending events: they are removed only once
+        // the events are dispatched
+        let timers_executed = self.run_timers();
+
+        if events.is_empty() {
+            if !timers_executed {
+                debug!(
+                    target: TAG,
+                    "Spurious wakeup: poll() returned without any event"
+                );
+            }
+            self.clean_removed_tokens();
+            return Ok(());
+        }
+
+        self.run_handlers(events);
+        Ok(())
+    }
+
+    fn run_handlers(&mut self, events: &[Event]) {
+        for &event in events {
             debug!(target: TAG, "event={:?}", event);
             let handler = self
                 .handlers
@@ -122,3 +250,93 @@ impl Selector {
         self.clean_removed_tokens();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::net::UdpSocket;
+    use std::cell::Cell;
+
+    fn readable_socket() -> UdpSocket {
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"x", socket.local_addr().unwrap()).unwrap();
+        socket
+    }
+
+    #[test]
+    fn timer_deregisters_handler_with_pending_event() {
+        let mut selector = Selector::create().unwrap();
+        let socket = Rc::new(readable_socket());
+        let calls = Rc::new(Cell::new(0));
+        let calls2 = calls.clone();
+        let token = selector
+            .register(
+                &*socket,
+                move |_: &mut Selector, _| calls2.set(calls2.get() + 1),
+                Ready::readable(),
+                PollOpt::level(),
+            )
+            .unwrap();
+        // wait for the datagram, so that the next poll() reports the event
+        std::thread::sleep(Duration::from_millis(50));
+
+        let other_calls = Rc::new(Cell::new(0));
+        let other_calls2 = other_calls.clone();
+        let other_socket = Rc::new(UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap());
+        let (socket2, other_socket2) = (socket.clone(), other_socket.clone());
+        selector.schedule(Instant::now(), move |selector| {
+            // close the handler, then register another one (which must not reuse its slot yet)
+            selector.deregister(&*socket2, token).unwrap();
+            let other_token = selector
+                .register(
+                    &*other_socket2,
+                    move |_: &mut Selector, _| other_calls2.set(other_calls2.get() + 1),
+                    Ready::readable(),
+                    PollOpt::level(),
+                )
+                .unwrap();
+            assert_ne!(token, other_token);
+        });
+
+        let mut events = Vec::new();
+        selector.run_once(&mut events).unwrap();
+        assert_eq!(1, events.len());
+        // the pending event is dispatched to the closed handler, not to the new one
+        assert_eq!(1, calls.get());
+        assert_eq!(0, other_calls.get());
+        // the slot is released once the events are dispatched
+        assert!(selector.handlers.get(token.0).is_none());
+    }
+
+    #[test]
+    fn waker() {
+        let mut selector = Selector::create().unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let calls2 = calls.clone();
+        let waker = selector
+            .register_waker(move |_: &mut Selector, _| calls2.set(calls2.get() + 1))
+            .unwrap();
+        let mut events = Vec::new();
+
+        waker.wake().unwrap();
+        selector
+            .poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(1, events.len());
+
+        // the readiness has been reset
+        selector
+            .poll(&mut events, Some(Duration::from_secs(0)))
+            .unwrap();
+        assert!(events.is_empty());
+
+        waker.wake().unwrap();
+        selector
+            .poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(1, events.len());
+        selector.run_handlers(&events);
+        assert_eq!(1, calls.get());
+    }
+}
@@ -0,0 +1,467 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use rand::random;
+use std::cell::RefCell;
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+use std::rc::Weak;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::client::Client;
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+use super::network_profile::{NetworkEmulation, NetworkProfile};
+use super::selector::Selector;
+use super::stream_buffer::StreamBuffer;
+
+const TAG: &str = "Shaper";
+
+const QUEUE_CAPACITY: usize = 16 * MAX_PACKET_LENGTH;
+// the relay never retransmits the TCP segments sent to the client, so they are delayed (as if
+// retransmitted) rather than lost
+const RETRANSMISSION_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Queued,
+    Dropped,
+    // the queue is full, the packet is not accepted
+    Full,
+}
+
+struct ShapedPacket {
+    release: Instant,
+    sequence: u64,
+    raw: Vec<u8>,
+}
+
+// BinaryHeap is a max-heap, order the packets so that the first to release is the greatest
+impl Ord for ShapedPacket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .release
+            .cmp(&self.release)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for ShapedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ShapedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ShapedPacket {}
+
+/// Packets delayed in one direction, according to a network profile.
+pub struct ShapingQueue {
+    packets: BinaryHeap<ShapedPacket>,
+    size: usize,
+    capacity: usize,
+    sequence: u64,
+    // when the link becomes available for the next packet (bandwidth cap)
+    link_available: Option<Instant>,
+    // the packets are released in order, unless reordered on purpose
+    last_release: Option<Instant>,
+}
+
+impl ShapingQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: BinaryHeap::new(),
+            size: 0,
+            capacity,
+            sequence: 0,
+            link_available: None,
+            last_release: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Delay `raw` according to `profile`, with the bandwidth cap `rate` (in bits per second).
+    ///
+    /// If `droppable` is false, a lost packet is delayed instead of dropped.
+    pub fn push(
+        &mut self,
+        raw: &[u8],
+        profile: &NetworkProfile,
+        rate: Option<u64>,
+        droppable: bool,
+        now: Instant,
+    ) -> Verdict {
+        if self.size + raw.len() > self.capacity {
+            return Verdict::Full;
+        }
+        let mut extra_delay = Duration::ZERO;
+        if profile.loss() > 0.0 && random::<f64>() < profile.loss() {
+            if droppable {
+                return Verdict::Dropped;
+            }
+            extra_delay = RETRANSMISSION_DELAY;
+        }
+
+        let mut departure = now;
+        if let Some(rate) = rate {
+            let start = cmp::max(now, self.link_available.unwrap_or(now));
+            let transmission_nanos = raw.len() as u64 * 8 * 1_000_000_000 / rate;
+            departure = start + Duration::from_nanos(transmission_nanos);
+            self.link_available = Some(departure);
+        }
+
+        let release = if profile.reorder() > 0.0 && random::<f64>() < profile.reorder() {
+            // overtake the packets in flight
+            departure
+        } else {
+            let release = departure + extra_delay + Self::jittered_delay(profile);
+            let release = cmp::max(release, self.last_release.unwrap_or(release));
+            self.last_release = Some(release);
+            release
+        };
+
+        self.packets.push(ShapedPacket {
+            release,
+            sequence: self.sequence,
+            raw: raw.to_vec(),
+        });
+        self.sequence += 1;
+        self.size += raw.len();
+        Verdict::Queued
+    }
+
+    fn jittered_delay(profile: &NetworkProfile) -> Duration {
+        let jitter = profile.jitter();
+        if jitter == Duration::ZERO {
+            return profile.delay();
+        }
+        // uniform in [delay - jitter; delay + jitter]
+        let offset = jitter.mul_f64(2.0 * random::<f64>());
+        (profile.delay() + offset).saturating_sub(jitter)
+    }
+
+    pub fn next_release(&self) -> Option<Instant> {
+        self.packets.peek().map(|packet| packet.release)
+    }
+
+    /// The length of the next packet, if it must be released at `now`.
+    pub fn due_length(&self, now: Instant) -> Option<usize> {
+        self.packets
+            .peek()
+            .filter(|packet| packet.release <= now)
+            .map(|packet| packet.raw.len())
+    }
+
+    /// Remove and return the next packet, if it must be released at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.due_length(now)?;
+        let packet = self.packets.pop().unwrap();
+        self.size -= packet.raw.len();
+        Some(packet.raw)
+    }
+}
+
+/// Network condition emulation for one client, in both directions.
+pub struct Shaper {
+    client: Weak<RefCell<Client>>,
+    client_id: u32,
+    device: Option<Arc<str>>,
+    emulation: NetworkEmulation,
+    // the last profile applied, to log changes
+    profile: NetworkProfile,
+    uplink: ShapingQueue,
+    downlink: ShapingQueue,
+    // set while the next due downlink packet waits for space in the client buffer
+    downlink_blocked: bool,
+    timer_deadline: Option<Instant>,
+    // bandwidth cap (in bits/s) applied on top of the profile, once the data quota is exceeded
+    throttling_rate: Option<u64>,
+}
+
+impl Shaper {
+    /// `device` is the serial of the device of the client, if identified.
+    pub fn new(client_id: u32, device: Option<Arc<str>>, emulation: NetworkEmulation) -> Self {
+        let profile = emulation.profile(device.as_deref());
+        if !profile.is_neutral() {
+            info!(target: TAG, "Client #{} network profile: {}", client_id, profile);
+        }
+        Self {
+            client: Weak::new(),
+            client_id,
+            device,
+            emulation,
+            profile,
+            uplink: ShapingQueue::new(QUEUE_CAPACITY),
+            downlink: ShapingQueue::new(QUEUE_CAPACITY),
+            downlink_blocked: false,
+            timer_deadline: None,
+            throttling_rate: None,
+        }
+    }
+
+    // expose client initialization after construction to break cyclic initialization dependencies
+    pub fn set_client(&mut self, client: Weak<RefCell<Client>>) {
+        self.client = client;
+    }
+
+    /// Refresh the profile from the emulation settings, which may change at any time.
+    ///
+    /// Return `true` if packets must be shaped (or are still queued).
+    pub fn refresh(&mut self) -> bool {
+        let profile = self.emulation.profile(self.device.as_deref());
+        if profile != self.profile {
+            info!(
+                target: TAG,
+                "Client #{} network profile changed: {}",
+                self.client_id,
+                profile
+            );
+            self.profile = profile;
+        }
+        !self.profile.is_neutral()
+            || self.throttling_rate.is_some()
+            || !self.uplink.is_empty()
+            || !self.downlink.is_empty()
+    }
+
+    /// Cap the bandwidth in each direction, whatever the profile.
+    pub fn throttle(&mut self, rate: u64) {
+        self.throttling_rate = Some(rate);
+    }
+
+    fn cap(&self, rate: Option<u64>) -> Option<u64> {
+        match (rate, self.throttling_rate) {
+            (Some(rate), Some(max)) => Some(cmp::min(rate, max)),
+            (rate, max) => rate.or(max),
+        }
+    }
+
+    pub fn push_uplink(&mut self, selector: &mut Selector, raw: &[u8]) -> Verdict {
+        let rate = self.cap(self.profile.uplink_rate());
+        let verdict = self
+            .uplink
+            .push(raw, &self.profile, rate, true, Instant::now());
+        self.schedule(selector);
+        verdict
+    }
+
+    pub fn push_downlink(
+        &mut self,
+        selector: &mut Selector,
+        raw: &[u8],
+        droppable: bool,
+    ) -> Verdict {
+        let rate = self.cap(self.profile.downlink_rate());
+        let verdict = self
+            .downlink
+            .push(raw, &self.profile, rate, droppable, Instant::now());
+        self.schedule(selector);
+        verdict
+    }
+
+    pub fn uplink(&mut self) -> &mut ShapingQueue {
+        &mut self.uplink
+    }
+
+    /// Move the due downlink packets to the client `buffer`, as long as they fit.
+    ///
+    /// If the next due packet does not fit, it must be released again once the client buffer is
+    /// written: no timer is scheduled for it meanwhile.
+    pub fn release_downlink(&mut self, buffer: &mut StreamBuffer, now: Instant) {
+        while let Some(length) = self.downlink.due_length(now) {
+            if length > buffer.remaining() {
+                self.downlink_blocked = true;
+                return;
+            }
+            let raw = self.downlink.pop_due(now).unwrap();
+            buffer.read_from(&raw);
+        }
+        self.downlink_blocked = false;
+    }
+
+    /// Acknowledge the expiration of a timer.
+    ///
+    /// Return `false` if the timer is obsolete (another one has been scheduled since).
+    pub fn on_timer(&mut self, deadline: Instant) -> bool {
+        if self.timer_deadline != Some(deadline) {
+            return false;
+        }
+        self.timer_deadline = None;
+        true
+    }
+
+    /// Schedule a timer for the next packet to release, if necessary.
+    pub fn schedule(&mut self, selector: &mut Selector) {
+        // a blocked downlink packet is already due, a timer would expire immediately (forever)
+        let downlink_release = self
+            .downlink
+            .next_release()
+            .filter(|_| !self.downlink_blocked);
+        let next_release = match (self.uplink.next_release(), downlink_release) {
+            (Some(u), Some(d)) => cmp::min(u, d),
+            (Some(u), None) => u,
+            (None, Some(d)) => d,
+            (None, None) => return,
+        };
+        if self
+            .timer_deadline
+            .is_some_and(|deadline| deadline <= next_release)
+        {
+            // the current timer will fire soon enough
+            return;
+        }
+        self.timer_deadline = Some(next_release);
+        let client = self.client.clone();
+        selector.schedule(next_release, move |selector| {
+            if let Some(client) = client.upgrade() {
+                client.borrow_mut().on_shaper_timer(selector, next_release);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::network_profile::EmulationPolicy;
+
+    fn profile(s: &str) -> NetworkProfile {
+        NetworkProfile::parse(s).unwrap()
+    }
+
+    #[test]
+    fn delay_packets() {
+        let mut queue = ShapingQueue::new(QUEUE_CAPACITY);
+        let profile = profile("delay=100ms");
+        let now = Instant::now();
+        assert_eq!(Verdict::Queued, queue.push(&[1], &profile, None, true, now));
+        assert_eq!(Verdict::Queued, queue.push(&[2], &profile, None, true, now));
+        assert_eq!(None, queue.pop_due(now + Duration::from_millis(99)));
+        assert_eq!(Some(now + Duration::from_millis(100)), queue.next_release());
+        let later = now + Duration::from_millis(100);
+        assert_eq!(Some(vec![1]), queue.pop_due(later));
+        assert_eq!(Some(vec![2]), queue.pop_due(later));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn cap_bandwidth() {
+        let mut queue = ShapingQueue::new(QUEUE_CAPACITY);
+        // 1000 bytes take 10ms at 800 kbit/s
+        let profile = profile("rate=800kbit");
+        let now = Instant::now();
+        queue.push(&[0; 1000], &profile, profile.uplink_rate(), true, now);
+        queue.push(&[0; 1000], &profile, profile.uplink_rate(), true, now);
+        assert_eq!(Some(now + Duration::from_millis(10)), queue.next_release());
+        queue.pop_due(now + Duration::from_millis(10)).unwrap();
+        assert_eq!(Some(now + Duration::from_millis(20)), queue.next_release());
+    }
+
+    #[test]
+    fn keep_order_despite_jitter() {
+        let mut queue = ShapingQueue::new(QUEUE_CAPACITY);
+        let profile = profile("delay=50ms,jitter=50ms");
+        let now = Instant::now();
+        for i in 0..100 {
+            queue.push(&[i], &profile, None, true, now);
+        }
+        let later = now + Duration::from_millis(100);
+        for i in 0..100 {
+            assert_eq!(Some(vec![i]), queue.pop_due(later));
+        }
+    }
+
+    #[test]
+    fn reorder_packets() {
+        let mut queue = ShapingQueue::new(QUEUE_CAPACITY);
+        let now = Instant::now();
+        queue.push(&[1], &profile("delay=100ms"), None, true, now);
+        queue.push(&[2], &profile("delay=100ms,reorder=100%"), None, true, now);
+        assert_eq!(Some(vec![2]), queue.pop_due(now));
+        assert_eq!(None, queue.pop_due(now));
+    }
+
+    #[test]
+    fn lose_packets() {
+        let mut queue = ShapingQueue::new(QUEUE_CAPACITY);
+        let profile = profile("loss=100%");
+        let now = Instant::now();
+        assert_eq!(
+            Verdict::Dropped,
+            queue.push(&[1], &profile, None, true, now)
+        );
+        assert!(queue.is_empty());
+        // not droppable, delayed instead
+        assert_eq!(
+            Verdict::Queued,
+            queue.push(&[1], &profile, None, false, now)
+        );
+        assert_eq!(Some(now + RETRANSMISSION_DELAY), queue.next_release());
+    }
+
+    #[test]
+    fn wait_for_client_buffer() {
+        let emulation = NetworkEmulation::new(EmulationPolicy::parse("delay=10ms").unwrap());
+        let mut shaper = Shaper::new(0, None, emulation);
+        shaper.refresh();
+        let mut selector = Selector::create().unwrap();
+        let mut buffer = StreamBuffer::new(1500);
+        buffer.read_from(&[0; 1000]);
+        assert_eq!(
+            Verdict::Queued,
+            shaper.push_downlink(&mut selector, &[1; 1000], true)
+        );
+        let deadline = selector.next_deadline().unwrap();
+        assert!(shaper.on_timer(deadline));
+
+        // the client buffer is full, the packet is kept without scheduling a timer
+        let later = deadline + Duration::from_millis(1);
+        shaper.release_downlink(&mut buffer, later);
+        shaper.schedule(&mut selector);
+        assert_eq!(500, buffer.remaining());
+        assert_eq!(None, shaper.timer_deadline);
+
+        // released once the client buffer is written
+        buffer.write_to(&mut Vec::new()).unwrap();
+        shaper.release_downlink(&mut buffer, later);
+        assert_eq!(1000, buffer.size());
+        assert!(!shaper.downlink_blocked);
+    }
+
+    #[test]
+    fn refuse_packets_when_full() {
+        let mut queue = ShapingQueue::new(1500);
+        let profile = profile("delay=10ms");
+        let now = Instant::now();
+        assert_eq!(
+            Verdict::Queued,
+            queue.push(&[0; 1000], &profile, None, true, now)
+        );
+        assert_eq!(
+            Verdict::Full,
+            queue.push(&[0; 1000], &profile, None, true, now)
+        );
+    }
+}
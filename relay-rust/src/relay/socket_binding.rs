@@ -0,0 +1,284 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::net::{TcpStream, UdpSocket};
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use super::device_tunnels;
+
+/// Options applied to the upstream sockets, to select the uplink used to reach the destinations
+/// (or the upstream proxy).
+///
+/// Syntax: `OPTION[,OPTION...]`, where `OPTION` is `src:IP` (bind to a source address before
+/// connecting), `dev:INTERFACE` (`SO_BINDTODEVICE`) or `mark:MARK` (`SO_MARK`, decimal or
+/// hexadecimal with the `0x` prefix). The interface and the mark are only supported on Linux.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketBinding {
+    source_address: Option<Ipv4Addr>,
+    interface: Option<String>,
+    mark: Option<u32>,
+}
+
+/// Socket binding, with optional per-device overrides.
+///
+/// Syntax: `ENTRY[;ENTRY...]`, where `ENTRY` is `[SERIAL=]BINDING`. An entry without serial sets
+/// the default binding.
+///
+/// For example, `dev:eth0;emulator-5554=dev:wlan0` pins `emulator-5554` to the Wi-Fi interface
+/// and all the other devices to the ethernet one.
+///
+/// The overrides only apply to the devices identified by their tunnel port.
+#[derive(Clone, Debug, Default)]
+pub struct SocketBindingPolicy {
+    default: SocketBinding,
+    per_device: HashMap<String, SocketBinding>,
+}
+
+impl SocketBinding {
+    pub fn new(
+        source_address: Option<Ipv4Addr>,
+        interface: Option<String>,
+        mark: Option<u32>,
+    ) -> Self {
+        Self {
+            source_address,
+            interface,
+            mark,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut binding = Self::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let index = option
+                .find(':')
+                .ok_or_else(|| format!("Invalid binding option: \"{}\"", option))?;
+            let value = &option[index + 1..];
+            match &option[..index] {
+                "src" => {
+                    let address = value
+                        .parse::<Ipv4Addr>()
+                        .map_err(|_| format!("Invalid source address: \"{}\"", value))?;
+                    binding.source_address = Some(address);
+                }
+                "dev" if !value.is_empty() => binding.interface = Some(value.to_string()),
+                "mark" => {
+                    let mark = match value.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => value.parse::<u32>(),
+                    };
+                    binding.mark = Some(mark.map_err(|_| format!("Invalid mark: \"{}\"", value))?);
+                }
+                _ => return Err(format!("Invalid binding option: \"{}\"", option)),
+            }
+        }
+        Ok(binding)
+    }
+
+    fn is_default(&self) -> bool {
+        self.source_address.is_none() && self.interface.is_none() && self.mark.is_none()
+    }
+
+    /// Open a non-blocking TCP connection to `address`.
+    pub fn connect_tcp(&self, address: &SocketAddr) -> io::Result<TcpStream> {
+        if self.is_default() {
+            return TcpStream::connect(address);
+        }
+        let socket = Socket::new(Domain::for_address(*address), Type::STREAM, None)?;
+        self.apply(&socket)?;
+        TcpStream::connect_stream(socket.into(), address)
+    }
+
+    /// Create a non-blocking UDP socket, bound to the source address if any.
+    pub fn bind_udp(&self) -> io::Result<UdpSocket> {
+        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
+        if self.is_default() {
+            return UdpSocket::bind(&autobind_addr);
+        }
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        self.apply(&socket)?;
+        if self.source_address.is_none() {
+            socket.bind(&autobind_addr.into())?;
+        }
+        UdpSocket::from_socket(socket.into())
+    }
+
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(ref interface) = self.interface {
+            Self::bind_device(socket, interface)?;
+        }
+        if let Some(mark) = self.mark {
+            Self::set_mark(socket, mark)?;
+        }
+        if let Some(source_address) = self.source_address {
+            // bind to any port
+            let address = SocketAddr::new(IpAddr::V4(source_address), 0);
+            socket.bind(&address.into())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+        socket.bind_device(Some(interface.as_bytes()))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Binding to an interface is not supported on this platform",
+        ))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+        socket.set_mark(mark)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Socket marks are not supported on this platform",
+        ))
+    }
+}
+
+impl fmt::Display for SocketBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut options = Vec::new();
+        if let Some(source_address) = self.source_address {
+            options.push(format!("src:{}", source_address));
+        }
+        if let Some(ref interface) = self.interface {
+            options.push(format!("dev:{}", interface));
+        }
+        if let Some(mark) = self.mark {
+            options.push(format!("mark:{:#x}", mark));
+        }
+        if options.is_empty() {
+            write!(f, "default")
+        } else {
+            write!(f, "{}", options.join(","))
+        }
+    }
+}
+
+impl SocketBindingPolicy {
+    pub fn new(default: SocketBinding) -> Self {
+        Self {
+            default,
+            per_device: HashMap::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.find('=') {
+                Some(index) => {
+                    let serial = device_tunnels::parse_serial(&entry[..index])?;
+                    let binding = SocketBinding::parse(entry[index + 1..].trim())?;
+                    policy.set_device_binding(serial, binding);
+                }
+                None => policy.default = SocketBinding::parse(entry)?,
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn set_device_binding(&mut self, serial: String, binding: SocketBinding) {
+        self.per_device.insert(serial, binding);
+    }
+
+    /// The binding of the device `device`, or the default one if it is not identified.
+    pub fn binding(&self, device: Option<&str>) -> &SocketBinding {
+        device
+            .and_then(|serial| self.per_device.get(serial))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl fmt::Display for SocketBindingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        let mut serials: Vec<&String> = self.per_device.keys().collect();
+        serials.sort();
+        for serial in serials {
+            write!(f, ";{}={}", serial, self.per_device[serial])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_binding() {
+        let binding = SocketBinding::parse("src:192.168.1.10,dev:wlan0,mark:0x64").unwrap();
+        assert_eq!(Some(Ipv4Addr::new(192, 168, 1, 10)), binding.source_address);
+        assert_eq!(Some("wlan0"), binding.interface.as_deref());
+        assert_eq!(Some(100), binding.mark);
+        assert_eq!("src:192.168.1.10,dev:wlan0,mark:0x64", binding.to_string());
+        assert_eq!(Some(7), SocketBinding::parse("mark:7").unwrap().mark);
+    }
+
+    #[test]
+    fn parse_invalid_binding() {
+        assert!(SocketBinding::parse("192.168.1.10").is_err());
+        assert!(SocketBinding::parse("src:wlan0").is_err());
+        assert!(SocketBinding::parse("dev:").is_err());
+        assert!(SocketBinding::parse("mark:0xZ").is_err());
+        assert!(SocketBinding::parse("port:80").is_err());
+    }
+
+    #[test]
+    fn per_device_binding() {
+        let policy = SocketBindingPolicy::parse("dev:eth0; 192.168.1.2:5555=dev:wlan0").unwrap();
+        assert_eq!(Some("eth0"), policy.binding(None).interface.as_deref());
+        assert_eq!(
+            Some("eth0"),
+            policy.binding(Some("emulator-5554")).interface.as_deref()
+        );
+        assert_eq!(
+            Some("wlan0"),
+            policy
+                .binding(Some("192.168.1.2:5555"))
+                .interface
+                .as_deref()
+        );
+        assert_eq!("dev:eth0;192.168.1.2:5555=dev:wlan0", policy.to_string());
+        assert!(SocketBindingPolicy::parse("=dev:eth0").is_err());
+        assert!(SocketBindingPolicy::parse("x=dev:").is_err());
+    }
+
+    #[test]
+    fn bind_udp_to_source_address() {
+        let binding = SocketBinding::parse("src:127.0.0.1").unwrap();
+        let socket = binding.bind_udp().unwrap();
+        assert_eq!(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            socket.local_addr().unwrap().ip()
+        );
+    }
+}
@@ -0,0 +1,497 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use super::datagram::{DatagramReceiver, DatagramSender, MAX_DATAGRAM_LENGTH};
+use super::proxy::Credentials;
+
+// See RFC1928: <https://tools.ietf.org/html/rfc1928>
+// and RFC1929: <https://tools.ietf.org/html/rfc1929>
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN_NAME: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Length of the header of a UDP datagram relayed with an IPv4 destination.
+pub const UDP_HEADER_LENGTH: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Socks5Command {
+    Connect,
+    UdpAssociate,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    MethodSelection,
+    Authentication,
+    // the reply length depends on its address type, read the first 5 bytes to know it
+    ReplyHead,
+    ReplyTail,
+    Done,
+}
+
+/// Client side of a SOCKS5 negotiation, driven by non-blocking reads and writes.
+///
+/// It never reads more bytes than the negotiation requires, so that the data following the reply
+/// are left untouched in the socket.
+pub struct Socks5Handshake {
+    command: Socks5Command,
+    destination: SocketAddrV4,
+    credentials: Option<Credentials>,
+    state: State,
+    output: Vec<u8>,
+    input: Vec<u8>,
+    expected: usize,
+    bound_address: Option<SocketAddrV4>,
+}
+
+impl Socks5Handshake {
+    pub fn new(
+        command: Socks5Command,
+        destination: SocketAddrV4,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        let output = if credentials.is_some() {
+            vec![VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]
+        } else {
+            vec![VERSION, 1, METHOD_NO_AUTH]
+        };
+        Self {
+            command,
+            destination,
+            credentials,
+            state: State::MethodSelection,
+            output,
+            input: Vec::new(),
+            expected: 2,
+            bound_address: None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// The address (`BND.ADDR` and `BND.PORT`) returned by the proxy, once the handshake is done.
+    pub fn bound_address(&self) -> Option<SocketAddrV4> {
+        self.bound_address
+    }
+
+    pub fn write_to<W: io::Write>(&mut self, destination: &mut W) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match destination.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(w) => {
+                    self.output.drain(..w);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and handle the replies from the proxy.
+    ///
+    /// Return `Ok(true)` once the handshake is complete.
+    pub fn read_from<R: io::Read>(&mut self, source: &mut R) -> io::Result<bool> {
+        // a new request may not be sent before the previous one is fully written
+        while !self.is_done() && !self.wants_write() {
+            let start = self.input.len();
+            self.input.resize(self.expected, 0);
+            match source.read(&mut self.input[start..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "SOCKS5 proxy closed the connection",
+                    ));
+                }
+                Ok(r) => {
+                    self.input.truncate(start + r);
+                    if self.input.len() == self.expected {
+                        self.handle_reply()?;
+                    }
+                }
+                Err(err) => {
+                    self.input.truncate(start);
+                    if err.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(self.is_done())
+    }
+
+    fn expect(&mut self, state: State, length: usize) {
+        self.state = state;
+        self.input.clear();
+        self.expected = length;
+    }
+
+    fn handle_reply(&mut self) -> io::Result<()> {
+        match self.state {
+            State::MethodSelection => {
+                if self.input[0] != VERSION {
+                    return Err(io::Error::other("Invalid SOCKS5 version"));
+                }
+                match self.input[1] {
+                    METHOD_NO_AUTH => self.send_request(),
+                    METHOD_USERNAME_PASSWORD if self.credentials.is_some() => {
+                        self.send_authentication()?
+                    }
+                    METHOD_NOT_ACCEPTABLE => {
+                        return Err(io::Error::other(
+                            "SOCKS5 proxy accepts none of the authentication methods",
+                        ));
+                    }
+                    method => {
+                        return Err(io::Error::other(format!(
+                            "Unexpected SOCKS5 authentication method: {}",
+                            method
+                        )));
+                    }
+                }
+            }
+            State::Authentication => {
+                if self.input[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 authentication failed",
+                    ));
+                }
+                self.send_request();
+            }
+            State::ReplyHead => {
+                if self.input[0] != VERSION {
+                    return Err(io::Error::other("Invalid SOCKS5 version"));
+                }
+                let status = self.input[1];
+                if status != 0 {
+                    return Err(io::Error::other(format!(
+                        "SOCKS5 request failed: {}",
+                        reply_message(status)
+                    )));
+                }
+                // 4 bytes before the address, 2 bytes for the port
+                let length = match self.input[3] {
+                    ATYP_IPV4 => 4 + 4 + 2,
+                    ATYP_DOMAIN_NAME => 4 + 1 + self.input[4] as usize + 2,
+                    ATYP_IPV6 => 4 + 16 + 2,
+                    atyp => {
+                        return Err(io::Error::other(format!(
+                            "Invalid SOCKS5 address type: {}",
+                            atyp
+                        )));
+                    }
+                };
+                self.state = State::ReplyTail;
+                self.expected = length;
+            }
+            State::ReplyTail => {
+                if self.input[3] == ATYP_IPV4 {
+                    let ip =
+                        Ipv4Addr::new(self.input[4], self.input[5], self.input[6], self.input[7]);
+                    let port = BigEndian::read_u16(&self.input[8..10]);
+                    self.bound_address = Some(SocketAddrV4::new(ip, port));
+                }
+                self.expect(State::Done, 0);
+            }
+            State::Done => unreachable!("Handshake already done"),
+        }
+        Ok(())
+    }
+
+    fn send_authentication(&mut self) -> io::Result<()> {
+        let credentials = self.credentials.as_ref().unwrap();
+        let username = credentials.username().as_bytes();
+        let password = credentials.password().as_bytes();
+        if username.len() > 255 || password.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS5 username and password must not exceed 255 bytes",
+            ));
+        }
+        self.output.push(AUTH_VERSION);
+        self.output.push(username.len() as u8);
+        self.output.extend_from_slice(username);
+        self.output.push(password.len() as u8);
+        self.output.extend_from_slice(password);
+        self.expect(State::Authentication, 2);
+        Ok(())
+    }
+
+    fn send_request(&mut self) {
+        let command = match self.command {
+            Socks5Command::Connect => CMD_CONNECT,
+            Socks5Command::UdpAssociate => CMD_UDP_ASSOCIATE,
+        };
+        self.output.extend_from_slice(&[VERSION, command, 0]);
+        write_address(&mut self.output, &self.destination);
+        self.expect(State::ReplyHead, 5);
+    }
+}
+
+fn write_address(output: &mut Vec<u8>, address: &SocketAddrV4) {
+    let mut port = [0u8; 2];
+    BigEndian::write_u16(&mut port, address.port());
+    output.push(ATYP_IPV4);
+    output.extend_from_slice(&address.ip().octets());
+    output.extend_from_slice(&port);
+}
+
+fn reply_message(status: u8) -> &'static str {
+    match status {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Send datagrams through a SOCKS5 UDP relay, by prepending the UDP request header.
+pub struct Socks5UdpSender<'a, S: DatagramSender> {
+    sender: &'a mut S,
+    destination: SocketAddrV4,
+    buf: &'a mut [u8],
+}
+
+impl<'a, S: DatagramSender> Socks5UdpSender<'a, S> {
+    pub fn new(sender: &'a mut S, destination: SocketAddrV4, buf: &'a mut [u8]) -> Self {
+        assert!(buf.len() >= UDP_HEADER_LENGTH + MAX_DATAGRAM_LENGTH);
+        Self {
+            sender,
+            destination,
+            buf,
+        }
+    }
+}
+
+impl<'a, S: DatagramSender> DatagramSender for Socks5UdpSender<'a, S> {
+    fn send(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut header = Vec::with_capacity(UDP_HEADER_LENGTH);
+        // RSV (2 bytes) and FRAG
+        header.extend_from_slice(&[0, 0, 0]);
+        write_address(&mut header, &self.destination);
+        let length = UDP_HEADER_LENGTH + data.len();
+        self.buf[..UDP_HEADER_LENGTH].copy_from_slice(&header);
+        self.buf[UDP_HEADER_LENGTH..length].copy_from_slice(data);
+        let w = self.sender.send(&self.buf[..length])?;
+        // report the number of payload bytes written
+        Ok(w.saturating_sub(UDP_HEADER_LENGTH))
+    }
+}
+
+/// Receive datagrams from a SOCKS5 UDP relay, by stripping the UDP request header.
+pub struct Socks5UdpReceiver<'a, R: DatagramReceiver> {
+    receiver: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<'a, R: DatagramReceiver> Socks5UdpReceiver<'a, R> {
+    pub fn new(receiver: &'a mut R, buf: &'a mut [u8]) -> Self {
+        assert!(buf.len() >= UDP_HEADER_LENGTH + MAX_DATAGRAM_LENGTH);
+        Self { receiver, buf }
+    }
+}
+
+impl<'a, R: DatagramReceiver> DatagramReceiver for Socks5UdpReceiver<'a, R> {
+    fn recv(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let r = self.receiver.recv(self.buf)?;
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if r < 4 {
+            return Err(invalid("SOCKS5 UDP datagram too short"));
+        }
+        if self.buf[2] != 0 {
+            return Err(invalid("SOCKS5 UDP fragmentation not supported"));
+        }
+        let header_length = match self.buf[3] {
+            ATYP_IPV4 => 4 + 4 + 2,
+            ATYP_DOMAIN_NAME if r > 4 => 4 + 1 + self.buf[4] as usize + 2,
+            ATYP_IPV6 => 4 + 16 + 2,
+            _ => return Err(invalid("Invalid SOCKS5 UDP datagram header")),
+        };
+        if r < header_length {
+            return Err(invalid("SOCKS5 UDP datagram too short"));
+        }
+        let length = r - header_length;
+        if length > data.len() {
+            return Err(invalid("SOCKS5 UDP datagram too large"));
+        }
+        data[..length].copy_from_slice(&self.buf[header_length..r]);
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::datagram::tests::MockDatagramSocket;
+    use std::io::Cursor;
+
+    fn destination() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 0x1F90)
+    }
+
+    // non-blocking source having received only part of the data
+    struct PartialSource(Cursor<Vec<u8>>);
+
+    impl io::Read for PartialSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                r => Ok(r),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_without_authentication() {
+        let mut handshake = Socks5Handshake::new(Socks5Command::Connect, destination(), None);
+
+        let mut output = Vec::new();
+        handshake.write_to(&mut output).unwrap();
+        assert_eq!(vec![5, 1, 0], output);
+
+        let mut input = Cursor::new(vec![5, 0]);
+        assert!(!handshake.read_from(&mut input).unwrap());
+
+        output.clear();
+        handshake.write_to(&mut output).unwrap();
+        assert_eq!(vec![5, 1, 0, 1, 1, 2, 3, 4, 0x1F, 0x90], output);
+
+        // the data following the reply must not be consumed
+        let mut input = Cursor::new(vec![5, 0, 0, 1, 10, 0, 0, 1, 0x04, 0x38, 42]);
+        assert!(handshake.read_from(&mut input).unwrap());
+        assert_eq!(10, input.position());
+        assert_eq!(
+            "10.0.0.1:1080",
+            handshake.bound_address().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn connect_with_authentication() {
+        let credentials = Credentials::new("user", "pw");
+        let mut handshake =
+            Socks5Handshake::new(Socks5Command::Connect, destination(), Some(credentials));
+
+        let mut output = Vec::new();
+        handshake.write_to(&mut output).unwrap();
+        assert_eq!(vec![5, 2, 0, 2], output);
+
+        let mut input = Cursor::new(vec![5, 2]);
+        assert!(!handshake.read_from(&mut input).unwrap());
+
+        output.clear();
+        handshake.write_to(&mut output).unwrap();
+        assert_eq!(vec![1, 4, b'u', b's', b'e', b'r', 2, b'p', b'w'], output);
+
+        let mut input = Cursor::new(vec![1, 0]);
+        assert!(!handshake.read_from(&mut input).unwrap());
+
+        output.clear();
+        handshake.write_to(&mut output).unwrap();
+        assert_eq!(CMD_CONNECT, output[1]);
+
+        let mut input = Cursor::new(vec![5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(handshake.read_from(&mut input).unwrap());
+    }
+
+    #[test]
+    fn authentication_failure() {
+        let credentials = Credentials::new("user", "bad");
+        let mut handshake =
+            Socks5Handshake::new(Socks5Command::Connect, destination(), Some(credentials));
+        handshake.write_to(&mut Vec::new()).unwrap();
+        handshake.read_from(&mut Cursor::new(vec![5, 2])).unwrap();
+        handshake.write_to(&mut Vec::new()).unwrap();
+        let err = handshake
+            .read_from(&mut Cursor::new(vec![1, 1]))
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    }
+
+    #[test]
+    fn request_refused() {
+        let mut handshake = Socks5Handshake::new(Socks5Command::Connect, destination(), None);
+        handshake.write_to(&mut Vec::new()).unwrap();
+        handshake.read_from(&mut Cursor::new(vec![5, 0])).unwrap();
+        handshake.write_to(&mut Vec::new()).unwrap();
+        let result = handshake.read_from(&mut Cursor::new(vec![5, 5, 0, 1, 0]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reply_split_across_reads() {
+        let mut handshake = Socks5Handshake::new(Socks5Command::UdpAssociate, destination(), None);
+        handshake.write_to(&mut Vec::new()).unwrap();
+        handshake.read_from(&mut Cursor::new(vec![5, 0])).unwrap();
+        handshake.write_to(&mut Vec::new()).unwrap();
+        assert!(!handshake
+            .read_from(&mut PartialSource(Cursor::new(vec![5, 0, 0])))
+            .unwrap());
+        assert!(!handshake
+            .read_from(&mut PartialSource(Cursor::new(vec![1, 127, 0])))
+            .unwrap());
+        assert!(handshake
+            .read_from(&mut Cursor::new(vec![0, 1, 0x13, 0x88]))
+            .unwrap());
+        assert_eq!(
+            "127.0.0.1:5000",
+            handshake.bound_address().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn udp_header_roundtrip() {
+        let mut buf = vec![0u8; UDP_HEADER_LENGTH + MAX_DATAGRAM_LENGTH];
+        let mut socket = MockDatagramSocket::new();
+        {
+            let mut sender = Socks5UdpSender::new(&mut socket, destination(), &mut buf);
+            assert_eq!(3, sender.send(&[7, 8, 9]).unwrap());
+        }
+        assert_eq!(
+            &[0, 0, 0, 1, 1, 2, 3, 4, 0x1F, 0x90, 7, 8, 9],
+            socket.data()
+        );
+
+        let mut data = [0u8; 16];
+        let mut receiver = Socks5UdpReceiver::new(&mut socket, &mut buf);
+        let r = receiver.recv(&mut data).unwrap();
+        assert_eq!(&[7, 8, 9], &data[..r]);
+    }
+}
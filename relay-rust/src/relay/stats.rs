@@ -0,0 +1,228 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Write;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use super::buffer_pool::MemoryUsage;
+use super::connection::ConnectionId;
+use super::flow_log;
+use super::traffic::TrafficCounters;
+
+/// State of a connection, at the time of the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    /// The destination actually contacted, after NAT.
+    pub upstream: SocketAddrV4,
+    /// The TCP state (like `established`), or `open` for UDP (`associating` while the SOCKS5
+    /// association is negotiated).
+    pub state: &'static str,
+    pub age: Duration,
+    pub counters: TrafficCounters,
+    /// Bytes received from the device, not sent to the network yet.
+    pub uplink_buffered: usize,
+}
+
+/// State of a client and of its connections, at the time of the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientStats {
+    pub id: u32,
+    pub worker: usize,
+    pub age: Duration,
+    /// Including the previous traffic of the device, if it is identified.
+    pub counters: TrafficCounters,
+    /// Bytes to be sent to the device.
+    pub downlink_buffered: usize,
+    /// Number of packets which could not be written to the client buffer (dropped or postponed).
+    pub buffer_full_events: u64,
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// State of the whole relay, collected from all the workers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayStats {
+    pub uptime: Duration,
+    pub workers: usize,
+    /// Connections open by all the clients (as counted by the connection limits).
+    pub open_connections: usize,
+    pub memory: MemoryUsage,
+    pub clients: Vec<ClientStats>,
+}
+
+impl ConnectionStats {
+    fn write_json(&self, json: &mut String) {
+        // all the values are numbers, addresses or keywords, nothing to escape
+        write!(
+            json,
+            "{{\"protocol\":\"{}\",\"source\":\"{}\",\"destination\":\"{}\",\"upstream\":\"{}\",\
+             \"state\":\"{}\",\"age\":{},",
+            flow_log::protocol_name(self.id.protocol()),
+            self.id.source(),
+            self.id.destination(),
+            self.upstream,
+            self.state,
+            self.age.as_secs()
+        )
+        .unwrap();
+        write_counters(json, &self.counters);
+        write!(json, ",\"uplink_buffered\":{}}}", self.uplink_buffered).unwrap();
+    }
+}
+
+impl ClientStats {
+    fn write_json(&self, json: &mut String) {
+        write!(
+            json,
+            "{{\"id\":{},\"worker\":{},\"age\":{},",
+            self.id,
+            self.worker,
+            self.age.as_secs()
+        )
+        .unwrap();
+        write_counters(json, &self.counters);
+        write!(
+            json,
+            ",\"downlink_buffered\":{},\"buffer_full_events\":{},\"connections\":[",
+            self.downlink_buffered, self.buffer_full_events
+        )
+        .unwrap();
+        for (i, connection) in self.connections.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            connection.write_json(json);
+        }
+        json.push_str("]}");
+    }
+}
+
+impl RelayStats {
+    /// The traffic of the connected clients.
+    pub fn total_counters(&self) -> TrafficCounters {
+        self.clients
+            .iter()
+            .fold(TrafficCounters::default(), |total, client| {
+                total + client.counters
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"uptime\":{},\"workers\":{},\"client_count\":{},\"connection_count\":{},\
+             \"memory_used\":{},\"memory_peak\":{},\"memory_pooled\":{},\"memory_limit\":",
+            self.uptime.as_secs(),
+            self.workers,
+            self.clients.len(),
+            self.open_connections,
+            self.memory.used,
+            self.memory.peak,
+            self.memory.pooled
+        )
+        .unwrap();
+        match self.memory.limit {
+            Some(limit) => write!(json, "{}", limit).unwrap(),
+            None => json.push_str("null"),
+        }
+        json.push(',');
+        write_counters(&mut json, &self.total_counters());
+        json.push_str(",\"clients\":[");
+        for (i, client) in self.clients.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            client.write_json(&mut json);
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn write_counters(json: &mut String, counters: &TrafficCounters) {
+    write!(
+        json,
+        "\"uplink_bytes\":{},\"uplink_packets\":{},\"downlink_bytes\":{},\"downlink_packets\":{}",
+        counters.uplink_bytes(),
+        counters.uplink_packets(),
+        counters.downlink_bytes(),
+        counters.downlink_packets()
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_header::Protocol;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn format_stats() {
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(600);
+        counters.record_downlink(1500);
+        let connection = ConnectionStats {
+            id: ConnectionId::new(
+                Protocol::Tcp,
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 41234),
+                SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443),
+            ),
+            upstream: SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443),
+            state: "established",
+            age: Duration::from_millis(2500),
+            counters,
+            uplink_buffered: 100,
+        };
+        let client = ClientStats {
+            id: 3,
+            worker: 1,
+            age: Duration::from_secs(60),
+            counters: counters + counters,
+            downlink_buffered: 0,
+            buffer_full_events: 5,
+            connections: vec![connection],
+        };
+        let stats = RelayStats {
+            uptime: Duration::from_secs(3600),
+            workers: 2,
+            open_connections: 1,
+            memory: MemoryUsage {
+                used: 4096,
+                pooled: 0,
+                peak: 8192,
+                limit: None,
+            },
+            clients: vec![client],
+        };
+        assert_eq!(1200, stats.total_counters().uplink_bytes());
+        assert_eq!(
+            "{\"uptime\":3600,\"workers\":2,\"client_count\":1,\"connection_count\":1,\
+             \"memory_used\":4096,\"memory_peak\":8192,\"memory_pooled\":0,\"memory_limit\":null,\
+             \"uplink_bytes\":1200,\"uplink_packets\":2,\"downlink_bytes\":3000,\
+             \"downlink_packets\":2,\"clients\":[{\"id\":3,\"worker\":1,\"age\":60,\
+             \"uplink_bytes\":1200,\"uplink_packets\":2,\"downlink_bytes\":3000,\
+             \"downlink_packets\":2,\"downlink_buffered\":0,\"buffer_full_events\":5,\"connections\":[{\"protocol\":\"tcp\",\
+             \"source\":\"10.0.0.2:41234\",\"destination\":\"1.2.3.4:443\",\
+             \"upstream\":\"1.2.3.4:443\",\"state\":\"established\",\"age\":2,\
+             \"uplink_bytes\":600,\"uplink_packets\":1,\"downlink_bytes\":1500,\
+             \"downlink_packets\":1,\"uplink_buffered\":100}]}]}",
+            stats.to_json()
+        );
+    }
+}
@@ -14,21 +14,36 @@
  * limitations under the License.
  */
 
+use std::cmp;
 use std::io;
 
+use super::buffer_pool::{self, PooledBuffer};
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+
+/// The capacity a buffer may always use, even if the memory budget is exhausted.
+///
+/// It must be enough for one packet on top of the headroom reserved by the client for
+/// interactive traffic, so that any connection may make progress once the buffer is empty.
+const GUARANTEED_CAPACITY: usize = 2 * MAX_PACKET_LENGTH;
+
 /// Circular buffer to store a stream. Read/write boundaries are not preserved.
+///
+/// The storage is borrowed from the buffer pool lazily, grows as needed up to `capacity`, and is
+/// given back as soon as the buffer is empty.
 pub struct StreamBuffer {
-    buf: Box<[u8]>,
+    buf: PooledBuffer,
     head: usize,
     tail: usize,
+    capacity: usize,
 }
 
 impl StreamBuffer {
     pub fn new(capacity: usize) -> Self {
         Self {
-            buf: vec![0; capacity + 1].into_boxed_slice(),
+            buf: PooledBuffer::default(),
             head: 0,
             tail: 0,
+            capacity,
         }
     }
 
@@ -44,12 +59,21 @@ impl StreamBuffer {
         }
     }
 
-    pub fn capacity(&self) -> usize {
-        self.buf.len() - 1
-    }
-
+    /// The space available for `read_from()`.
+    ///
+    /// When the memory budget is exhausted, the buffer does not grow beyond its current storage
+    /// (or `GUARANTEED_CAPACITY`), so that the callers apply backpressure.
     pub fn remaining(&self) -> usize {
-        self.capacity() - self.size()
+        let usable_capacity = if buffer_pool::is_exhausted() {
+            let storage_capacity = self.buf.len().saturating_sub(1);
+            cmp::min(
+                self.capacity,
+                cmp::max(storage_capacity, GUARANTEED_CAPACITY),
+            )
+        } else {
+            self.capacity
+        };
+        usable_capacity.saturating_sub(self.size())
     }
 
     pub fn write_to<W: io::Write>(&mut self, destination: &mut W) -> io::Result<usize> {
@@ -73,11 +97,17 @@ impl StreamBuffer {
         }
     }
 
+    /// Store `source`, which the caller checked against `remaining()`.
+    ///
+    /// The memory budget is shared with the other workers, so it may get exhausted between the
+    /// check and the write: the write is then accepted anyway (the budget is a soft limit), only
+    /// the capacity of the buffer is enforced.
     pub fn read_from(&mut self, source: &[u8]) {
         assert!(
-            source.len() <= self.remaining(),
+            self.size() + source.len() <= self.capacity,
             "StreamBuffer is full, check remaining() before calling read_from()"
         );
+        self.reserve(self.size() + source.len());
         let source_len = source.len();
         let buf_len = self.buf.len();
         if source_len <= buf_len - self.head {
@@ -98,6 +128,28 @@ impl StreamBuffer {
         self.head = (self.head + source_len) % buf_len;
     }
 
+    /// Grow the storage (if necessary) so that it can hold `size` bytes.
+    fn reserve(&mut self, size: usize) {
+        // 1 extra byte to distinguish empty vs full
+        if size < self.buf.len() {
+            return;
+        }
+        let block_size = buffer_pool::block_size(size + 1, self.capacity + 1);
+        let mut buf = PooledBuffer::allocate(block_size);
+        // move the data to the front of the new storage
+        let len = self.size();
+        if self.head >= self.tail {
+            buf[..len].copy_from_slice(&self.buf[self.tail..self.head]);
+        } else {
+            let first = self.buf.len() - self.tail;
+            buf[..first].copy_from_slice(&self.buf[self.tail..]);
+            buf[first..len].copy_from_slice(&self.buf[..self.head]);
+        }
+        self.buf = buf;
+        self.tail = 0;
+        self.head = len;
+    }
+
     /// To avoid unnecessary copies, StreamBuffer writes at most until the "end" of the circular
     /// buffer, which is suboptimal (it could have written more data if they have been contiguous).
     ///
@@ -106,10 +158,13 @@ impl StreamBuffer {
     ///
     /// This is especially useful when the StreamBuffer is used to read/write one packet at a time,
     /// so the "end" of the buffer is guaranteed to never be reached.
+    ///
+    /// The storage of an empty buffer is also given back to the pool.
     fn optimize(&mut self) {
         if self.is_empty() {
             self.head = 0;
             self.tail = 0;
+            self.buf = PooledBuffer::default();
         }
     }
 }
@@ -175,6 +230,53 @@ mod tests {
         assert_eq!([0, 1, 2, 3, 4, 5, 0, 1, 2], &result[..]);
     }
 
+    #[test]
+    fn grow_lazily() {
+        let mut stream_buffer = StreamBuffer::new(64 * 1024);
+        assert_eq!(0, stream_buffer.buf.len());
+
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        stream_buffer.read_from(&data);
+        assert_eq!(buffer_pool::MIN_BLOCK_SIZE, stream_buffer.buf.len());
+
+        // wrap around the end of the storage, then grow
+        read_some(&mut stream_buffer, 2000);
+        stream_buffer.read_from(&data);
+        stream_buffer.read_from(&data);
+        assert_eq!(2 * buffer_pool::MIN_BLOCK_SIZE, stream_buffer.buf.len());
+
+        let result = read(&mut stream_buffer);
+        let mut expected = data[2000..].to_vec();
+        expected.extend_from_slice(&data);
+        expected.extend_from_slice(&data);
+        assert_eq!(expected, result);
+
+        // the storage is given back once empty
+        assert!(stream_buffer.is_empty());
+        assert_eq!(0, stream_buffer.buf.len());
+    }
+
+    #[test]
+    fn budget_exhausted_after_check() {
+        let budget = buffer_pool::MemoryBudget::new(Some(64 * 1024));
+        buffer_pool::install(budget.clone());
+
+        let mut stream_buffer = StreamBuffer::new(256 * 1024);
+        let data = vec![42u8; 200 * 1024];
+        assert!(data.len() <= stream_buffer.remaining());
+
+        // another buffer exhausts the budget between the check and the write
+        let other = PooledBuffer::allocate(64 * 1024);
+        assert!(buffer_pool::is_exhausted());
+        assert!(data.len() > stream_buffer.remaining());
+
+        stream_buffer.read_from(&data);
+        assert_eq!(data, read(&mut stream_buffer));
+
+        drop(other);
+        buffer_pool::install(buffer_pool::MemoryBudget::default());
+    }
+
     fn read_some(stream_buffer: &mut StreamBuffer, bytes: usize) -> Vec<u8> {
         let mut vec = vec![0u8; bytes];
         {
@@ -23,25 +23,36 @@ use std::cmp;
 use std::io;
 use std::num::Wrapping;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
 use super::connection::{Connection, ConnectionId};
+use super::flow_log::{CloseReason, Flow};
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::observer::DropReason;
+use super::packet_scheduler::TrafficClass;
 use super::packet_source::PacketSource;
 use super::packetizer::Packetizer;
+use super::proxy_handshake::ProxyHandshake;
 use super::selector::Selector;
+use super::stats::ConnectionStats;
 use super::stream_buffer::StreamBuffer;
 use super::tcp_header::{self, TcpHeader, TcpHeaderMut};
+use super::timer_wheel::TimerId;
 use super::transport_header::{TransportHeader, TransportHeaderMut};
+use super::upstream::Upstream;
 
 const TAG: &str = "TcpConnection";
 
 // same value as GnirehtetService.MTU in the client
 const MTU: u16 = 0x4000;
 // 20 bytes for IP headers, 20 bytes for TCP headers
-const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20 as u16;
+const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20;
+
+// do not let the client wait for the (long) system timeout of unreachable destinations
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct TcpConnection {
     self_weak: Weak<RefCell<TcpConnection>>,
@@ -53,8 +64,16 @@ pub struct TcpConnection {
     client_to_network: StreamBuffer,
     network_to_client: Packetizer,
     packet_for_client_length: Option<u16>,
+    // payload sent to the client so far, to tell short flows from bulk transfers
+    sent_to_client: u64,
+    // error of a read performed while the client was borrowed, to be handled on the next read
+    read_error: Option<io::Error>,
     closed: bool,
     tcb: Tcb,
+    // negotiation with the upstream proxy, if any, to be completed before connecting the client
+    handshake: Option<ProxyHandshake>,
+    connect_timer: Option<TimerId>,
+    flow: Flow,
 }
 
 // Transport Control Block
@@ -88,6 +107,20 @@ impl TcpState {
         self != &TcpState::Init && self != &TcpState::SynSent && self != &TcpState::SynReceived
     }
 
+    fn name(&self) -> &'static str {
+        match self {
+            TcpState::Init => "init",
+            TcpState::SynSent => "syn-sent",
+            TcpState::SynReceived => "syn-received",
+            TcpState::Established => "established",
+            TcpState::CloseWait => "close-wait",
+            TcpState::LastAck => "last-ack",
+            TcpState::Closing => "closing",
+            TcpState::FinWait1 => "fin-wait-1",
+            TcpState::FinWait2 => "fin-wait-2",
+        }
+    }
+
     fn is_closed(&self) -> bool {
         self == &TcpState::FinWait1
             || self == &TcpState::FinWait2
@@ -136,11 +169,25 @@ impl TcpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        upstream: &Upstream,
+        mut flow: Flow,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
-        cx_info!(target: TAG, id, "Open");
-        let stream = Self::create_stream(&id)?;
+        let packet_length = usize::from(ipv4_header.total_length());
+        let destination = upstream.destination();
+        if destination == id.destination() {
+            cx_info!(target: TAG, id, "Open");
+        } else {
+            cx_info!(target: TAG, id, "Open (rewritten to {})", destination);
+        }
+        let (stream, handshake) = match Self::create_stream(&id, upstream) {
+            Ok(result) => result,
+            Err(err) => {
+                flow.open_failed(packet_length, &err);
+                return Err(err);
+            }
+        };
 
         let tcp_header = Self::tcp_header_of_transport(transport_header);
 
@@ -174,8 +221,13 @@ impl TcpConnection {
             client_to_network: StreamBuffer::new(4 * MAX_PACKET_LENGTH),
             network_to_client: packetizer,
             packet_for_client_length: None,
+            sent_to_client: 0,
+            read_error: None,
             closed: false,
             tcb: Tcb::new(),
+            handshake,
+            connect_timer: None,
+            flow,
         }));
 
         {
@@ -189,14 +241,40 @@ impl TcpConnection {
             let handler =
                 move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
             let token =
-                selector.register(&self_ref.stream, handler, interests, PollOpt::level())?;
+                match selector.register(&self_ref.stream, handler, interests, PollOpt::level()) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        self_ref.flow.open_failed(packet_length, &err);
+                        return Err(err);
+                    }
+                };
             self_ref.token = token;
         }
         Ok(rc)
     }
 
-    fn create_stream(id: &ConnectionId) -> io::Result<TcpStream> {
-        TcpStream::connect(&id.rewritten_destination().into())
+    fn create_stream(
+        id: &ConnectionId,
+        upstream: &Upstream,
+    ) -> io::Result<(TcpStream, Option<ProxyHandshake>)> {
+        if let Err(err) = upstream.check_access() {
+            cx_warn!(target: TAG, id, "Blocked: {}", err);
+            return Err(err);
+        }
+        match upstream.proxy() {
+            Some(proxy) => {
+                cx_debug!(target: TAG, id, "Connecting through {}", proxy);
+                let stream = upstream.binding().connect_tcp(&proxy.address())?;
+                let handshake = ProxyHandshake::connect(proxy, upstream.destination());
+                Ok((stream, Some(handshake)))
+            }
+            None => {
+                let stream = upstream
+                    .binding()
+                    .connect_tcp(&upstream.destination().into())?;
+                Ok((stream, None))
+            }
+        }
     }
 
     fn remove_from_router(&self) {
@@ -215,22 +293,31 @@ impl TcpConnection {
             }
             Err(_) => panic!("Unexpected unhandled error"),
         }
+        self.release_packet_buffer();
     }
+
+    // give the packet buffer back to the pool, unless it holds a pending packet
+    fn release_packet_buffer(&mut self) {
+        if self.packet_for_client_length.is_none() {
+            self.network_to_client.release();
+        }
+    }
+
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
         if !self.closed {
             let ready = event.readiness();
             if ready.is_readable() || ready.is_writable() {
-                if ready.is_writable() {
-                    if self.tcb.state == TcpState::SynSent {
-                        // writable is first triggered when the stream is connected
-                        self.process_connect(selector);
-                    } else {
+                if self.tcb.state == TcpState::SynSent {
+                    // writable is first triggered when the stream is connected
+                    self.process_connect(selector, ready);
+                } else {
+                    if ready.is_writable() {
                         self.process_send(selector)?;
                     }
-                }
-                if !self.closed && ready.is_readable() {
-                    self.process_receive(selector)?;
+                    if !self.closed && ready.is_readable() {
+                        self.process_receive(selector)?;
+                    }
                 }
                 if !self.closed {
                     self.update_interests(selector);
@@ -238,7 +325,21 @@ impl TcpConnection {
             } else {
                 cx_debug!(target: TAG, self.id, "received ready = {:?}", ready);
                 // error or hup
-                self.close(selector);
+                if self.tcb.state == TcpState::SynSent {
+                    if self.handshake.is_some() {
+                        cx_error!(target: TAG, self.id, "Cannot connect to the proxy");
+                    } else {
+                        cx_error!(target: TAG, self.id, "Cannot connect");
+                    }
+                    // do not let the client wait for a SYN/ACK
+                    self.send_empty_packet_to_client(
+                        selector,
+                        tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+                    );
+                    self.close(selector, CloseReason::ConnectFailed);
+                } else {
+                    self.close(selector, CloseReason::Error);
+                }
             }
             if self.closed {
                 // on_ready is not called from the router, so the connection must remove itself
@@ -274,7 +375,7 @@ impl TcpConnection {
                         self.send_empty_packet_to_client(selector, tcp_header::FLAG_ACK);
                     }
                 } else {
-                    self.close(selector);
+                    self.close(selector, CloseReason::Error);
                 }
             }
             Err(err) => {
@@ -290,7 +391,7 @@ impl TcpConnection {
                     err
                 );
                 self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
-                self.close(selector);
+                self.close(selector, CloseReason::of_error(&err));
             }
         }
         Ok(())
@@ -302,6 +403,10 @@ impl TcpConnection {
             self.packet_for_client_length.is_none(),
             "A pending packet was not sent"
         );
+        if let Some(err) = self.read_error.take() {
+            self.read_failed(selector, &err);
+            return Ok(());
+        }
         let remaining_client_window = self.tcb.remaining_client_window();
         assert!(
             remaining_client_window > 0,
@@ -314,12 +419,13 @@ impl TcpConnection {
             &self.tcb,
             tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
         );
+        let traffic_class = self.traffic_class();
         match self
             .network_to_client
             .packetize_read(&mut self.stream, max_payload_length)
         {
             Ok(Some(ipv4_packet)) => {
-                match Self::send_to_client(&self.client, selector, &ipv4_packet) {
+                match Self::send_to_client(&self.client, selector, &ipv4_packet, traffic_class) {
                     Ok(_) => {
                         let len = ipv4_packet.payload().unwrap().len();
                         cx_debug!(
@@ -330,13 +436,15 @@ impl TcpConnection {
                             self.tcb.numbers()
                         );
                         self.tcb.sequence_number += Wrapping(len as u32);
+                        self.sent_to_client += len as u64;
+                        self.flow.record_downlink(ipv4_packet.raw().len());
                     }
                     Err(_) => {
                         // ask to the client to pull when its buffer is not full
                         let client_rc = self.client.upgrade().expect("Expected client not found");
                         let mut client = client_rc.borrow_mut();
                         let self_rc = self.self_weak.upgrade().unwrap();
-                        client.register_pending_packet_source(self_rc);
+                        client.register_pending_packet_source(self_rc, traffic_class);
                         self.packet_for_client_length = Some(ipv4_packet.length());
                     }
                 };
@@ -349,36 +457,145 @@ impl TcpConnection {
                     // rethrow
                     return Err(err);
                 }
-                cx_error!(
-                    target: TAG,
-                    self.id,
-                    "Cannot read: [{:?}] {}",
-                    err.kind(),
-                    err
-                );
-                self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
-                self.close(selector);
+                self.read_failed(selector, &err);
             }
         }
         Ok(())
     }
 
-    fn process_connect(&mut self, selector: &mut Selector) {
+    fn read_failed(&mut self, selector: &mut Selector, err: &io::Error) {
+        cx_error!(
+            target: TAG,
+            self.id,
+            "Cannot read: [{:?}] {}",
+            err.kind(),
+            err
+        );
+        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
+        self.close(selector, CloseReason::of_error(err));
+    }
+
+    /// Read the next packet for the client without sending it
+    ///
+    /// To be used if called by the client (so it is already borrowed): the packet is pulled by
+    /// the client, so that the connection remains backlogged in its scheduler. The end of stream
+    /// is detected again by the next read.
+    fn prefetch(&mut self) {
+        if self.closed || !self.may_read() {
+            return;
+        }
+        let max_payload_length =
+            Some(cmp::min(self.tcb.remaining_client_window(), MAX_PAYLOAD_LENGTH) as usize);
+        Self::update_headers(
+            &mut self.network_to_client,
+            &self.tcb,
+            tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+        );
+        match self
+            .network_to_client
+            .packetize_read(&mut self.stream, max_payload_length)
+        {
+            Ok(Some(ipv4_packet)) => self.packet_for_client_length = Some(ipv4_packet.length()),
+            Ok(None) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => self.read_error = Some(err),
+        }
+    }
+
+    fn process_connect(&mut self, selector: &mut Selector, ready: Ready) {
         assert_eq!(self.tcb.state, TcpState::SynSent);
+        // a failed connection is also reported as writable
+        let connect_error = match self.stream.take_error() {
+            Ok(err) => err,
+            Err(err) => Some(err),
+        };
+        if let Some(err) = connect_error {
+            cx_error!(target: TAG, self.id, "Cannot connect: {}", err);
+            // in SYN-SENT state, the client only accepts a RST acking its SYN
+            self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST | tcp_header::FLAG_ACK);
+            self.close(selector, CloseReason::ConnectFailed);
+            return;
+        }
+        if let Some(ref mut handshake) = self.handshake {
+            match Self::process_handshake(handshake, &mut self.stream, ready) {
+                Ok(false) => {
+                    // negotiation in progress
+                    return;
+                }
+                Ok(true) => {
+                    cx_debug!(target: TAG, self.id, "Connected through the proxy");
+                    self.handshake = None;
+                }
+                Err(err) => {
+                    cx_error!(target: TAG, self.id, "Proxy negotiation failed: {}", err);
+                    // in SYN-SENT state, the client only accepts a RST acking its SYN
+                    self.send_empty_packet_to_client(
+                        selector,
+                        tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+                    );
+                    self.close(selector, CloseReason::ConnectFailed);
+                    return;
+                }
+            }
+        }
+        self.cancel_connect_timeout(selector);
         self.tcb.state = TcpState::SynReceived;
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         self.send_empty_packet_to_client(selector, tcp_header::FLAG_SYN | tcp_header::FLAG_ACK);
         self.tcb.sequence_number += Wrapping(1); // SYN counts for 1 byte
     }
 
+    fn schedule_connect_timeout(&mut self, selector: &mut Selector) {
+        let weak = self.self_weak.clone();
+        let timer = selector.schedule(Instant::now() + CONNECT_TIMEOUT, move |selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_connect_timeout(selector);
+            }
+        });
+        self.connect_timer = Some(timer);
+    }
+
+    fn cancel_connect_timeout(&mut self, selector: &mut Selector) {
+        if let Some(timer) = self.connect_timer.take() {
+            selector.cancel(timer);
+        }
+    }
+
+    fn on_connect_timeout(&mut self, selector: &mut Selector) {
+        self.connect_timer = None;
+        if self.closed || self.tcb.state != TcpState::SynSent {
+            return;
+        }
+        cx_warn!(target: TAG, self.id, "Connection timed out");
+        // in SYN-SENT state, the client only accepts a RST acking its SYN
+        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST | tcp_header::FLAG_ACK);
+        self.close(selector, CloseReason::ConnectFailed);
+        self.remove_from_router();
+    }
+
+    fn process_handshake(
+        handshake: &mut ProxyHandshake,
+        stream: &mut TcpStream,
+        ready: Ready,
+    ) -> io::Result<bool> {
+        if ready.is_writable() && handshake.wants_write() {
+            handshake.write_to(stream)?;
+        }
+        if ready.is_readable() {
+            return handshake.read_from(stream);
+        }
+        Ok(false)
+    }
+
     fn send_to_client(
         client: &Weak<RefCell<Client>>,
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
+        class: TrafficClass,
     ) -> io::Result<()> {
         let client_rc = client.upgrade().expect("Expected client not found");
         let mut client = client_rc.borrow_mut();
-        client.send_to_client(selector, &ipv4_packet)
+        client.send_to_client(selector, ipv4_packet, class)
     }
 
     /// Borrow self.client and send empty packet to it
@@ -406,15 +623,20 @@ impl TcpConnection {
             &self.tcb,
             flags,
         );
-        if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+        match client_channel.send_to_client(selector, &ipv4_packet, TrafficClass::Interactive) {
+            Ok(_) => self.flow.record_downlink(ipv4_packet.raw().len()),
             // losing such an empty packet will not break the TCP connection
-            cx_warn!(
-                target: TAG,
-                self.id,
-                "Cannot send packet to client: {}",
-                err
-            );
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send packet to client: {}",
+                    err
+                );
+                self.flow.packet_dropped(DropReason::ClientBufferFull);
+            }
         }
+        self.release_packet_buffer();
     }
 
     fn eof(&mut self, selector: &mut Selector) {
@@ -509,7 +731,7 @@ impl TcpConnection {
         );
 
         if tcp_header.is_rst() {
-            self.close(selector);
+            self.close(selector, CloseReason::Reset);
             return;
         }
 
@@ -560,6 +782,7 @@ impl TcpConnection {
             self.tcb.client_window = tcp_header.window();
             self.tcb.state = TcpState::SynSent;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
+            self.schedule_connect_timeout(selector);
         } else {
             cx_warn!(
                 target: TAG,
@@ -572,7 +795,7 @@ impl TcpConnection {
             // make a RST in the window client
             self.tcb.sequence_number = Wrapping(tcp_header.acknowledgement_number());
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
-            self.close(selector);
+            self.close(selector, CloseReason::Reset);
         }
     }
 
@@ -592,7 +815,7 @@ impl TcpConnection {
         } else if their_sequence_number != self.tcb.syn_sequence_number {
             // duplicate SYN with different sequence number
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
-            self.close(selector);
+            self.close(selector, CloseReason::Reset);
         }
     }
 
@@ -637,7 +860,7 @@ impl TcpConnection {
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait2 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
-            self.close(selector);
+            self.close(selector, CloseReason::Fin);
         } else {
             cx_warn!(
                 target: TAG,
@@ -650,7 +873,7 @@ impl TcpConnection {
 
     fn handle_fin_ack(&mut self, selector: &mut Selector) {
         if self.tcb.state == TcpState::LastAck || self.tcb.state == TcpState::Closing {
-            self.close(selector);
+            self.close(selector, CloseReason::Fin);
         } else if self.tcb.state == TcpState::FinWait1 {
             self.tcb.state = TcpState::FinWait2;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
@@ -694,6 +917,7 @@ impl TcpConnection {
 
         if self.client_to_network.remaining() < payload.len() {
             cx_warn!(target: TAG, self.id, "Not enough space, dropping packet");
+            self.flow.packet_dropped(DropReason::NetworkBufferFull);
             return;
         }
 
@@ -734,8 +958,11 @@ impl TcpConnection {
         assert!(!self.closed);
         let mut ready = Ready::empty();
         if self.tcb.state == TcpState::SynSent {
-            // waiting for connectable
-            ready = Ready::writable()
+            ready = match self.handshake {
+                Some(ref handshake) if !handshake.wants_write() => Ready::readable(),
+                // waiting for connectable, or for writing to the proxy
+                _ => Ready::writable(),
+            }
         } else {
             if self.may_read() {
                 ready |= Ready::readable()
@@ -754,6 +981,10 @@ impl TcpConnection {
         }
     }
 
+    fn traffic_class(&self) -> TrafficClass {
+        TrafficClass::of_flow(&self.id, self.sent_to_client)
+    }
+
     fn may_read(&self) -> bool {
         if !self.tcb.state.is_connected() || self.tcb.state.is_closed() {
             return false;
@@ -781,15 +1012,18 @@ impl Connection for TcpConnection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
+        self.flow.record_uplink(ipv4_packet.raw().len());
         self.handle_packet(selector, client_channel, ipv4_packet);
         if !self.closed {
             self.update_interests(selector);
         }
     }
 
-    fn close(&mut self, selector: &mut Selector) {
-        cx_info!(target: TAG, self.id, "Close");
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason) {
+        cx_info!(target: TAG, self.id, "Close ({})", reason);
         self.closed = true;
+        self.flow.finish(reason);
+        self.cancel_connect_timeout(selector);
         if let Err(err) = selector.deregister(&self.stream, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -803,18 +1037,38 @@ impl Connection for TcpConnection {
         // socket will be closed by RAII
     }
 
-    fn is_expired(&self) -> bool {
-        // no external timeout expiration
-        false
+    fn abort(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        self.reply_empty_packet_to_client(
+            selector,
+            client_channel,
+            tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+        );
+        self.close(selector, CloseReason::Aborted);
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn idle_since(&self) -> Option<Instant> {
+        // never evicted, the application would lose data
+        None
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            id: self.id.clone(),
+            upstream: self.flow.upstream(),
+            state: self.tcb.state.name(),
+            age: self.flow.age(),
+            counters: *self.flow.counters(),
+            uplink_buffered: self.client_to_network.size(),
+        }
+    }
 }
 
 impl PacketSource for TcpConnection {
-    fn get(&mut self) -> Option<Ipv4Packet> {
+    fn get(&mut self) -> Option<Ipv4Packet<'_>> {
         if let Some(len) = self.packet_for_client_length {
             Some(self.network_to_client.inflate(len))
         } else {
@@ -826,15 +1080,106 @@ impl PacketSource for TcpConnection {
         let len = self
             .packet_for_client_length
             .expect("next() called on empty packet source");
+        let payload_length = self
+            .network_to_client
+            .inflate(len)
+            .payload()
+            .expect("No payload")
+            .len();
         cx_debug!(
             target: TAG,
             self.id,
             "Deferred packet ({} bytes) sent to client {}",
-            len,
+            payload_length,
             self.tcb.numbers()
         );
-        self.tcb.sequence_number += Wrapping(u32::from(len));
+        self.tcb.sequence_number += Wrapping(payload_length as u32);
+        self.sent_to_client += payload_length as u64;
+        self.flow.record_downlink(usize::from(len));
         self.packet_for_client_length = None;
+        if self.traffic_class() == TrafficClass::Bulk {
+            // the interactive flows register again with their current class
+            self.prefetch();
+        }
+        self.release_packet_buffer();
         self.update_interests(selector);
     }
+
+    fn traffic_class(&self) -> TrafficClass {
+        TcpConnection::traffic_class(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfig;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    fn create_syn(destination_port: u16) -> Vec<u8> {
+        let mut raw = vec![0u8; 40];
+        raw[0] = 0x45;
+        raw[3] = 40; // total length
+        raw[8] = 64; // TTL
+        raw[9] = 6; // TCP
+        raw[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        raw[16..20].copy_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        raw[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        raw[22..24].copy_from_slice(&destination_port.to_be_bytes());
+        raw[32] = 5 << 4; // data offset
+        raw[33] = 0x02; // SYN
+        raw
+    }
+
+    #[test]
+    fn advance_sequence_number_by_deferred_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Rc::new(RelayConfig::new());
+        let mut selector = Selector::create().unwrap();
+        let mut raw = create_syn(listener.local_addr().unwrap().port());
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let upstream = Upstream::new(&config, None, &id, None);
+        let flow = Flow::new(0, &id, upstream.destination(), config.clone());
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let connection = TcpConnection::create(
+            &mut selector,
+            id,
+            Weak::new(),
+            &upstream,
+            flow,
+            ipv4_header,
+            transport_header.unwrap(),
+        )
+        .unwrap();
+
+        let mut connection = connection.borrow_mut();
+        connection.tcb.state = TcpState::Established;
+        let initial = connection.tcb.sequence_number;
+        // a packet the client could not accept, pulled later by its scheduler
+        let payload = [42u8; 100];
+        let length = {
+            let TcpConnection {
+                ref mut network_to_client,
+                ref tcb,
+                ..
+            } = *connection;
+            TcpConnection::update_headers(network_to_client, tcb, tcp_header::FLAG_ACK);
+            let ipv4_packet = network_to_client
+                .packetize_read(&mut &payload[..], None)
+                .unwrap()
+                .unwrap();
+            ipv4_packet.length()
+        };
+        assert_eq!(140, length);
+        connection.packet_for_client_length = Some(length);
+
+        PacketSource::next(&mut *connection, &mut selector);
+        // the sequence number counts the payload bytes, not the headers
+        assert_eq!(
+            initial + Wrapping(payload.len() as u32),
+            connection.tcb.sequence_number
+        );
+    }
 }
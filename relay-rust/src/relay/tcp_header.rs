@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use super::checksum::Checksum;
 use super::ipv4_header::Ipv4HeaderData;
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
@@ -138,10 +139,7 @@ macro_rules! tcp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -304,7 +302,7 @@ impl<'a> TcpHeaderMut<'a> {
             ipv4_header_data.total_length() - u16::from(ipv4_header_data.header_length());
 
         let header_length = self.header_length();
-        debug_assert!(header_length % 2 == 0 && header_length >= 20);
+        debug_assert!(header_length & 1 == 0 && header_length >= 20);
 
         let payload_length = transport_length - u16::from(header_length);
         debug_assert_eq!(
@@ -313,51 +311,20 @@ impl<'a> TcpHeaderMut<'a> {
             "Payload length does not match"
         );
 
-        let mut sum = 6u32; // protocol: TCP = 6
-        sum += source >> 16;
-        sum += source & 0xFFFF;
-        sum += destination >> 16;
-        sum += destination & 0xFFFF;
-        sum += u32::from(transport_length);
+        let mut checksum = Checksum::new();
+        checksum.add_u16(6); // protocol: TCP = 6
+        checksum.add_u32(source);
+        checksum.add_u32(destination);
+        checksum.add_u16(transport_length);
 
         // reset checksum field, so that it can be added with other bytes
         self.set_checksum(0);
 
         // checksum computation is the most CPU-intensive task in gnirehtet
-        // prefer optimization over readability/safety
-
-        let mut hsum = 0; // high-order bytes sum
-
-        unsafe {
-            let mut p = self.raw.as_ptr();
-            let end = p.offset(header_length as isize);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-
-            let mut p = payload.as_ptr();
-            // -1 to ignore the last if payload_length is odd
-            let end = p.offset(payload_length as isize - 1);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-            if payload_length % 2 != 0 {
-                // if payload length is odd, the last byte is considered high-order
-                hsum += u32::from(*payload.get_unchecked((payload_length - 1) as usize));
-            }
-        }
-
-        // add high-order bytes sum to the global sum
-        sum += hsum << 8;
-
-        while (sum & !0xFFFF) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        // the header length is even, so the payload is 16-bit aligned
+        checksum.add_bytes(&self.raw[..header_length as usize]);
+        checksum.add_bytes(payload);
+        self.set_checksum(checksum.finish());
     }
 }
 
@@ -368,6 +335,7 @@ mod tests {
     use crate::relay::transport_header::TransportHeaderMut;
     use byteorder::{BigEndian, WriteBytesExt};
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_packet() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(44);
@@ -396,6 +364,7 @@ mod tests {
         raw
     }
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_odd_packet() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(45);
@@ -426,6 +395,7 @@ mod tests {
         raw
     }
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_empty_packet() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(40);
@@ -452,6 +422,7 @@ mod tests {
         raw
     }
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_tcp_header() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(20);
@@ -515,6 +486,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn compute_checksum() {
         let raw = &mut create_packet()[..];
         let mut ipv4_packet = Ipv4Packet::parse(raw);
@@ -557,6 +529,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn compute_checksum_odd() {
         let raw = &mut create_odd_packet()[..];
         let mut ipv4_packet = Ipv4Packet::parse(raw);
@@ -599,6 +572,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn compute_checksum_empty_payload() {
         let raw = &mut create_empty_packet()[..];
         let mut ipv4_packet = Ipv4Packet::parse(raw);
@@ -637,6 +611,7 @@ mod tests {
         }
     }
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_long_packet() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(45);
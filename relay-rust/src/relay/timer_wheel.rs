@@ -0,0 +1,385 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp::{self, Ordering};
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Instant;
+
+// each level has 64 slots, a slot of level n spans 64^n ticks (of 1 ms)
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+// about 2 years: a later timer is placed as if it expired then, and placed again once reached
+const MAX_TICKS: u64 = (1 << (SLOT_BITS * LEVELS)) - 1;
+
+/// Identifier of a scheduled timer, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// Timers on the monotonic clock, stored in a hierarchical timing wheel.
+///
+/// Scheduling and cancelling are O(1), and a timer is moved at most once per level until it
+/// expires. The wheel has a resolution of 1 ms, but the exact deadlines are kept: a timer never
+/// expires early, and the expired timers are executed in deadline order (then in scheduling
+/// order).
+pub struct TimerWheel<H> {
+    origin: Instant,
+    // the ticks elapsed since origin, up to which the wheel has been advanced
+    elapsed: u64,
+    levels: Vec<Level>,
+    timers: HashMap<TimerId, Timer<H>>,
+    // the timers whose tick has elapsed, the earliest first
+    expired: BinaryHeap<Deadline>,
+    next_id: u64,
+}
+
+struct Timer<H> {
+    deadline: Instant,
+    tick: u64,
+    handler: H,
+}
+
+struct Level {
+    slots: Vec<Vec<TimerId>>,
+    // bit i is set if slot i is not empty
+    occupied: u64,
+}
+
+// the start (in ticks) of the next slot to process
+struct Expiration {
+    level: usize,
+    slot: usize,
+    tick: u64,
+}
+
+struct Deadline {
+    instant: Instant,
+    id: TimerId,
+}
+
+// BinaryHeap is a max-heap, order the deadlines so that the earliest one is the greatest
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .instant
+            .cmp(&self.instant)
+            .then_with(|| other.id.0.cmp(&self.id.0))
+    }
+}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Deadline {}
+
+impl Level {
+    fn new() -> Self {
+        Self {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            occupied: 0,
+        }
+    }
+
+    // the first non-empty slot from the current position of the wheel, if any
+    fn next_expiration(&self, level: usize, elapsed: u64) -> Option<Expiration> {
+        if self.occupied == 0 {
+            return None;
+        }
+        let shift = level * SLOT_BITS;
+        let current = ((elapsed >> shift) as usize) % SLOTS;
+        let slot = (current + self.occupied.rotate_right(current as u32).trailing_zeros() as usize)
+            % SLOTS;
+        let level_range = 1u64 << (shift + SLOT_BITS);
+        let level_start = elapsed & !(level_range - 1);
+        let mut tick = level_start + ((slot as u64) << shift);
+        if tick <= elapsed {
+            // the slot is in the next rotation of this level
+            tick += level_range;
+        }
+        Some(Expiration { level, slot, tick })
+    }
+
+    fn add(&mut self, slot: usize, id: TimerId) {
+        self.slots[slot].push(id);
+        self.occupied |= 1 << slot;
+    }
+
+    fn take(&mut self, slot: usize) -> Vec<TimerId> {
+        self.occupied &= !(1 << slot);
+        std::mem::take(&mut self.slots[slot])
+    }
+}
+
+// the level where a timer expiring at tick must be placed
+fn level_for(elapsed: u64, tick: u64) -> usize {
+    // the highest bit differing from the current position
+    let masked = cmp::min((elapsed ^ tick) | (SLOTS as u64 - 1), MAX_TICKS);
+    let significant = 63 - masked.leading_zeros() as usize;
+    significant / SLOT_BITS
+}
+
+impl<H> TimerWheel<H> {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            elapsed: 0,
+            levels: (0..LEVELS).map(|_| Level::new()).collect(),
+            timers: HashMap::new(),
+            expired: BinaryHeap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn schedule(&mut self, deadline: Instant, handler: H) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // rounded up, so that the timer never expires early
+        let nanos = deadline.saturating_duration_since(self.origin).as_nanos();
+        let tick = nanos.div_ceil(1_000_000) as u64;
+        self.timers.insert(
+            id,
+            Timer {
+                deadline,
+                tick,
+                handler,
+            },
+        );
+        self.place(id, deadline, tick);
+        id
+    }
+
+    fn place(&mut self, id: TimerId, deadline: Instant, tick: u64) {
+        if tick <= self.elapsed {
+            self.expired.push(Deadline {
+                instant: deadline,
+                id,
+            });
+            return;
+        }
+        let tick = cmp::min(tick, self.elapsed + MAX_TICKS);
+        let level = level_for(self.elapsed, tick);
+        let slot = ((tick >> (level * SLOT_BITS)) as usize) % SLOTS;
+        self.levels[level].add(slot, id);
+    }
+
+    /// Cancel a timer.
+    ///
+    /// Return `false` if it has already expired (or been cancelled).
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        // its identifier is discarded once its slot is processed
+        self.timers.remove(&id).is_some()
+    }
+
+    /// The deadline of the earliest timer, if any.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(deadline) = self.expired.peek() {
+            if self.timers.contains_key(&deadline.id) {
+                return Some(deadline.instant);
+            }
+            self.expired.pop();
+        }
+        while let Some(expiration) = self.next_expiration() {
+            // the timers of the first non-empty slot expire before all the others
+            let level = &mut self.levels[expiration.level];
+            let timers = &self.timers;
+            level.slots[expiration.slot].retain(|id| timers.contains_key(id));
+            let earliest = level.slots[expiration.slot]
+                .iter()
+                .map(|id| timers[id].deadline)
+                .min();
+            match earliest {
+                Some(earliest) => return Some(earliest),
+                // all of them have been cancelled
+                None => level.occupied &= !(1 << expiration.slot),
+            }
+        }
+        None
+    }
+
+    /// Remove and return the handler of the earliest timer expired at `now`, if any.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<H> {
+        self.advance(now);
+        while let Some(deadline) = self.expired.peek() {
+            if deadline.instant > now {
+                // expiring later in the current tick
+                return None;
+            }
+            let id = deadline.id;
+            self.expired.pop();
+            if let Some(timer) = self.timers.remove(&id) {
+                return Some(timer.handler);
+            }
+        }
+        None
+    }
+
+    fn next_expiration(&self) -> Option<Expiration> {
+        // the lower levels always expire first
+        self.levels
+            .iter()
+            .enumerate()
+            .find_map(|(index, level)| level.next_expiration(index, self.elapsed))
+    }
+
+    // process the slots up to now, to move the expired timers to the expired heap
+    fn advance(&mut self, now: Instant) {
+        // rounded up, like the deadlines, the expired heap checks the exact instants
+        let nanos = now.saturating_duration_since(self.origin).as_nanos();
+        let now_tick = nanos.div_ceil(1_000_000) as u64;
+        while let Some(expiration) = self.next_expiration() {
+            if expiration.tick > now_tick {
+                break;
+            }
+            self.elapsed = expiration.tick;
+            // the timers expired at this tick, the others are placed at a lower level
+            for id in self.levels[expiration.level].take(expiration.slot) {
+                if let Some(timer) = self.timers.get(&id) {
+                    let (deadline, tick) = (timer.deadline, timer.tick);
+                    self.place(id, deadline, tick);
+                }
+            }
+        }
+        self.elapsed = cmp::max(self.elapsed, now_tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn expire_in_order() {
+        let mut timers = TimerWheel::new();
+        let now = Instant::now();
+        timers.schedule(now + Duration::from_millis(20), "b");
+        timers.schedule(now + Duration::from_millis(10), "a");
+        timers.schedule(now + Duration::from_millis(20), "c");
+        assert_eq!(
+            Some(now + Duration::from_millis(10)),
+            timers.next_deadline()
+        );
+
+        assert_eq!(None, timers.pop_expired(now));
+        let later = now + Duration::from_millis(20);
+        assert_eq!(Some("a"), timers.pop_expired(later));
+        // same deadline, in scheduling order
+        assert_eq!(Some("b"), timers.pop_expired(later));
+        assert_eq!(Some("c"), timers.pop_expired(later));
+        assert_eq!(None, timers.pop_expired(later));
+        assert_eq!(None, timers.next_deadline());
+    }
+
+    #[test]
+    fn cancel_timer() {
+        let mut timers = TimerWheel::new();
+        let now = Instant::now();
+        let first = timers.schedule(now + Duration::from_millis(5), 1);
+        timers.schedule(now + Duration::from_secs(1), 2);
+        assert!(timers.cancel(first));
+        assert!(!timers.cancel(first));
+        assert_eq!(1, timers.timers.len());
+        assert_eq!(Some(now + Duration::from_secs(1)), timers.next_deadline());
+        assert_eq!(None, timers.pop_expired(now + Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn never_expire_early() {
+        let mut timers = TimerWheel::new();
+        let deadline = timers.origin + Duration::from_micros(1500);
+        timers.schedule(deadline, ());
+        assert_eq!(
+            None,
+            timers.pop_expired(deadline - Duration::from_micros(1))
+        );
+        assert_eq!(Some(()), timers.pop_expired(deadline));
+    }
+
+    #[test]
+    fn expire_far_timers() {
+        let mut timers = TimerWheel::new();
+        let now = timers.origin;
+        let hour = now + Duration::from_secs(3600);
+        let days = now + Duration::from_secs(3 * 86400);
+        // beyond the range of the wheel
+        let years = now + Duration::from_secs(5 * 365 * 86400);
+        timers.schedule(years, "years");
+        timers.schedule(days, "days");
+        timers.schedule(hour, "hour");
+        assert_eq!(Some(hour), timers.next_deadline());
+        assert_eq!(None, timers.pop_expired(hour - Duration::from_millis(1)));
+        assert_eq!(Some("hour"), timers.pop_expired(hour));
+        assert_eq!(Some(days), timers.next_deadline());
+        assert_eq!(None, timers.pop_expired(days - Duration::from_millis(1)));
+        assert_eq!(Some("days"), timers.pop_expired(days));
+        assert_eq!(None, timers.pop_expired(years - Duration::from_millis(1)));
+        assert_eq!(Some(years), timers.next_deadline());
+        assert_eq!(Some("years"), timers.pop_expired(years));
+    }
+
+    #[test]
+    fn expire_many_timers_in_order() {
+        let mut timers = TimerWheel::new();
+        let now = timers.origin;
+        let mut deadlines = Vec::new();
+        // deterministic pseudo-random delays, from 0 to ~70 minutes
+        let mut seed: u64 = 42;
+        for i in 0..2000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let delay = Duration::from_micros((seed >> 32) % 4_200_000_000);
+            let deadline = now + delay;
+            timers.schedule(deadline, i);
+            deadlines.push((deadline, i));
+        }
+        deadlines.sort();
+        let mut clock = now;
+        for &(deadline, i) in &deadlines {
+            assert_eq!(Some(deadline), timers.next_deadline());
+            // advance by irregular steps, never beyond the next deadline
+            if deadline > clock {
+                clock += (deadline - clock) / 2;
+                assert_eq!(None, timers.pop_expired(clock));
+                clock = deadline;
+            }
+            assert_eq!(Some(i), timers.pop_expired(clock));
+        }
+        assert_eq!(None, timers.next_deadline());
+    }
+
+    #[test]
+    fn discard_cancelled_timers() {
+        let mut timers = TimerWheel::new();
+        let now = Instant::now();
+        for i in 0..1000 {
+            let id = timers.schedule(now + Duration::from_secs(i), i);
+            timers.cancel(id);
+        }
+        assert!(timers.timers.is_empty());
+        assert_eq!(None, timers.next_deadline());
+        assert!(timers.levels.iter().all(|level| level.occupied == 0));
+    }
+}
@@ -0,0 +1,726 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::device_tunnels;
+use super::network_profile;
+
+/// Bytes and packets relayed for a client (or a device), in each direction.
+///
+/// The uplink is the traffic from the device to the network, the downlink the traffic from the
+/// network to the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficCounters {
+    uplink_bytes: u64,
+    uplink_packets: u64,
+    downlink_bytes: u64,
+    downlink_packets: u64,
+}
+
+impl TrafficCounters {
+    pub fn record_uplink(&mut self, length: usize) {
+        self.uplink_bytes += length as u64;
+        self.uplink_packets += 1;
+    }
+
+    pub fn record_downlink(&mut self, length: usize) {
+        self.downlink_bytes += length as u64;
+        self.downlink_packets += 1;
+    }
+
+    pub fn uplink_bytes(&self) -> u64 {
+        self.uplink_bytes
+    }
+
+    pub fn uplink_packets(&self) -> u64 {
+        self.uplink_packets
+    }
+
+    pub fn downlink_bytes(&self) -> u64 {
+        self.downlink_bytes
+    }
+
+    pub fn downlink_packets(&self) -> u64 {
+        self.downlink_packets
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.uplink_bytes + self.downlink_bytes
+    }
+}
+
+impl ops::Add for TrafficCounters {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            uplink_bytes: self.uplink_bytes + other.uplink_bytes,
+            uplink_packets: self.uplink_packets + other.uplink_packets,
+            downlink_bytes: self.downlink_bytes + other.downlink_bytes,
+            downlink_packets: self.downlink_packets + other.downlink_packets,
+        }
+    }
+}
+
+impl ops::Sub for TrafficCounters {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            uplink_bytes: self.uplink_bytes - other.uplink_bytes,
+            uplink_packets: self.uplink_packets - other.uplink_packets,
+            downlink_bytes: self.downlink_bytes - other.downlink_bytes,
+            downlink_packets: self.downlink_packets - other.downlink_packets,
+        }
+    }
+}
+
+impl fmt::Display for TrafficCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "up {} ({} packets), down {} ({} packets)",
+            ByteCount(self.uplink_bytes),
+            self.uplink_packets,
+            ByteCount(self.downlink_bytes),
+            self.downlink_packets
+        )
+    }
+}
+
+/// Human-readable amount of data.
+pub struct ByteCount(pub u64);
+
+impl fmt::Display for ByteCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+        if self.0 < 1000 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1000.0;
+        let mut unit = 0;
+        while value >= 1000.0 && unit < UNITS.len() - 1 {
+            value /= 1000.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Refuse the new connections.
+    Block,
+    /// Cap the bandwidth (in bits/s) in each direction.
+    Throttle(u64),
+}
+
+/// Data quota of a device, in bytes transferred in both directions.
+///
+/// Syntax: `LIMIT[:ACTION]`, where `LIMIT` is an amount of data (e.g. `500MB`, `2GB`, `1GiB`) and
+/// `ACTION` is `block` (the default) or `throttle=RATE` (e.g. `throttle=256kbit`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    limit: u64,
+    action: QuotaAction,
+}
+
+impl Quota {
+    pub fn new(limit: u64, action: QuotaAction) -> Self {
+        Self { limit, action }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (limit, action) = match s.find(':') {
+            Some(index) => (&s[..index], Some(s[index + 1..].trim())),
+            None => (s, None),
+        };
+        let limit = parse_size(limit.trim())?;
+        let action = match action {
+            None | Some("block") => QuotaAction::Block,
+            Some(action) => {
+                let rate = action
+                    .strip_prefix("throttle=")
+                    .ok_or_else(|| format!("Invalid quota action: \"{}\"", action))?;
+                let rate = network_profile::parse_rate(rate)?
+                    .ok_or_else(|| format!("Invalid throttling rate: \"{}\"", rate))?;
+                QuotaAction::Throttle(rate)
+            }
+        };
+        Ok(Self::new(limit, action))
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn action(&self) -> QuotaAction {
+        self.action
+    }
+
+    pub fn is_exceeded(&self, counters: &TrafficCounters) -> bool {
+        counters.total_bytes() >= self.limit
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_size(f, self.limit)?;
+        if let QuotaAction::Throttle(rate) = self.action {
+            write!(f, ":throttle={}kbit", rate / 1000)?;
+        }
+        Ok(())
+    }
+}
+
+// sorted by decreasing multiplier
+const SIZE_UNITS: [(u64, &str); 8] = [
+    (1 << 40, "TiB"),
+    (1_000_000_000_000, "TB"),
+    (1 << 30, "GiB"),
+    (1_000_000_000, "GB"),
+    (1 << 20, "MiB"),
+    (1_000_000, "MB"),
+    (1 << 10, "KiB"),
+    (1_000, "KB"),
+];
+
+/// Write `size` with the largest unit dividing it (the syntax accepted by `parse_size()`).
+pub fn write_size(f: &mut fmt::Formatter, size: u64) -> fmt::Result {
+    let (multiplier, unit) = SIZE_UNITS
+        .iter()
+        .find(|&&(multiplier, _)| size >= multiplier && size / multiplier * multiplier == size)
+        .unwrap_or(&(1, "B"));
+    write!(f, "{}{}", size / multiplier, unit)
+}
+
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = network_profile::split_unit(value);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid amount of data: \"{}\"", value))?;
+    let multiplier = if unit.is_empty() || unit.eq_ignore_ascii_case("B") {
+        1
+    } else {
+        SIZE_UNITS
+            .iter()
+            .find(|(_, name)| unit.eq_ignore_ascii_case(name))
+            .map(|&(multiplier, _)| multiplier)
+            .ok_or_else(|| format!("Invalid amount of data unit: \"{}\"", value))?
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Amount of data too large: \"{}\"", value))
+}
+
+/// Data quotas, with optional per-device overrides.
+///
+/// Syntax: `ENTRY[;ENTRY...]`, where `ENTRY` is `[SERIAL=]QUOTA` or `[SERIAL=]none`. An entry
+/// without serial sets the default quota.
+///
+/// For example, `1GB;emulator-5554=none;emulator-5556=100MB:throttle=128kbit` limits every device
+/// to 1 GB, except `emulator-5554` which is not limited and `emulator-5556` which is throttled
+/// after 100 MB.
+///
+/// The overrides only apply to the devices identified by their tunnel port.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuotaPolicy {
+    default: Option<Quota>,
+    per_device: HashMap<String, Option<Quota>>,
+}
+
+impl QuotaPolicy {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            // a quota may contain '=' (and a serial ':'), so the entry has a serial only if it is
+            // not a quota
+            match Self::parse_quota(entry) {
+                Ok(quota) => policy.default = quota,
+                Err(err) => {
+                    let index = entry.find('=').ok_or(err)?;
+                    let serial = device_tunnels::parse_serial(&entry[..index])?;
+                    let quota = Self::parse_quota(entry[index + 1..].trim())?;
+                    policy.set_device_quota(serial, quota);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    fn parse_quota(s: &str) -> Result<Option<Quota>, String> {
+        if s == "none" {
+            Ok(None)
+        } else {
+            Quota::parse(s).map(Some)
+        }
+    }
+
+    pub fn set_default_quota(&mut self, quota: Option<Quota>) {
+        self.default = quota;
+    }
+
+    pub fn set_device_quota(&mut self, serial: String, quota: Option<Quota>) {
+        self.per_device.insert(serial, quota);
+    }
+
+    /// The quota of the device `device`, or the default one if it is not identified.
+    pub fn quota(&self, device: Option<&str>) -> Option<&Quota> {
+        device
+            .and_then(|serial| self.per_device.get(serial))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+impl fmt::Display for QuotaPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn write_quota(f: &mut fmt::Formatter, quota: &Option<Quota>) -> fmt::Result {
+            match quota {
+                Some(quota) => write!(f, "{}", quota),
+                None => write!(f, "none"),
+            }
+        }
+        write_quota(f, &self.default)?;
+        let mut serials: Vec<&String> = self.per_device.keys().collect();
+        serials.sort();
+        for serial in serials {
+            write!(f, ";{}=", serial)?;
+            write_quota(f, &self.per_device[serial])?;
+        }
+        Ok(())
+    }
+}
+
+/// Traffic of all the clients, shared by the relay and its users.
+///
+/// The clients count their own traffic and publish their counters regularly.
+///
+/// The client ids are only assigned in connection order, so they do not identify a device across
+/// reconnections or relay runs. The traffic is therefore counted per device, identified by its
+/// serial: the clients of a device continue from its traffic so far, so that a quota applies
+/// across reconnections (and relay runs, if a file is set). The clients whose device is not
+/// identified are counted for their session only: they start from zero, and their traffic is
+/// forgotten once they disconnect.
+///
+/// If a file is set, the counters of the devices are persisted there, along with the ones of the
+/// connected clients whose device is not identified (written as `client-ID`, never restored).
+#[derive(Clone, Debug, Default)]
+pub struct TrafficAccounting {
+    state: Arc<Mutex<State>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    clients: HashMap<u32, ClientTraffic>,
+    // the traffic of the devices, except the one of their connected clients
+    devices: HashMap<String, TrafficCounters>,
+}
+
+#[derive(Clone, Debug)]
+struct ClientTraffic {
+    device: Option<Arc<str>>,
+    // the traffic of the device when the client connected
+    initial: TrafficCounters,
+    counters: TrafficCounters,
+}
+
+impl State {
+    /// The traffic of the device `device`, including the one of its connected clients.
+    fn device_counters(&self, device: &str) -> TrafficCounters {
+        let saved = self.devices.get(device).cloned().unwrap_or_default();
+        self.clients
+            .values()
+            .filter(|client| client.device.as_deref() == Some(device))
+            .fold(saved, |total, client| {
+                total + (client.counters - client.initial)
+            })
+    }
+}
+
+impl TrafficAccounting {
+    /// Read the counters of the devices from `path` (if it exists), and persist them there on
+    /// `save()`.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let devices = match fs::read_to_string(&path) {
+            Ok(content) => parse_counters(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        let state = State {
+            clients: HashMap::new(),
+            devices,
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+            path: Some(Arc::new(path)),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| path.as_path())
+    }
+
+    /// Register a new client of the device `device` (if identified), and return its initial
+    /// counters: the traffic of its device so far, or zero.
+    pub fn client_connected(&self, client_id: u32, device: Option<Arc<str>>) -> TrafficCounters {
+        let mut state = self.state.lock().unwrap();
+        let initial = device
+            .as_deref()
+            .map(|device| state.device_counters(device))
+            .unwrap_or_default();
+        state.clients.insert(
+            client_id,
+            ClientTraffic {
+                device,
+                initial,
+                counters: initial,
+            },
+        );
+        initial
+    }
+
+    /// Unregister a client, after its last update.
+    ///
+    /// Its traffic is kept in the counters of its device, if identified.
+    pub fn client_disconnected(&self, client_id: u32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(client) = state.clients.remove(&client_id) {
+            if let Some(device) = client.device {
+                let total = state.devices.entry(device.to_string()).or_default();
+                *total = *total + (client.counters - client.initial);
+            }
+        }
+    }
+
+    pub fn update(&self, client_id: u32, counters: TrafficCounters) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(client) = state.clients.get_mut(&client_id) {
+            client.counters = counters;
+        }
+    }
+
+    /// The counters of all the connected clients, sorted by client id.
+    pub fn all(&self) -> Vec<(u32, TrafficCounters)> {
+        let state = self.state.lock().unwrap();
+        let mut all: Vec<_> = state
+            .clients
+            .iter()
+            .map(|(&client_id, client)| (client_id, client.counters))
+            .collect();
+        all.sort_by_key(|&(client_id, _)| client_id);
+        all
+    }
+
+    /// Persist the counters, if a file is set.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            // keep the counters locked while writing, so that concurrent saves never interleave
+            let state = self.state.lock().unwrap();
+            let mut devices: Vec<&str> = state
+                .devices
+                .keys()
+                .map(String::as_str)
+                .chain(state.clients.values().filter_map(|c| c.device.as_deref()))
+                .collect();
+            devices.sort_unstable();
+            devices.dedup();
+            let mut entries: Vec<(String, TrafficCounters)> = devices
+                .into_iter()
+                .map(|device| (device.to_string(), state.device_counters(device)))
+                .collect();
+            let mut sessions: Vec<(&u32, &ClientTraffic)> = state
+                .clients
+                .iter()
+                .filter(|(_, client)| client.device.is_none())
+                .collect();
+            sessions.sort_by_key(|&(client_id, _)| client_id);
+            for (client_id, client) in sessions {
+                let name = format!("{}{}", SESSION_PREFIX, client_id);
+                entries.push((name, client.counters));
+            }
+            let content = format_counters(&entries);
+            // write to a temporary file first, so that a crash never truncates the counters
+            let mut tmp_path = path.as_os_str().to_owned();
+            tmp_path.push(".tmp");
+            fs::write(&tmp_path, content)?;
+            fs::rename(&tmp_path, path.as_path())?;
+        }
+        Ok(())
+    }
+}
+
+const FILE_HEADER: &str = "# device uplink_bytes uplink_packets downlink_bytes downlink_packets\n";
+
+// the counters of the clients of an unidentified device, only valid for their session
+const SESSION_PREFIX: &str = "client-";
+
+fn format_counters(entries: &[(String, TrafficCounters)]) -> String {
+    let mut content = String::from(FILE_HEADER);
+    for (name, counters) in entries {
+        content.push_str(&format!(
+            "{} {} {} {} {}\n",
+            name,
+            counters.uplink_bytes,
+            counters.uplink_packets,
+            counters.downlink_bytes,
+            counters.downlink_packets
+        ));
+    }
+    content
+}
+
+fn parse_counters(content: &str) -> Result<HashMap<String, TrafficCounters>, String> {
+    let mut all = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut tokens = line.split_whitespace();
+        let name = tokens.next().unwrap();
+        let values = tokens
+            .map(str::parse::<u64>)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|values| values.len() == 4)
+            .ok_or_else(|| format!("Invalid traffic counters: \"{}\"", line))?;
+        if name.starts_with(SESSION_PREFIX) {
+            // the traffic of a previous session
+            continue;
+        }
+        let counters = TrafficCounters {
+            uplink_bytes: values[0],
+            uplink_packets: values[1],
+            downlink_bytes: values[2],
+            downlink_packets: values[3],
+        };
+        all.insert(name.to_string(), counters);
+    }
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_traffic() {
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(60);
+        counters.record_uplink(40);
+        counters.record_downlink(1500);
+        assert_eq!(100, counters.uplink_bytes());
+        assert_eq!(2, counters.uplink_packets());
+        assert_eq!(1, counters.downlink_packets());
+        assert_eq!(1600, counters.total_bytes());
+        assert_eq!(
+            "up 100 B (2 packets), down 1.5 KB (1 packets)",
+            counters.to_string()
+        );
+    }
+
+    #[test]
+    fn parse_quota() {
+        let quota = Quota::parse("500MB").unwrap();
+        assert_eq!(500_000_000, quota.limit());
+        assert_eq!(QuotaAction::Block, quota.action());
+
+        let quota = Quota::parse("1GiB:throttle=256kbit").unwrap();
+        assert_eq!(1 << 30, quota.limit());
+        assert_eq!(QuotaAction::Throttle(256_000), quota.action());
+        assert_eq!("1GiB:throttle=256kbit", quota.to_string());
+        assert_eq!("1500B", Quota::parse("1500").unwrap().to_string());
+
+        assert!(Quota::parse("1GB:slow").is_err());
+        assert!(Quota::parse("1GB:throttle=0kbit").is_err());
+        assert!(Quota::parse("12 apples").is_err());
+        assert!(Quota::parse("99999999999TB").is_err());
+    }
+
+    #[test]
+    fn quota_exceeded() {
+        let quota = Quota::parse("1KB").unwrap();
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(600);
+        assert!(!quota.is_exceeded(&counters));
+        counters.record_downlink(400);
+        assert!(quota.is_exceeded(&counters));
+    }
+
+    #[test]
+    fn per_device_quotas() {
+        let policy =
+            QuotaPolicy::parse("1GB;emulator-5554=none;192.168.1.2:5555=100MB:throttle=128kbit")
+                .unwrap();
+        assert_eq!(1_000_000_000, policy.quota(None).unwrap().limit());
+        assert_eq!(
+            1_000_000_000,
+            policy.quota(Some("emulator-5556")).unwrap().limit()
+        );
+        assert!(policy.quota(Some("emulator-5554")).is_none());
+        assert_eq!(
+            QuotaAction::Throttle(128_000),
+            policy.quota(Some("192.168.1.2:5555")).unwrap().action()
+        );
+        assert_eq!(
+            "1GB;192.168.1.2:5555=100MB:throttle=128kbit;emulator-5554=none",
+            policy.to_string()
+        );
+        assert!(QuotaPolicy::default().quota(None).is_none());
+        assert!(QuotaPolicy::parse("=1GB").is_err());
+        assert!(QuotaPolicy::parse("emulator-5554=1 apple").is_err());
+    }
+
+    #[test]
+    fn persist_counters() {
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(42);
+        counters.record_downlink(1000);
+        let content = format_counters(&[
+            (String::from("0123456789ABCDEF"), counters),
+            (String::from("client-3"), counters),
+        ]);
+        assert_eq!(
+            FILE_HEADER.to_string() + "0123456789ABCDEF 42 1 1000 1\n" + "client-3 42 1 1000 1\n",
+            content
+        );
+
+        // the counters of a session are never restored
+        let all = parse_counters(&content).unwrap();
+        assert_eq!(1, all.len());
+        assert_eq!(counters, all["0123456789ABCDEF"]);
+        assert!(parse_counters("device 2 3").is_err());
+    }
+
+    #[test]
+    fn share_counters() {
+        let accounting = TrafficAccounting::default();
+        let shared = accounting.clone();
+        assert_eq!(
+            TrafficCounters::default(),
+            accounting.client_connected(1, None)
+        );
+        let mut counters = TrafficCounters::default();
+        counters.record_downlink(10);
+        shared.update(1, counters);
+        assert_eq!(vec![(1, counters)], accounting.all());
+        // nothing to persist without file
+        accounting.save().unwrap();
+
+        accounting.client_disconnected(1);
+        assert!(accounting.all().is_empty());
+        // a late update does not register the client again
+        shared.update(1, counters);
+        assert!(accounting.all().is_empty());
+    }
+
+    fn write_traffic_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gnirehtet-traffic-{}-{}.txt",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn count_per_session_without_device() {
+        let path = write_traffic_file("session", "emulator-5554 10 1 20 1\nclient-0 5 1 5 1\n");
+        let accounting = TrafficAccounting::load(&path).unwrap();
+        // client ids are reassigned on every run, do not restore client-0
+        assert_eq!(
+            TrafficCounters::default(),
+            accounting.client_connected(0, None)
+        );
+        let mut counters = TrafficCounters::default();
+        counters.record_uplink(100);
+        accounting.update(0, counters);
+        accounting.save().unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            FILE_HEADER.to_string() + "emulator-5554 10 1 20 1\n" + "client-0 100 1 0 0\n",
+            content
+        );
+
+        // the traffic of the session is forgotten once the client disconnects
+        accounting.client_disconnected(0);
+        assert_eq!(
+            TrafficCounters::default(),
+            accounting.client_connected(1, None)
+        );
+        accounting.client_disconnected(1);
+        accounting.save().unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            FILE_HEADER.to_string() + "emulator-5554 10 1 20 1\n",
+            content
+        );
+    }
+
+    #[test]
+    fn restore_device_counters() {
+        let path = write_traffic_file("device", "emulator-5554 10 1 20 1\nother 1 1 1 1\n");
+        let accounting = TrafficAccounting::load(&path).unwrap();
+        let device: Arc<str> = Arc::from("emulator-5554");
+
+        let initial = accounting.client_connected(0, Some(device.clone()));
+        assert_eq!(10, initial.uplink_bytes());
+        assert_eq!(20, initial.downlink_bytes());
+        let mut counters = initial;
+        counters.record_uplink(100);
+        accounting.update(0, counters);
+        accounting.client_disconnected(0);
+
+        // a reconnection of the same device continues from its traffic so far
+        let initial = accounting.client_connected(1, Some(device.clone()));
+        assert_eq!(110, initial.uplink_bytes());
+        let mut counters = initial;
+        counters.record_downlink(1000);
+        accounting.update(1, counters);
+
+        // the other devices and the unidentified clients start from zero
+        let initial = accounting.client_connected(2, Some(Arc::from("emulator-5556")));
+        assert_eq!(TrafficCounters::default(), initial);
+        assert_eq!(
+            TrafficCounters::default(),
+            accounting.client_connected(3, None)
+        );
+        accounting.save().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            FILE_HEADER.to_string()
+                + "emulator-5554 110 2 1020 2\n"
+                + "emulator-5556 0 0 0 0\n"
+                + "other 1 1 1 1\n"
+                + "client-3 0 0 0 0\n",
+            content
+        );
+    }
+}
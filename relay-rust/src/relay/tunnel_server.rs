@@ -20,44 +20,61 @@ use mio::{Event, PollOpt, Ready};
 use std::cell::RefCell;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
-use std::ptr;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use super::client::Client;
+use super::connection_limits;
+use super::device_tunnels::DeviceTunnels;
 use super::selector::Selector;
+use super::worker::WorkerHandle;
 
 const TAG: &str = "TunnelServer";
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
+/// Accept the clients, on the main port and on the tunnel ports of the devices.
 pub struct TunnelServer {
     self_weak: Weak<RefCell<TunnelServer>>,
-    clients: Vec<Rc<RefCell<Client>>>,
-    tcp_listener: TcpListener,
+    workers: Arc<[WorkerHandle]>,
+    listeners: Vec<Listener>,
     next_client_id: u32,
 }
 
+struct Listener {
+    tcp_listener: TcpListener,
+    // the device whose tunnel port this is, if any
+    device: Option<Arc<str>>,
+}
+
 impl TunnelServer {
-    pub fn create(port: u16, selector: &mut Selector) -> io::Result<Rc<RefCell<Self>>> {
+    pub fn create(
+        port: u16,
+        workers: Arc<[WorkerHandle]>,
+        device_tunnels: DeviceTunnels,
+        selector: &mut Selector,
+    ) -> io::Result<Rc<RefCell<Self>>> {
         let tcp_listener = Self::start_socket(port)?;
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
-            clients: Vec::new(),
-            tcp_listener,
+            workers,
+            listeners: Vec::new(),
             next_client_id: 0,
         }));
 
-        // keep a shared reference to this
-        rc.borrow_mut().self_weak = Rc::downgrade(&rc);
+        {
+            let mut self_ref = rc.borrow_mut();
+            // keep a shared reference to this
+            self_ref.self_weak = Rc::downgrade(&rc);
+            self_ref.listen(selector, tcp_listener, None)?;
+        }
 
         let rc2 = rc.clone();
+        let tunnels = device_tunnels.clone();
         // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
-        let handler =
-            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
-        selector.register(
-            &rc.borrow().tcp_listener,
-            handler,
-            Ready::readable(),
-            PollOpt::edge(),
-        )?;
+        let handler = move |selector: &mut Selector, _| {
+            rc2.borrow_mut().listen_device_tunnels(selector, &tunnels)
+        };
+        device_tunnels.bind(selector.register_waker(handler)?)?;
         Ok(rc)
     }
 
@@ -68,54 +85,86 @@ impl TunnelServer {
         Ok(server)
     }
 
-    fn on_ready(&mut self, selector: &mut Selector, _: Event) {
-        match self.accept_client(selector) {
-            Ok(_) => debug!(target: TAG, "New client accepted"),
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                debug!(target: TAG, "Spurious event, ignoring");
+    fn listen(
+        &mut self,
+        selector: &mut Selector,
+        tcp_listener: TcpListener,
+        device: Option<Arc<str>>,
+    ) -> io::Result<()> {
+        let index = self.listeners.len();
+        let rc = self.self_weak.upgrade().expect("TunnelServer not shared");
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc.borrow_mut().on_ready(selector, event, index);
+        selector.register(&tcp_listener, handler, Ready::readable(), PollOpt::edge())?;
+        self.listeners.push(Listener {
+            tcp_listener,
+            device,
+        });
+        Ok(())
+    }
+
+    fn listen_device_tunnels(&mut self, selector: &mut Selector, device_tunnels: &DeviceTunnels) {
+        for (device, listener) in device_tunnels.take_pending() {
+            let result = TcpListener::from_std(listener).and_then(|tcp_listener| {
+                let port = tcp_listener.local_addr()?.port();
+                self.listen(selector, tcp_listener, Some(device.clone()))?;
+                Ok(port)
+            });
+            match result {
+                Ok(port) => info!(target: TAG, "Device {} tunnel on port {}", device, port),
+                Err(err) => error!(target: TAG, "Cannot listen for device {}: {}", device, err),
             }
-            Err(err) => error!(target: TAG, "Cannot accept client: {}", err),
         }
     }
 
-    fn accept_client(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let (stream, _) = self.tcp_listener.accept()?;
-        let client_id = self.next_client_id;
-        self.next_client_id += 1;
-        let weak = self.self_weak.clone();
-        let on_client_closed = Box::new(move |client: &Client| {
-            if let Some(rc) = weak.upgrade() {
-                let mut tunnel_server = rc.borrow_mut();
-                tunnel_server.remove_client(client);
-            } else {
+    fn on_ready(&mut self, selector: &mut Selector, _: Event, index: usize) {
+        self.accept(selector, index);
+    }
+
+    fn accept(&mut self, selector: &mut Selector, index: usize) {
+        match self.accept_client(index) {
+            Ok(_) => debug!(target: TAG, "New client accepted"),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!(target: TAG, "Spurious event, ignoring");
+            }
+            Err(ref err) if connection_limits::is_descriptor_exhaustion(err) => {
+                // the listener is edge-triggered, the pending client would never be accepted
                 warn!(
                     target: TAG,
-                    "on_client_closed called but no tunnel_server available"
+                    "Cannot accept client (too many open files), retrying in {}s",
+                    ACCEPT_RETRY_DELAY.as_secs()
                 );
+                let weak = self.self_weak.clone();
+                selector.schedule(Instant::now() + ACCEPT_RETRY_DELAY, move |selector| {
+                    if let Some(rc) = weak.upgrade() {
+                        rc.borrow_mut().accept(selector, index);
+                    }
+                });
             }
-        });
-        let client = Client::create(client_id, selector, stream, on_client_closed)?;
-        self.clients.push(client);
-        info!(target: TAG, "Client #{} connected", client_id);
-        Ok(())
+            Err(err) => error!(target: TAG, "Cannot accept client: {}", err),
+        }
     }
 
-    fn remove_client(&mut self, client: &Client) {
-        info!(target: TAG, "Client #{} disconnected", client.id());
-        let index = self
-            .clients
+    fn accept_client(&mut self, index: usize) -> io::Result<()> {
+        let listener = &self.listeners[index];
+        let (stream, _) = listener.tcp_listener.accept()?;
+        let device = listener.device.clone();
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        // hand off the client to the least loaded worker
+        let worker = self
+            .workers
             .iter()
-            .position(|item| {
-                // compare pointers to find the client to remove
-                ptr::eq(client, item.as_ptr())
-            })
-            .expect("Trying to remove an unknown client");
-        self.clients.swap_remove(index);
-    }
-
-    pub fn clean_up(&mut self, selector: &mut Selector) {
-        for client in &self.clients {
-            client.borrow_mut().clean_expired_connections(selector);
-        }
+            .min_by_key(|worker| worker.client_count())
+            .expect("No worker to run the client");
+        worker.dispatch(client_id, device, stream)?;
+        debug!(
+            target: TAG,
+            "Client #{} handed off to worker #{}",
+            client_id,
+            worker.id()
+        );
+        Ok(())
     }
 }
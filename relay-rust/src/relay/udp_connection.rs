@@ -15,29 +15,43 @@
  */
 
 use log::*;
-use mio::net::UdpSocket;
+use mio::net::{TcpStream, UdpSocket};
 use mio::{Event, PollOpt, Ready, Token};
 use std::cell::RefCell;
-use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::rc::{Rc, Weak};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
 use super::connection::{Connection, ConnectionId};
+use super::datagram::MAX_DATAGRAM_LENGTH;
+use super::datagram_batch;
 use super::datagram_buffer::DatagramBuffer;
+use super::dns;
+use super::flow_log::{CloseReason, Flow};
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::observer::DropReason;
+use super::packet_scheduler::TrafficClass;
 use super::packetizer::Packetizer;
+use super::proxy::ProxyConfig;
 use super::selector::Selector;
+use super::socket_binding::SocketBinding;
+use super::socks5::{
+    Socks5Command, Socks5Handshake, Socks5UdpReceiver, Socks5UdpSender, UDP_HEADER_LENGTH,
+};
+use super::stats::ConnectionStats;
 use super::transport_header::TransportHeader;
+use super::upstream::Upstream;
 
 const TAG: &str = "UdpConnection";
 
-pub const IDLE_TIMEOUT_SECONDS: u64 = 2 * 60;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
 
 pub struct UdpConnection {
+    self_weak: Weak<RefCell<UdpConnection>>,
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
     socket: UdpSocket,
@@ -47,6 +61,72 @@ pub struct UdpConnection {
     network_to_client: Packetizer,
     closed: bool,
     idle_since: Instant,
+    socks5: Option<Socks5Association>,
+    flow: Flow,
+}
+
+// Datagrams relayed through a SOCKS5 proxy (UDP ASSOCIATE)
+//
+// The association lasts as long as the control stream remains open.
+struct Socks5Association {
+    control: TcpStream,
+    control_interests: Ready,
+    control_token: Token,
+    handshake: Socks5Handshake,
+    proxy_address: SocketAddr,
+    destination: SocketAddrV4,
+    // to add or remove the SOCKS5 UDP headers
+    buf: Box<[u8]>,
+}
+
+impl Socks5Association {
+    fn connect(
+        proxy: &ProxyConfig,
+        destination: SocketAddrV4,
+        binding: &SocketBinding,
+    ) -> io::Result<Self> {
+        let control = binding.connect_tcp(&proxy.address())?;
+        // the address the datagrams will be sent from is unknown yet
+        let unspecified = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
+        let handshake = Socks5Handshake::new(
+            Socks5Command::UdpAssociate,
+            unspecified,
+            proxy.credentials().cloned(),
+        );
+        Ok(Self {
+            control,
+            control_interests: Ready::writable(),
+            control_token: Token(0), // default value, will be set afterwards
+            handshake,
+            proxy_address: proxy.address(),
+            destination,
+            buf: vec![0; UDP_HEADER_LENGTH + MAX_DATAGRAM_LENGTH].into_boxed_slice(),
+        })
+    }
+
+    fn is_ready(&self) -> bool {
+        self.handshake.is_done()
+    }
+
+    // the address of the UDP relay, provided by the proxy
+    fn relay_address(&self) -> io::Result<SocketAddr> {
+        let bound_address = self
+            .handshake
+            .bound_address()
+            .ok_or_else(|| io::Error::other("SOCKS5 UDP relay address is not an IPv4 address"))?;
+        if bound_address.ip().is_unspecified() {
+            // the relay is on the proxy host
+            Ok(SocketAddr::new(
+                self.proxy_address.ip(),
+                bound_address.port(),
+            ))
+        } else {
+            Ok(SocketAddr::new(
+                IpAddr::V4(*bound_address.ip()),
+                bound_address.port(),
+            ))
+        }
+    }
 }
 
 impl UdpConnection {
@@ -55,14 +135,34 @@ impl UdpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        upstream: &Upstream,
+        mut flow: Flow,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
-        cx_info!(target: TAG, id, "Open");
-        let socket = Self::create_socket(&id)?;
+        let packet_length = usize::from(ipv4_header.total_length());
+        let destination = upstream.destination();
+        if destination == id.destination() {
+            cx_info!(target: TAG, id, "Open");
+        } else {
+            cx_info!(target: TAG, id, "Open (rewritten to {})", destination);
+        }
+        let (socket, socks5) = match Self::create_socket(&id, upstream) {
+            Ok(result) => result,
+            Err(err) => {
+                flow.open_failed(packet_length, &err);
+                return Err(err);
+            }
+        };
         let packetizer = Packetizer::new(&ipv4_header, &transport_header);
-        let interests = Ready::readable();
+        // when relayed by a proxy, wait for the association before reading or writing
+        let interests = if socks5.is_some() {
+            Ready::empty()
+        } else {
+            Ready::readable()
+        };
         let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
             id,
             client,
             socket,
@@ -72,27 +172,79 @@ impl UdpConnection {
             network_to_client: packetizer,
             closed: false,
             idle_since: Instant::now(),
+            socks5,
+            flow,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
 
+            // keep a shared reference to this
+            self_ref.self_weak = Rc::downgrade(&rc);
+
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
             let handler =
                 move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
             let token =
-                selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
+                match selector.register(&self_ref.socket, handler, interests, PollOpt::level()) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        self_ref.flow.open_failed(packet_length, &err);
+                        return Err(err);
+                    }
+                };
             self_ref.token = token;
+            self_ref.schedule_idle_timeout(selector);
+
+            if let Some(ref mut socks5) = self_ref.socks5 {
+                let rc2 = rc.clone();
+                let handler = move |selector: &mut Selector, event| {
+                    rc2.borrow_mut().on_control_ready(selector, event)
+                };
+                let token = match selector.register(
+                    &socks5.control,
+                    handler,
+                    socks5.control_interests,
+                    PollOpt::level(),
+                ) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        self_ref.flow.open_failed(packet_length, &err);
+                        return Err(err);
+                    }
+                };
+                socks5.control_token = token;
+            }
         }
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<UdpSocket> {
-        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
-        let udp_socket = UdpSocket::bind(&autobind_addr)?;
-        udp_socket.connect(id.rewritten_destination().into())?;
-        Ok(udp_socket)
+    fn create_socket(
+        id: &ConnectionId,
+        upstream: &Upstream,
+    ) -> io::Result<(UdpSocket, Option<Socks5Association>)> {
+        if let Err(err) = upstream.check_access() {
+            cx_warn!(target: TAG, id, "Blocked: {}", err);
+            return Err(err);
+        }
+        let udp_socket = upstream.binding().bind_udp()?;
+        let socks5 = match upstream.datagram_proxy() {
+            Some(proxy) => {
+                cx_debug!(target: TAG, id, "Relaying through {}", proxy);
+                // the socket will be connected to the proxy UDP relay once known
+                Some(Socks5Association::connect(
+                    proxy,
+                    upstream.destination(),
+                    upstream.binding(),
+                )?)
+            }
+            None => {
+                udp_socket.connect(upstream.destination().into())?;
+                None
+            }
+        };
+        Ok((udp_socket, socks5))
     }
 
     fn remove_from_router(&self) {
@@ -111,6 +263,76 @@ impl UdpConnection {
             }
             Err(_) => panic!("Unexpected unhandled error"),
         }
+        // the packets are never kept pending, give the buffer back to the pool
+        self.network_to_client.release();
+    }
+
+    fn on_control_ready(&mut self, selector: &mut Selector, event: Event) {
+        if self.closed {
+            return;
+        }
+        if let Err(err) = self.process_control(selector, event) {
+            cx_error!(target: TAG, self.id, "SOCKS5 association failed: {}", err);
+            let reason = if self.socks5.as_ref().is_some_and(|s| s.is_ready()) {
+                CloseReason::Error
+            } else {
+                CloseReason::ConnectFailed
+            };
+            self.close(selector, reason);
+        }
+        if self.closed {
+            // on_control_ready is not called from the router, so the connection must remove
+            // itself
+            self.remove_from_router();
+        }
+    }
+
+    fn process_control(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
+        let ready = event.readiness();
+        if !ready.is_readable() && !ready.is_writable() {
+            // error or hup
+            return Err(io::Error::other("SOCKS5 control stream closed"));
+        }
+        let socks5 = self.socks5.as_mut().expect("No SOCKS5 association");
+        if socks5.is_ready() {
+            // nothing is expected on the control stream, it only indicates the association end
+            let r = socks5.control.read(&mut socks5.buf)?;
+            if r == 0 {
+                return Err(io::Error::other("SOCKS5 control stream closed"));
+            }
+            return Ok(());
+        }
+        if ready.is_writable() && socks5.handshake.wants_write() {
+            socks5.handshake.write_to(&mut socks5.control)?;
+        }
+        if ready.is_readable() && socks5.handshake.read_from(&mut socks5.control)? {
+            let relay_address = socks5.relay_address()?;
+            self.socket.connect(relay_address)?;
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "SOCKS5 association established, relay at {}",
+                relay_address
+            );
+        }
+        let control_interests = if socks5.handshake.wants_write() {
+            Ready::writable()
+        } else {
+            Ready::readable()
+        };
+        if socks5.control_interests != control_interests {
+            socks5.control_interests = control_interests;
+            selector
+                .reregister(
+                    &socks5.control,
+                    socks5.control_token,
+                    control_interests,
+                    PollOpt::level(),
+                )
+                .expect("Cannot register on poll");
+        }
+        self.update_interests(selector);
+        Ok(())
     }
 
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
@@ -130,7 +352,7 @@ impl UdpConnection {
                 }
             } else {
                 // error or hup
-                self.close(selector);
+                self.close(selector, CloseReason::Error);
             }
             if self.closed {
                 // on_ready is not called from the router, so the connection must remove itself
@@ -159,7 +381,7 @@ impl UdpConnection {
                     err.kind(),
                     err
                 );
-                self.close(selector);
+                self.close(selector, CloseReason::of_error(&err));
             }
         }
         Ok(())
@@ -174,6 +396,11 @@ impl UdpConnection {
                     // rethrow
                     return Err(err);
                 }
+                if err.kind() == io::ErrorKind::InvalidData {
+                    // invalid datagram from the proxy UDP relay
+                    cx_warn!(target: TAG, self.id, "Dropping datagram: {}", err);
+                    return Ok(());
+                }
                 cx_error!(
                     target: TAG,
                     self.id,
@@ -181,47 +408,89 @@ impl UdpConnection {
                     err.kind(),
                     err
                 );
-                self.close(selector);
+                self.close(selector, CloseReason::of_error(&err));
             }
         }
         Ok(())
     }
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self.network_to_client.packetize(&mut self.socket)?;
         let client_rc = self.client.upgrade().expect("Expected client not found");
-        match client_rc
-            .borrow_mut()
-            .send_to_client(selector, &ipv4_packet)
-        {
+        match self.socks5 {
+            Some(ref mut socks5) => {
+                let mut receiver = Socks5UdpReceiver::new(&mut self.socket, &mut socks5.buf);
+                let ipv4_packet = self.network_to_client.packetize(&mut receiver)?;
+                Self::send_to_client(&self.id, &mut self.flow, &client_rc, selector, &ipv4_packet);
+            }
+            None => {
+                let id = &self.id;
+                let flow = &mut self.flow;
+                let packetizer = &mut self.network_to_client;
+                datagram_batch::recv_batch(&self.socket, |datagram| {
+                    let ipv4_packet = packetizer.packetize_datagram(datagram);
+                    Self::send_to_client(id, flow, &client_rc, selector, &ipv4_packet);
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_to_client(
+        id: &ConnectionId,
+        flow: &mut Flow,
+        client: &Rc<RefCell<Client>>,
+        selector: &mut Selector,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        let class = TrafficClass::of_flow(id, flow.counters().downlink_bytes());
+        let mut client = client.borrow_mut();
+        if id.destination_port() == dns::DNS_PORT {
+            client
+                .router()
+                .record_dns_response(ipv4_packet.payload().expect("No payload"));
+        }
+        match client.send_to_client(selector, ipv4_packet, class) {
             Ok(_) => {
+                flow.record_downlink(ipv4_packet.raw().len());
                 cx_debug!(
                     target: TAG,
-                    self.id,
+                    id,
                     "Packet ({} bytes) sent to client",
                     ipv4_packet.length()
                 );
                 if log_enabled!(target: TAG, Level::Trace) {
                     cx_trace!(
                         target: TAG,
-                        self.id,
+                        id,
                         "{}",
                         binary::build_packet_string(ipv4_packet.raw())
                     );
                 }
             }
-            Err(_) => cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet"),
+            Err(_) => {
+                cx_warn!(target: TAG, id, "Cannot send to client, drop packet");
+                flow.packet_dropped(DropReason::ClientBufferFull);
+            }
         }
-        Ok(())
     }
 
     fn write(&mut self) -> io::Result<()> {
-        self.client_to_network.write_to(&mut self.socket)?;
+        match self.socks5 {
+            Some(ref mut socks5) => {
+                let mut sender =
+                    Socks5UdpSender::new(&mut self.socket, socks5.destination, &mut socks5.buf);
+                self.client_to_network.write_to(&mut sender)?;
+            }
+            None => self.client_to_network.write_batch_to(&mut self.socket)?,
+        }
         Ok(())
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
+        let ready = if self.socks5.as_ref().is_some_and(|s| !s.is_ready()) {
+            // the association is not established yet
+            Ready::empty()
+        } else if self.client_to_network.is_empty() {
             Ready::readable()
         } else {
             Ready::readable() | Ready::writable()
@@ -236,6 +505,30 @@ impl UdpConnection {
         }
     }
 
+    // the timer is not rescheduled on every packet: on expiration, it is postponed if the
+    // connection has been active in the meantime
+    fn schedule_idle_timeout(&self, selector: &mut Selector) {
+        let weak = self.self_weak.clone();
+        selector.schedule(self.idle_since + IDLE_TIMEOUT, move |selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_idle_timeout(selector);
+            }
+        });
+    }
+
+    fn on_idle_timeout(&mut self, selector: &mut Selector) {
+        if self.closed {
+            return;
+        }
+        if self.idle_since.elapsed() >= IDLE_TIMEOUT {
+            cx_debug!(target: TAG, self.id, "Idle timeout");
+            self.close(selector, CloseReason::IdleTimeout);
+            self.remove_from_router();
+        } else {
+            self.schedule_idle_timeout(selector);
+        }
+    }
+
     fn touch(&mut self) {
         self.idle_since = Instant::now();
     }
@@ -257,20 +550,25 @@ impl Connection for UdpConnection {
             .read_from(ipv4_packet.payload().expect("No payload"))
         {
             Ok(_) => {
+                self.flow.record_uplink(ipv4_packet.raw().len());
                 self.update_interests(selector);
             }
-            Err(err) => cx_warn!(
-                target: TAG,
-                self.id,
-                "Cannot send to network, drop packet: {}",
-                err
-            ),
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.flow.packet_dropped(DropReason::NetworkBufferFull);
+            }
         }
     }
 
-    fn close(&mut self, selector: &mut Selector) {
-        cx_info!(target: TAG, self.id, "Close");
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason) {
+        cx_info!(target: TAG, self.id, "Close ({})", reason);
         self.closed = true;
+        self.flow.finish(reason);
         if let Err(err) = selector.deregister(&self.socket, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -281,14 +579,45 @@ impl Connection for UdpConnection {
                 err
             );
         }
+        if let Some(ref socks5) = self.socks5 {
+            if let Err(err) = selector.deregister(&socks5.control, socks5.control_token) {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Fail to deregister SOCKS5 control stream: {:?}",
+                    err
+                );
+            }
+        }
         // socket will be closed by RAII
     }
 
-    fn is_expired(&self) -> bool {
-        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+    fn abort(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        // there is nothing to reset on the client side
+        self.close(selector, CloseReason::Aborted);
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn idle_since(&self) -> Option<Instant> {
+        // UDP flows have no explicit end, the application may not use them anymore
+        Some(self.idle_since)
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        let state = match self.socks5 {
+            Some(ref socks5) if !socks5.is_ready() => "associating",
+            _ => "open",
+        };
+        ConnectionStats {
+            id: self.id.clone(),
+            upstream: self.flow.upstream(),
+            state,
+            age: self.flow.age(),
+            counters: *self.flow.counters(),
+            uplink_buffered: self.client_to_network.size(),
+        }
+    }
 }
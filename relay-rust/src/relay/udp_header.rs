@@ -73,10 +73,7 @@ macro_rules! udp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -160,6 +157,7 @@ mod tests {
     use super::*;
     use byteorder::{BigEndian, WriteBytesExt};
 
+    #[allow(clippy::reserve_after_initialization)]
     fn create_header() -> Vec<u8> {
         let mut raw = Vec::new();
         raw.reserve(8);
@@ -0,0 +1,88 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::net::SocketAddrV4;
+
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::loopback_policy::{self, LoopbackAccess};
+use super::proxy::ProxyConfig;
+use super::socket_binding::SocketBinding;
+use super::upstream_selector::Route;
+
+/// How to reach the destination of a new connection.
+pub struct Upstream<'a> {
+    destination: SocketAddrV4,
+    loopback_access: &'a LoopbackAccess,
+    proxy: Option<&'a ProxyConfig>,
+    binding: &'a SocketBinding,
+}
+
+impl<'a> Upstream<'a> {
+    /// `device` is the serial of the device of the client, if identified.
+    ///
+    /// `hostname` is the name the client resolved to the destination address, if known.
+    pub fn new(
+        config: &'a RelayConfig,
+        device: Option<&str>,
+        id: &ConnectionId,
+        hostname: Option<&str>,
+    ) -> Self {
+        let destination =
+            config
+                .nat_table()
+                .rewrite(id.destination_ip(), id.destination_port(), hostname);
+        // the host loopback is never reached through a proxy
+        let proxy = config.upstream_proxy().filter(|_| {
+            !loopback_policy::is_loopback(destination.ip())
+                && !config.no_proxy().contains(destination.ip(), hostname)
+                && config.upstream_selector().select(&destination, hostname) == Route::Proxy
+        });
+        Self {
+            destination,
+            loopback_access: config.loopback_policy().access(device),
+            proxy,
+            binding: config.socket_binding_policy().binding(device),
+        }
+    }
+
+    /// The destination, after NAT.
+    pub fn destination(&self) -> SocketAddrV4 {
+        self.destination
+    }
+
+    pub fn check_access(&self) -> io::Result<()> {
+        self.loopback_access.check(&self.destination)
+    }
+
+    /// The options to apply to the upstream sockets.
+    pub fn binding(&self) -> &'a SocketBinding {
+        self.binding
+    }
+
+    /// The proxy to open a TCP connection through, if any.
+    pub fn proxy(&self) -> Option<&'a ProxyConfig> {
+        self.proxy
+    }
+
+    /// The proxy to relay UDP datagrams through, if any.
+    ///
+    /// Proxies unable to relay UDP are bypassed.
+    pub fn datagram_proxy(&self) -> Option<&'a ProxyConfig> {
+        self.proxy.filter(|proxy| proxy.kind().supports_udp())
+    }
+}
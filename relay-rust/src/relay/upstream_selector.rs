@@ -0,0 +1,180 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::SocketAddrV4;
+
+/// How to reach a destination when an upstream proxy is configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    Proxy,
+}
+
+/// Policy selecting, for every new connection, whether it goes through the upstream proxy.
+///
+/// It is not consulted for destinations which never use the proxy (the host loopback and the
+/// no-proxy list).
+pub trait UpstreamSelector: fmt::Debug + Send + Sync {
+    /// Select the route to `destination` (after NAT).
+    ///
+    /// `hostname` is the name the device resolved to the destination address, if known.
+    fn select(&self, destination: &SocketAddrV4, hostname: Option<&str>) -> Route;
+}
+
+/// Pattern matching the host name a device resolved to a destination address.
+///
+/// Syntax: a host name, `*.DOMAIN` (any subdomain of `DOMAIN`) or `*` (any destination).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostnamePattern {
+    // "*": any destination, even if its host name is unknown
+    Any,
+    // "*.example.com": any subdomain
+    Suffix(String),
+    Exact(String),
+}
+
+/// Route selection by host name, as resolved by the device.
+///
+/// Syntax: `RULE[,RULE...]`, where `RULE` is `PATTERN=direct` or `PATTERN=proxy`, and `PATTERN` is
+/// a host name, `*.DOMAIN` (any subdomain of `DOMAIN`) or `*` (any destination). The first
+/// matching rule applies; destinations matching no rule go through the proxy.
+///
+/// For example, `*.internal.example=direct` connects directly to the internal hosts, while
+/// `*.public.example=proxy,*=direct` only proxies the public ones.
+#[derive(Clone, Debug, Default)]
+pub struct HostnameRoutes {
+    rules: Vec<(HostnamePattern, Route)>,
+}
+
+impl HostnamePattern {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim_end_matches('.').to_ascii_lowercase();
+        if s == "*" {
+            Ok(HostnamePattern::Any)
+        } else if let Some(domain) = s.strip_prefix("*.") {
+            Ok(HostnamePattern::Suffix(format!(".{}", domain)))
+        } else if !s.is_empty() && !s.contains('*') {
+            Ok(HostnamePattern::Exact(s))
+        } else {
+            Err(format!("Invalid host name pattern: \"{}\"", s))
+        }
+    }
+
+    pub fn matches(&self, hostname: Option<&str>) -> bool {
+        match (self, hostname) {
+            (HostnamePattern::Any, _) => true,
+            (HostnamePattern::Suffix(suffix), Some(hostname)) => hostname.ends_with(suffix),
+            (HostnamePattern::Exact(name), Some(hostname)) => name == hostname,
+            (_, None) => false,
+        }
+    }
+}
+
+impl fmt::Display for HostnamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostnamePattern::Any => write!(f, "*"),
+            HostnamePattern::Suffix(suffix) => write!(f, "*{}", suffix),
+            HostnamePattern::Exact(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl HostnameRoutes {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let index = rule
+                .find('=')
+                .ok_or_else(|| format!("Invalid route (expected PATTERN=ROUTE): \"{}\"", rule))?;
+            let pattern = HostnamePattern::parse(rule[..index].trim())?;
+            let route = match rule[index + 1..].trim() {
+                "direct" => Route::Direct,
+                "proxy" => Route::Proxy,
+                route => return Err(format!("Invalid route: \"{}\"", route)),
+            };
+            rules.push((pattern, route));
+        }
+        Ok(Self { rules })
+    }
+}
+
+impl UpstreamSelector for HostnameRoutes {
+    fn select(&self, _destination: &SocketAddrV4, hostname: Option<&str>) -> Route {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(hostname))
+            .map_or(Route::Proxy, |&(_, route)| route)
+    }
+}
+
+impl fmt::Display for HostnameRoutes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|(pattern, route)| match route {
+                Route::Direct => format!("{}=direct", pattern),
+                Route::Proxy => format!("{}=proxy", pattern),
+            })
+            .collect();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn select(routes: &HostnameRoutes, hostname: Option<&str>) -> Route {
+        let destination = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443);
+        routes.select(&destination, hostname)
+    }
+
+    #[test]
+    fn route_internal_hosts_directly() {
+        let routes = HostnameRoutes::parse("*.internal.example=direct").unwrap();
+        assert_eq!(Route::Direct, select(&routes, Some("a.internal.example")));
+        assert_eq!(Route::Direct, select(&routes, Some("b.a.internal.example")));
+        assert_eq!(Route::Proxy, select(&routes, Some("internal.example")));
+        assert_eq!(Route::Proxy, select(&routes, Some("www.example.com")));
+        assert_eq!(Route::Proxy, select(&routes, None));
+    }
+
+    #[test]
+    fn route_public_hosts_through_proxy() {
+        let routes = HostnameRoutes::parse("*.public.example=proxy, *=direct").unwrap();
+        assert_eq!(Route::Proxy, select(&routes, Some("www.public.example")));
+        assert_eq!(Route::Direct, select(&routes, Some("staging.corp")));
+        assert_eq!(Route::Direct, select(&routes, None));
+        assert_eq!("*.public.example=proxy,*=direct", routes.to_string());
+    }
+
+    #[test]
+    fn match_case_insensitive() {
+        let routes = HostnameRoutes::parse("Staging.Example.=direct").unwrap();
+        assert_eq!(Route::Direct, select(&routes, Some("staging.example")));
+    }
+
+    #[test]
+    fn parse_invalid_routes() {
+        assert!(HostnameRoutes::parse("*.example").is_err());
+        assert!(HostnameRoutes::parse("*.example=vpn").is_err());
+        assert!(HostnameRoutes::parse("a*.example=direct").is_err());
+    }
+}
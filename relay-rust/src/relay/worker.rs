@@ -0,0 +1,358 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::TcpStream;
+use mio::Event;
+use std::cell::RefCell;
+use std::io;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::buffer_pool;
+use super::client::Client;
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::selector::Selector;
+use super::selector::Wake;
+use super::stats::ClientStats;
+
+const TAG: &str = "Worker";
+const TRAFFIC_PUBLISHING_INTERVAL: Duration = Duration::from_secs(10);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// messages sent to a worker from the main thread
+enum Message {
+    Client(u32, Option<Arc<str>>, TcpStream),
+    Stats(Sender<Vec<ClientStats>>),
+    DisconnectClient(u32, Sender<bool>),
+    AbortConnection(u32, ConnectionId, Sender<bool>),
+    Reload(Box<RelayConfig>),
+}
+
+/// Handle to a worker event loop, shared by the `TunnelServer` and the control servers.
+///
+/// A worker runs its own `Selector` on a dedicated thread, and owns the clients handed off to it
+/// (along with their routers and connections). The event loops only share the thread-safe
+/// runtime state of the `RelayConfig`.
+pub struct WorkerHandle {
+    id: usize,
+    sender: Sender<Message>,
+    waker: Box<dyn Wake>,
+    clients: Arc<AtomicUsize>,
+}
+
+impl WorkerHandle {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The number of clients currently running on this worker.
+    pub fn client_count(&self) -> usize {
+        self.clients.load(Ordering::Relaxed)
+    }
+
+    /// Hand off an accepted client stream to the worker.
+    ///
+    /// `device` is the serial of the device, if the client was accepted on its tunnel port.
+    pub fn dispatch(
+        &self,
+        client_id: u32,
+        device: Option<Arc<str>>,
+        stream: TcpStream,
+    ) -> io::Result<()> {
+        self.send(Message::Client(client_id, device, stream))?;
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        // wake up the worker event loop
+        self.waker.wake()
+    }
+
+    /// Collect the state of the clients running on this worker.
+    ///
+    /// Block until the worker replies.
+    pub fn stats(&self) -> io::Result<Vec<ClientStats>> {
+        self.request(Message::Stats)
+    }
+
+    /// Disconnect a client, if it runs on this worker.
+    ///
+    /// Return `false` if the client is unknown to this worker.
+    pub fn disconnect_client(&self, client_id: u32) -> io::Result<bool> {
+        self.request(|reply| Message::DisconnectClient(client_id, reply))
+    }
+
+    /// Close a connection of a client, if it runs on this worker.
+    ///
+    /// Return `false` if the client or the connection is unknown to this worker.
+    pub fn abort_connection(&self, client_id: u32, id: &ConnectionId) -> io::Result<bool> {
+        self.request(|reply| Message::AbortConnection(client_id, id.clone(), reply))
+    }
+
+    /// Apply a new configuration to the clients running on this worker.
+    ///
+    /// The clients keep their existing connections; the new ones use the new configuration.
+    pub fn reload(&self, config: RelayConfig) -> io::Result<()> {
+        self.send(Message::Reload(Box::new(config)))?;
+        self.waker.wake()
+    }
+
+    fn request<T, F>(&self, message: F) -> io::Result<T>
+    where
+        F: FnOnce(Sender<T>) -> Message,
+    {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+        self.send(message(reply_sender))?;
+        self.waker.wake()?;
+        reply_receiver.recv_timeout(REPLY_TIMEOUT).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Worker #{} did not reply", self.id),
+            )
+        })
+    }
+
+    fn send(&self, message: Message) -> io::Result<()> {
+        self.sender.send(message).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("Worker #{} is not running", self.id),
+            )
+        })
+    }
+}
+
+/// Start a worker event loop on a new thread.
+pub fn spawn(id: usize, config: RelayConfig) -> io::Result<WorkerHandle> {
+    let (sender, receiver) = mpsc::channel();
+    let (startup_sender, startup_receiver) = mpsc::channel();
+    let clients = Arc::new(AtomicUsize::new(0));
+    let client_count = clients.clone();
+    thread::Builder::new()
+        .name(format!("worker-{}", id))
+        .spawn(move || {
+            let mut selector = match Worker::start(id, receiver, client_count, config) {
+                Ok((selector, waker)) => {
+                    let _ = startup_sender.send(Ok(waker));
+                    selector
+                }
+                Err(err) => {
+                    let _ = startup_sender.send(Err(err));
+                    return;
+                }
+            };
+            if let Err(err) = selector.run() {
+                error!(target: TAG, "Worker #{} stopped: {}", id, err);
+            }
+        })?;
+    let waker = startup_receiver
+        .recv()
+        .map_err(|_| io::Error::other(format!("Worker #{} failed to start", id)))??;
+    Ok(WorkerHandle {
+        id,
+        sender,
+        waker,
+        clients,
+    })
+}
+
+struct Worker {
+    id: usize,
+    self_weak: Weak<RefCell<Worker>>,
+    clients: Vec<Rc<RefCell<Client>>>,
+    receiver: Receiver<Message>,
+    client_count: Arc<AtomicUsize>,
+    config: Rc<RelayConfig>,
+}
+
+impl Worker {
+    fn start(
+        id: usize,
+        receiver: Receiver<Message>,
+        client_count: Arc<AtomicUsize>,
+        config: RelayConfig,
+    ) -> io::Result<(Selector, Box<dyn Wake>)> {
+        let mut selector = Selector::create()?;
+        buffer_pool::install(config.memory_budget().clone());
+        let rc = Rc::new(RefCell::new(Self {
+            id,
+            self_weak: Weak::new(),
+            clients: Vec::new(),
+            receiver,
+            client_count,
+            config: Rc::new(config),
+        }));
+
+        // keep a shared reference to this
+        rc.borrow_mut().self_weak = Rc::downgrade(&rc);
+
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        let waker = selector.register_waker(handler)?;
+        Self::schedule_traffic_publishing(&mut selector, Rc::downgrade(&rc));
+        debug!(target: TAG, "Worker #{} started", id);
+        Ok((selector, waker))
+    }
+
+    // publish the traffic counters of the clients regularly
+    fn schedule_traffic_publishing(selector: &mut Selector, weak: Weak<RefCell<Worker>>) {
+        let deadline = Instant::now() + TRAFFIC_PUBLISHING_INTERVAL;
+        selector.schedule(deadline, move |selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow().publish_traffic();
+                Self::schedule_traffic_publishing(selector, weak);
+            }
+        });
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, _: Event) {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(Message::Client(client_id, device, stream)) => {
+                    self.add_client(selector, client_id, device, stream)
+                }
+                Ok(Message::Stats(reply)) => {
+                    // the requester may have given up
+                    let _ = reply.send(self.stats());
+                }
+                Ok(Message::DisconnectClient(client_id, reply)) => {
+                    let _ = reply.send(self.disconnect_client(selector, client_id));
+                }
+                Ok(Message::AbortConnection(client_id, id, reply)) => {
+                    let _ = reply.send(self.abort_connection(selector, client_id, &id));
+                }
+                Ok(Message::Reload(config)) => self.reload(*config),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    debug!(target: TAG, "Worker #{} disconnected from the server", self.id);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_client(
+        &mut self,
+        selector: &mut Selector,
+        client_id: u32,
+        device: Option<Arc<str>>,
+        stream: TcpStream,
+    ) {
+        let weak = self.self_weak.clone();
+        let on_client_closed = Box::new(move |client: &Client| {
+            if let Some(rc) = weak.upgrade() {
+                let mut worker = rc.borrow_mut();
+                worker.remove_client(client);
+            } else {
+                warn!(target: TAG, "on_client_closed called but no worker available");
+            }
+        });
+        match Client::create(
+            client_id,
+            device.clone(),
+            selector,
+            stream,
+            self.config.clone(),
+            on_client_closed,
+        ) {
+            Ok(client) => {
+                self.clients.push(client);
+                match device {
+                    Some(device) => info!(
+                        target: TAG,
+                        "Client #{} connected from device {} (worker #{})", client_id, device, self.id
+                    ),
+                    None => info!(
+                        target: TAG,
+                        "Client #{} connected (worker #{})", client_id, self.id
+                    ),
+                }
+            }
+            Err(err) => {
+                self.client_count.fetch_sub(1, Ordering::Relaxed);
+                error!(target: TAG, "Cannot create client #{}: {}", client_id, err);
+            }
+        }
+    }
+
+    fn remove_client(&mut self, client: &Client) {
+        info!(target: TAG, "Client #{} disconnected", client.id());
+        let index = self
+            .clients
+            .iter()
+            .position(|item| {
+                // compare pointers to find the client to remove
+                ptr::eq(client, item.as_ptr())
+            })
+            .expect("Trying to remove an unknown client");
+        self.clients.swap_remove(index);
+        self.client_count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn find_client(&self, client_id: u32) -> Option<&Rc<RefCell<Client>>> {
+        self.clients
+            .iter()
+            .find(|client| client.borrow().id() == client_id)
+    }
+
+    fn stats(&self) -> Vec<ClientStats> {
+        self.clients
+            .iter()
+            .map(|client| client.borrow().stats(self.id))
+            .collect()
+    }
+
+    fn disconnect_client(&self, selector: &mut Selector, client_id: u32) -> bool {
+        let weak = match self.find_client(client_id) {
+            Some(client) => Rc::downgrade(client),
+            None => return false,
+        };
+        // closing the client removes it from the worker, which is currently borrowed
+        selector.schedule(Instant::now(), move |selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().disconnect(selector);
+            }
+        });
+        true
+    }
+
+    fn abort_connection(&self, selector: &mut Selector, client_id: u32, id: &ConnectionId) -> bool {
+        match self.find_client(client_id) {
+            Some(client) => client.borrow_mut().abort_connection(selector, id),
+            None => false,
+        }
+    }
+
+    fn reload(&mut self, config: RelayConfig) {
+        self.config = Rc::new(config);
+        for client in &self.clients {
+            client.borrow_mut().set_config(self.config.clone());
+        }
+        debug!(target: TAG, "Worker #{} reloaded its configuration", self.id);
+    }
+
+    fn publish_traffic(&self) {
+        for client in &self.clients {
+            client.borrow().publish_traffic();
+        }
+    }
+}
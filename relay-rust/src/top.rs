@@ -0,0 +1,499 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::json::{self, Value};
+use relaylib::ByteCount;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+// clear the screen and move the cursor to the top-left corner
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+// state of the relay, as read from the control server
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    taken_at: Instant,
+    uptime: u64,
+    memory_used: u64,
+    clients: Vec<ClientRow>,
+    connections: Vec<ConnectionRow>,
+}
+
+#[derive(Debug, PartialEq)]
+struct ClientRow {
+    id: u64,
+    age: u64,
+    uplink_bytes: u64,
+    downlink_bytes: u64,
+    connections: usize,
+}
+
+#[derive(Debug, PartialEq)]
+struct ConnectionRow {
+    client: u64,
+    protocol: String,
+    source: String,
+    destination: String,
+    state: String,
+    uplink_bytes: u64,
+    downlink_bytes: u64,
+}
+
+impl ConnectionRow {
+    fn key(&self) -> (u64, &str, &str, &str) {
+        (self.client, &self.protocol, &self.source, &self.destination)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.uplink_bytes + self.downlink_bytes
+    }
+}
+
+// bytes per second in each direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Rate {
+    uplink: u64,
+    downlink: u64,
+}
+
+impl Rate {
+    // None if there is no previous sample
+    fn between(
+        previous: Option<(u64, u64)>,
+        current: (u64, u64),
+        elapsed: Duration,
+    ) -> Option<Self> {
+        let (previous_uplink, previous_downlink) = previous?;
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let per_second = |current: u64, previous: u64| {
+            (current.saturating_sub(previous) as f64 / seconds).round() as u64
+        };
+        Some(Self {
+            uplink: per_second(current.0, previous_uplink),
+            downlink: per_second(current.1, previous_downlink),
+        })
+    }
+
+    fn total(&self) -> u64 {
+        self.uplink + self.downlink
+    }
+}
+
+fn field(value: &Value, key: &str) -> Result<u64, String> {
+    value
+        .get(key)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| format!("Missing field \"{}\"", key))
+}
+
+fn text_field(value: &Value, key: &str) -> Result<String, String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or_else(|| format!("Missing field \"{}\"", key))
+}
+
+fn array_field<'a>(value: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("Missing field \"{}\"", key))
+}
+
+impl Snapshot {
+    fn parse(stats: &str, taken_at: Instant) -> Result<Self, String> {
+        let root = json::parse(stats)?;
+        let mut clients = Vec::new();
+        let mut connections = Vec::new();
+        for client in array_field(&root, "clients")? {
+            let id = field(client, "id")?;
+            let client_connections = array_field(client, "connections")?;
+            clients.push(ClientRow {
+                id,
+                age: field(client, "age")?,
+                uplink_bytes: field(client, "uplink_bytes")?,
+                downlink_bytes: field(client, "downlink_bytes")?,
+                connections: client_connections.len(),
+            });
+            for connection in client_connections {
+                connections.push(ConnectionRow {
+                    client: id,
+                    protocol: text_field(connection, "protocol")?,
+                    source: text_field(connection, "source")?,
+                    destination: text_field(connection, "destination")?,
+                    state: text_field(connection, "state")?,
+                    uplink_bytes: field(connection, "uplink_bytes")?,
+                    downlink_bytes: field(connection, "downlink_bytes")?,
+                });
+            }
+        }
+        Ok(Self {
+            taken_at,
+            uptime: field(&root, "uptime")?,
+            memory_used: field(&root, "memory_used")?,
+            clients,
+            connections,
+        })
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    ByteCount(bytes).to_string()
+}
+
+fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", ByteCount(rate)),
+        None => "-".to_string(),
+    }
+}
+
+// render the view, fitting in height lines (at least the header and the clients are rendered)
+fn render(current: &Snapshot, previous: Option<&Snapshot>, port: u16, height: usize) -> String {
+    let elapsed = previous
+        .map(|previous| current.taken_at.duration_since(previous.taken_at))
+        .unwrap_or_default();
+    let mut out = String::new();
+    writeln!(
+        out,
+        "gnirehtet top - relay 127.0.0.1:{} - up {} - {} clients, {} connections - buffers {}",
+        port,
+        format_duration(current.uptime),
+        current.clients.len(),
+        current.connections.len(),
+        format_bytes(current.memory_used)
+    )
+    .unwrap();
+
+    let client_rates: Vec<Option<Rate>> = current
+        .clients
+        .iter()
+        .map(|client| {
+            let before = previous.map(|previous| {
+                previous
+                    .clients
+                    .iter()
+                    .find(|item| item.id == client.id)
+                    .map_or((0, 0), |item| (item.uplink_bytes, item.downlink_bytes))
+            });
+            Rate::between(
+                before,
+                (client.uplink_bytes, client.downlink_bytes),
+                elapsed,
+            )
+        })
+        .collect();
+    let total = client_rates
+        .iter()
+        .flatten()
+        .fold(Rate::default(), |total, rate| Rate {
+            uplink: total.uplink + rate.uplink,
+            downlink: total.downlink + rate.downlink,
+        });
+    let has_rates = previous.is_some();
+    writeln!(
+        out,
+        "total: up {}  down {}",
+        format_rate(Some(total.uplink).filter(|_| has_rates)),
+        format_rate(Some(total.downlink).filter(|_| has_rates))
+    )
+    .unwrap();
+    out.push('\n');
+
+    writeln!(
+        out,
+        "{:>6}  {:>9}  {:>12}  {:>12}  {:>10}  {:>10}  {:>5}",
+        "CLIENT", "CONNECTED", "UP", "DOWN", "UP TOTAL", "DOWN TOTAL", "CONNS"
+    )
+    .unwrap();
+    for (client, rate) in current.clients.iter().zip(&client_rates) {
+        writeln!(
+            out,
+            "{:>6}  {:>9}  {:>12}  {:>12}  {:>10}  {:>10}  {:>5}",
+            client.id,
+            format_duration(client.age),
+            format_rate(rate.map(|rate| rate.uplink)),
+            format_rate(rate.map(|rate| rate.downlink)),
+            format_bytes(client.uplink_bytes),
+            format_bytes(client.downlink_bytes),
+            client.connections
+        )
+        .unwrap();
+    }
+
+    // the connections having the most traffic first
+    let previous_connections: HashMap<_, _> = previous
+        .map(|previous| {
+            previous
+                .connections
+                .iter()
+                .map(|connection| (connection.key(), connection))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut connections: Vec<(&ConnectionRow, Option<Rate>)> = current
+        .connections
+        .iter()
+        .map(|connection| {
+            let before = previous.map(|_| {
+                previous_connections
+                    .get(&connection.key())
+                    .map_or((0, 0), |item| (item.uplink_bytes, item.downlink_bytes))
+            });
+            let rate = Rate::between(
+                before,
+                (connection.uplink_bytes, connection.downlink_bytes),
+                elapsed,
+            );
+            (connection, rate)
+        })
+        .collect();
+    connections.sort_by_key(|&(connection, rate)| {
+        let rate = rate.map_or(0, |rate| rate.total());
+        std::cmp::Reverse((rate, connection.total_bytes()))
+    });
+
+    out.push('\n');
+    writeln!(
+        out,
+        "{:>6}  {:<5}  {:<21}  {:<21}  {:<12}  {:>12}  {:>12}  {:>10}",
+        "CLIENT", "PROTO", "SOURCE", "DESTINATION", "STATE", "UP", "DOWN", "TOTAL"
+    )
+    .unwrap();
+    let available = height.saturating_sub(out.lines().count());
+    // keep the last line to tell how many connections are not displayed
+    let shown = if connections.len() > available {
+        available.saturating_sub(1)
+    } else {
+        connections.len()
+    };
+    for (connection, rate) in &connections[..shown] {
+        writeln!(
+            out,
+            "{:>6}  {:<5}  {:<21}  {:<21}  {:<12}  {:>12}  {:>12}  {:>10}",
+            connection.client,
+            connection.protocol,
+            connection.source,
+            connection.destination,
+            connection.state,
+            format_rate(rate.map(|rate| rate.uplink)),
+            format_rate(rate.map(|rate| rate.downlink)),
+            format_bytes(connection.total_bytes())
+        )
+        .unwrap();
+    }
+    if shown < connections.len() {
+        writeln!(out, "({} more connections)", connections.len() - shown).unwrap();
+    }
+    out
+}
+
+fn fetch_stats(port: u16) -> io::Result<String> {
+    let addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(b"GET /stats HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("Invalid HTTP response"))?;
+    let status = head.lines().next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(invalid(status));
+    }
+    Ok(body.to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn terminal_height() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ only writes into the winsize struct
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_row > 0 {
+        size.ws_row as usize
+    } else {
+        24
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn terminal_height() -> usize {
+    24
+}
+
+fn fetch_snapshot(port: u16) -> io::Result<Snapshot> {
+    let stats = fetch_stats(port)?;
+    Snapshot::parse(&stats, Instant::now())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn display(screen: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{}{}", CLEAR_SCREEN, screen)?;
+    stdout.flush()
+}
+
+/// Display the activity of the relay serving control requests on `port`, until interrupted.
+pub fn run(port: u16) -> io::Result<()> {
+    // fail early if the relay is not reachable at all
+    let mut previous = fetch_snapshot(port).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "Cannot read the statistics of the relay on 127.0.0.1:{} (is it started with \
+                 -C {}?): {}",
+                port, port, err
+            ),
+        )
+    })?;
+    display(&render(&previous, None, port, terminal_height() - 1))?;
+    let mut reachable = true;
+    loop {
+        thread::sleep(REFRESH_INTERVAL);
+        match fetch_snapshot(port) {
+            Ok(snapshot) => {
+                // no rates right after the relay became reachable again
+                let last = if reachable { Some(&previous) } else { None };
+                display(&render(&snapshot, last, port, terminal_height() - 1))?;
+                previous = snapshot;
+                reachable = true;
+            }
+            Err(err) => {
+                display(&format!(
+                    "Cannot read the statistics of the relay on 127.0.0.1:{}: {}\n\
+                     (the relay must be started with -C {})\n",
+                    port, err, port
+                ))?;
+                reachable = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATS: &str = r#"{"uptime":75,"workers":1,"client_count":1,"connection_count":2,
+        "memory_used":8192,"memory_peak":8192,"memory_pooled":0,"memory_limit":null,
+        "uplink_bytes":3000,"uplink_packets":10,"downlink_bytes":50000,"downlink_packets":40,
+        "clients":[{"id":0,"worker":0,"age":70,"uplink_bytes":3000,"uplink_packets":10,
+        "downlink_bytes":50000,"downlink_packets":40,"downlink_buffered":0,
+        "buffer_full_events":0,"connections":[
+        {"protocol":"tcp","source":"10.0.0.2:40000","destination":"1.2.3.4:443",
+        "upstream":"1.2.3.4:443","state":"established","age":60,"uplink_bytes":1000,
+        "uplink_packets":4,"downlink_bytes":45000,"downlink_packets":30,"uplink_buffered":0},
+        {"protocol":"udp","source":"10.0.0.2:50000","destination":"8.8.8.8:53",
+        "upstream":"8.8.8.8:53","state":"open","age":2,"uplink_bytes":2000,
+        "uplink_packets":6,"downlink_bytes":5000,"downlink_packets":10,"uplink_buffered":0}
+        ]}]}"#;
+
+    #[test]
+    fn parse_snapshot() {
+        let snapshot = Snapshot::parse(STATS, Instant::now()).unwrap();
+        assert_eq!(75, snapshot.uptime);
+        assert_eq!(8192, snapshot.memory_used);
+        assert_eq!(
+            vec![ClientRow {
+                id: 0,
+                age: 70,
+                uplink_bytes: 3000,
+                downlink_bytes: 50000,
+                connections: 2,
+            }],
+            snapshot.clients
+        );
+        assert_eq!(2, snapshot.connections.len());
+        assert_eq!("udp", snapshot.connections[1].protocol);
+        assert_eq!("8.8.8.8:53", snapshot.connections[1].destination);
+        assert_eq!(7000, snapshot.connections[1].total_bytes());
+
+        assert!(Snapshot::parse(r#"{"uptime":1}"#, Instant::now()).is_err());
+    }
+
+    #[test]
+    fn compute_rates() {
+        let elapsed = Duration::from_secs(2);
+        assert_eq!(None, Rate::between(None, (100, 100), elapsed));
+        assert_eq!(
+            Some(Rate {
+                uplink: 50,
+                downlink: 1000
+            }),
+            Rate::between(Some((100, 0)), (200, 2000), elapsed)
+        );
+        // counters reset (e.g. another connection with the same addresses)
+        assert_eq!(
+            Some(Rate::default()),
+            Rate::between(Some((500, 500)), (100, 100), elapsed)
+        );
+    }
+
+    #[test]
+    fn render_busiest_connections_first() {
+        let now = Instant::now();
+        let previous = Snapshot::parse(STATS, now).unwrap();
+        let mut current = Snapshot::parse(STATS, now + Duration::from_secs(1)).unwrap();
+        // only the UDP flow transferred data during the last second
+        current.connections[1].downlink_bytes += 4096;
+        current.clients[0].downlink_bytes += 4096;
+
+        let screen = render(&current, Some(&previous), 31417, 24);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert!(lines[0].contains("up 1m15s - 1 clients, 2 connections"));
+        assert!(lines[1].contains("down 4.1 KB/s"));
+        assert!(lines[7].contains("8.8.8.8:53"));
+        assert!(lines[8].contains("1.2.3.4:443"));
+
+        // without a previous snapshot, sort by total traffic and show no rates
+        let screen = render(&previous, None, 31417, 24);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert!(lines[1].contains("up -  down -"));
+        assert!(lines[7].contains("1.2.3.4:443"));
+    }
+
+    #[test]
+    fn render_truncated() {
+        let snapshot = Snapshot::parse(STATS, Instant::now()).unwrap();
+        let screen = render(&snapshot, None, 31417, 9);
+        assert_eq!(9, screen.lines().count());
+
+        let screen = render(&snapshot, None, 31417, 8);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert_eq!(8, lines.len());
+        assert_eq!("(2 more connections)", lines[7]);
+    }
+}